@@ -0,0 +1,132 @@
+//! Count heap allocations of hot paths, to check transient buffers are
+//! reused. Counts are only reported when neither `jemalloc` nor `mimalloc`
+//! is enabled, as agatedb sets the global allocator itself with them.
+
+mod common;
+
+use agatedb::{key_with_ts, Agate, AgateOptions, Entry, TableBuilder, TableOptions, Value};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use common::rand_value;
+use criterion::{criterion_group, criterion_main, Criterion};
+use tempdir::TempDir;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+mod counting {
+    use super::ALLOCATIONS;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::Ordering;
+
+    struct CountingAlloc;
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+}
+
+/// Benchmark `f`, and print the average number of allocations it makes.
+fn bench_allocations(c: &mut Criterion, name: &str, mut f: impl FnMut()) {
+    let (mut iterations, mut allocations) = (0, 0);
+    c.bench_function(name, |b| {
+        b.iter_custom(|iters| {
+            let before = ALLOCATIONS.load(Ordering::Relaxed);
+            let start = Instant::now();
+            for _ in 0..iters {
+                f();
+            }
+            let elapsed = start.elapsed();
+            allocations += ALLOCATIONS.load(Ordering::Relaxed) - before;
+            iterations += iters;
+            elapsed
+        })
+    });
+    if cfg!(not(any(feature = "jemalloc", feature = "mimalloc"))) {
+        println!(
+            "{}: {:.2} allocations per iteration",
+            name,
+            allocations as f64 / iterations.max(1) as f64
+        );
+    }
+}
+
+fn bench_table_builder(c: &mut Criterion) {
+    let keys: Vec<_> = (0..10000)
+        .map(|i| key_with_ts(format!("{:032}", i).as_str(), 1))
+        .collect();
+    let value = Value::new(Bytes::from(rand_value()));
+    let opts = TableOptions {
+        block_size: 4 * 1024,
+        bloom_false_positive: 0.01,
+        table_size: 1 << 20,
+        ..Default::default()
+    };
+    bench_allocations(c, "alloc table builder", || {
+        let mut builder = TableBuilder::new(opts.clone());
+        for key in &keys {
+            builder.add(key, value.clone(), 0);
+        }
+        builder.finish();
+    });
+}
+
+fn bench_value_decode(c: &mut Criterion) {
+    let mut buf = BytesMut::new();
+    Value::new(Bytes::from(rand_value())).encode(&mut buf);
+    let encoded = buf.freeze();
+    let mut value = Value::default();
+    bench_allocations(c, "alloc value decode", || value.decode(&encoded));
+}
+
+fn bench_wal_write(c: &mut Criterion) {
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let mut opts = AgateOptions::default();
+    opts.mem_table_size = 1 << 20;
+    let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+    let value = Bytes::from(rand_value());
+    let mut version = 0;
+    bench_allocations(c, "alloc wal write", || {
+        version += 1;
+        let entries = (0..16)
+            .map(|i| {
+                Entry::new(
+                    key_with_ts(format!("key{:02}", i).as_str(), version),
+                    value.clone(),
+                )
+            })
+            .collect();
+        agate.write_entries(entries).unwrap();
+    });
+}
+
+criterion_group! {
+    name = benches_alloc;
+    config = Criterion::default();
+    targets = bench_table_builder, bench_value_decode, bench_wal_write
+}
+
+criterion_main!(benches_alloc);
@@ -1,12 +1,14 @@
 mod common;
 
-use agatedb::{get_ts, key_with_ts};
+use agatedb::{get_ts, key_with_ts, with_key_ts, KeyTs};
+use bytes::BytesMut;
 use criterion::{criterion_group, criterion_main, Criterion};
 
-#[cfg(not(target_env = "msvc"))]
+// agatedb sets the global allocator itself with `jemalloc` or `mimalloc`.
+#[cfg(not(any(target_env = "msvc", feature = "jemalloc", feature = "mimalloc")))]
 use tikv_jemallocator::Jemalloc;
 
-#[cfg(not(target_env = "msvc"))]
+#[cfg(not(any(target_env = "msvc", feature = "jemalloc", feature = "mimalloc")))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
@@ -14,6 +16,16 @@ fn bench_format(c: &mut Criterion) {
     c.bench_function("format make key with ts", |b| {
         b.iter(|| key_with_ts("aaabbbcccddd", 233))
     });
+    c.bench_function("format encode key with ts to buffer", |b| {
+        let mut buf = BytesMut::with_capacity(64);
+        b.iter(|| {
+            buf.clear();
+            KeyTs::new(b"aaabbbcccddd", 233).encode_to(&mut buf);
+        })
+    });
+    c.bench_function("format make key with ts in thread local buffer", |b| {
+        b.iter(|| with_key_ts(KeyTs::new(b"aaabbbcccddd", 233), |key| key.len()))
+    });
     let key = key_with_ts("aaabbbcccddd", 233);
     c.bench_function("format get ts", |b| b.iter(|| get_ts(&key)));
 }
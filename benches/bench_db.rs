@@ -0,0 +1,171 @@
+mod common;
+
+use agatedb::{key_with_ts, test_util, Agate, AgateOptions, CachePolicy, Entry};
+
+use bytes::Bytes;
+use common::rand_value;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rand::Rng;
+use tempdir::TempDir;
+
+// agatedb sets the global allocator itself with `jemalloc` or `mimalloc`.
+#[cfg(not(any(target_env = "msvc", feature = "jemalloc", feature = "mimalloc")))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+const KEY_COUNT: usize = 100000;
+const BATCH_SIZE: usize = 16;
+
+fn key(i: usize) -> String {
+    format!("key{:016}", i)
+}
+
+fn bench_options() -> AgateOptions {
+    AgateOptions::default()
+        .with_mem_table_size(16 << 20)
+        .with_block_cache(64 << 20, CachePolicy::Lru)
+}
+
+/// Open a database with `KEY_COUNT` keys, which are flushed to `tables`
+/// L0 tables. Keys of tables interleave, so iterators merge all tables.
+fn open_with_keys(tmp_dir: &TempDir, tables: usize) -> Agate {
+    let agate = Agate::open(bench_options(), tmp_dir.path()).unwrap();
+    let value = Bytes::from(rand_value());
+    for t in 0..tables {
+        let keys: Vec<_> = (t..KEY_COUNT).step_by(tables).collect();
+        for batch in keys.chunks(BATCH_SIZE) {
+            let entries = batch
+                .iter()
+                .map(|&i| Entry::new(key_with_ts(key(i).as_str(), 1), value.clone()))
+                .collect();
+            agate.write_entries(entries).unwrap();
+        }
+        agate.flush_memtable(true).unwrap();
+    }
+    // Reopen, so that reads see versions written without transactions.
+    test_util::reopen(agate).unwrap()
+}
+
+fn bench_write(c: &mut Criterion) {
+    let value = Bytes::from(rand_value());
+    let mut write = |name: &str, next_key: &mut dyn FnMut() -> usize| {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(bench_options(), tmp_dir.path()).unwrap();
+        let mut version = 0;
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                version += 1;
+                let entries = (0..BATCH_SIZE)
+                    .map(|_| {
+                        Entry::new(
+                            key_with_ts(key(next_key()).as_str(), version),
+                            value.clone(),
+                        )
+                    })
+                    .collect();
+                agate.write_entries(entries).unwrap();
+            })
+        });
+    };
+
+    let mut i = 0;
+    write("db sequential write", &mut || {
+        i += 1;
+        i
+    });
+    let mut rng = rand::thread_rng();
+    write("db random write", &mut || rng.gen_range(0, KEY_COUNT));
+}
+
+fn bench_get(c: &mut Criterion) {
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = open_with_keys(&tmp_dir, 4);
+    let mut rng = rand::thread_rng();
+    for hit_rate in [0, 50, 100] {
+        c.bench_function(&format!("db point get with {}% hits", hit_rate), |b| {
+            b.iter_batched(
+                || {
+                    // Missing keys fall between existing ones.
+                    let i = rng.gen_range(0, KEY_COUNT);
+                    if rng.gen_range(0, 100) < hit_rate {
+                        key_with_ts(key(i).as_str(), u64::MAX)
+                    } else {
+                        key_with_ts(format!("{}0", key(i)).as_str(), u64::MAX)
+                    }
+                },
+                |key| {
+                    let _ = agate.get(&key);
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+fn bench_scan(c: &mut Criterion) {
+    for tables in [1, 4] {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = open_with_keys(&tmp_dir, tables);
+        c.bench_function(&format!("db scan merging {} tables", tables), |b| {
+            b.iter(|| {
+                let mut count = 0;
+                agate
+                    .snapshot()
+                    .scan(b"", b"", |_| {
+                        count += 1;
+                        Ok(true)
+                    })
+                    .unwrap();
+                assert_eq!(count, KEY_COUNT);
+            })
+        });
+    }
+
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = open_with_keys(&tmp_dir, 4);
+    let mut rng = rand::thread_rng();
+    c.bench_function("db short scan", |b| {
+        b.iter_batched(
+            || key(rng.gen_range(0, KEY_COUNT - 100)),
+            |start| {
+                let mut count = 0;
+                agate
+                    .snapshot()
+                    .scan(start.as_bytes(), b"", |_| {
+                        count += 1;
+                        Ok(count < 100)
+                    })
+                    .unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_wal_replay(c: &mut Criterion) {
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = Agate::open(bench_options(), tmp_dir.path()).unwrap();
+    let value = Bytes::from(rand_value());
+    let keys: Vec<_> = (0..KEY_COUNT / 10).collect();
+    for batch in keys.chunks(BATCH_SIZE) {
+        let entries = batch
+            .iter()
+            .map(|&i| Entry::new(key_with_ts(key(i).as_str(), 1), value.clone()))
+            .collect();
+        agate.write_entries(entries).unwrap();
+    }
+    // Mutable memtable is not flushed by reopening, so its WAL is decoded
+    // every time.
+    let mut agate = Some(agate);
+    c.bench_function("db wal replay", |b| {
+        b.iter(|| agate = Some(test_util::reopen(agate.take().unwrap()).unwrap()))
+    });
+}
+
+criterion_group! {
+    name = benches_db;
+    config = Criterion::default().sample_size(20);
+    targets = bench_write, bench_get, bench_scan, bench_wal_replay
+}
+
+criterion_main!(benches_db);
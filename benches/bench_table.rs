@@ -11,16 +11,9 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use rand::Rng;
 use tempdir::TempDir;
 
-fn bench_table_builder(c: &mut Criterion) {
-    c.bench_function("table builder", |b| {
-        let mut key_list = vec![];
-
-        const KEY_COUNT: usize = 1300000; // about 64MB
-
-        for i in 0..KEY_COUNT {
-            let k = Bytes::from(format!("{:032}", i));
-            key_list.push(k);
-        }
+fn bench_builder(c: &mut Criterion, name: &str, key_count: usize, key: impl Fn(usize) -> Bytes) {
+    c.bench_function(name, |b| {
+        let key_list: Vec<_> = (0..key_count).map(&key).collect();
 
         let vs = Value::new(Bytes::from(rand_value()));
 
@@ -29,18 +22,31 @@ fn bench_table_builder(c: &mut Criterion) {
             bloom_false_positive: 0.01,
             table_size: 5 << 20,
             checksum_mode: NoVerification,
+            ..Default::default()
         };
 
         b.iter(|| {
             let mut builder = TableBuilder::new(opt.clone());
-            for j in 0..KEY_COUNT {
-                builder.add(&key_list[j], vs.clone(), 0);
+            for key in &key_list {
+                builder.add(key, vs.clone(), 0);
             }
             builder.finish()
         });
     });
 }
 
+fn bench_table_builder(c: &mut Criterion) {
+    // about 64MB
+    bench_builder(c, "table builder", 1300000, |i| {
+        Bytes::from(format!("{:032}", i))
+    });
+    // Long keys share long prefixes with base keys of blocks.
+    let prefix = "k".repeat(256);
+    bench_builder(c, "table builder with long keys", 200000, |i| {
+        Bytes::from(format!("{}{:032}", prefix, i))
+    });
+}
+
 /// TableGuard saves Table and TempDir, so as to ensure
 /// temporary directory is removed after table is closed.
 /// According to Rust RFC, the drop order is first `table` then
@@ -65,14 +71,19 @@ impl DerefMut for TableGuard {
 }
 
 fn get_table_for_benchmark(count: usize) -> TableGuard {
+    get_table_with_block_size(count, 4 * 1024)
+}
+
+fn get_table_with_block_size(count: usize, block_size: usize) -> TableGuard {
     let tmp_dir = TempDir::new("agatedb").unwrap();
 
     let opts = TableOptions {
         // TODO: add compression parameter
-        block_size: 4 * 1024,
+        block_size,
         bloom_false_positive: 0.01,
         table_size: 0,
         checksum_mode: NoVerification,
+        ..Default::default()
     };
 
     let mut builder = TableBuilder::new(opts.clone());
@@ -103,11 +114,24 @@ fn bench_table(c: &mut Criterion) {
         });
     });
 
+    // Large blocks make iteration dominated by decoding entries of blocks.
+    c.bench_function("table block iteration", |b| {
+        let table = get_table_with_block_size(n, 256 * 1024);
+        b.iter(|| {
+            let mut it = table.new_iterator(0);
+            it.seek_to_first();
+            while it.valid() {
+                it.next();
+            }
+        });
+    });
+
     let builder_opts = TableOptions {
         block_size: 4 * 1024,
         bloom_false_positive: 0.01,
         table_size: 0,
         checksum_mode: NoVerification,
+        ..Default::default()
     };
 
     c.bench_function("table read and build", |b| {
@@ -146,6 +170,20 @@ fn bench_table(c: &mut Criterion) {
             criterion::BatchSize::SmallInput,
         );
     });
+
+    // Large blocks make seeks dominated by binary search among entries.
+    c.bench_function("table random seek in large blocks", |b| {
+        let table = get_table_with_block_size(n, 256 * 1024);
+        let mut it = table.new_iterator(0);
+        b.iter_batched(
+            || Bytes::from(format!("{:016x}", rng.gen_range(0, n))),
+            |k| {
+                it.seek(&k);
+                assert!(it.valid());
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
 }
 
 criterion_group! {
@@ -5,4 +5,4 @@ mod list;
 const MAX_HEIGHT: usize = 20;
 
 pub use key::{FixedLengthSuffixComparator, KeyComparator};
-pub use list::Skiplist;
+pub use list::{Hint, IterRef, Skiplist, MAX_NODE_SIZE};
@@ -10,6 +10,9 @@ use std::{mem, ptr, u32};
 
 const HEIGHT_INCREASE: u32 = u32::MAX / 3;
 
+/// Maximum size of a node allocated in arena, not including key and value.
+pub const MAX_NODE_SIZE: usize = mem::size_of::<Node>();
+
 // Uses C layout to make sure tower is at the bottom
 #[derive(Debug)]
 #[repr(C)]
@@ -49,6 +52,30 @@ struct SkiplistCore {
     arena: Arena,
 }
 
+/// `Hint` caches the splice of last insertion, see `Skiplist::put_with_hint`.
+pub struct Hint {
+    /// Keeps arena alive, so nodes in splice are always valid.
+    list: Option<Arc<SkiplistCore>>,
+    prev: [*mut Node; MAX_HEIGHT + 1],
+    next: [*mut Node; MAX_HEIGHT + 1],
+    /// Number of valid levels in splice.
+    height: usize,
+}
+
+impl Default for Hint {
+    fn default() -> Hint {
+        Hint {
+            list: None,
+            prev: [ptr::null_mut(); MAX_HEIGHT + 1],
+            next: [ptr::null_mut(); MAX_HEIGHT + 1],
+            height: 0,
+        }
+    }
+}
+
+// Nodes in hint are never freed before the arena.
+unsafe impl Send for Hint {}
+
 #[derive(Clone)]
 pub struct Skiplist<C> {
     core: Arc<SkiplistCore>,
@@ -165,13 +192,55 @@ impl<C: KeyComparator> Skiplist<C> {
     }
 
     pub fn put(&self, key: impl Into<Bytes>, value: impl Into<Bytes>) -> Option<(Bytes, Bytes)> {
+        self.put_with_hint(key, value, &mut Hint::default())
+    }
+
+    /// Check if `key` falls between `before` and `after` in a splice.
+    unsafe fn splice_contains(&self, key: &[u8], before: *mut Node, after: *mut Node) -> bool {
+        if before != self.core.head.as_ptr()
+            && self.c.compare_key(&(*before).key, key) != std::cmp::Ordering::Less
+        {
+            return false;
+        }
+        after.is_null() || self.c.compare_key(key, &(*after).key) == std::cmp::Ordering::Less
+    }
+
+    /// Same as `put`, but starts searching from the splice of last insertion
+    /// recorded in `hint`. If keys are inserted in order, only a few nodes
+    /// need to be compared for every insertion.
+    pub fn put_with_hint(
+        &self,
+        key: impl Into<Bytes>,
+        value: impl Into<Bytes>,
+        hint: &mut Hint,
+    ) -> Option<(Bytes, Bytes)> {
         let (key, value) = (key.into(), value.into());
         let mut list_height = self.height();
-        let mut prev = [ptr::null_mut(); MAX_HEIGHT + 1];
-        let mut next = [ptr::null_mut(); MAX_HEIGHT + 1];
-        prev[list_height + 1] = self.core.head.as_ptr();
-        next[list_height + 1] = ptr::null_mut();
-        for i in (0..=list_height).rev() {
+        let same_list = match hint.list {
+            Some(ref core) => Arc::ptr_eq(core, &self.core),
+            None => false,
+        };
+        if !same_list {
+            *hint = Hint {
+                list: Some(self.core.clone()),
+                ..Hint::default()
+            };
+        }
+        let (prev, next) = (&mut hint.prev, &mut hint.next);
+        for i in hint.height..=list_height + 1 {
+            prev[i] = self.core.head.as_ptr();
+            next[i] = ptr::null_mut();
+        }
+        // Invalidate hint until insertion succeeds.
+        hint.height = 0;
+
+        // Find the lowest level, from which all cached splices still contain
+        // the key. The search only needs to start from there.
+        let mut level = list_height + 1;
+        while level > 0 && unsafe { self.splice_contains(&key, prev[level - 1], next[level - 1]) } {
+            level -= 1;
+        }
+        for i in (0..level).rev() {
             let (p, n) = unsafe { self.find_splice_for_level(&key, prev[i + 1], i) };
             prev[i] = p;
             next[i] = n;
@@ -199,6 +268,7 @@ impl<C: KeyComparator> Skiplist<C> {
             }
         }
         let x: &mut Node = unsafe { &mut *self.core.arena.get_mut(node_offset) };
+        let top = list_height.max(height);
         for i in 0..=height {
             loop {
                 if prev[i].is_null() {
@@ -238,6 +308,18 @@ impl<C: KeyComparator> Skiplist<C> {
                 }
             }
         }
+        // New node becomes the predecessor of following insertions.
+        let node_ptr: *mut Node = x;
+        for p in prev.iter_mut().take(height + 1) {
+            *p = node_ptr;
+        }
+        for i in height + 1..=top {
+            if prev[i].is_null() {
+                prev[i] = self.core.head.as_ptr();
+                next[i] = ptr::null_mut();
+            }
+        }
+        hint.height = top + 1;
         None
     }
 
@@ -164,6 +164,43 @@ fn test_one_key() {
     assert!(mark.load(Ordering::SeqCst));
 }
 
+#[test]
+fn test_put_with_hint() {
+    let n = 1000;
+    let comp = FixedLengthSuffixComparator::new(8);
+    let list = Skiplist::with_capacity(comp, ARENA_SIZE);
+    let mut hint = Hint::default();
+    // sequential inserts followed by out-of-order ones
+    let keys = (0..n).map(|i| i * 2).chain((0..n).rev().map(|i| i * 2 + 1));
+    for i in keys {
+        let key = key_with_ts(format!("{:05}", i).as_str(), 0);
+        assert!(list.put_with_hint(key, new_value(i), &mut hint).is_none());
+    }
+    let key = key_with_ts(format!("{:05}", 10).as_str(), 0);
+    assert!(list
+        .put_with_hint(key.clone(), new_value(10), &mut hint)
+        .is_none());
+    assert_eq!(
+        list.put_with_hint(key, new_value(11), &mut hint),
+        Some((key_with_ts("00010", 0), new_value(11)))
+    );
+
+    // hint of another list should be ignored
+    let other = Skiplist::with_capacity(FixedLengthSuffixComparator::new(8), ARENA_SIZE);
+    other.put_with_hint(key_with_ts("00000", 0), new_value(0), &mut hint);
+    assert_eq!(other.len(), 1);
+
+    assert_eq!(list.len(), 2 * n);
+    let mut iter_ref = list.iter_ref();
+    iter_ref.seek_to_first();
+    for i in 0..2 * n {
+        assert!(iter_ref.valid());
+        assert_eq!(*iter_ref.value(), new_value(i));
+        iter_ref.next();
+    }
+    assert!(!iter_ref.valid());
+}
+
 #[test]
 fn test_iterator_next() {
     let n = 100;
@@ -0,0 +1,51 @@
+//! Global allocators enabled by crate features, so that binaries using
+//! agatedb as the primary storage engine don't need to set one themselves.
+//! `jemalloc` takes precedence when both `jemalloc` and `mimalloc` are
+//! enabled.
+
+#[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(
+    feature = "mimalloc",
+    not(all(feature = "jemalloc", not(target_env = "msvc")))
+))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// Memory usage reported by the global allocator, in bytes.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// bytes allocated by the process
+    pub allocated: u64,
+    /// bytes in pages which contain allocations, which is larger than
+    /// `allocated` because of fragmentation
+    pub active: u64,
+    /// bytes in pages which are physically resident, including allocator
+    /// metadata
+    pub resident: u64,
+    /// bytes mapped by the allocator
+    pub mapped: u64,
+}
+
+/// Fetch stats of the global allocator, or `None` if the allocator doesn't
+/// report any, which is the case unless `jemalloc` is enabled.
+#[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
+pub(crate) fn fetch_stats() -> Option<AllocatorStats> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // Stats are cached by jemalloc, and only refreshed when epoch advances.
+    epoch::advance().ok()?;
+    Some(AllocatorStats {
+        allocated: stats::allocated::read().ok()? as u64,
+        active: stats::active::read().ok()? as u64,
+        resident: stats::resident::read().ok()? as u64,
+        mapped: stats::mapped::read().ok()? as u64,
+    })
+}
+
+#[cfg(not(all(feature = "jemalloc", not(target_env = "msvc"))))]
+pub(crate) fn fetch_stats() -> Option<AllocatorStats> {
+    None
+}
@@ -86,8 +86,9 @@ impl<'a> Bloom<'a> {
 
     /// Check if a bloom filter may contain some data
     pub fn may_contain(&self, mut h: u32) -> bool {
-        if self.k > 30 {
-            // potential new encoding for short bloom filters
+        if self.k > 30 || self.filter.is_empty() {
+            // potential new encoding for short bloom filters, or a corrupted
+            // filter
             true
         } else {
             let nbits = self.filter.bit_len();
@@ -0,0 +1,142 @@
+//! A thread local pool of `BytesMut`, to reuse transient buffers of hot
+//! paths like encoding WAL entries and building tables.
+//!
+//! Buffers are grouped by size classes of powers of two, class `n` holds
+//! buffers with capacity in `[1 << n, 1 << (n + 1))`. A request is served
+//! by a large enough buffer in its class or a few larger classes, as
+//! buffers grow when they are used. Buffers can be given back in any
+//! thread, but they are only reused by the thread they are given back to.
+
+use bytes::BytesMut;
+use std::cell::RefCell;
+
+/// Buffers smaller than 4KB are cheap to allocate, and not pooled.
+const MIN_CLASS: usize = 12;
+/// Buffers larger than 1GB are not pooled.
+const MAX_CLASS: usize = 30;
+const MAX_BUFFERS_PER_CLASS: usize = 4;
+/// A request may be served by buffers up to 16 times larger.
+const MAX_CLASS_DISTANCE: usize = 4;
+/// Bytes of idle buffers kept by every thread at most.
+const MAX_POOLED_BYTES: usize = 256 << 20;
+
+#[derive(Default)]
+struct Pool {
+    classes: Vec<Vec<BytesMut>>,
+    pooled_bytes: usize,
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::new(Pool::default());
+}
+
+/// Class of a buffer of `capacity` bytes, which must not be 0.
+fn class_of(capacity: usize) -> usize {
+    (usize::BITS - 1 - capacity.leading_zeros()) as usize
+}
+
+/// Take an empty buffer of at least `capacity` bytes from the pool of the
+/// current thread, or allocate a new one.
+pub(crate) fn take(capacity: usize) -> BytesMut {
+    let class = class_of(capacity.max(1));
+    if (MIN_CLASS..=MAX_CLASS).contains(&class) {
+        let reused = POOL
+            .try_with(|pool| {
+                let mut pool = pool.borrow_mut();
+                let end = pool.classes.len().min(class + MAX_CLASS_DISTANCE + 1);
+                let buf = pool
+                    .classes
+                    .get_mut(class..end)?
+                    .iter_mut()
+                    .find_map(|bufs| {
+                        let pos = bufs.iter().position(|buf| buf.capacity() >= capacity)?;
+                        Some(bufs.swap_remove(pos))
+                    })?;
+                pool.pooled_bytes -= buf.capacity();
+                Some(buf)
+            })
+            .ok()
+            .flatten();
+        if let Some(buf) = reused {
+            return buf;
+        }
+    }
+    BytesMut::with_capacity(capacity)
+}
+
+/// Give `buf` back to the pool of the current thread. It's dropped if it's
+/// too small or too large, or the pool is full.
+pub(crate) fn give_back(mut buf: BytesMut) {
+    let capacity = buf.capacity();
+    if capacity == 0 {
+        return;
+    }
+    let class = class_of(capacity);
+    if !(MIN_CLASS..=MAX_CLASS).contains(&class) {
+        return;
+    }
+    buf.clear();
+    // The pool may be destroyed already when the thread exits.
+    let _ = POOL.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.pooled_bytes + capacity > MAX_POOLED_BYTES {
+            return;
+        }
+        if pool.classes.len() <= class {
+            pool.classes.resize_with(class + 1, Vec::new);
+        }
+        if pool.classes[class].len() < MAX_BUFFERS_PER_CLASS {
+            pool.classes[class].push(buf);
+            pool.pooled_bytes += capacity;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        assert_eq!(class_of(4096), 12);
+        assert_eq!(class_of(8191), 12);
+        assert_eq!(class_of(8192), 13);
+
+        let mut buf = take(6000);
+        let capacity = buf.capacity();
+        assert!(capacity >= 6000);
+        buf.extend_from_slice(b"data");
+        let ptr = buf.as_ptr();
+        give_back(buf);
+        // The pooled buffer is too small.
+        let larger = take(capacity + 1);
+        assert_ne!(larger.as_ptr(), ptr);
+        let buf = take(5000);
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        give_back(buf);
+        give_back(larger);
+
+        // Buffers of small requests are not pooled.
+        assert_eq!(POOL.with(|pool| pool.borrow().classes.len()), 13);
+        let small = take(100);
+        give_back(small);
+        assert_eq!(POOL.with(|pool| pool.borrow().classes[6].len()), 0);
+
+        // Slightly larger buffers are reused, but much larger ones are not.
+        let large = BytesMut::with_capacity(1 << 20);
+        let large_ptr = large.as_ptr();
+        give_back(large);
+        let buf = take(4096);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_ne!(take(4096).as_ptr(), large_ptr);
+        assert_eq!(take(1 << 17).as_ptr(), large_ptr);
+
+        for _ in 0..MAX_BUFFERS_PER_CLASS + 1 {
+            give_back(BytesMut::with_capacity(4096));
+        }
+        let pooled = POOL.with(|pool| pool.borrow().classes[12].len());
+        assert_eq!(pooled, MAX_BUFFERS_PER_CLASS);
+        drop(buf);
+    }
+}
@@ -0,0 +1,826 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::format::{get_ts, user_key};
+use crate::table::Block;
+use crate::value::Value;
+use bytes::Bytes;
+use proto::meta::TableIndex;
+
+const NUM_SHARDS: usize = 16;
+
+/// How a cache chooses entries to keep when it's full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Evict least recently used entries.
+    Lru,
+    /// Evict least recently used entries, but only admit a new entry if it's
+    /// accessed more often than the entries it evicts, so a scan can't flush
+    /// hot entries out of cache.
+    TinyLfu,
+}
+
+/// Statistics of a cache since the database is opened.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// max bytes of cached entries
+    pub capacity: u64,
+    /// bytes of cached entries
+    pub usage: u64,
+    /// bytes of pinned entries, which are included in `usage`
+    pub pinned_usage: u64,
+    /// number of cached entries
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// number of entries evicted to make room for new ones, entries removed
+    /// with their tables are not counted
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Ratio of lookups served by cache, 0 if there is no lookup.
+    pub fn hit_ratio(&self) -> f64 {
+        hit_ratio(self.hits, self.misses)
+    }
+}
+
+pub(crate) fn hit_ratio(hits: u64, misses: u64) -> f64 {
+    if hits + misses == 0 {
+        return 0.0;
+    }
+    hits as f64 / (hits + misses) as f64
+}
+
+/// Cache lookups of a single table, see `LevelCacheStats`.
+#[derive(Default)]
+pub(crate) struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounters {
+    pub fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Cache lookups of tables in a level, which only counts tables currently
+/// in the level, so lookups of compacted tables are not included.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct LevelCacheStats {
+    pub level: usize,
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    pub index_cache_hits: u64,
+    pub index_cache_misses: u64,
+}
+
+/// Snapshot of caches for debugging, see `Agate::cache_status`.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CacheStatus {
+    /// `None` if block cache is disabled
+    pub block_cache: Option<CacheStats>,
+    /// `None` if index cache is disabled
+    pub index_cache: Option<CacheStats>,
+    /// `None` if row cache is disabled
+    pub row_cache: Option<CacheStats>,
+    /// lookups of every level starting from L0
+    pub levels: Vec<LevelCacheStats>,
+}
+
+/// Approximate access frequency of keys, a count-min sketch with 4 rows of
+/// 8-bit counters. Counters are halved periodically so that frequency
+/// reflects recent accesses.
+struct FrequencySketch {
+    rows: [Vec<u8>; 4],
+    mask: u64,
+    additions: usize,
+    reset_at: usize,
+}
+
+const SKETCH_SEEDS: [u64; 4] = [
+    0x9e37_79b9_7f4a_7c15,
+    0xc2b2_ae3d_27d4_eb4f,
+    0x1656_67b1_9e37_79f9,
+    0x27d4_eb2f_1656_67c5,
+];
+
+impl FrequencySketch {
+    fn new(width: usize) -> Self {
+        let width = width.next_power_of_two();
+        Self {
+            rows: [
+                vec![0; width],
+                vec![0; width],
+                vec![0; width],
+                vec![0; width],
+            ],
+            mask: width as u64 - 1,
+            additions: 0,
+            reset_at: width * 10,
+        }
+    }
+
+    fn index(&self, hash: u64, row: usize) -> usize {
+        let h = (hash ^ SKETCH_SEEDS[row]).wrapping_mul(SKETCH_SEEDS[(row + 1) % 4]);
+        ((h >> 32) & self.mask) as usize
+    }
+
+    fn increment(&mut self, hash: u64) {
+        for row in 0..4 {
+            let idx = self.index(hash, row);
+            let counter = &mut self.rows[row][idx];
+            *counter = counter.saturating_add(1);
+        }
+        self.additions += 1;
+        if self.additions >= self.reset_at {
+            for row in self.rows.iter_mut() {
+                for counter in row.iter_mut() {
+                    *counter >>= 1;
+                }
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency(&self, hash: u64) -> u8 {
+        (0..4)
+            .map(|row| self.rows[row][self.index(hash, row)])
+            .min()
+            .unwrap()
+    }
+}
+
+struct CacheEntry<V> {
+    value: V,
+    charge: usize,
+    tick: u64,
+    /// pinned entries are never evicted, so they are not in `Shard::lru`
+    pinned: bool,
+}
+
+struct Shard<K, V> {
+    capacity: usize,
+    usage: usize,
+    pinned_usage: usize,
+    evictions: u64,
+    tick: u64,
+    entries: HashMap<K, CacheEntry<V>>,
+    /// unpinned keys ordered by last access, oldest first
+    lru: BTreeMap<u64, K>,
+    sketch: Option<FrequencySketch>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Shard<K, V> {
+    fn touch<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        if !entry.pinned {
+            let key = self.lru.remove(&entry.tick).unwrap();
+            entry.tick = tick;
+            self.lru.insert(tick, key);
+        }
+        Some(entry.value.clone())
+    }
+
+    /// Check whether `hash` is accessed more often than the entries which
+    /// have to be evicted to make room for `charge` bytes.
+    fn admit(&self, hash: u64, charge: usize) -> bool {
+        let sketch = match &self.sketch {
+            Some(sketch) => sketch,
+            None => return true,
+        };
+        let frequency = sketch.frequency(hash);
+        let mut freed = self.capacity - self.usage.min(self.capacity);
+        for key in self.lru.values() {
+            if freed >= charge {
+                break;
+            }
+            if sketch.frequency(hash_of(key)) > frequency {
+                return false;
+            }
+            freed += self.entries[key].charge;
+        }
+        true
+    }
+
+    fn remove<Q: Hash + Eq + ?Sized>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+    {
+        if let Some(entry) = self.entries.remove(key) {
+            if entry.pinned {
+                self.pinned_usage -= entry.charge;
+            } else {
+                self.lru.remove(&entry.tick);
+            }
+            self.usage -= entry.charge;
+        }
+    }
+
+    fn pin<Q: Hash + Eq + ?Sized>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+    {
+        if let Some(entry) = self.entries.get_mut(key) {
+            if !entry.pinned {
+                entry.pinned = true;
+                self.lru.remove(&entry.tick);
+                self.pinned_usage += entry.charge;
+            }
+        }
+    }
+
+    /// Change capacity of the shard, evicting unpinned entries until usage
+    /// is within it.
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.usage > self.capacity {
+            let victim = match self.lru.pop_first() {
+                Some((_, victim)) => victim,
+                None => break,
+            };
+            let entry = self.entries.remove(&victim).unwrap();
+            self.usage -= entry.charge;
+            self.evictions += 1;
+        }
+    }
+
+    /// Insert an entry, returns false if it's not admitted. Pinned entries
+    /// are always admitted, even if the shard is full.
+    fn insert(&mut self, key: K, value: V, charge: usize, pinned: bool) -> bool {
+        if !pinned && (charge > self.capacity || !self.admit(hash_of(&key), charge)) {
+            return false;
+        }
+        self.remove(&key);
+        while self.usage + charge > self.capacity {
+            let victim = match self.lru.pop_first() {
+                Some((_, victim)) => victim,
+                None if pinned => break,
+                // The rest are all pinned.
+                None => return false,
+            };
+            let entry = self.entries.remove(&victim).unwrap();
+            self.usage -= entry.charge;
+            self.evictions += 1;
+        }
+        self.tick += 1;
+        if pinned {
+            self.pinned_usage += charge;
+        } else {
+            self.lru.insert(self.tick, key.clone());
+        }
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                charge,
+                tick: self.tick,
+                pinned,
+            },
+        );
+        self.usage += charge;
+        true
+    }
+}
+
+fn hash_of<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A sharded cache bounded by the total charge of its entries.
+pub(crate) struct Cache<K, V> {
+    shards: Vec<Mutex<Shard<K, V>>>,
+    capacity: AtomicUsize,
+    policy: CachePolicy,
+    counters: CacheCounters,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
+    /// Create a cache holding at most `capacity` bytes of entries.
+    pub fn new(capacity: usize, policy: CachePolicy) -> Self {
+        let shard_capacity = capacity.div_ceil(NUM_SHARDS);
+        let shards = (0..NUM_SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    capacity: shard_capacity,
+                    usage: 0,
+                    pinned_usage: 0,
+                    evictions: 0,
+                    tick: 0,
+                    entries: HashMap::new(),
+                    lru: BTreeMap::new(),
+                    // Assume entries are blocks of a few kilobytes.
+                    sketch: match policy {
+                        CachePolicy::Lru => None,
+                        CachePolicy::TinyLfu => {
+                            Some(FrequencySketch::new((shard_capacity >> 10).max(64)))
+                        }
+                    },
+                })
+            })
+            .collect();
+        Self {
+            shards,
+            capacity: AtomicUsize::new(capacity),
+            policy,
+            counters: CacheCounters::default(),
+        }
+    }
+
+    fn shard(&self, hash: u64) -> &Mutex<Shard<K, V>> {
+        &self.shards[hash as usize % NUM_SHARDS]
+    }
+
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.get_if(key, |_| true)
+    }
+
+    /// Like `get`, but values not accepted by `cond` are treated as misses.
+    pub fn get_if<Q: Hash + Eq + ?Sized>(&self, key: &Q, cond: impl FnOnce(&V) -> bool) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        let hash = hash_of(key);
+        let mut shard = self.shard(hash).lock().unwrap();
+        if let Some(sketch) = &mut shard.sketch {
+            sketch.increment(hash);
+        }
+        let accepted = shard.entries.get(key).is_some_and(|e| cond(&e.value));
+        let value = if accepted { shard.touch(key) } else { None };
+        self.counters.record(value.is_some());
+        value
+    }
+
+    /// Check if `key` is cached, without counting a hit or miss or touching
+    /// the entry.
+    pub fn contains<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        let shard = self.shard(hash_of(key)).lock().unwrap();
+        shard.entries.contains_key(key)
+    }
+
+    /// Insert `value` which takes `charge` bytes. Entries larger than a
+    /// shard, or rejected by the admission policy, are not cached.
+    pub fn insert(&self, key: K, value: V, charge: usize) -> bool {
+        let hash = hash_of(&key);
+        self.shard(hash)
+            .lock()
+            .unwrap()
+            .insert(key, value, charge, false)
+    }
+
+    /// Insert `value` which is never evicted. It still takes up capacity,
+    /// until it's removed.
+    pub fn insert_pinned(&self, key: K, value: V, charge: usize) {
+        let hash = hash_of(&key);
+        self.shard(hash)
+            .lock()
+            .unwrap()
+            .insert(key, value, charge, true);
+    }
+
+    /// Pin the entry of `key` if it's cached.
+    pub fn pin<Q: Hash + Eq + ?Sized>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+    {
+        self.shard(hash_of(key)).lock().unwrap().pin(key);
+    }
+
+    /// Like `insert`, but only inserts if `cond` returns true, which is
+    /// checked while the shard of `key` is locked.
+    pub fn insert_if(&self, key: K, value: V, charge: usize, cond: impl FnOnce() -> bool) -> bool {
+        let hash = hash_of(&key);
+        let mut shard = self.shard(hash).lock().unwrap();
+        cond() && shard.insert(key, value, charge, false)
+    }
+
+    pub fn remove<Q: Hash + Eq + ?Sized>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+    {
+        self.shard(hash_of(key)).lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.entries.clear();
+            shard.lru.clear();
+            shard.usage = 0;
+            shard.pinned_usage = 0;
+        }
+    }
+
+    /// Change capacity of the cache, which is split evenly across shards.
+    /// Unpinned entries are evicted if a shard is over its new capacity.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let shard_capacity = capacity.div_ceil(NUM_SHARDS);
+        for shard in &self.shards {
+            shard.lock().unwrap().set_capacity(shard_capacity);
+        }
+    }
+
+    /// Total charge of cached entries.
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().usage).sum()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.counters.hits()
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.counters.misses()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            capacity: self.capacity.load(Ordering::Relaxed) as u64,
+            hits: self.hits(),
+            misses: self.misses(),
+            ..Default::default()
+        };
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            stats.usage += shard.usage as u64;
+            stats.pinned_usage += shard.pinned_usage as u64;
+            stats.entries += shard.entries.len() as u64;
+            stats.evictions += shard.evictions;
+        }
+        stats
+    }
+}
+
+impl<K, V> fmt::Debug for Cache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("capacity", &self.capacity.load(Ordering::Relaxed))
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// Cache of parsed blocks of all tables in a database, keyed by table ID and
+/// block index. It's created by `Agate::open` with
+/// `AgateOptions::block_cache_size` bytes.
+pub struct BlockCache {
+    cache: Cache<(u64, usize), Arc<Block>>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: usize, policy: CachePolicy) -> Self {
+        Self {
+            cache: Cache::new(capacity, policy),
+        }
+    }
+
+    pub(crate) fn get(&self, table_id: u64, idx: usize) -> Option<Arc<Block>> {
+        self.cache.get(&(table_id, idx))
+    }
+
+    pub(crate) fn contains(&self, table_id: u64, idx: usize) -> bool {
+        self.cache.contains(&(table_id, idx))
+    }
+
+    pub(crate) fn insert(&self, table_id: u64, idx: usize, block: Arc<Block>, pinned: bool) {
+        let charge = block.size() as usize;
+        if pinned {
+            self.cache.insert_pinned((table_id, idx), block, charge);
+        } else {
+            self.cache.insert((table_id, idx), block, charge);
+        }
+    }
+
+    /// Pin cached blocks of a table.
+    pub(crate) fn pin_table(&self, table_id: u64, num_blocks: usize) {
+        for idx in 0..num_blocks {
+            self.cache.pin(&(table_id, idx));
+        }
+    }
+
+    /// Remove blocks of a table which is deleted.
+    pub(crate) fn remove_table(&self, table_id: u64, num_blocks: usize) {
+        for idx in 0..num_blocks {
+            self.cache.remove(&(table_id, idx));
+        }
+    }
+
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.cache.set_capacity(capacity);
+    }
+
+    /// Bytes of cached blocks.
+    pub fn usage(&self) -> usize {
+        self.cache.usage()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.cache.hits()
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.cache.misses()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.cache.fmt(f)
+    }
+}
+
+/// Cache of table indexes and bloom filters, keyed by table ID. It's
+/// created by `Agate::open` with `AgateOptions::index_cache_size` bytes,
+/// so indexes don't compete with blocks for space.
+pub struct IndexCache {
+    cache: Cache<u64, Arc<TableIndex>>,
+}
+
+impl IndexCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            cache: Cache::new(capacity, CachePolicy::Lru),
+        }
+    }
+
+    pub(crate) fn get(&self, table_id: u64) -> Option<Arc<TableIndex>> {
+        self.cache.get(&table_id)
+    }
+
+    /// Insert index of a table, where `charge` is its encoded size.
+    pub(crate) fn insert(
+        &self,
+        table_id: u64,
+        index: Arc<TableIndex>,
+        charge: usize,
+        pinned: bool,
+    ) {
+        if pinned {
+            self.cache.insert_pinned(table_id, index, charge);
+        } else {
+            self.cache.insert(table_id, index, charge);
+        }
+    }
+
+    pub(crate) fn pin(&self, table_id: u64) {
+        self.cache.pin(&table_id);
+    }
+
+    pub(crate) fn remove(&self, table_id: u64) {
+        self.cache.remove(&table_id);
+    }
+
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.cache.set_capacity(capacity);
+    }
+
+    /// Bytes of cached indexes.
+    pub fn usage(&self) -> usize {
+        self.cache.usage()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.cache.hits()
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.cache.misses()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+impl fmt::Debug for IndexCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.cache.fmt(f)
+    }
+}
+
+/// Cache of the latest values of user keys, see
+/// `AgateOptions::row_cache_size`.
+///
+/// A cached value must be the latest version of its key. Writes remove
+/// their keys after they are inserted into memtable, and bump `generation`,
+/// so that a lookup which started before a write can't fill the cache with
+/// the overwritten value.
+pub(crate) struct RowCache {
+    cache: Cache<Bytes, Value>,
+    generation: AtomicU64,
+    /// largest version ever written
+    max_version: AtomicU64,
+}
+
+impl RowCache {
+    pub fn new(capacity: usize, max_version: u64) -> Self {
+        Self {
+            cache: Cache::new(capacity, CachePolicy::Lru),
+            generation: AtomicU64::new(0),
+            max_version: AtomicU64::new(max_version),
+        }
+    }
+
+    /// Get the cached value of `user_key` if it's visible at `read_ts`.
+    pub fn get(&self, user_key: &[u8], read_ts: u64) -> Option<Value> {
+        self.cache
+            .get_if(user_key, |value| value.version <= read_ts)
+    }
+
+    /// Start a lookup at `read_ts`, which can fill the cache only if no
+    /// version newer than `read_ts` exists. Returns a ticket for `fill`.
+    pub fn begin_fill(&self, read_ts: u64) -> Option<u64> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        if read_ts >= self.max_version.load(Ordering::SeqCst) {
+            Some(generation)
+        } else {
+            None
+        }
+    }
+
+    /// Cache `value` read after `begin_fill`, unless any write happened
+    /// since then.
+    pub fn fill(&self, ticket: u64, user_key: &[u8], value: Value) {
+        let charge = user_key.len() + value.value.len() + std::mem::size_of::<Value>();
+        self.cache
+            .insert_if(Bytes::copy_from_slice(user_key), value, charge, || {
+                self.generation.load(Ordering::SeqCst) == ticket
+            });
+    }
+
+    /// Invalidate keys of written entries, which should be called after
+    /// entries are inserted into memtable.
+    pub fn invalidate(&self, keys: &[Bytes]) {
+        if keys.is_empty() {
+            return;
+        }
+        let max_version = keys.iter().map(|key| get_ts(key)).max().unwrap();
+        self.max_version.fetch_max(max_version, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        for key in keys {
+            self.cache.remove(user_key(key));
+        }
+    }
+
+    /// Invalidate all keys, after tables with versions up to `max_version`
+    /// are installed.
+    pub fn clear(&self, max_version: u64) {
+        self.max_version.fetch_max(max_version, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.cache.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        // Keys 0..4 are in the same shard, each shard holds 3 entries.
+        let cache = Cache::new(3 * NUM_SHARDS, CachePolicy::Lru);
+        let keys: Vec<u64> = (0..)
+            .filter(|k| (hash_of(k) as usize).is_multiple_of(NUM_SHARDS))
+            .take(4)
+            .collect();
+        for k in &keys[..3] {
+            assert!(cache.insert(*k, *k, 1));
+        }
+        assert_eq!(cache.get(&keys[0]), Some(keys[0]));
+        assert!(cache.insert(keys[3], keys[3], 1));
+        assert_eq!(cache.get(&keys[1]), None);
+        for k in [keys[0], keys[2], keys[3]].iter() {
+            assert_eq!(cache.get(k), Some(*k));
+        }
+        assert_eq!((cache.hits(), cache.misses()), (4, 1));
+        assert_eq!(cache.usage(), 3);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (3, 1));
+        assert_eq!(stats.hit_ratio(), 0.8);
+
+        assert!(!cache.insert(keys[1], keys[1], 4));
+        cache.remove(&keys[0]);
+        assert_eq!(cache.get(&keys[0]), None);
+        assert_eq!(cache.usage(), 2);
+    }
+
+    #[test]
+    fn test_pin() {
+        let cache = Cache::new(2 * NUM_SHARDS, CachePolicy::Lru);
+        let keys: Vec<u64> = (0..)
+            .filter(|k| (hash_of(k) as usize).is_multiple_of(NUM_SHARDS))
+            .take(4)
+            .collect();
+        cache.insert_pinned(keys[0], 0, 1);
+        cache.insert(keys[1], 1, 1);
+        cache.pin(&keys[1]);
+        // The shard is full of pinned entries.
+        assert!(!cache.insert(keys[2], 2, 1));
+        cache.insert_pinned(keys[2], 2, 1);
+        for (i, k) in keys[..3].iter().enumerate() {
+            assert_eq!(cache.get(k), Some(i as u64));
+        }
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.usage, stats.pinned_usage, stats.evictions),
+            (3, 3, 0)
+        );
+
+        cache.remove(&keys[0]);
+        cache.remove(&keys[1]);
+        assert!(cache.insert(keys[3], 3, 1));
+        assert_eq!(cache.stats().pinned_usage, 1);
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let cache = Cache::new(3 * NUM_SHARDS, CachePolicy::Lru);
+        let keys: Vec<u64> = (0..)
+            .filter(|k| (hash_of(k) as usize).is_multiple_of(NUM_SHARDS))
+            .take(4)
+            .collect();
+        cache.insert_pinned(keys[0], 0, 1);
+        cache.insert(keys[1], 1, 1);
+        cache.insert(keys[2], 2, 1);
+
+        // Least recently used entries are evicted, but not pinned ones.
+        cache.set_capacity(2 * NUM_SHARDS);
+        assert_eq!(cache.get(&keys[1]), None);
+        assert_eq!(cache.get(&keys[2]), Some(2));
+        cache.set_capacity(0);
+        assert_eq!(cache.get(&keys[0]), Some(0));
+        assert_eq!(cache.get(&keys[2]), None);
+        assert!(!cache.insert(keys[3], 3, 1));
+        let stats = cache.stats();
+        assert_eq!((stats.capacity, stats.usage, stats.evictions), (0, 1, 2));
+
+        cache.set_capacity(3 * NUM_SHARDS);
+        assert!(cache.insert(keys[3], 3, 1));
+    }
+
+    #[test]
+    fn test_tiny_lfu() {
+        let cache = Cache::new(2 * NUM_SHARDS, CachePolicy::TinyLfu);
+        let keys: Vec<u64> = (0..)
+            .filter(|k| (hash_of(k) as usize).is_multiple_of(NUM_SHARDS))
+            .take(10)
+            .collect();
+        cache.insert(keys[0], 0, 1);
+        cache.insert(keys[1], 1, 1);
+        for _ in 0..5 {
+            cache.get(&keys[0]);
+            cache.get(&keys[1]);
+        }
+        // Keys only scanned once can't evict hot keys.
+        for k in &keys[2..] {
+            assert_eq!(cache.get(k), None);
+            assert!(!cache.insert(*k, 2, 1));
+        }
+        assert_eq!(cache.get(&keys[0]), Some(0));
+        assert_eq!(cache.get(&keys[1]), Some(1));
+
+        // But keys accessed more often are admitted.
+        for _ in 0..10 {
+            cache.get(&keys[2]);
+        }
+        assert!(cache.insert(keys[2], 2, 1));
+        assert_eq!(cache.get(&keys[2]), Some(2));
+    }
+}
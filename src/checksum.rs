@@ -2,15 +2,33 @@ use crate::{Error, Result};
 use crc::crc32;
 use proto::meta::{checksum::Algorithm as ChecksumAlgorithm, Checksum};
 
+/// CRC32 with Castagnoli polynomial, which uses CRC instructions of CPU
+/// if they are available and the `hw-crc32c` feature is enabled.
+pub fn crc32c(data: &[u8]) -> u32 {
+    #[cfg(all(
+        feature = "hw-crc32c",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    {
+        if hw::is_supported() {
+            return unsafe { hw::crc32c(data) };
+        }
+    }
+    crc32::checksum_castagnoli(data)
+}
+
 pub fn calculate_checksum(data: &[u8], algo: ChecksumAlgorithm) -> u64 {
     match algo {
-        ChecksumAlgorithm::Crc32c => crc32::checksum_castagnoli(data) as u64,
+        ChecksumAlgorithm::Crc32c => crc32c(data) as u64,
         ChecksumAlgorithm::XxHash64 => xxhash::checksum(data),
     }
 }
 
 pub fn verify_checksum(data: &[u8], expected: &Checksum) -> Result<()> {
-    let actual = calculate_checksum(data, ChecksumAlgorithm::from_i32(expected.algo).unwrap());
+    let algo = ChecksumAlgorithm::from_i32(expected.algo).ok_or_else(|| {
+        Error::InvalidChecksum(format!("unknown checksum algorithm {}", expected.algo))
+    })?;
+    let actual = calculate_checksum(data, algo);
     if actual == expected.sum {
         return Ok(());
     }
@@ -20,6 +38,61 @@ pub fn verify_checksum(data: &[u8], expected: &Checksum) -> Result<()> {
     )))
 }
 
+#[cfg(all(feature = "hw-crc32c", target_arch = "x86_64"))]
+mod hw {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    use std::ptr;
+
+    pub fn is_supported() -> bool {
+        is_x86_feature_detected!("sse4.2")
+    }
+
+    /// # Safety
+    ///
+    /// CPU must support SSE4.2.
+    #[target_feature(enable = "sse4.2")]
+    pub unsafe fn crc32c(data: &[u8]) -> u32 {
+        let mut crc = !0u64;
+        let mut chunks = data.chunks_exact(8);
+        for chunk in &mut chunks {
+            let v = u64::from_le(ptr::read_unaligned(chunk.as_ptr() as *const u64));
+            crc = _mm_crc32_u64(crc, v);
+        }
+        let mut crc = crc as u32;
+        for &b in chunks.remainder() {
+            crc = _mm_crc32_u8(crc, b);
+        }
+        !crc
+    }
+}
+
+#[cfg(all(feature = "hw-crc32c", target_arch = "aarch64"))]
+mod hw {
+    use std::arch::aarch64::{__crc32cb, __crc32cd};
+    use std::ptr;
+
+    pub fn is_supported() -> bool {
+        std::arch::is_aarch64_feature_detected!("crc")
+    }
+
+    /// # Safety
+    ///
+    /// CPU must support CRC instructions of ARMv8.
+    #[target_feature(enable = "crc")]
+    pub unsafe fn crc32c(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        let mut chunks = data.chunks_exact(8);
+        for chunk in &mut chunks {
+            let v = u64::from_le(ptr::read_unaligned(chunk.as_ptr() as *const u64));
+            crc = __crc32cd(crc, v);
+        }
+        for &b in chunks.remainder() {
+            crc = __crc32cb(crc, b);
+        }
+        !crc
+    }
+}
+
 mod xxhash {
     use std::{ptr, u64};
 
@@ -155,3 +228,23 @@ mod xxhash {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        // Check value of CRC-32C.
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(crc32c(b""), 0);
+        let data: Vec<u8> = (0..1024u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        // Different lengths and alignments exercise both chunks and tails.
+        for start in 0..8 {
+            for end in (start..data.len()).step_by(37) {
+                let slice = &data[start..end];
+                assert_eq!(crc32c(slice), crc32::checksum_castagnoli(slice));
+            }
+        }
+    }
+}
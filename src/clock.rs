@@ -0,0 +1,83 @@
+use crate::util::unix_time;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Source of time. All reads of wall time in database go through the clock
+/// set in `AgateOptions`, so that TTL can be tested deterministically.
+pub trait Clock: Send + Sync {
+    /// Seconds since unix epoch, used to calculate and check expiration.
+    fn unix_time(&self) -> u64;
+
+    /// Monotonic time, used to measure durations in metrics.
+    fn now(&self) -> Instant;
+}
+
+/// A clock which reads time from operating system.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_time(&self) -> u64 {
+        unix_time()
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves forward when `advance` is called.
+pub struct ManualClock {
+    start_unix_time: u64,
+    start: Instant,
+    /// elapsed time in nanoseconds
+    elapsed: AtomicU64,
+}
+
+impl ManualClock {
+    /// Create a clock which starts at `unix_time` seconds since unix epoch.
+    pub fn new(unix_time: u64) -> Self {
+        Self {
+            start_unix_time: unix_time,
+            start: Instant::now(),
+            elapsed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed.load(Ordering::SeqCst))
+    }
+}
+
+impl Clock for ManualClock {
+    fn unix_time(&self) -> u64 {
+        self.start_unix_time + self.elapsed().as_secs()
+    }
+
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(100);
+        let start = clock.now();
+        assert_eq!(clock.unix_time(), 100);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(clock.unix_time(), 101);
+        assert_eq!(clock.now() - start, Duration::from_millis(1500));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(clock.unix_time(), 102);
+    }
+}
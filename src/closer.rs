@@ -0,0 +1,204 @@
+//! Registry of background threads, so that they are named, stopped and
+//! joined in one place instead of by every feature spawning them.
+
+use crate::{Error, Result};
+
+use log::error;
+use std::any::Any;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Cancellation signal shared by tasks of a `Closer`. Tasks which don't
+/// exit by their channels being closed, like periodic ones, should check it
+/// and return once it's closed.
+#[derive(Clone, Default)]
+pub(crate) struct Signal {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Signal {
+    pub fn is_closed(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Ask all tasks to stop, without waiting for them.
+    pub fn close(&self) {
+        let (closed, cvar) = &*self.inner;
+        *closed.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    /// Sleep for `timeout` or until the signal is closed, returning whether
+    /// it's closed.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (closed, cvar) = &*self.inner;
+        let closed = closed.lock().unwrap();
+        let (closed, _) = cvar
+            .wait_timeout_while(closed, timeout, |closed| !*closed)
+            .unwrap();
+        *closed
+    }
+}
+
+struct Task {
+    name: String,
+    handle: JoinHandle<()>,
+}
+
+/// Owner of background threads. Every thread is registered with a name,
+/// which is also the name of the thread, and gets the shared `Signal` for
+/// cooperative cancellation. Panics of threads are returned as
+/// `Error::TaskPanicked` when they are joined.
+///
+/// Remaining threads are closed and joined on drop.
+#[derive(Default)]
+pub(crate) struct Closer {
+    signal: Signal,
+    tasks: Vec<Task>,
+}
+
+impl Closer {
+    /// Spawn a thread named `name` running `f`.
+    pub fn spawn(
+        &mut self,
+        name: impl Into<String>,
+        f: impl FnOnce(Signal) + Send + 'static,
+    ) -> Result<()> {
+        let name = name.into();
+        let signal = self.signal.clone();
+        let handle = thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || f(signal))?;
+        self.tasks.push(Task { name, handle });
+        Ok(())
+    }
+
+    pub fn signal(&self) -> &Signal {
+        &self.signal
+    }
+
+    /// Number of running tasks whose names start with `prefix`.
+    pub fn count(&self, prefix: &str) -> usize {
+        self.tasks
+            .iter()
+            .filter(|t| t.name.starts_with(prefix))
+            .count()
+    }
+
+    /// Join tasks whose names start with `prefix`, without closing the
+    /// signal. It's used to stop tasks in order, after they are told to
+    /// exit by other means like messages in their channels.
+    pub fn join(&mut self, prefix: &str) -> Result<()> {
+        let (matched, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.tasks)
+            .into_iter()
+            .partition(|t| t.name.starts_with(prefix));
+        self.tasks = rest;
+        join_all(matched)
+    }
+
+    /// Close the signal and join all remaining tasks.
+    pub fn close(&mut self) -> Result<()> {
+        self.signal.close();
+        join_all(std::mem::take(&mut self.tasks))
+    }
+}
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        // Panics are already logged.
+        let _ = self.close();
+    }
+}
+
+/// Join all `tasks` in order, even if some of them panicked. The first
+/// panic is returned.
+fn join_all(tasks: Vec<Task>) -> Result<()> {
+    let mut result = Ok(());
+    for task in tasks {
+        if let Err(payload) = task.handle.join() {
+            let err = Error::TaskPanicked {
+                name: task.name,
+                message: panic_message(&*payload),
+            };
+            error!("{}", err);
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_closer() {
+        let mut closer = Closer::default();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        for i in 0..2 {
+            let ticks = ticks.clone();
+            closer
+                .spawn(format!("ticker-{}", i), move |signal| {
+                    while !signal.wait_timeout(Duration::from_millis(1)) {
+                        ticks.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+                .unwrap();
+        }
+        let (tx, rx) = crossbeam_channel::unbounded::<()>();
+        closer
+            .spawn("worker", move |_| {
+                assert_eq!(thread::current().name(), Some("worker"));
+                for _ in rx {}
+            })
+            .unwrap();
+        assert_eq!(closer.count("ticker-"), 2);
+        assert_eq!(closer.count(""), 3);
+
+        // Joining a task doesn't cancel others.
+        drop(tx);
+        closer.join("worker").unwrap();
+        assert_eq!(closer.count(""), 2);
+        assert!(!closer.signal().is_closed());
+
+        closer.close().unwrap();
+        assert!(closer.signal().is_closed());
+        assert_eq!(closer.count(""), 0);
+        let ticks_after_close = ticks.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(ticks.load(Ordering::SeqCst), ticks_after_close);
+    }
+
+    #[test]
+    fn test_closer_panic() {
+        let mut closer = Closer::default();
+        closer.spawn("ok", |_| {}).unwrap();
+        closer.spawn("bad", |_| panic!("boom {}", 1)).unwrap();
+        closer.spawn("worse", |_| panic!("boom")).unwrap();
+
+        // All tasks are joined, and the first panic is returned.
+        match closer.close() {
+            Err(Error::TaskPanicked { name, message }) => {
+                assert_eq!(name, "bad");
+                assert_eq!(message, "boom 1");
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(closer.count(""), 0);
+        closer.close().unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use crate::format::user_key;
+use crate::util::KeyComparator;
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
+
+/// Defines the order of user keys, which are keys without timestamps.
+///
+/// A database must be opened with the same comparator every time, as keys
+/// in memtables and SSTs are sorted by it. Different keys must never be
+/// considered equal, and keys sharing a prefix should be ordered together
+/// to iterate by prefix, like streaming with `Stream::with_prefix`.
+pub trait UserComparator: Send + Sync {
+    /// Name of the comparator, for logs and debugging.
+    fn name(&self) -> &str;
+
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering;
+}
+
+/// Orders user keys lexicographically by bytes, which is the default.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct BytewiseComparator;
+
+impl UserComparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "agate.BytewiseComparator"
+    }
+
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        lhs.cmp(rhs)
+    }
+}
+
+/// Orders keys with timestamps by user keys with `UserComparator`, then
+/// newer versions first. Timestamp suffixes are only handled here.
+#[derive(Clone, Default)]
+pub struct Comparator {
+    /// `None` for bytewise order, which avoids dynamic dispatch.
+    user: Option<Arc<dyn UserComparator>>,
+}
+
+impl Comparator {
+    pub fn new(user: Option<Arc<dyn UserComparator>>) -> Self {
+        Self { user }
+    }
+
+    pub fn name(&self) -> &str {
+        match &self.user {
+            Some(user) => user.name(),
+            None => BytewiseComparator.name(),
+        }
+    }
+
+    /// Compare user keys, which are keys without timestamps.
+    #[inline]
+    pub fn compare_user_key(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        match &self.user {
+            Some(user) => user.compare(lhs, rhs),
+            None => lhs.cmp(rhs),
+        }
+    }
+}
+
+impl KeyComparator for Comparator {
+    #[inline]
+    fn compare_key(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        assert!(
+            lhs.len() >= 8 && rhs.len() >= 8,
+            "cannot compare keys without timestamps: {:?}, {:?}",
+            lhs,
+            rhs
+        );
+        let (l_key, l_ts) = lhs.split_at(lhs.len() - 8);
+        let (r_key, r_ts) = rhs.split_at(rhs.len() - 8);
+        // Timestamps are encoded so that newer ones are smaller in bytes.
+        self.compare_user_key(l_key, r_key)
+            .then_with(|| l_ts.cmp(r_ts))
+    }
+
+    #[inline]
+    fn same_key(&self, lhs: &[u8], rhs: &[u8]) -> bool {
+        user_key(lhs) == user_key(rhs)
+    }
+}
+
+impl fmt::Debug for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Comparator").field(&self.name()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::key_with_ts;
+
+    struct ReverseComparator;
+
+    impl UserComparator for ReverseComparator {
+        fn name(&self) -> &str {
+            "test.ReverseComparator"
+        }
+
+        fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+            rhs.cmp(lhs)
+        }
+    }
+
+    #[test]
+    fn test_comparator() {
+        let bytewise = Comparator::default();
+        let reverse = Comparator::new(Some(Arc::new(ReverseComparator)));
+        assert_eq!(bytewise.name(), "agate.BytewiseComparator");
+        assert_eq!(reverse.name(), "test.ReverseComparator");
+
+        let (a1, a2, b1) = (
+            key_with_ts("a", 1),
+            key_with_ts("a", 2),
+            key_with_ts("b", 1),
+        );
+        assert_eq!(bytewise.compare_key(&a1, &b1), Ordering::Less);
+        assert_eq!(reverse.compare_key(&a1, &b1), Ordering::Greater);
+        // Newer versions come first in both orders.
+        assert_eq!(bytewise.compare_key(&a2, &a1), Ordering::Less);
+        assert_eq!(reverse.compare_key(&a2, &a1), Ordering::Less);
+        assert_eq!(reverse.compare_key(&a1, &a1), Ordering::Equal);
+        assert!(reverse.same_key(&a1, &a2));
+        assert!(!reverse.same_key(&a1, &b1));
+    }
+}
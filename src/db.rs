@@ -1,29 +1,209 @@
+#[cfg(feature = "async")]
+mod async_agate;
+mod backup;
+mod bulk_load;
+mod checkpoint;
+mod expiry;
+mod flatten;
+mod histogram;
+mod identity;
+mod info;
+mod load;
+mod lock;
 mod opt;
+mod rocksdb;
+mod stream;
+mod stream_writer;
+mod subscribe;
 
-use super::memtable::{MemTable, MemTables};
-use super::Result;
+use super::memtable::{MemTable, MemTables, MemoryUsage};
+use super::{Error, ErrorContext, OpenStage, Result};
+use crate::cache::{CacheStatus, RowCache};
+use crate::clock::Clock;
+use crate::closer::{Closer, Signal};
+use crate::comparator::Comparator;
 use crate::entry::Entry;
-use crate::value::{Request, Value};
+use crate::env::IoPriority;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::env::StdEnv;
+use crate::event::FlushInfo;
+use crate::format::{get_ts, key_with_ts, key_with_ts_first, user_key, with_key_ts, KeyTs};
+#[cfg(feature = "async")]
+use crate::future::WriteFuture;
+use crate::iterator_trait::AgateIterator;
+use crate::key_registry::{self, KeyRegistry};
+use crate::levels::{
+    ConsistencyReport, DbSize, LevelInfo, LevelsController, SizeEstimate, TableInfo, VerifyReport,
+    COMPACTION_INTERVAL,
+};
+use crate::manifest::ManifestFile;
+use crate::merge::MergeOperator;
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::ops::oracle::Oracle;
+use crate::ops::transaction::TXN_KEY;
+use crate::opt::build_level_table_options;
+use crate::rate_limiter::RateLimiter;
+use crate::table::{self, PrefixStats, Table};
+use crate::util::KeyComparator;
+use crate::value::{
+    Request, Value, WriteCallback, VALUE_DELETE, VALUE_FIN_TXN, VALUE_MERGE_ENTRY, VALUE_POINTER,
+    VALUE_TXN,
+};
+use crate::wal::{self, Wal};
 
-pub use opt::AgateOptions;
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, error, info, warn};
+use skiplist::Skiplist;
 
-use std::fs;
+#[cfg(feature = "async")]
+pub use async_agate::AsyncAgate;
+pub use bulk_load::BulkLoader;
+pub use histogram::{HistogramData, KeyValueHistogram};
+pub use identity::StoreIdentity;
+pub use info::DbInfo;
+use lock::DirLockGuard;
+pub use opt::{
+    AgateOptions, IsolationLevel, OpenProgress, OpenProgressCallback, OpenProgressStage,
+    RuntimeOption,
+};
+pub use stream::{Stream, StreamKeyFilter};
+pub use stream_writer::StreamWriter;
+use subscribe::Subscribers;
+pub use subscribe::Subscription;
+
+use std::collections::VecDeque;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Maximum length of user keys. Lengths of keys are encoded as u16 in table
+/// blocks, which leaves room for the 8 bytes timestamp.
+pub(crate) const MAX_KEY_LENGTH: usize = 65000;
+
+/// How long a flush waits for compactors to make room in L0 before it gives
+/// up with `Error::WriteStalled` and is retried.
+const LEVEL_ZERO_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A memtable which is rotated out and waits to be flushed to L0.
+struct FlushTask {
+    mt: Arc<MemTable>,
+    /// Reserved on rotation, so that file IDs of L0 tables follow the order
+    /// of memtables even if they are flushed concurrently.
+    file_id: u64,
+}
+
+/// Steps of a flush task done by earlier attempts, so that retrying the task
+/// after a transient error doesn't repeat them.
+#[derive(Default)]
+struct FlushProgress {
+    /// Table built from the memtable, whose file is created only once.
+    table: Option<Table>,
+    /// Whether the table is recorded in manifest and added to L0.
+    added: bool,
+}
+
+/// A batch of entries which has been appended to WAL of `mt`, and waits to
+/// be inserted into its skiplist before writers are notified.
+struct InsertTask {
+    /// `Err` if entries failed to be appended to WAL.
+    batch: Result<(Arc<MemTable>, Vec<Entry>)>,
+    dones: Vec<WriteCallback>,
+}
 
 pub struct Core {
-    mt: Mutex<MemTables>,
+    mts: RwLock<MemTables>,
+    lvctl: LevelsController,
+    manifest: Arc<ManifestFile>,
     opts: AgateOptions,
-    next_mem_fid: usize,
+    next_mem_fid: AtomicUsize,
+    /// Set when closing starts, after which new reads and writes are rejected.
+    closed: AtomicBool,
+    /// `None` tells a flush worker to exit.
+    flush_channel: (Sender<Option<FlushTask>>, Receiver<Option<FlushTask>>),
+    /// `None` tells write thread to exit.
+    write_channel: (Sender<Option<Request>>, Receiver<Option<Request>>),
+    /// `None` tells insert thread to exit.
+    insert_channel: (Sender<Option<InsertTask>>, Receiver<Option<InsertTask>>),
+    pub(crate) orc: Oracle,
+    /// Notified when an immutable memtable is flushed and writers
+    /// stalled by full memtables may continue.
+    write_stall: (Mutex<()>, Condvar),
+    /// Set when a flush worker fails with a permanent error, after which
+    /// no more memtables are flushed.
+    flush_error: Mutex<Option<Arc<Error>>>,
+    metrics: Metrics,
+    rate_limiter: RateLimiter,
+    /// `AgateOptions::sync_writes`, which can be changed at runtime.
+    sync_writes: AtomicBool,
+    identity: StoreIdentity,
+    pub(crate) key_registry: KeyRegistry,
+    subscribers: Subscribers,
+    row_cache: Option<RowCache>,
+    /// Released after all other fields are dropped, as fields are dropped
+    /// in declaration order.
+    dir_lock: Option<DirLockGuard>,
 }
 
-#[derive(Clone)]
 pub struct Agate {
-    core: Arc<Core>,
+    pub(crate) core: Arc<Core>,
+    tasks: Closer,
+    /// Compactors are stopped after memtables are flushed on close, as
+    /// flushes may wait for L0 to be compacted.
+    compactors: Mutex<Closer>,
+    open_timings: OpenTimings,
+}
+
+/// Time spent in every stage of `Agate::open`, in the order they are
+/// executed. Stages which are skipped, like creating directories in
+/// read-only mode, are not recorded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenTimings {
+    pub stages: Vec<(OpenStage, Duration)>,
+}
+
+impl OpenTimings {
+    /// Get time spent in `stage`, or `None` if it's skipped.
+    pub fn stage(&self, stage: OpenStage) -> Option<Duration> {
+        self.stages
+            .iter()
+            .find(|(s, _)| *s == stage)
+            .map(|(_, d)| *d)
+    }
+
+    /// Total time of all stages.
+    pub fn total(&self) -> Duration {
+        self.stages.iter().map(|(_, d)| *d).sum()
+    }
+
+    /// Run `f` as `stage`, recording its duration and wrapping its error
+    /// with the stage.
+    fn record<T>(
+        &mut self,
+        clock: &dyn Clock,
+        stage: OpenStage,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let start = clock.now();
+        let res = f().map_err(Error::at_stage(stage));
+        self.stages.push((stage, clock.now() - start));
+        res
+    }
 }
 
 const MEMTABLE_FILE_EXT: &str = ".mem";
+const KV_WRITE_CH_CAPACITY: usize = 1000;
+const MAX_REQUESTS_PER_WRITE: usize = 3 * KV_WRITE_CH_CAPACITY;
+
+// Names of background tasks, flushers and compactors are suffixed with
+// their indexes.
+const FLUSHER_TASK: &str = "agate-flusher-";
+const COMPACTOR_TASK: &str = "agate-compactor-";
+const WRITER_TASK: &str = "agate-writer";
+const INSERTER_TASK: &str = "agate-inserter";
+const EXPIRY_SCANNER_TASK: &str = "agate-expiry-scanner";
 
 impl Agate {
     /*
@@ -38,9 +218,112 @@ impl Agate {
     */
 }
 
+impl Drop for Agate {
+    fn drop(&mut self) {
+        if self.core.is_closed() {
+            return;
+        }
+        if matches!(self.core.mts.read(), Ok(mts) if !mts.table_mut().skl.is_empty()) {
+            warn!("memtable is not flushed on drop, its WAL will be replayed on next open");
+        }
+        if let Err(err) = self.close_impl(false) {
+            error!("failed to close database on drop: {:?}", err);
+        }
+    }
+}
+
 impl Core {
-    fn new(_opts: AgateOptions) -> Result<Self> {
-        unimplemented!()
+    fn new(opts: AgateOptions, timings: &mut OpenTimings) -> Result<Self> {
+        let clock = opts.clock.as_ref();
+        let dir_lock = if opts.in_memory || opts.bypass_lock_guard || !opts.env.is_local() {
+            None
+        } else if opts.read_only {
+            timings.record(clock, OpenStage::Lock, || {
+                DirLockGuard::acquire_shared(&opts.dir)
+            })?
+        } else {
+            Some(timings.record(clock, OpenStage::Lock, || DirLockGuard::acquire(&opts.dir))?)
+        };
+
+        let identity = if opts.in_memory {
+            StoreIdentity::generate()
+        } else if opts.read_only {
+            timings
+                .record(clock, OpenStage::Identity, || {
+                    StoreIdentity::load(opts.env.as_ref(), &opts.dir)
+                })?
+                .unwrap_or_else(StoreIdentity::generate)
+        } else {
+            timings.record(clock, OpenStage::Identity, || {
+                StoreIdentity::open(opts.env.as_ref(), &opts.dir)
+            })?
+        };
+
+        let key_registry =
+            timings.record(clock, OpenStage::KeyRegistry, || KeyRegistry::open(&opts))?;
+        if !opts.encryption_key.is_empty() {
+            warn!("encryption key is set, but tables and WALs are not encrypted yet");
+        }
+
+        let manifest = timings.record(clock, OpenStage::Manifest, || {
+            opts.report_open_progress(OpenProgressStage::Manifest, 0, 1);
+            let manifest = ManifestFile::open_or_create_manifest_file(&opts)?;
+            opts.report_open_progress(OpenProgressStage::Manifest, 1, 1);
+            Ok(Arc::new(manifest))
+        })?;
+        let lvctl = timings.record(clock, OpenStage::Levels, || {
+            LevelsController::new(opts.clone(), manifest.clone())
+        })?;
+
+        let (imm, mt, next_mem_fid) = timings.record(clock, OpenStage::Memtables, || {
+            Self::open_all_mem_tables(&opts)
+        })?;
+
+        let mts = MemTables::new(Arc::new(mt), imm);
+        // TODO: take value log into account
+        let max_version = mts.max_version().max(lvctl.max_version()?);
+
+        Ok(Self {
+            mts: RwLock::new(mts),
+            lvctl,
+            manifest,
+            flush_channel: crossbeam_channel::bounded(opts.num_memtables),
+            write_channel: crossbeam_channel::bounded(KV_WRITE_CH_CAPACITY),
+            // Rendezvous channel, so that at most one batch is being inserted
+            // while the next one is appended to WAL.
+            insert_channel: crossbeam_channel::bounded(0),
+            orc: Oracle::new(max_version, opts.managed_txns),
+            write_stall: (Mutex::new(()), Condvar::new()),
+            flush_error: Mutex::new(None),
+            metrics: Metrics::default(),
+            identity,
+            key_registry,
+            subscribers: Subscribers::default(),
+            row_cache: match opts.row_cache_size {
+                0 => None,
+                size => Some(RowCache::new(size as usize, max_version)),
+            },
+            sync_writes: AtomicBool::new(opts.sync_writes),
+            rate_limiter: RateLimiter::new(
+                opts.write_bytes_per_sec,
+                opts.write_ops_per_sec,
+                opts.clock.now(),
+            ),
+            opts,
+            next_mem_fid: AtomicUsize::new(next_mem_fid),
+            closed: AtomicBool::new(false),
+            dir_lock,
+        })
+    }
+
+    /// Open immutable memtables from WALs, and a new mutable memtable. Returns
+    /// the memtables and the next file ID of memtables.
+    fn open_all_mem_tables(
+        opts: &AgateOptions,
+    ) -> Result<(VecDeque<Arc<MemTable>>, MemTable, usize)> {
+        let (imm, next_mem_fid) = Self::open_mem_tables(opts)?;
+        let mt = Self::open_mem_table(&opts.dir, opts.clone(), next_mem_fid)?;
+        Ok((imm, mt, next_mem_fid + 1))
     }
 
     fn memtable_file_path(base_path: &Path, file_id: usize) -> PathBuf {
@@ -50,65 +333,3299 @@ impl Core {
     }
 
     fn open_mem_table<P: AsRef<Path>>(
-        _base_path: P,
-        _opts: AgateOptions,
-        _file_id: usize,
+        base_path: P,
+        opts: AgateOptions,
+        file_id: usize,
     ) -> Result<MemTable> {
-        unimplemented!()
+        let path = Self::memtable_file_path(base_path.as_ref(), file_id);
+        let c = Comparator::new(opts.comparator.clone());
+        // TODO: refactor skiplist to use `u64`
+        let skl = Skiplist::with_capacity(c, opts.arena_size() as u32);
+
+        // We don't need to create the WAL for the skiplist in in-memory mode so return the memtable.
+        if opts.in_memory {
+            return Ok(MemTable::new(skl, None, opts));
+        }
+        if opts.read_only {
+            let mem_table = MemTable::new(skl, None, opts);
+            if path.exists() {
+                mem_table
+                    .replay_read_only(&path)
+                    .map_err(|e| e.with_context(ErrorContext::path(&path)))?;
+            }
+            return Ok(mem_table);
+        }
+
+        let context = || ErrorContext::path(&path);
+        let wal = Wal::open(path.clone(), opts.clone()).map_err(|e| e.with_context(context()))?;
+
+        let mem_table = MemTable::new(skl, Some(wal), opts);
+
+        mem_table
+            .update_skip_list()
+            .map_err(|e| e.with_context(context()))?;
+
+        Ok(mem_table)
     }
 
-    fn open_mem_tables(&mut self) -> Result<()> {
-        unimplemented!()
+    /// Replay all memtables left on disk. Non-empty ones are returned in
+    /// order of file ID, together with the next memtable file ID to use.
+    fn open_mem_tables(opts: &AgateOptions) -> Result<(VecDeque<Arc<MemTable>>, usize)> {
+        let mut mts = VecDeque::new();
+        if opts.in_memory {
+            return Ok((mts, 1));
+        }
+
+        let mut fids = vec![];
+        for filename in opts.env.list_dir(&opts.dir)? {
+            if !filename.ends_with(MEMTABLE_FILE_EXT) {
+                continue;
+            }
+            if opts.badger_compat {
+                return Err(Error::CustomError(format!(
+                    "memtable {} of Badger can't be replayed, close Badger cleanly first",
+                    filename
+                )));
+            }
+            let fid = filename[..filename.len() - MEMTABLE_FILE_EXT.len()]
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidFilename(filename.to_string()))?;
+            fids.push(fid);
+        }
+        fids.sort_unstable();
+
+        opts.report_open_progress(OpenProgressStage::Memtables, 0, fids.len());
+        for (i, fid) in fids.iter().enumerate() {
+            let mt = Self::open_mem_table(&opts.dir, opts.clone(), *fid)?;
+            debug!(
+                "replayed WAL of memtable {}, {} bytes of data recovered",
+                fid,
+                mt.memory_usage().data
+            );
+            opts.report_open_progress(OpenProgressStage::Memtables, i + 1, fids.len());
+            if mt.skl.is_empty() {
+                if !opts.read_only {
+                    mt.delete_wal()?;
+                }
+                continue;
+            }
+            mts.push_back(Arc::new(mt));
+        }
+
+        let next_mem_fid = fids.last().map_or(1, |fid| fid + 1);
+        Ok((mts, next_mem_fid))
     }
 
-    fn new_mem_table(&mut self) -> Result<MemTable> {
-        unimplemented!()
+    fn new_mem_table(&self) -> Result<MemTable> {
+        let file_id = self.next_mem_fid.fetch_add(1, Ordering::SeqCst);
+        let mt = Self::open_mem_table(&self.opts.dir, self.opts.clone(), file_id)?;
+        if !mt.skl.is_empty() {
+            return Err(Error::CustomError(format!(
+                "memtable {} already exists and is not empty",
+                file_id
+            )));
+        }
+        Ok(mt)
+    }
+
+    pub(crate) fn opts(&self) -> &AgateOptions {
+        &self.opts
+    }
+
+    pub(crate) fn comparator(&self) -> &Comparator {
+        self.lvctl.comparator()
+    }
+
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        self.opts.clock.as_ref()
     }
 
     pub fn is_closed(&self) -> bool {
-        // TODO: check db closed
-        false
+        self.closed.load(Ordering::Acquire)
     }
 
-    pub(crate) fn get(&self, _key: &[u8]) -> Result<Value> {
-        unimplemented!()
+    /// Get the latest version of `key` not newer than its timestamp. An
+    /// empty value is returned if the version has expired.
+    pub(crate) fn get(&self, key: &Bytes) -> Result<Value> {
+        let value = match &self.row_cache {
+            Some(cache) => self.get_value_cached(cache, key)?,
+            None => self.get_value(key)?,
+        };
+        if value.meta & VALUE_POINTER != 0 {
+            // TODO: read value from value log
+            return Err(Error::CustomError(format!(
+                "value of {:?} is stored in value log, which is not supported yet",
+                key
+            )));
+        }
+        if value.is_expired(self.opts.clock.unix_time()) {
+            return Ok(Value::default());
+        }
+        let base_ts = value.version.checked_sub(1);
+        self.fold_merge(user_key(key), value, base_ts)
+    }
+
+    pub(crate) fn merge_operator(&self) -> Result<&dyn MergeOperator> {
+        match &self.opts.merge_operator {
+            Some(op) => Ok(op.as_ref()),
+            None => Err(Error::Config(
+                "merge operator is required for merge operands".to_string(),
+            )),
+        }
+    }
+
+    /// If `value` is a merge operand, fold it with operands of older versions,
+    /// until a complete value, a deletion or the oldest version is reached.
+    /// Only versions not greater than `base_ts` are taken into account.
+    // TODO: fold operands during compaction
+    pub(crate) fn fold_merge(
+        &self,
+        user_key: &[u8],
+        mut value: Value,
+        mut base_ts: Option<u64>,
+    ) -> Result<Value> {
+        if value.meta & VALUE_MERGE_ENTRY == 0 {
+            return Ok(value);
+        }
+        let op = self.merge_operator()?;
+
+        let now = self.opts.clock.unix_time();
+        let mut operands = vec![value.value.clone()];
+        let mut base = None;
+        while let Some(ts) = base_ts {
+            let older = with_key_ts(KeyTs::new(user_key, ts), |key| self.get_value(key))?;
+            // Missing values are returned with version 0.
+            if older.version == 0 && older.meta == 0 && older.value.is_empty() {
+                break;
+            }
+            if older.meta & VALUE_DELETE != 0 || older.is_expired(now) {
+                break;
+            }
+            if older.meta & VALUE_MERGE_ENTRY == 0 {
+                base = Some(older.value);
+                break;
+            }
+            operands.push(older.value);
+            base_ts = older.version.checked_sub(1);
+        }
+
+        for operand in operands.iter().rev() {
+            base = Some(op.merge(user_key, base.as_deref(), operand));
+        }
+        value.value = base.unwrap();
+        value.meta &= !VALUE_MERGE_ENTRY;
+        Ok(value)
+    }
+
+    /// Get value from row cache, or from LSM tree and cache it.
+    fn get_value_cached(&self, cache: &RowCache, key: &Bytes) -> Result<Value> {
+        if self.is_closed() {
+            return Err(Error::DBClosed);
+        }
+        let (user_key, read_ts) = (user_key(key), get_ts(key));
+        if let Some(value) = cache.get(user_key, read_ts) {
+            return Ok(value);
+        }
+        let ticket = cache.begin_fill(read_ts);
+        let value = self.get_value(key)?;
+        // Folded merge operands change once older operands expire.
+        if value.meta & (VALUE_MERGE_ENTRY | VALUE_POINTER) == 0 {
+            if let Some(ticket) = ticket {
+                cache.fill(ticket, user_key, value.clone());
+            }
+        }
+        Ok(value)
+    }
+
+    /// Keys of `entries` to invalidate in row cache once they are inserted.
+    fn row_cache_keys(&self, entries: &[Entry]) -> Vec<Bytes> {
+        if self.row_cache.is_none() {
+            return vec![];
+        }
+        entries
+            .iter()
+            .filter(|e| e.meta & VALUE_FIN_TXN == 0)
+            .map(|e| e.key.clone())
+            .collect()
+    }
+
+    fn invalidate_rows(&self, keys: &[Bytes]) {
+        if let Some(cache) = &self.row_cache {
+            cache.invalidate(keys);
+        }
+    }
+
+    /// Install tables at the last level, see `LevelsController::add_bottom_tables`.
+    pub(crate) fn add_bottom_tables(&self, tables: Vec<Table>) -> Result<()> {
+        let max_version = tables.iter().map(Table::max_version).max().unwrap_or(0);
+        self.lvctl.add_bottom_tables(tables)?;
+        if let Some(cache) = &self.row_cache {
+            cache.clear(max_version);
+        }
+        Ok(())
+    }
+
+    fn get_value(&self, key: &Bytes) -> Result<Value> {
+        if self.is_closed() {
+            return Err(Error::DBClosed);
+        }
+
+        let view = self.mts.read()?.view();
+        let version = get_ts(key);
+        let mut max_value = Value::default();
+
+        for table in view.tables() {
+            if let Some((found_key, found_value)) = table.get_with_key(key) {
+                let mut value = Value::default();
+                value.decode(found_value);
+                value.version = get_ts(found_key);
+                if value.version == version {
+                    return Ok(value);
+                }
+                if max_value.version < value.version {
+                    max_value = value;
+                }
+            }
+        }
+
+        self.lvctl.get(key, max_value)
+    }
+
+    /// See `Agate::get_multi`.
+    pub(crate) fn get_multi(&self, keys: &[Bytes]) -> Result<Vec<Value>> {
+        if self.is_closed() {
+            return Err(Error::DBClosed);
+        }
+
+        let mut indices: Vec<usize> = (0..keys.len()).collect();
+        let c = self.lvctl.comparator();
+        indices.sort_by(|a, b| c.compare_key(&keys[*a], &keys[*b]));
+        let mut values = vec![Value::default(); keys.len()];
+
+        let view = self.mts.read()?.view();
+        for table in view.tables() {
+            for &i in &indices {
+                if values[i].version == get_ts(&keys[i]) {
+                    continue;
+                }
+                if let Some((found_key, found_value)) = table.get_with_key(&keys[i]) {
+                    let version = get_ts(found_key);
+                    if values[i].version < version {
+                        values[i].decode(found_value);
+                        values[i].version = version;
+                    }
+                }
+            }
+        }
+
+        self.lvctl.get_multi(keys, &indices, &mut values)?;
+
+        let now = self.opts.clock.unix_time();
+        for (key, value) in keys.iter().zip(&mut values) {
+            if value.is_expired(now) {
+                *value = Value::default();
+            } else if value.meta & VALUE_MERGE_ENTRY != 0 {
+                let base_ts = value.version.checked_sub(1);
+                *value = self.fold_merge(user_key(key), std::mem::take(value), base_ts)?;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Sync WALs of all memtables and database directories, so that all
+    /// writes acknowledged so far survive power loss.
+    fn sync(&self) -> Result<()> {
+        if self.opts.in_memory || self.opts.read_only {
+            return Ok(());
+        }
+        {
+            let mts = self.mts.read()?;
+            // WALs of immutable memtables may be deleted by flusher, which
+            // is skipped by `sync_wal`.
+            for idx in 0..mts.nums_of_memtable() - 1 {
+                mts.table_imm(idx).sync_wal()?;
+            }
+            mts.table_mut().sync_wal()?;
+        }
+        // TODO: sync value log
+        Agate::sync_dirs(&self.opts)
+    }
+
+    fn max_version(&self) -> Result<u64> {
+        // TODO: take value log into account
+        let memtable_version = self.mts.read()?.max_version();
+        Ok(memtable_version.max(self.lvctl.max_version()?))
+    }
+
+    /// Rotate mutable memtable if it's full, or its WAL has no room for a
+    /// batch of `size` bytes encoded. `Error::WriteNoRoom` is returned if
+    /// there are already `num_memtables` memtables waiting to be flushed.
+    fn ensure_room_for_write(&self, size: usize) -> Result<()> {
+        let mut mts = self.mts.write()?;
+        let mt = mts.table_mut();
+        if !mt.is_full() && mt.wal_has_room(size) {
+            return Ok(());
+        }
+
+        self.rotate_memtable(&mut mts)
+    }
+
+    /// Make mutable memtable immutable and send it to flusher.
+    fn rotate_memtable(&self, mts: &mut MemTables) -> Result<()> {
+        self.check_flush_error()?;
+        if mts.nums_of_memtable() >= self.opts.num_memtables {
+            return Err(Error::WriteNoRoom(()));
+        }
+
+        // Check room of flush channel before creating the WAL and reserving
+        // the file ID. Memtables are only rotated with `mts` locked, so
+        // the room is kept until sending.
+        if self.flush_channel.0.is_full() {
+            return Err(Error::WriteNoRoom(()));
+        }
+
+        // Entries are written to the new memtable from now on, so its WAL
+        // can reuse the encoding buffer of the current one.
+        mts.table_mut().release_wal_buffer();
+        let mt = Arc::new(self.new_mem_table()?);
+        let task = FlushTask {
+            mt: mts.table_mut().clone(),
+            file_id: self.lvctl.reserve_file_id(),
+        };
+        if self.flush_channel.0.try_send(Some(task)).is_err() {
+            // Flushers have exited, the new WAL is never used.
+            let _ = mt.delete_wal();
+            return Err(Error::WriteNoRoom(()));
+        }
+        mts.use_new_table(mt);
+
+        Ok(())
+    }
+
+    /// Rotate mutable memtable even if it's not full, so that all data written
+    /// so far will be flushed to L0. If `wait` is true, block until they are
+    /// flushed.
+    pub(crate) fn flush_memtable(&self, wait: bool) -> Result<()> {
+        let mut guard = self.write_stall.0.lock()?;
+        let target = loop {
+            let mut mts = self.mts.write()?;
+            let mt = mts.table_mut().clone();
+            if mt.skl.is_empty() {
+                // Nothing to rotate, only need to wait for existing immutable memtables.
+                match mts.nums_of_memtable() {
+                    1 => return Ok(()),
+                    n => break mts.table_imm(n - 2).clone(),
+                }
+            }
+            match self.rotate_memtable(&mut mts) {
+                Ok(()) => break mt,
+                Err(Error::WriteNoRoom(())) => {}
+                Err(err) => return Err(err),
+            }
+            drop(mts);
+            guard = self.write_stall.1.wait(guard)?;
+        };
+
+        if !wait {
+            return Ok(());
+        }
+
+        // Memtables are flushed in order, so all memtables before `target`
+        // are flushed once `target` is removed.
+        loop {
+            {
+                let mts = self.mts.read()?;
+                let flushed = (0..mts.nums_of_memtable() - 1)
+                    .all(|idx| !Arc::ptr_eq(mts.table_imm(idx), &target));
+                if flushed {
+                    return Ok(());
+                }
+            }
+            self.check_flush_error()?;
+            guard = self.write_stall.1.wait(guard)?;
+        }
+    }
+
+    /// Block until mutable memtable has room for a batch of `size` bytes
+    /// encoded. `Error::WriteStalled` is returned if memtables are still
+    /// saturated when `deadline` is reached.
+    fn wait_for_room(&self, deadline: Option<Instant>, size: usize) -> Result<()> {
+        // Hold the lock while checking, so that notification from flusher
+        // won't be missed between checking and waiting.
+        let mut guard = self.write_stall.0.lock()?;
+        let mut stalled_at = None;
+        let res = loop {
+            match self.ensure_room_for_write(size) {
+                Err(Error::WriteNoRoom(())) => {}
+                res => break res,
+            }
+
+            let now = Instant::now();
+            if stalled_at.is_none() {
+                stalled_at = Some(self.opts.clock.now());
+                self.opts.notify(|l| l.on_stall_begin());
+            }
+            guard = match deadline {
+                None => self.write_stall.1.wait(guard)?,
+                Some(deadline) => {
+                    if now >= deadline {
+                        break Err(Error::WriteStalled);
+                    }
+                    self.write_stall.1.wait_timeout(guard, deadline - now)?.0
+                }
+            };
+        };
+
+        if let Some(stalled_at) = stalled_at {
+            let stalled = self.opts.clock.now().saturating_duration_since(stalled_at);
+            self.metrics.record_write_stall(stalled);
+            self.opts.notify(|l| l.on_stall_end(stalled));
+        }
+        res
     }
 
-    /// `write_to_lsm` will only be called in write thread (or write coroutine).
+    /// Write entries of `request` to WAL and memtable in current thread. Write
+    /// thread doesn't call it, but pipelines `append_to_wal` for next batch
+    /// with inserting previous batch in insert thread instead.
     ///
     /// By using a fine-grained lock approach, writing to LSM tree acquires:
     /// 1. read lock of memtable list (only block flush)
     /// 2. write lock of mutable memtable WAL (won't block mut-table read).
     /// 3. level controller lock (TBD)
-    pub(crate) fn write_to_lsm(&self, _request: Request) -> Result<()> {
-        unimplemented!()
+    pub(crate) fn write_to_lsm(&self, mut request: Request) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.wait_for_room(request.deadline, wal::encoded_size(&request.entries))?;
+
+        self.resolve_ttl(&mut request.entries);
+        let mt = self.append_to_wal(&request.entries)?;
+        fail::fail_point!("write_after_wal");
+        let changes = self.subscribers.collect(&request.entries);
+        let rows = self.row_cache_keys(&request.entries);
+        mt.insert_batch(request.entries);
+        self.invalidate_rows(&rows);
+        if self.sync_writes.load(Ordering::Relaxed) {
+            mt.sync_wal()?;
+        }
+        self.subscribers.deliver(changes);
+        Ok(())
+    }
+
+    /// Append entries to WAL of mutable memtable, and return the memtable.
+    /// Entries should be inserted into the memtable with
+    /// `MemTable::insert_batch` afterwards.
+    fn append_to_wal(&self, entries: &[Entry]) -> Result<Arc<MemTable>> {
+        let (mut puts, mut deletes) = (0, 0);
+        for entry in entries {
+            if entry.meta & VALUE_FIN_TXN != 0 {
+                continue;
+            }
+            if entry.meta & VALUE_DELETE != 0 {
+                deletes += 1;
+            } else {
+                puts += 1;
+            }
+        }
+
+        // Hold the read lock while appending, so that the memtable can't be
+        // rotated and sent to flusher before the batch is counted.
+        let mts = self.mts.read()?;
+        let mt = mts.table_mut().clone();
+
+        // TODO: write value pointer if value is stored in value log
+        let wal_bytes = mt.append_wal(entries)?;
+
+        self.metrics
+            .record_write_batch(puts, deletes, wal_bytes as u64);
+        Ok(mt)
+    }
+
+    /// Calculate `expires_at` of entries with TTL by current time.
+    fn resolve_ttl(&self, entries: &mut [Entry]) {
+        let now = self.opts.clock.unix_time();
+        for entry in entries {
+            if let Some(ttl) = entry.ttl.take() {
+                entry.expires_at = now + ttl.as_secs();
+            }
+        }
+    }
+
+    /// Check lengths of an entry, whose key doesn't contain timestamp. Values
+    /// are encoded with u32 lengths, and the entry must fit in an empty WAL
+    /// together with its header and checksum.
+    pub(crate) fn check_entry_size(&self, key_len: usize, value_len: usize) -> Result<()> {
+        if key_len > MAX_KEY_LENGTH {
+            return Err(Error::TooLong {
+                what: "key",
+                len: key_len,
+                limit: MAX_KEY_LENGTH,
+            });
+        }
+        // Header of the next entry is zeroed after the entry.
+        let overhead = (key_len + 8 + 2 * wal::MAX_HEADER_SIZE + wal::CRC_SIZE) as u64;
+        let limit = (2 * self.opts.value_log_file_size)
+            .saturating_sub(overhead)
+            .min(u32::MAX as u64) as usize;
+        if value_len > limit {
+            return Err(Error::TooLong {
+                what: "value",
+                len: value_len,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Send entries to write channel. `done` is called with the result once
+    /// entries are written to LSM tree by write thread. If an error is
+    /// returned, `done` is dropped without being called.
+    pub(crate) fn send_to_write_channel(
+        &self,
+        mut entries: Vec<Entry>,
+        deadline: Option<Instant>,
+        done: WriteCallback,
+    ) -> Result<()> {
+        if self.is_closed() {
+            return Err(Error::DBClosed);
+        }
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        for entry in &entries {
+            self.check_entry_size(entry.key.len().saturating_sub(8), entry.value.len())?;
+        }
+        let size: u64 = entries
+            .iter()
+            .map(|entry| (entry.key.len() + entry.value.len()) as u64)
+            .sum();
+        if entries.len() as u64 > self.opts.max_batch_count || size > self.opts.max_batch_size {
+            return Err(Error::TxnTooBig);
+        }
+
+        let throttled = self.rate_limiter.acquire(
+            size,
+            entries.len() as u64,
+            self.opts.clock.as_ref(),
+            deadline,
+        )?;
+        if throttled > Duration::from_secs(0) {
+            self.metrics.record_write_throttle(throttled);
+        }
+
+        self.resolve_ttl(&mut entries);
+        let request = Request {
+            entries,
+            deadline,
+            done: Some(done),
+        };
+        if self.write_channel.0.send(Some(request)).is_err() {
+            return Err(Error::DBClosed);
+        }
+
+        Ok(())
+    }
+
+    /// Write entries through write thread and wait for the result.
+    pub(crate) fn write_entries(
+        &self,
+        entries: Vec<Entry>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let done = Box::new(move |result| {
+            // Writer may have given up waiting, so it's fine if it fails.
+            let _ = tx.send(result);
+        });
+        self.send_to_write_channel(entries, deadline, done)?;
+        match rx.recv() {
+            Ok(result) => result,
+            Err(_) => Err(Error::DBClosed),
+        }
+    }
+
+    /// See `Agate::apply`.
+    pub(crate) fn apply(&self, batch: Vec<Entry>, commit_ts: u64) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let batch = Self::txn_batch(batch, commit_ts)?;
+        self.write_entries(batch, None)
+    }
+
+    /// Append timestamp to keys in `batch`, and mark them as entries of a
+    /// transaction committed at `commit_ts`.
+    pub(crate) fn txn_batch(mut batch: Vec<Entry>, commit_ts: u64) -> Result<Vec<Entry>> {
+        for entry in &mut batch {
+            if entry.key.is_empty() {
+                return Err(Error::EmptyKey);
+            }
+            entry.key = key_with_ts(&entry.key[..], commit_ts);
+            entry.meta |= VALUE_TXN;
+        }
+        let mut fin = Entry::new(
+            key_with_ts(TXN_KEY, commit_ts),
+            Bytes::from(commit_ts.to_string()),
+        );
+        fin.meta = VALUE_FIN_TXN;
+        batch.push(fin);
+
+        Ok(batch)
+    }
+
+    /// Merge `requests` into a single batch so that they are written with one
+    /// WAL write and at most one sync, then notify all writers.
+    fn write_requests(&self, mut requests: Vec<Request>) {
+        // Wait for room before merging, so that requests past their deadline
+        // can fail alone.
+        loop {
+            let deadline = requests.iter().filter_map(|r| r.deadline).min();
+            let size = requests.iter().map(|r| wal::encoded_size(&r.entries)).sum();
+            match self.wait_for_room(deadline, size) {
+                Ok(()) => break,
+                Err(Error::WriteStalled) => {
+                    let now = Instant::now();
+                    let (expired, rest) = requests
+                        .into_iter()
+                        .partition(|r| matches!(r.deadline, Some(d) if d <= now));
+                    requests = rest;
+                    Self::notify_requests(expired, || Err(Error::WriteStalled));
+                    if requests.is_empty() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    let err = Arc::new(err);
+                    Self::notify_requests(requests, || Err(Error::Shared(err.clone())));
+                    return;
+                }
+            }
+        }
+
+        let mut entries = Vec::with_capacity(requests.iter().map(|r| r.entries.len()).sum());
+        let mut dones = Vec::with_capacity(requests.len());
+        for request in requests {
+            entries.extend(request.entries);
+            if let Some(done) = request.done {
+                dones.push(done);
+            }
+        }
+
+        // Failed batches also go through insert thread, so that writers are
+        // always notified in the order of their writes.
+        let batch = self.append_to_wal(&entries).map(|mt| (mt, entries));
+        fail::fail_point!("write_after_wal");
+        // Dropped callbacks get `Error::DBClosed` if insert thread has exited.
+        let _ = self
+            .insert_channel
+            .0
+            .send(Some(InsertTask { batch, dones }));
+    }
+
+    /// Insert batches appended to WAL by write thread into memtables, and
+    /// notify writers until `None` is received.
+    fn insert_batches(&self) {
+        for task in self.insert_channel.1.iter() {
+            let task = match task {
+                Some(task) => task,
+                None => break,
+            };
+            let result = task.batch.and_then(|(mt, entries)| {
+                let changes = self.subscribers.collect(&entries);
+                let rows = self.row_cache_keys(&entries);
+                mt.insert_batch(entries);
+                self.invalidate_rows(&rows);
+                if self.sync_writes.load(Ordering::Relaxed) {
+                    mt.sync_wal()?;
+                }
+                // Changes are published before writers are notified, so
+                // they are queued once commits return.
+                self.subscribers.deliver(changes);
+                Ok(())
+            });
+
+            // `Error` is not clone, so writers share the same one.
+            let result = result.map_err(Arc::new);
+            for done in task.dones {
+                done(result.clone().map_err(Error::Shared));
+            }
+        }
+    }
+
+    fn notify_requests(requests: Vec<Request>, result: impl Fn() -> Result<()>) {
+        for request in requests {
+            if let Some(done) = request.done {
+                done(result());
+            }
+        }
+    }
+
+    /// Pick up requests from write channel and write them in groups until
+    /// `None` is received.
+    fn do_writes(&self) {
+        let mut pending: Option<Request> = None;
+        let mut closed = false;
+
+        while !closed {
+            let first = match pending.take() {
+                Some(request) => request,
+                None => match self.write_channel.1.recv() {
+                    Ok(Some(request)) => request,
+                    _ => break,
+                },
+            };
+
+            let mut count = first.entries.len() as u64;
+            let mut size = wal::encoded_size(&first.entries) as u64;
+            let mut requests = vec![first];
+            while requests.len() < MAX_REQUESTS_PER_WRITE {
+                match self.write_channel.1.try_recv() {
+                    Ok(Some(request)) => {
+                        // Keep the merged batch within memtable arena headroom,
+                        // and small enough to fit in an empty WAL.
+                        let request_size = wal::encoded_size(&request.entries) as u64;
+                        if count + request.entries.len() as u64 > self.opts.max_batch_count
+                            || size + request_size > self.opts.value_log_file_size
+                        {
+                            pending = Some(request);
+                            break;
+                        }
+                        count += request.entries.len() as u64;
+                        size += request_size;
+                        requests.push(request);
+                    }
+                    Ok(None) => {
+                        closed = true;
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            self.write_requests(requests);
+        }
+
+        if let Some(request) = pending {
+            self.write_requests(vec![request]);
+        }
+    }
+
+    /// Finish `builder` and create a table with a newly reserved file ID,
+    /// either in memory or on disk.
+    fn create_table(&self, builder: table::builder::Builder) -> Result<Table> {
+        self.create_table_with_id(builder, self.lvctl.reserve_file_id())
+    }
+
+    fn create_table_with_id(
+        &self,
+        builder: table::builder::Builder,
+        file_id: u64,
+    ) -> Result<Table> {
+        self.lvctl.create_table(builder, file_id)
+    }
+
+    /// Block until memtables rotated before `mt` are flushed by other flush
+    /// workers, so that `mt` becomes the oldest immutable memtable.
+    fn wait_for_flush_turn(&self, mt: &Arc<MemTable>) -> Result<()> {
+        let mut guard = self.write_stall.0.lock()?;
+        loop {
+            if Arc::ptr_eq(self.mts.read()?.table_imm(0), mt) {
+                return Ok(());
+            }
+            self.check_flush_error()?;
+            guard = self.write_stall.1.wait(guard)?;
+        }
+    }
+
+    /// Build an L0 table from `mt`, and remove `mt` from immutable memtables
+    /// after the table is recorded in manifest. Tables are built
+    /// concurrently, but added to L0 in the order of memtables. The task can
+    /// be retried with the same `progress` if a retryable error is returned.
+    fn handle_flush_task(&self, task: &FlushTask, progress: &mut FlushProgress) -> Result<()> {
+        let start = self.opts.clock.now();
+        // Batches appended before rotation may still be being inserted.
+        task.mt.wait_applied();
+        let is_empty = task.mt.skl.is_empty();
+        let mut info = FlushInfo {
+            memtable_size: task.mt.memory_usage().total(),
+            table_id: None,
+            duration: Duration::from_secs(0),
+        };
+        debug!("flushing memtable of {} bytes", info.memtable_size);
+        self.opts.notify(|l| l.on_flush_begin(&info));
+        if !is_empty && progress.table.is_none() {
+            let table_opts = build_level_table_options(&self.opts, 0);
+            let mut builder = table::builder::Builder::new(table_opts);
+            let mut iter = task.mt.new_iterator(false);
+            iter.rewind();
+            while iter.valid() {
+                // TODO: set vlog_len when value log is implemented
+                builder.add(&Bytes::copy_from_slice(iter.key()), iter.value(), 0);
+                iter.next();
+            }
+            progress.table = Some(self.create_table_with_id(builder, task.file_id)?);
+        }
+
+        self.wait_for_flush_turn(&task.mt)?;
+        if let Some(table) = &progress.table {
+            info.table_id = Some(table.id());
+            if !progress.added {
+                fail::fail_point!("flush_before_manifest", |_| Err(Error::CustomError(
+                    "failpoint flush_before_manifest".to_string()
+                )));
+                let deadline = Instant::now() + LEVEL_ZERO_STALL_TIMEOUT;
+                self.lvctl.add_l0_table(table.clone(), deadline)?;
+                progress.added = true;
+                fail::fail_point!("flush_after_add_l0", |_| Err(Error::Io(Box::new(
+                    std::io::Error::other("failpoint flush_after_add_l0")
+                ))));
+            }
+        }
+
+        fail::fail_point!("flush_before_delete_wal", |_| Err(Error::CustomError(
+            "failpoint flush_before_delete_wal".to_string()
+        )));
+        // Data is persisted in L0 now, so WAL is no longer needed.
+        task.mt.delete_wal()?;
+
+        {
+            let mut mts = self.mts.write()?;
+            assert!(Arc::ptr_eq(mts.table_imm(0), &task.mt));
+            mts.pop_imm();
+        }
+        info.duration = self.opts.clock.now().saturating_duration_since(start);
+        if !is_empty {
+            self.metrics.record_memtable_flush(info.duration);
+        }
+        debug!(
+            "flushed memtable to table {:?} in {:?}",
+            info.table_id, info.duration
+        );
+        self.opts.notify(|l| l.on_flush_completed(&info));
+
+        let _guard = self.write_stall.0.lock()?;
+        self.write_stall.1.notify_all();
+
+        Ok(())
+    }
+
+    /// Flush immutable memtables sent by writers until `None` is received.
+    fn flush_memtables(&self) {
+        for task in self.flush_channel.1.iter() {
+            let task = match task {
+                Some(task) => task,
+                None => break,
+            };
+            // Keep receiving tasks after failure, so that writers won't be
+            // blocked on sending.
+            if self.check_flush_error().is_err() {
+                continue;
+            }
+            let mut progress = FlushProgress::default();
+            while let Err(err) = self.handle_flush_task(&task, &mut progress) {
+                if err.is_retryable() {
+                    warn!("failed to flush memtable: {:?}, retrying", err);
+                    thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+                let mut flush_error = self.flush_error.lock().unwrap();
+                // Other workers stop waiting for their turns once one of them
+                // fails, keep the first error.
+                if flush_error.is_none() {
+                    error!("failed to flush memtable: {:?}, stop flushing", err);
+                    *flush_error = Some(Arc::new(err));
+                }
+                drop(flush_error);
+                // Wake up writers waiting for room, which will see the error.
+                let _guard = self.write_stall.0.lock().unwrap();
+                self.write_stall.1.notify_all();
+                break;
+            }
+        }
+    }
+
+    /// Compact levels which need it until `signal` is closed. Compactions
+    /// run back to back, and levels are checked every `COMPACTION_INTERVAL`
+    /// once there is nothing to compact.
+    fn run_compactor(&self, id: usize, signal: &Signal) {
+        let mut interval = COMPACTION_INTERVAL;
+        while !signal.wait_timeout(interval) {
+            interval = match self.lvctl.compact(id, self.orc.discard_ts()) {
+                Ok(true) => Duration::ZERO,
+                Ok(false) => COMPACTION_INTERVAL,
+                Err(err) => {
+                    warn!("compactor {} failed: {:?}", id, err);
+                    COMPACTION_INTERVAL
+                }
+            };
+        }
+    }
+
+    fn check_flush_error(&self) -> Result<()> {
+        match &*self.flush_error.lock()? {
+            Some(err) => Err(Error::FlushFailed(err.clone())),
+            None => Ok(()),
+        }
     }
 }
 
 impl Agate {
     pub fn get(&self, key: &[u8]) -> Result<Value> {
-        self.core.get(key)
+        with_key_ts(KeyTs::decode(key), |key| self.core.get(key))
+    }
+
+    /// Get values of all `keys` in a batch, which is faster than calling
+    /// `get` for every key. Keys should contain timestamp, and results are
+    /// returned in the same order as `keys`.
+    pub fn get_multi(&self, keys: &[Bytes]) -> Result<Vec<Value>> {
+        self.core.get_multi(keys)
     }
 
     pub fn write_to_lsm(&self, request: Request) -> Result<()> {
         self.core.write_to_lsm(request)
     }
 
-    pub fn open<P: AsRef<Path>>(mut opts: AgateOptions, path: P) -> Result<Self> {
-        opts.fix_options()?;
+    /// Write entries through write thread, where concurrent writes are
+    /// grouped and committed together. Keys of entries should contain
+    /// timestamp.
+    pub fn write_entries(&self, entries: Vec<Entry>) -> Result<()> {
+        self.core.write_entries(entries, None)
+    }
 
-        opts.dir = path.as_ref().to_path_buf();
+    /// Same as `write_entries`, but gives up with `Error::WriteStalled` if
+    /// memtables are still saturated when `deadline` is reached.
+    pub fn write_entries_with_deadline(
+        &self,
+        entries: Vec<Entry>,
+        deadline: Instant,
+    ) -> Result<()> {
+        self.core.write_entries(entries, Some(deadline))
+    }
+
+    /// Same as `write_entries`, but returns a future which resolves once the
+    /// entries are written, instead of blocking current thread.
+    #[cfg(feature = "async")]
+    pub fn write_entries_async(&self, entries: Vec<Entry>) -> WriteFuture {
+        let (future, done) = WriteFuture::new();
+        if let Err(err) = self.core.send_to_write_channel(entries, None, done) {
+            return WriteFuture::ready(Err(err));
+        }
+        future
+    }
+
+    /// Write a single key value pair asynchronously. `key` should contain
+    /// timestamp. See `write_entries_async`.
+    #[cfg(feature = "async")]
+    pub fn put_async(&self, key: Bytes, value: Bytes) -> WriteFuture {
+        self.write_entries_async(vec![Entry::new(key, value)])
+    }
+
+    /// Apply a batch of entries prepared by caller atomically at `commit_ts`.
+    /// Keys in `batch` should not contain timestamp. All entries are written
+    /// to WAL in one write, and either all or none of them are recovered after
+    /// a crash.
+    pub fn apply(&self, batch: Vec<Entry>, commit_ts: u64) -> Result<()> {
+        self.core.apply(batch, commit_ts)
+    }
+
+    /// Rotate and flush mutable memtable to L0 regardless of its size. WAL of
+    /// the memtable is removed after flush. If `wait` is true, block until
+    /// all memtables rotated so far are flushed.
+    pub fn flush_memtable(&self, wait: bool) -> Result<()> {
+        if self.core.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.core.flush_memtable(wait)
+    }
+
+    /// Estimate on-disk bytes and key count of user keys within `[start, end)`.
+    ///
+    /// The estimation is based on table index metadata, and memtables
+    /// are not taken into account.
+    pub fn estimate_size(&self, start: &[u8], end: &[u8]) -> SizeEstimate {
+        self.core.lvctl.estimate_size(start, end)
+    }
+
+    /// Get on-disk size of tables at every level and value log files.
+    /// Memtables are not included, see `memory_usage`. `DbSize::vlog` is
+    /// always 0, as value log is not implemented yet.
+    pub fn size(&self) -> Result<DbSize> {
+        Ok(DbSize {
+            levels: self.core.lvctl.level_sizes()?,
+            // TODO: sum up lengths of value log files
+            vlog: 0,
+        })
+    }
+
+    /// Rewrite a value log file if at least `discard_ratio` of it can be
+    /// discarded, like `badger` value log GC. Returns whether a file is
+    /// rewritten. `discard_ratio` should be in `(0, 1)`.
+    ///
+    /// Value log is not implemented yet, as all values are kept in LSM
+    /// tree, so it always fails with `Error::Config` for now.
+    pub fn run_gc(&self, discard_ratio: f64) -> Result<bool> {
+        if !(discard_ratio > 0.0 && discard_ratio < 1.0) {
+            return Err(Error::Config(format!(
+                "discard ratio {} should be in (0, 1)",
+                discard_ratio
+            )));
+        }
+        if self.core.is_closed() {
+            return Err(Error::DBClosed);
+        }
+        if self.core.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        // TODO: pick and rewrite value log files when value log is
+        // implemented.
+        Err(Error::Config("value log not supported".to_string()))
+    }
+
+    /// Get the summary of verifying tables on open, which is only available
+    /// with `ChecksumVerificationMode::OnTableOpen`.
+    pub fn verify_report(&self) -> Option<&VerifyReport> {
+        self.core.lvctl.verify_report()
+    }
+
+    /// Get a snapshot of metadata of every level and its tables.
+    pub fn levels(&self) -> Result<Vec<LevelInfo>> {
+        self.core.lvctl.level_infos()
+    }
+
+    /// Get statistics of prefix compression of tables at every level,
+    /// indexed by level.
+    pub fn prefix_stats(&self) -> Vec<PrefixStats> {
+        self.core.lvctl.level_prefix_stats()
+    }
+
+    /// Get metadata of all tables, ordered by level.
+    pub fn tables(&self) -> Result<Vec<TableInfo>> {
+        let levels = self.levels()?;
+        Ok(levels.into_iter().flat_map(|level| level.tables).collect())
+    }
+
+    /// Get memory held by all memtables, including the ones waiting to be
+    /// flushed.
+    pub fn memory_usage(&self) -> Result<MemoryUsage> {
+        Ok(self.core.mts.read()?.memory_usage())
+    }
+
+    /// Get the max version of all data in the database, which includes
+    /// entries written with explicit timestamps.
+    pub fn max_version(&self) -> Result<u64> {
+        self.core.max_version()
+    }
+
+    /// Get identity of the store. Incarnation is 0 if the identity is not
+    /// persisted, like in in-memory mode, or when a read-only database has
+    /// no `IDENTITY` file.
+    pub fn identity(&self) -> &StoreIdentity {
+        &self.core.identity
+    }
+
+    /// Persist all writes acknowledged so far, which is useful when
+    /// `sync_writes` is disabled and durability is only needed at some
+    /// points, like checkpoints of applications.
+    pub fn sync(&self) -> Result<()> {
+        self.core.sync()
+    }
+
+    /// Change `option` of the running database, which takes effect for
+    /// following operations.
+    pub fn set_option(&self, option: RuntimeOption) -> Result<()> {
+        let core = &self.core;
+        match option {
+            RuntimeOption::WriteRateLimit {
+                bytes_per_sec,
+                ops_per_sec,
+            } => core
+                .rate_limiter
+                .set_rates(bytes_per_sec, ops_per_sec, core.opts.clock.now()),
+            RuntimeOption::SyncWrites(sync_writes) => {
+                if sync_writes && core.opts.in_memory {
+                    return Err(Error::Config(
+                        "in-memory mode doesn't support sync_writes".to_string(),
+                    ));
+                }
+                core.sync_writes.store(sync_writes, Ordering::Relaxed);
+                Ok(())
+            }
+            RuntimeOption::BlockCacheSize(size) => match &core.opts.block_cache {
+                Some(cache) => {
+                    cache.set_capacity(size as usize);
+                    Ok(())
+                }
+                None => Err(Error::Config(
+                    "block cache is disabled by block_cache_size 0 on open".to_string(),
+                )),
+            },
+            RuntimeOption::IndexCacheSize(size) => match &core.opts.index_cache {
+                Some(cache) => {
+                    cache.set_capacity(size as usize);
+                    Ok(())
+                }
+                None => Err(Error::Config(
+                    "index cache is disabled by index_cache_size 0 on open".to_string(),
+                )),
+            },
+            RuntimeOption::NumCompactors(num) => {
+                if core.opts.read_only {
+                    return Err(Error::ReadOnly);
+                }
+                let mut compactors = self.compactors.lock()?;
+                let result = compactors.close();
+                *compactors = Self::spawn_compactors(core, num)?;
+                result
+            }
+        }
+    }
+
+    fn spawn_compactors(core: &Arc<Core>, num: usize) -> Result<Closer> {
+        let mut compactors = Closer::default();
+        for i in 0..num {
+            let core = core.clone();
+            compactors.spawn(format!("{}{}", COMPACTOR_TASK, i), move |signal| {
+                core.run_compactor(i, &signal)
+            })?;
+        }
+        Ok(compactors)
+    }
+
+    /// Get time spent in every stage of opening the database.
+    pub fn open_timings(&self) -> &OpenTimings {
+        &self.open_timings
+    }
+
+    /// Get a snapshot of counters recorded since the database is opened.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let mut snapshot = self.core.metrics.snapshot();
+        if let Some(cache) = &self.core.opts.block_cache {
+            let stats = cache.stats();
+            snapshot.block_cache_hits = stats.hits;
+            snapshot.block_cache_misses = stats.misses;
+            snapshot.block_cache_evictions = stats.evictions;
+            snapshot.block_cache_bytes = stats.usage;
+        }
+        if let Some(cache) = &self.core.opts.index_cache {
+            let stats = cache.stats();
+            snapshot.index_cache_hits = stats.hits;
+            snapshot.index_cache_misses = stats.misses;
+            snapshot.index_cache_evictions = stats.evictions;
+            snapshot.index_cache_bytes = stats.usage;
+        }
+        let thresholds = self.core.lvctl.level_zero_thresholds();
+        snapshot.level_zero_compaction_trigger = thresholds.compaction;
+        snapshot.level_zero_stall_trigger = thresholds.stall;
+        snapshot.allocator = crate::allocator::fetch_stats();
+        snapshot
+    }
+
+    /// Read all blocks of tables with `ids` into block cache, to avoid
+    /// latency spikes of a cold cache after opening. Unknown IDs are
+    /// ignored. Returns the number of read blocks.
+    pub fn warm_up_tables(&self, ids: &[u64]) -> Result<usize> {
+        self.check_block_cache()?;
+        self.core
+            .lvctl
+            .warm_up(|table| ids.contains(&table.id()), None)
+    }
+
+    /// Read blocks of all tables which may contain user keys within
+    /// `[start, end)` into block cache. Returns the number of read blocks.
+    pub fn warm_up_range(&self, start: &[u8], end: &[u8]) -> Result<usize> {
+        self.check_block_cache()?;
+        let start = key_with_ts_first(start);
+        let end = key_with_ts_first(end);
+        self.core
+            .lvctl
+            .warm_up(|_| true, Some((&start[..], &end[..])))
+    }
+
+    fn check_block_cache(&self) -> Result<()> {
+        if self.core.opts.block_cache.is_none() {
+            return Err(Error::Config(
+                "block cache is disabled by block_cache_size".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get statistics of block cache and index cache, and cache lookups of
+    /// every level, to help sizing caches.
+    pub fn cache_status(&self) -> CacheStatus {
+        let opts = &self.core.opts;
+        CacheStatus {
+            block_cache: opts.block_cache.as_ref().map(|cache| cache.stats()),
+            index_cache: opts.index_cache.as_ref().map(|cache| cache.stats()),
+            row_cache: self.core.row_cache.as_ref().map(|cache| cache.stats()),
+            levels: self.core.lvctl.level_cache_stats(),
+        }
+    }
+
+    /// Close database, after which reads and writes are rejected with
+    /// `Error::DBClosed`. Pending writes are written before closing, and
+    /// mutable memtable is flushed to L0 if `flush_memtable` is true,
+    /// otherwise its WAL is replayed on next open. Dropping the database
+    /// closes it the same way without flushing, but errors are only logged.
+    ///
+    /// Directory lock is held until transactions and bulk loaders created
+    /// from the database are dropped as well.
+    pub fn close(mut self, flush_memtable: bool) -> Result<()> {
+        self.close_impl(flush_memtable)
+    }
+
+    fn close_impl(&mut self, flush_memtable: bool) -> Result<()> {
+        let core = &self.core;
+        if core.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
 
-        if !opts.in_memory {
-            if !opts.dir.exists() {
-                fs::create_dir_all(&opts.dir)?;
+        // Background jobs write through write thread, so they are stopped
+        // before it.
+        self.tasks.signal().close();
+        let mut result = self.tasks.join(EXPIRY_SCANNER_TASK);
+
+        // Stop write thread, so that all pending writes reach memtables.
+        let _ = core.write_channel.0.send(None);
+        result = result.and(self.tasks.join(WRITER_TASK));
+        // Requests sent after write thread exits will never be written.
+        while let Ok(Some(request)) = core.write_channel.1.try_recv() {
+            if let Some(done) = request.done {
+                done(Err(Error::DBClosed));
             }
-            // TODO: create wal path, acquire database path lock
         }
+        let _ = core.insert_channel.0.send(None);
+        result = result.and(self.tasks.join(INSERTER_TASK));
+        core.subscribers.clear();
+
+        if core.opts.read_only {
+            // nothing is written in read-only mode
+        } else if flush_memtable {
+            result = result.and_then(|_| core.flush_memtable(true));
+        } else {
+            result = result.and_then(|_| core.mts.read()?.table_mut().sync_wal());
+        }
+
+        // Memtables already in queue will be flushed before flush workers exit.
+        for _ in 0..self.tasks.count(FLUSHER_TASK) {
+            let _ = core.flush_channel.0.send(None);
+        }
+        result = result.and(self.tasks.join(FLUSHER_TASK));
+        result = result.and(
+            self.compactors
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .close(),
+        );
+        // Other tasks exit on the signal.
+        result = result.and(self.tasks.close());
+
+        // TODO: sync value log and persist discard stats once they are
+        // implemented. Max version needs no persistence, as it's recovered
+        // from tables and WALs.
+        if !core.opts.in_memory {
+            result = result.and_then(|_| Self::sync_dirs(&core.opts));
+        }
+
+        result
+    }
+
+    fn sync_dirs(opts: &AgateOptions) -> Result<()> {
+        for dir in &[&opts.dir, &opts.value_dir] {
+            if !dir.as_os_str().is_empty() && opts.env.exists(dir) {
+                opts.env.sync_dir(dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn create_dirs(opts: &AgateOptions) -> Result<()> {
+        for dir in &[&opts.dir, &opts.value_dir] {
+            if !dir.as_os_str().is_empty() && !opts.env.exists(dir) {
+                opts.env.create_dir_all(dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Open or create a database at `path`. The directory is locked
+    /// exclusively until the database is dropped, and any failure after
+    /// options are checked is reported as `Error::Open` with its stage.
+    pub fn open<P: AsRef<Path>>(mut opts: AgateOptions, path: P) -> Result<Self> {
+        opts.dir = path.as_ref().to_path_buf();
+
+        opts.fix_options()?;
+
+        let clock = opts.clock.clone();
+        let mut timings = OpenTimings::default();
+        if !opts.in_memory && !opts.read_only {
+            timings.record(clock.as_ref(), OpenStage::CreateDir, || {
+                Self::create_dirs(&opts)
+            })?;
+        }
+
+        let core = Arc::new(Core::new(opts, &mut timings)?);
+
+        let (tasks, compactors) = timings.record(clock.as_ref(), OpenStage::Workers, || {
+            let mut tasks = Closer::default();
+            let mut spawn = |name: String, f: fn(&Core)| {
+                let core = core.clone();
+                tasks.spawn(name, move |_| f(&core))
+            };
+            for i in 0..core.opts.num_flush_workers {
+                spawn(format!("{}{}", FLUSHER_TASK, i), Core::flush_memtables)?;
+            }
+            spawn(WRITER_TASK.to_string(), Core::do_writes)?;
+            spawn(INSERTER_TASK.to_string(), Core::insert_batches)?;
+            if !core.opts.read_only && !core.opts.expiry_scan_interval.is_zero() {
+                let core = core.clone();
+                tasks.spawn(EXPIRY_SCANNER_TASK, move |signal| {
+                    core.scan_expired(&signal)
+                })?;
+            }
+            let compactors = if core.opts.read_only {
+                Closer::default()
+            } else {
+                Self::spawn_compactors(&core, core.opts.num_compactors)?
+            };
+            Ok((tasks, compactors))
+        })?;
+
+        // Memtables replayed from WAL should also be flushed.
+        let imm: Vec<_> = {
+            let mts = core.mts.read()?;
+            (0..mts.nums_of_memtable() - 1)
+                .map(|idx| mts.table_imm(idx).clone())
+                .collect()
+        };
+        // In read-only mode, they are kept in memory instead.
+        if !core.opts.read_only {
+            for mt in imm {
+                let file_id = core.lvctl.reserve_file_id();
+                core.flush_channel
+                    .0
+                    .send(Some(FlushTask { mt, file_id }))
+                    .unwrap();
+            }
+        }
+
+        info!(
+            "opened database at {} in {:?}",
+            core.opts.dir.display(),
+            timings.total()
+        );
+        debug!("time spent in opening stages: {:?}", timings.stages);
 
-        // TODO: open or create manifest
         Ok(Agate {
-            core: Arc::new(Core::new(opts)?),
+            core,
+            tasks,
+            compactors: Mutex::new(compactors),
+            open_timings: timings,
         })
     }
+
+    /// Re-encrypt the key registry of the closed database at `path` with
+    /// `new_key`, without rewriting any data file. `old_key` must be the
+    /// encryption key the database was opened with. The directory is locked
+    /// during rotation, so it fails if the database is opened.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn rotate_master_key<P: AsRef<Path>>(
+        path: P,
+        old_key: &[u8],
+        new_key: &[u8],
+    ) -> Result<()> {
+        let _lock = DirLockGuard::acquire(path.as_ref())?;
+        key_registry::rotate_master_key(&StdEnv, path.as_ref(), old_key, new_key)
+    }
+
+    /// Check the closed database at `path` offline: tables in manifest
+    /// against files on disk, checksums of tables, key ranges of levels,
+    /// and value pointers. Nothing is modified, and it fails if the
+    /// database is opened for writing. Problems found are returned in the
+    /// report instead of as errors.
+    pub fn verify<P: AsRef<Path>>(path: P, mut opts: AgateOptions) -> Result<ConsistencyReport> {
+        let _lock = DirLockGuard::acquire_shared(path.as_ref())?;
+        opts.dir = path.as_ref().to_path_buf();
+        opts.fix_options()?;
+        crate::levels::check_consistency(&opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CachePolicy;
+    use crate::opt::ChecksumVerificationMode;
+    use crate::util::unix_time;
+    use crate::{Env, MappedFile, OpenMode, ReadableFile, StdEnv, WritableFile};
+    use crate::{ErrorKind, UserComparator};
+    use std::fs;
+    use tempdir::TempDir;
+
+    pub(crate) fn test_options() -> AgateOptions {
+        AgateOptions {
+            mem_table_size: 1 << 14,
+            value_log_file_size: 1 << 20,
+            num_memtables: 3,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn write_keys(agate: &Agate, start: u64, end: u64) {
+        for i in start..end {
+            let entry = Entry::new(
+                key_with_ts(format!("key{:05}", i).as_str(), i + 1),
+                Bytes::from(format!("value{:05}", i)),
+            );
+            agate
+                .write_to_lsm(Request {
+                    entries: vec![entry],
+                    deadline: None,
+                    done: None,
+                })
+                .unwrap();
+        }
+    }
+
+    pub(crate) fn count_files(dir: &Path, ext: &str) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_str()
+                    .unwrap()
+                    .ends_with(ext)
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_flush_memtable() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 1000);
+
+        // wait for all immutable memtables to be flushed
+        while agate.core.mts.read().unwrap().nums_of_memtable() > 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(agate.core.lvctl.estimate_size(b"", b"z").keys > 0);
+        assert!(count_files(tmp_dir.path(), ".sst") > 0);
+        assert_eq!(count_files(tmp_dir.path(), MEMTABLE_FILE_EXT), 1);
+
+        for i in (0..1000).step_by(37) {
+            let value = agate
+                .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                .unwrap();
+            assert_eq!(value.value, format!("value{:05}", i));
+            assert_eq!(value.version, i + 1);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_flush() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options().with_num_flush_workers(4);
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        assert_eq!(agate.tasks.count(FLUSHER_TASK), 4);
+        write_keys(&agate, 0, 1000);
+        agate.flush_memtable(true).unwrap();
+
+        // Tables are added in the order of memtables, and so are file IDs.
+        let check_l0 = |agate: &Agate| {
+            let tables = agate.core.lvctl.all_tables().swap_remove(0);
+            assert!(tables.len() > 1);
+            for pair in tables.windows(2) {
+                assert!(pair[0].id() < pair[1].id());
+                assert!(pair[0].max_version() < pair[1].max_version());
+            }
+            for i in (0..1000).step_by(37) {
+                let value = agate
+                    .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                    .unwrap();
+                assert_eq!(value.value, format!("value{:05}", i));
+            }
+        };
+        check_l0(&agate);
+        drop(agate);
+
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        check_l0(&agate);
+    }
+
+    #[test]
+    fn test_adaptive_level_zero() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let clock = Arc::new(crate::ManualClock::new(0));
+        let opts = test_options()
+            .with_level_zero_tables(2, 4)
+            .with_adaptive_level_zero(true)
+            .with_clock(clock.clone());
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let thresholds = |agate: &Agate| {
+            let metrics = agate.metrics();
+            (
+                metrics.level_zero_compaction_trigger,
+                metrics.level_zero_stall_trigger,
+            )
+        };
+        assert_eq!(thresholds(&agate), (2, 4));
+
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        assert_eq!(thresholds(&agate), (1, 5));
+        // Thresholds recover once ingest stops.
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(thresholds(&agate), (2, 4));
+    }
+
+    #[test]
+    fn test_reopen() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        {
+            let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+            write_keys(&agate, 0, 1000);
+        }
+
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        for i in (0..1000).step_by(37) {
+            let value = agate
+                .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                .unwrap();
+            assert_eq!(value.value, format!("value{:05}", i));
+        }
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mut opts = test_options();
+        opts.mem_table_size = 1 << 20;
+        opts.sync_writes = true;
+        let agate = Arc::new(Agate::open(opts, tmp_dir.path()).unwrap());
+
+        let handles: Vec<_> = (0..8u64)
+            .map(|t| {
+                let agate = agate.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = format!("key{}-{:03}", t, i);
+                        let entry = Entry::new(key_with_ts(key.as_str(), 1), Bytes::from(key));
+                        agate.write_entries(vec![entry]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for t in 0..8 {
+            for i in 0..100 {
+                let key = format!("key{}-{:03}", t, i);
+                let value = agate.get(&key_with_ts(key.as_str(), 1)).unwrap();
+                assert_eq!(value.value, key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_too_big() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let entry = Entry::new(key_with_ts("key", 1), Bytes::from(vec![0; 1 << 14]));
+        assert!(matches!(
+            agate.write_entries(vec![entry]),
+            Err(Error::TxnTooBig)
+        ));
+    }
+
+    #[test]
+    fn test_write_stalled() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mut opts = test_options();
+        opts.dir = tmp_dir.path().to_path_buf();
+        opts.fix_options().unwrap();
+        // No flusher is running, so rotated memtables are never freed.
+        let core = Core::new(opts, &mut OpenTimings::default()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let mut i = 0;
+        let err = loop {
+            let key = format!("key{:05}", i);
+            let entry = Entry::new(key_with_ts(key.as_str(), 1), Bytes::from(key));
+            let request = Request {
+                entries: vec![entry],
+                deadline: Some(deadline),
+                done: None,
+            };
+            if let Err(err) = core.write_to_lsm(request) {
+                break err;
+            }
+            i += 1;
+        };
+        assert!(matches!(err, Error::WriteStalled), "{:?}", err);
+        assert!(Instant::now() >= deadline);
+        assert_eq!(
+            core.mts.read().unwrap().nums_of_memtable(),
+            core.opts.num_memtables
+        );
+    }
+
+    #[derive(Default)]
+    struct EventRecorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl EventRecorder {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.events.lock().unwrap())
+        }
+
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    impl crate::EventListener for EventRecorder {
+        fn on_flush_begin(&self, info: &FlushInfo) {
+            assert!(info.memtable_size > 0);
+            self.record(format!("flush begin {:?}", info.table_id));
+        }
+
+        fn on_flush_completed(&self, info: &FlushInfo) {
+            self.record(format!("flush completed {:?}", info.table_id));
+        }
+
+        fn on_compaction_begin(&self, info: &crate::CompactionInfo) {
+            assert!(info.output_tables.is_empty());
+            self.record(format!(
+                "compaction begin L{} to L{} with {:?}",
+                info.level, info.next_level, info.input_tables
+            ));
+        }
+
+        fn on_compaction_completed(&self, info: &crate::CompactionInfo) {
+            self.record(format!(
+                "compaction completed L{} to L{} with {:?}",
+                info.level, info.next_level, info.output_tables
+            ));
+        }
+
+        fn on_table_created(&self, info: &crate::TableCreationInfo) {
+            assert!(info.size > 0);
+            self.record(format!(
+                "table {} created at L{} by {:?}",
+                info.id, info.level, info.reason
+            ));
+        }
+
+        fn on_table_deleted(&self, info: &crate::TableDeletionInfo) {
+            assert!(!info.path.exists());
+            self.record(format!("table {} deleted", info.id));
+        }
+
+        fn on_stall_begin(&self) {
+            self.record("stall begin".to_string());
+        }
+
+        fn on_stall_end(&self, stalled: Duration) {
+            assert!(stalled > Duration::from_secs(0));
+            self.record("stall end".to_string());
+        }
+    }
+
+    #[test]
+    fn test_event_listener() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let recorder = Arc::new(EventRecorder::default());
+        let opts = test_options().with_event_listener(recorder.clone());
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 10);
+        agate.flush_memtable(true).unwrap();
+        let mut loader = agate.new_bulk_loader();
+        loader
+            .add(key_with_ts("key", 1), Value::new(Bytes::from("value")))
+            .unwrap();
+        loader.finish().unwrap();
+        let last_level = opts.max_levels - 1;
+        assert_eq!(
+            recorder.take(),
+            vec![
+                "flush begin None".to_string(),
+                "table 1 created at L0 by Flush".to_string(),
+                "flush completed Some(1)".to_string(),
+                format!("table 2 created at L{} by BulkLoad", last_level),
+            ]
+        );
+        agate.close(false).unwrap();
+
+        // Tables not recorded in manifest are deleted on open.
+        fs::write(table::new_filename(99, tmp_dir.path()), b"").unwrap();
+        drop(Agate::open(opts.clone(), tmp_dir.path()).unwrap());
+        assert_eq!(recorder.take(), vec!["table 99 deleted".to_string()]);
+
+        // Without flusher, writes stall once all memtables are full.
+        let mut opts = opts;
+        opts.dir = tmp_dir.path().join("stall");
+        opts.fix_options().unwrap();
+        fs::create_dir_all(&opts.dir).unwrap();
+        let core = Core::new(opts, &mut OpenTimings::default()).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        for i in 0.. {
+            let entry = Entry::new(
+                key_with_ts(format!("key{:05}", i).as_str(), 1),
+                Bytes::new(),
+            );
+            let request = Request {
+                entries: vec![entry],
+                deadline: Some(deadline),
+                done: None,
+            };
+            if core.write_to_lsm(request).is_err() {
+                break;
+            }
+        }
+        assert_eq!(
+            recorder.take(),
+            vec!["stall begin".to_string(), "stall end".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_compaction_event_listener() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let recorder = Arc::new(EventRecorder::default());
+        let opts = test_options()
+            .with_num_compactors(1)
+            .with_level_zero_tables(2, 15)
+            .with_event_listener(recorder.clone());
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        for _ in 0..2 {
+            write_keys(&agate, 0, 10);
+            agate.flush_memtable(true).unwrap();
+        }
+        let mut events = vec![];
+        for _ in 0..100 {
+            events.extend(recorder.take());
+            if events.iter().any(|e| e.starts_with("compaction completed")) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        events.retain(|e| e.contains("ompaction"));
+        // L0 is compacted into the last level while the database is small.
+        let last_level = opts.max_levels - 1;
+        assert_eq!(
+            events,
+            vec![
+                format!("compaction begin L0 to L{} with [1, 2]", last_level),
+                format!("table 3 created at L{} by Compaction", last_level),
+                format!("compaction completed L0 to L{} with [3]", last_level),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flush_memtable_manually() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mut opts = test_options();
+        opts.mem_table_size = 1 << 20;
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+
+        // empty memtable is not flushed
+        agate.flush_memtable(true).unwrap();
+        assert_eq!(count_files(tmp_dir.path(), ".sst"), 0);
+
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        assert_eq!(count_files(tmp_dir.path(), ".sst"), 1);
+        assert_eq!(count_files(tmp_dir.path(), MEMTABLE_FILE_EXT), 1);
+        assert_eq!(agate.core.mts.read().unwrap().nums_of_memtable(), 1);
+        assert_eq!(agate.estimate_size(b"", b"z").keys, 100);
+
+        write_keys(&agate, 100, 200);
+        agate.flush_memtable(false).unwrap();
+        agate.flush_memtable(true).unwrap();
+        assert_eq!(count_files(tmp_dir.path(), ".sst"), 2);
+        for i in (0..200).step_by(7) {
+            let value = agate
+                .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                .unwrap();
+            assert_eq!(value.value, format!("value{:05}", i));
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        {
+            let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+            let batch = (0..10)
+                .map(|i| Entry::new(Bytes::from(format!("key{}", i)), Bytes::from(i.to_string())))
+                .collect();
+            agate.apply(batch, 5).unwrap();
+            assert!(matches!(
+                agate.apply(vec![Entry::new(Bytes::new(), Bytes::new())], 6),
+                Err(Error::EmptyKey)
+            ));
+        }
+
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        for i in 0..10 {
+            let value = agate
+                .get(&key_with_ts(format!("key{}", i).as_str(), 10))
+                .unwrap();
+            assert_eq!(value.value, i.to_string());
+            assert_eq!(value.version, 5);
+        }
+        let value = agate.get(&key_with_ts(TXN_KEY, 10)).unwrap();
+        assert!(value.value.is_empty());
+    }
+
+    #[test]
+    fn test_get_expired() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mut opts = test_options();
+        opts.mem_table_size = 1 << 20;
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+
+        let mut expired = Entry::new(key_with_ts("expired", 1), Bytes::from("value"));
+        expired.expires_at = 1;
+        let alive = Entry::new(key_with_ts("alive", 1), Bytes::from("value"))
+            .with_ttl(Duration::from_secs(3600));
+        // newer version expired, older versions should not be visible either
+        let old = Entry::new(key_with_ts("shadowed", 1), Bytes::from("value"));
+        let mut new = Entry::new(key_with_ts("shadowed", 2), Bytes::from("value"));
+        new.expires_at = 1;
+        agate.write_entries(vec![expired, alive, old, new]).unwrap();
+
+        for flushed in [false, true].iter() {
+            if *flushed {
+                agate.flush_memtable(true).unwrap();
+            }
+            let value = agate.get(&key_with_ts("expired", 1)).unwrap();
+            assert!(value.value.is_empty(), "{}", flushed);
+            let value = agate.get(&key_with_ts("alive", 1)).unwrap();
+            assert_eq!(value.value, "value");
+            assert!(value.expires_at > unix_time());
+            let value = agate.get(&key_with_ts("shadowed", 2)).unwrap();
+            assert!(value.value.is_empty(), "{}", flushed);
+            let value = agate.get(&key_with_ts("shadowed", 1)).unwrap();
+            assert_eq!(value.value, "value");
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mut opts = test_options();
+        opts.mem_table_size = 1 << 20;
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let metrics = agate.metrics();
+        assert_eq!(
+            metrics.allocator.is_some(),
+            cfg!(all(feature = "jemalloc", not(target_env = "msvc")))
+        );
+        assert_eq!(
+            metrics,
+            MetricsSnapshot {
+                level_zero_compaction_trigger: 5,
+                level_zero_stall_trigger: 15,
+                allocator: metrics.allocator,
+                ..Default::default()
+            }
+        );
+
+        let entries = (0..10)
+            .map(|i| Entry::new(key_with_ts(format!("key{}", i).as_str(), 1), Bytes::new()))
+            .collect();
+        agate.write_entries(entries).unwrap();
+        let mut delete = Entry::new(key_with_ts("key0", 2), Bytes::new());
+        delete.mark_delete();
+        agate.write_entries(vec![delete]).unwrap();
+        let batch = vec![Entry::new(Bytes::from("key1"), Bytes::new())];
+        agate.apply(batch, 3).unwrap();
+
+        let metrics = agate.metrics();
+        assert_eq!(metrics.num_puts, 11);
+        assert_eq!(metrics.num_deletes, 1);
+        assert_eq!(metrics.num_write_batches, 3);
+        // transaction marker is not counted
+        assert_eq!(metrics.num_batched_entries, 12);
+        assert_eq!(metrics.max_batch_entries, 10);
+        assert!(metrics.wal_bytes > 0);
+        assert_eq!(metrics.num_memtable_flushes, 0);
+
+        agate.flush_memtable(true).unwrap();
+        // empty memtable is not flushed
+        agate.flush_memtable(true).unwrap();
+        let metrics = agate.metrics();
+        assert_eq!(metrics.num_memtable_flushes, 1);
+        assert_eq!(metrics.write_stall_duration, Duration::default());
+    }
+
+    #[test]
+    fn test_write_rate_limit() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 20,
+            write_ops_per_sec: 100,
+            ..test_options()
+        };
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+
+        let start = Instant::now();
+        for i in 0..130 {
+            let entry = Entry::new(key_with_ts(format!("key{}", i).as_str(), 1), Bytes::new());
+            agate.write_entries(vec![entry]).unwrap();
+        }
+        // 100 ops of burst, and 30 more ops take 300ms.
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(agate.metrics().write_throttle_duration > Duration::default());
+
+        let entries = (0..20)
+            .map(|i| Entry::new(key_with_ts(format!("key{}", i).as_str(), 2), Bytes::new()))
+            .collect();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let res = agate.write_entries_with_deadline(entries, deadline);
+        assert!(matches!(res, Err(Error::WriteStalled)), "{:?}", res);
+    }
+
+    #[test]
+    fn test_pipelined_write() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Arc::new(Agate::open(test_options(), tmp_dir.path()).unwrap());
+
+        // Memtables are rotated and flushed while batches are being inserted.
+        let handles: Vec<_> = (0..4u64)
+            .map(|t| {
+                let agate = agate.clone();
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        let key = format!("key{}-{:03}", t, i);
+                        let entry = Entry::new(key_with_ts(key.as_str(), 1), Bytes::from(key));
+                        agate.write_entries(vec![entry]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        agate.flush_memtable(true).unwrap();
+        assert!(agate.metrics().num_memtable_flushes > 1);
+
+        for t in 0..4 {
+            for i in 0..200 {
+                let key = format!("key{}-{:03}", t, i);
+                let value = agate.get(&key_with_ts(key.as_str(), 1)).unwrap();
+                assert_eq!(value.value, key);
+            }
+        }
+        assert_eq!(agate.estimate_size(b"", b"z").keys, 800);
+    }
+
+    #[test]
+    fn test_write_out_of_wal_room() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 18,
+            value_log_file_size: 1 << 16,
+            ..Default::default()
+        };
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let value = Bytes::from(vec![b'v'; 60 << 10]);
+        let write = |keys: &[&str], value: &Bytes| {
+            let entries = keys
+                .iter()
+                .map(|key| Entry::new(key_with_ts(*key, 1), value.clone()))
+                .collect();
+            agate
+                .write_to_lsm(Request {
+                    entries,
+                    deadline: None,
+                    done: None,
+                })
+                .unwrap();
+        };
+        write(&["a"], &value);
+        // The batch doesn't fit in the rest of WAL, so memtable is rotated.
+        write(&["b", "c"], &value);
+        write(&["d"], &Bytes::from("d"));
+
+        for key in &["a", "b", "c"] {
+            let v = agate.get(&key_with_ts(*key, u64::MAX)).unwrap();
+            assert_eq!(v.value, value);
+        }
+        let v = agate.get(&key_with_ts("d", u64::MAX)).unwrap();
+        assert_eq!(v.value, "d");
+    }
+
+    #[test]
+    fn test_get_multi() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mut opts = test_options();
+        opts.mem_table_size = 1 << 20;
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 50, 150);
+        agate.flush_memtable(true).unwrap();
+        // newer versions in memtable
+        for i in (0..150).step_by(3) {
+            let entry = Entry::new(
+                key_with_ts(format!("key{:05}", i).as_str(), 1000),
+                Bytes::from(format!("new{:05}", i)),
+            );
+            agate.write_entries(vec![entry]).unwrap();
+        }
+
+        let mut keys: Vec<_> = (0..200)
+            .rev()
+            .map(|i| key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+            .collect();
+        keys.push(key_with_ts("key00003", 999));
+        keys.push(key_with_ts("key00010", 5));
+        keys.push(key_with_ts("key00010", 11));
+        let values = agate.get_multi(&keys).unwrap();
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.iter().zip(&values) {
+            let expected = agate.get(key).unwrap();
+            assert_eq!(value.value, expected.value, "{:?}", key);
+            assert_eq!(value.version, expected.version, "{:?}", key);
+        }
+        assert_eq!(values[199].value, "new00000");
+        assert_eq!(values[198].value, "value00001");
+        assert!(values[0].value.is_empty());
+        assert_eq!(values[200].value, "value00003");
+        assert!(values[201].value.is_empty());
+        assert_eq!(values[202].version, 11);
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            in_memory: true,
+            ..test_options()
+        };
+        assert!(matches!(
+            Agate::open(opts.clone(), tmp_dir.path()),
+            Err(Error::Config(_))
+        ));
+
+        let agate = Agate::open(opts, "").unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 100, 200);
+        assert_eq!(agate.metrics().num_memtable_flushes, 1);
+        assert_eq!(agate.metrics().wal_bytes, 0);
+        assert_eq!(agate.estimate_size(b"", b"z").keys, 100);
+        for i in (0..200).step_by(7) {
+            let value = agate
+                .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                .unwrap();
+            assert_eq!(value.value, format!("value{:05}", i));
+        }
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options().with_block_cache(1 << 20, CachePolicy::Lru);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        let cache = agate.core.opts.block_cache.clone().unwrap();
+        assert_eq!(cache.usage(), 0);
+
+        let key = key_with_ts("key00042", u64::MAX);
+        agate.get(&key).unwrap();
+        let metrics = agate.metrics();
+        assert_eq!(metrics.block_cache_hits, 0);
+        assert!(metrics.block_cache_misses > 0);
+        assert!(cache.usage() > 0);
+        agate.get(&key).unwrap();
+        let hits = agate.metrics().block_cache_hits;
+        assert!(hits > 0);
+        let status = agate.cache_status();
+        assert!(status.index_cache.is_none());
+        let stats = status.block_cache.unwrap();
+        assert_eq!(stats.capacity, 1 << 20);
+        assert_eq!(stats.usage, cache.usage() as u64);
+        assert!(stats.entries > 0);
+        assert_eq!(stats.hits, hits);
+        assert_eq!(status.levels.len(), agate.core.opts.max_levels);
+        assert_eq!(
+            status
+                .levels
+                .iter()
+                .map(|l| l.block_cache_hits)
+                .sum::<u64>(),
+            hits
+        );
+        let metrics = agate.metrics();
+        assert_eq!(metrics.block_cache_bytes, stats.usage);
+        assert!(metrics.block_cache_hit_ratio() > 0.0);
+        assert_eq!(metrics.index_cache_hit_ratio(), 0.0);
+        assert_eq!(
+            agate.metrics().block_cache_misses,
+            metrics.block_cache_misses
+        );
+
+        // Blocks read without cache are not inserted.
+        let usage = cache.usage();
+        let mut iter = agate
+            .core
+            .new_merged_iterator(&crate::iterator::IteratorOptions {
+                no_cache: true,
+                ..Default::default()
+            })
+            .unwrap();
+        iter.rewind();
+        while iter.valid() {
+            iter.next();
+        }
+        drop(iter);
+        assert_eq!(cache.usage(), usage);
+        agate.histogram(b"").unwrap();
+        assert_eq!(cache.usage(), usage);
+
+        let opts = test_options().with_block_cache(0, CachePolicy::Lru);
+        drop(agate);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        assert!(agate.core.opts.block_cache.is_none());
+        agate.get(&key).unwrap();
+        assert_eq!(agate.metrics().block_cache_misses, 0);
+    }
+
+    #[test]
+    fn test_index_cache() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options().with_index_cache(1 << 20);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        let cache = agate.core.opts.index_cache.clone().unwrap();
+        assert!(cache.usage() > 0);
+
+        let value = agate.get(&key_with_ts("key00042", u64::MAX)).unwrap();
+        assert_eq!(value.value, Bytes::from("value00042"));
+        let metrics = agate.metrics();
+        assert!(metrics.index_cache_hits > 0);
+        assert_eq!(metrics.index_cache_misses, 0);
+    }
+
+    #[test]
+    fn test_lazy_load_table_index() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options()
+            .with_index_cache(1 << 20)
+            .with_lazy_load_table_index(true);
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        let infos = agate.core.lvctl.level_infos().unwrap();
+        drop(agate);
+
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let cache = agate.core.opts.index_cache.clone().unwrap();
+        assert_eq!(cache.usage(), 0);
+        assert_eq!(agate.max_version().unwrap(), 100);
+        let value = agate.get(&key_with_ts("key00042", u64::MAX)).unwrap();
+        assert_eq!(value.value, Bytes::from("value00042"));
+        assert!(cache.usage() > 0);
+
+        // Key ranges and versions recorded in manifest match the indexes.
+        let lazy_infos = agate.core.lvctl.level_infos().unwrap();
+        assert_eq!(infos.len(), lazy_infos.len());
+        for (info, lazy) in infos.iter().zip(&lazy_infos) {
+            assert_eq!(info.tables.len(), lazy.tables.len());
+            for (table, lazy) in info.tables.iter().zip(&lazy.tables) {
+                assert_eq!(table.smallest, lazy.smallest);
+                assert_eq!(table.biggest, lazy.biggest);
+                assert_eq!(table.max_version, lazy.max_version);
+                assert_eq!(table.key_count, lazy.key_count);
+            }
+        }
+    }
+
+    #[test]
+    fn test_row_cache() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options().with_row_cache(1 << 20);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let set = |key: &str, value: &str| {
+            let mut txn = agate.new_transaction(true);
+            txn.set(Bytes::from(key.to_string()), Bytes::from(value.to_string()))
+                .unwrap();
+            txn.commit().unwrap();
+        };
+        let get = |key: &str, ts: u64| agate.get(&key_with_ts(key, ts)).unwrap();
+        let stats = || agate.cache_status().row_cache.unwrap();
+
+        set("a", "1");
+        assert_eq!(get("a", u64::MAX).value, Bytes::from("1"));
+        assert_eq!(get("a", u64::MAX).value, Bytes::from("1"));
+        assert_eq!((stats().hits, stats().misses, stats().entries), (1, 1, 1));
+
+        // Writes invalidate cached values.
+        set("a", "2");
+        assert_eq!(stats().entries, 0);
+        assert_eq!(get("a", u64::MAX).value, Bytes::from("2"));
+        // Reads of old versions are not served or filled by cache.
+        assert_eq!(get("a", 1).value, Bytes::from("1"));
+        assert_eq!(get("a", u64::MAX).value, Bytes::from("2"));
+        assert_eq!(stats().hits, 2);
+
+        // Missing keys are cached too.
+        assert!(get("b", u64::MAX).value.is_empty());
+        assert!(get("b", u64::MAX).value.is_empty());
+        assert_eq!(stats().hits, 3);
+        let mut txn = agate.new_transaction(true);
+        txn.delete(Bytes::from("a")).unwrap();
+        txn.commit().unwrap();
+        assert_ne!(get("a", u64::MAX).meta & VALUE_DELETE, 0);
+
+        // Bulk loaded tables invalidate all keys.
+        let mut loader = agate.new_bulk_loader();
+        loader
+            .add(key_with_ts("b", 100), Value::new(Bytes::from("b")))
+            .unwrap();
+        loader.finish().unwrap();
+        assert_eq!(stats().entries, 0);
+        assert_eq!(get("b", u64::MAX).value, Bytes::from("b"));
+    }
+
+    #[test]
+    fn test_cache_warm_up() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options()
+            .with_block_size(256)
+            .with_block_cache(1 << 20, CachePolicy::Lru);
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        let id = agate.levels().unwrap()[0].tables[0].id;
+        drop(agate);
+
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        let stats = || agate.cache_status().block_cache.unwrap();
+        let num_blocks = agate.warm_up_tables(&[id]).unwrap();
+        assert!(num_blocks > 1);
+        assert_eq!(stats().entries, num_blocks as u64);
+        let misses = stats().misses;
+        agate.get(&key_with_ts("key00042", u64::MAX)).unwrap();
+        assert_eq!(stats().misses, misses);
+        assert_eq!(agate.warm_up_tables(&[id + 1]).unwrap(), 0);
+        drop(agate);
+
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        let stats = || agate.cache_status().block_cache.unwrap();
+        let count = agate.warm_up_range(b"key00010", b"key00020").unwrap();
+        assert!(count > 0 && count < num_blocks);
+        assert_eq!(stats().entries, count as u64);
+        assert_eq!(agate.warm_up_range(b"z", b"zz").unwrap(), 0);
+        assert_eq!(stats().pinned_usage, 0);
+        drop(agate);
+
+        // Blocks of L0 tables are pinned.
+        let opts = AgateOptions {
+            pin_l0_tables: true,
+            ..opts
+        };
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        agate.warm_up_tables(&[id]).unwrap();
+        let stats = agate.cache_status().block_cache.unwrap();
+        assert!(stats.usage > 0);
+        assert_eq!(stats.pinned_usage, stats.usage);
+        drop(agate);
+
+        let opts = test_options().with_block_cache(0, CachePolicy::Lru);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        assert!(agate.warm_up_tables(&[id]).is_err());
+    }
+
+    #[test]
+    fn test_compaction() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options()
+            .with_level_zero_tables(2, 4)
+            .with_base_sizes(1 << 12, 1 << 14)
+            .with_block_size(1 << 10);
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        assert_eq!(agate.compactors.lock().unwrap().count(COMPACTOR_TASK), 4);
+        for i in 0..10 {
+            write_keys(&agate, i * 100, (i + 1) * 100);
+            agate.flush_memtable(true).unwrap();
+        }
+
+        // Flushes are not stalled, and L0 eventually goes below the trigger.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while agate.levels().unwrap()[0].tables.len() >= 2 {
+            assert!(Instant::now() < deadline, "L0 is not compacted in time");
+            thread::sleep(Duration::from_millis(10));
+        }
+        agate.close(true).unwrap();
+
+        // Compacted tables are recorded in manifest.
+        let report = Agate::verify(tmp_dir.path(), opts.clone()).unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+        let agate = Agate::open(opts.with_read_only(true), tmp_dir.path()).unwrap();
+        let levels = agate.levels().unwrap();
+        assert!(levels[0].tables.len() < 2);
+        assert!(levels[1..].iter().any(|level| !level.tables.is_empty()));
+        for i in 0..1000 {
+            let value = agate
+                .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                .unwrap();
+            assert_eq!(value.value, format!("value{:05}", i));
+        }
+    }
+
+    #[test]
+    fn test_compaction_discard_versions() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options()
+            .with_num_compactors(0)
+            .with_level_zero_tables(2, 4);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        // key1 has 3 versions, key2 is deleted at version 2, and key3 is
+        // deleted at version 3.
+        let mut snapshot = None;
+        for version in 1..=3 {
+            let mut entries = vec![Entry::new(
+                key_with_ts("key1", version),
+                Bytes::from(format!("v{}", version)),
+            )];
+            for (key, deleted_at) in [("key2", 2), ("key3", 3)] {
+                if version > deleted_at {
+                    continue;
+                }
+                let mut entry = Entry::new(key_with_ts(key, version), Bytes::from("v"));
+                if version == deleted_at {
+                    entry.mark_delete();
+                }
+                entries.push(entry);
+            }
+            if version == 3 {
+                snapshot = Some(agate.snapshot());
+            }
+            agate.write_entries(entries).unwrap();
+            agate.flush_memtable(true).unwrap();
+            agate.core.orc.advance_to(version);
+        }
+        assert_eq!(agate.levels().unwrap()[0].tables.len(), 3);
+
+        // A running reader at 2 holds off discarding versions it can read.
+        let lvctl = &agate.core.lvctl;
+        assert!(lvctl.compact(0, agate.core.orc.discard_ts()).unwrap());
+        assert!(!lvctl.compact(0, agate.core.orc.discard_ts()).unwrap());
+        let levels = agate.levels().unwrap();
+        assert!(levels[0].tables.is_empty());
+        // Only the last level is not empty in a small database.
+        let last = levels.last().unwrap();
+        assert_eq!(last.tables.len(), 1);
+        // key1@3, key1@2, key3@3 and key3@2 are kept.
+        assert_eq!(last.tables[0].key_count, 4);
+
+        let get = |key: &str, version| agate.get(&key_with_ts(key, version)).unwrap();
+        assert_eq!(get("key1", 3).value, "v3");
+        assert_eq!(get("key1", 2).value, "v2");
+        assert!(get("key1", 1).value.is_empty());
+        assert!(get("key2", 3).value.is_empty());
+        assert_ne!(get("key3", 3).meta & VALUE_DELETE, 0);
+        assert_eq!(get("key3", 2).value, "v");
+        drop(snapshot);
+    }
+
+    #[test]
+    fn test_compaction_discard_running_reads() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options().with_level_zero_tables(2, 4);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let commit = |sets: &[(&'static str, &'static str)], deletes: &[&'static str]| {
+            let mut txn = agate.new_transaction(true);
+            for (key, value) in sets {
+                txn.set(Bytes::from(*key), Bytes::from(*value)).unwrap();
+            }
+            for key in deletes {
+                txn.delete(Bytes::from(*key)).unwrap();
+            }
+            txn.commit().unwrap();
+            agate.flush_memtable(true).unwrap();
+        };
+        let wait_for_compaction = || {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while agate.tables().unwrap().iter().any(|t| t.level == 0) {
+                assert!(Instant::now() < deadline, "L0 is not compacted in time");
+                thread::sleep(Duration::from_millis(10));
+            }
+            let tables = agate.tables().unwrap();
+            tables.iter().map(|t| t.key_count).sum::<u32>()
+        };
+
+        commit(&[("a", "a1"), ("b", "b1")], &[]);
+        let snapshot = agate.snapshot();
+        commit(&[("a", "a2")], &["b"]);
+        // Versions visible to the snapshot are kept.
+        assert_eq!(wait_for_compaction(), 4);
+        assert_eq!(snapshot.get(&Bytes::from("a")).unwrap().value(), "a1");
+        assert_eq!(snapshot.get(&Bytes::from("b")).unwrap().value(), "b1");
+
+        // Once it's dropped, overwritten and deleted versions are dropped.
+        drop(snapshot);
+        commit(&[("a", "a3"), ("c", "c3")], &[]);
+        commit(&[("c", "c4")], &[]);
+        assert_eq!(wait_for_compaction(), 2);
+        let txn = agate.new_transaction(false);
+        assert_eq!(txn.get(&Bytes::from("a")).unwrap().value(), "a3");
+        assert!(matches!(
+            txn.get(&Bytes::from("b")),
+            Err(Error::KeyNotFound)
+        ));
+        assert_eq!(txn.get(&Bytes::from("c")).unwrap().value(), "c4");
+    }
+
+    #[test]
+    fn test_custom_comparator() {
+        struct ReverseComparator;
+
+        impl UserComparator for ReverseComparator {
+            fn name(&self) -> &str {
+                "test.ReverseComparator"
+            }
+
+            fn compare(&self, lhs: &[u8], rhs: &[u8]) -> std::cmp::Ordering {
+                rhs.cmp(lhs)
+            }
+        }
+
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mut opts = test_options().with_comparator(Arc::new(ReverseComparator));
+        opts.base_table_size = 1 << 12;
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 100, 200);
+        agate.flush_memtable(true).unwrap();
+        agate.flatten().unwrap();
+        let info = agate.db_info().unwrap();
+        let tables = &info.levels[info.levels.len() - 1].tables;
+        assert!(tables.len() > 1);
+        assert!(tables
+            .windows(2)
+            .all(|pair| pair[0].smallest > pair[1].biggest));
+        drop(agate);
+
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        for i in (0..200).step_by(7) {
+            let key = format!("key{:05}", i);
+            let value = agate.get(&key_with_ts(key.as_str(), u64::MAX)).unwrap();
+            assert_eq!(value.value, format!("value{:05}", i));
+        }
+        let mut keys = vec![];
+        agate
+            .snapshot()
+            .scan(b"key00150", b"key00100", |item| {
+                keys.push(item.key().clone());
+                Ok(true)
+            })
+            .unwrap();
+        let expected: Vec<_> = (101..=150).rev().map(|i| format!("key{:05}", i)).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_iterator_prefetch() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options()
+            .with_block_size(256)
+            .with_block_cache(1 << 20, CachePolicy::Lru);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+
+        let stats = || agate.cache_status().block_cache.unwrap();
+        let misses = stats().misses;
+        let opts = crate::iterator::IteratorOptions {
+            prefetch_values: true,
+            ..Default::default()
+        };
+        let mut iter = agate.core.new_merged_iterator(&opts).unwrap();
+        iter.rewind();
+        let mut count = 0;
+        while iter.valid() {
+            count += 1;
+            iter.next();
+        }
+        assert_eq!(count, 100);
+        // Blocks are read ahead into block cache before iterator reaches
+        // them.
+        assert_eq!(stats().misses, misses);
+        assert!(stats().entries > 8);
+    }
+
+    #[test]
+    fn test_incremental_backup() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let commit = |puts: Vec<(&str, &str)>, deletes: Vec<&str>| {
+            let mut txn = agate.new_transaction(true);
+            for (key, value) in puts {
+                txn.set(Bytes::from(key.to_string()), Bytes::from(value.to_string()))
+                    .unwrap();
+            }
+            for key in deletes {
+                txn.delete(Bytes::from(key.to_string())).unwrap();
+            }
+            txn.commit().unwrap();
+        };
+        commit(vec![("a", "a1"), ("b", "b1"), ("c", "c1")], vec![]);
+        agate.flush_memtable(true).unwrap();
+        let mut full = vec![];
+        let ts = agate.backup(&mut full, 0).unwrap();
+        assert_eq!(ts, 1);
+
+        commit(vec![("b", "b2"), ("d", "d1")], vec!["c"]);
+        let mut incremental = vec![];
+        let ts = agate.backup(&mut incremental, ts + 1).unwrap();
+        assert_eq!(ts, 2);
+        // The flushed table has nothing newer, so it's not read at all.
+        let mut iters = vec![];
+        let opts = crate::iterator::IteratorOptions {
+            since_ts: ts,
+            ..Default::default()
+        };
+        agate.core.lvctl.append_iterators(&mut iters, &opts);
+        assert!(iters.is_empty());
+        let mut empty = vec![];
+        assert_eq!(agate.backup(&mut empty, ts + 1).unwrap(), ts);
+        assert!(empty.is_empty());
+
+        let check = |agate: &Agate, expected: Vec<(&str, Option<&str>)>| {
+            let txn = agate.new_transaction(false);
+            for (key, value) in expected {
+                let res = txn.get(&Bytes::from(key.to_string()));
+                match value {
+                    Some(value) => {
+                        assert_eq!(res.unwrap().value(), &Bytes::from(value.to_string()))
+                    }
+                    None => assert!(matches!(res, Err(Error::KeyNotFound)), "{}", key),
+                }
+            }
+        };
+        let restore_dir = TempDir::new("agatedb").unwrap();
+        let restored = Agate::open(test_options(), restore_dir.path()).unwrap();
+        restored.load(&incremental[..], 1).unwrap();
+        // Unchanged keys are not in incremental backups.
+        check(&restored, vec![("a", None), ("b", Some("b2")), ("c", None)]);
+
+        let restore_dir = TempDir::new("agatedb").unwrap();
+        let restored = Agate::open(test_options(), restore_dir.path()).unwrap();
+        restored.load(&full[..], 1).unwrap();
+        check(&restored, vec![("a", Some("a1")), ("c", Some("c1"))]);
+        restored.load(&incremental[..], 1).unwrap();
+        let expected = vec![
+            ("a", Some("a1")),
+            ("b", Some("b2")),
+            ("c", None),
+            ("d", Some("d1")),
+        ];
+        check(&restored, expected.clone());
+        check(&agate, expected);
+    }
+
+    #[test]
+    fn test_iterator_pick_tables() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 10, 20);
+        agate.flush_memtable(true).unwrap();
+        let table = agate.core.lvctl.all_tables()[0][0].clone();
+        let max_version = table.max_version();
+        let pick = |prefix: &str, since_ts: u64| {
+            let opts = crate::iterator::IteratorOptions {
+                prefix: Bytes::from(prefix.to_string()),
+                since_ts,
+                ..Default::default()
+            };
+            opts.pick_table(&table)
+        };
+        // Tables out of the prefix or older than since_ts are skipped.
+        assert!(pick("key0001", 0) && pick("key", 0) && pick("", max_version));
+        assert!(!pick("key00020", 0) && !pick("key00009", 0));
+        assert!(!pick("a", 0) && !pick("z", 0) && !pick("", max_version + 1));
+    }
+
+    #[test]
+    fn test_open_error_context() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        agate.close(false).unwrap();
+
+        let sst = table::new_filename(1, tmp_dir.path());
+        // Flip the last byte of table index, which is right before index
+        // size and checksum in the footer.
+        let mut data = fs::read(&sst).unwrap();
+        let mut checksum_len = [0; 4];
+        checksum_len.copy_from_slice(&data[data.len() - 4..]);
+        let pos = data.len() - 4 - u32::from_be_bytes(checksum_len) as usize - 4 - 1;
+        data[pos] ^= 0xff;
+        fs::write(&sst, data).unwrap();
+        let err = Agate::open(test_options(), tmp_dir.path()).err().unwrap();
+        assert!(
+            matches!(
+                err,
+                Error::Open {
+                    stage: OpenStage::Levels,
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(err.kind(), ErrorKind::Corruption, "{:?}", err);
+        let context = err.context().unwrap();
+        assert_eq!(context.table_id, Some(1));
+        assert_eq!(context.path.as_deref(), Some(sst.as_path()));
+    }
+
+    #[test]
+    fn test_flush_failure() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 10);
+
+        // Creating table file fails permanently as the file already exists.
+        fs::write(table::new_filename(1, tmp_dir.path()), b"").unwrap();
+        let err = agate.flush_memtable(true).err().unwrap();
+        assert!(matches!(err, Error::FlushFailed(_)), "{:?}", err);
+        assert!(!err.is_retryable());
+        assert_eq!(err.kind(), ErrorKind::Io);
+        // Memtables are not flushed anymore.
+        write_keys(&agate, 10, 20);
+        assert!(matches!(
+            agate.flush_memtable(false),
+            Err(Error::FlushFailed(_))
+        ));
+        // Writers get the flush error once memtable is full, without losing
+        // its kind.
+        let err = (20..10000)
+            .find_map(|i| {
+                let key = key_with_ts(format!("key{:05}", i).as_str(), i + 1);
+                agate
+                    .write_entries(vec![Entry::new(key, Bytes::from("value"))])
+                    .err()
+            })
+            .unwrap();
+        assert!(
+            matches!(err.root_cause(), Error::FlushFailed(_)),
+            "{:?}",
+            err
+        );
+        assert_eq!(err.kind(), ErrorKind::Io);
+        assert!(agate.get(&key_with_ts(b"key00000" as &[u8], 1)).is_ok());
+    }
+
+    /// `StdEnv` which fails to create table files once `fail_tables` is set,
+    /// and counts tables written in background.
+    #[derive(Debug, Default)]
+    struct FaultEnv {
+        inner: StdEnv,
+        fail_tables: AtomicBool,
+        background_tables: AtomicUsize,
+    }
+
+    impl Env for FaultEnv {
+        fn open_readable(
+            &self,
+            path: &Path,
+            priority: IoPriority,
+        ) -> Result<Box<dyn ReadableFile>> {
+            self.inner.open_readable(path, priority)
+        }
+
+        fn open_writable(
+            &self,
+            path: &Path,
+            mode: OpenMode,
+            priority: IoPriority,
+        ) -> Result<Box<dyn WritableFile>> {
+            if path.extension().is_some_and(|ext| ext == "sst") {
+                if self.fail_tables.load(Ordering::SeqCst) {
+                    return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied).into());
+                }
+                if priority == IoPriority::Background {
+                    self.background_tables.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            self.inner.open_writable(path, mode, priority)
+        }
+
+        fn open_mapped(&self, path: &Path, len: u64) -> Result<(Box<dyn MappedFile>, bool)> {
+            self.inner.open_mapped(path, len)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+            self.inner.list_dir(dir)
+        }
+
+        fn create_dir_all(&self, dir: &Path) -> Result<()> {
+            self.inner.create_dir_all(dir)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+            self.inner.hard_link(from, to)
+        }
+
+        fn remove_file(&self, path: &Path) -> Result<()> {
+            self.inner.remove_file(path)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> Result<()> {
+            self.inner.sync_dir(dir)
+        }
+    }
+
+    #[test]
+    fn test_custom_env() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let env = Arc::new(FaultEnv::default());
+        let opts = test_options().with_env(env.clone());
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 10);
+        agate.flush_memtable(true).unwrap();
+        assert_eq!(count_files(tmp_dir.path(), ".sst"), 1);
+        // Flush is background IO.
+        assert_eq!(env.background_tables.load(Ordering::SeqCst), 1);
+
+        write_keys(&agate, 10, 20);
+        env.fail_tables.store(true, Ordering::SeqCst);
+        let err = agate.flush_memtable(true).err().unwrap();
+        assert!(matches!(err, Error::FlushFailed(_)), "{:?}", err);
+        drop(agate);
+
+        env.fail_tables.store(false, Ordering::SeqCst);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        for i in 0..20 {
+            let key = key_with_ts(format!("key{:05}", i).as_str(), u64::MAX);
+            assert!(agate.get(&key).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_close() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 20,
+            ..test_options()
+        };
+        let check = |agate: &Agate, end: u64| {
+            for i in (0..end).step_by(7) {
+                let value = agate
+                    .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                    .unwrap();
+                assert_eq!(value.value, format!("value{:05}", i));
+            }
+        };
+
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        let txn = agate.new_transaction(true);
+        agate.close(true).unwrap();
+        assert_eq!(count_files(tmp_dir.path(), ".sst"), 1);
+        assert!(matches!(
+            txn.get(&Bytes::from("key00000")),
+            Err(Error::DBClosed)
+        ));
+        // Transaction still holds the directory lock.
+        assert!(Agate::open(opts.clone(), tmp_dir.path()).is_err());
+        drop(txn);
+
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        check(&agate, 100);
+        write_keys(&agate, 100, 200);
+        agate.close(false).unwrap();
+        assert_eq!(count_files(tmp_dir.path(), ".sst"), 1);
+
+        // Memtable not flushed is replayed from WAL.
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        check(&agate, 200);
+    }
+
+    #[test]
+    fn test_max_version() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        assert_eq!(agate.max_version().unwrap(), 0);
+        write_keys(&agate, 0, 50);
+        assert_eq!(agate.max_version().unwrap(), 50);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 50, 60);
+        assert_eq!(agate.max_version().unwrap(), 60);
+        drop(agate);
+
+        // Oracle starts after max version on restart.
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        assert_eq!(agate.max_version().unwrap(), 60);
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("key"), Bytes::from("value")).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(agate.max_version().unwrap(), 61);
+    }
+
+    #[test]
+    fn test_size() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let size = agate.size().unwrap();
+        assert_eq!(size.levels, vec![0; agate.core.opts.max_levels]);
+        assert_eq!(size.total(), 0);
+
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        let size = agate.size().unwrap();
+        let sst_len = fs::metadata(table::new_filename(1, tmp_dir.path()))
+            .unwrap()
+            .len();
+        assert_eq!(size.levels[0], sst_len);
+        assert_eq!(size.lsm(), sst_len);
+        assert_eq!(size.total(), sst_len);
+    }
+
+    #[test]
+    fn test_prefix_stats() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        assert!(agate.prefix_stats().iter().all(|s| s.tables == 0));
+        write_keys(&agate, 0, 50);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 50, 100);
+        agate.flush_memtable(true).unwrap();
+
+        let stats = agate.prefix_stats();
+        assert_eq!(stats.len(), agate.core.opts.max_levels);
+        assert_eq!(stats[0].tables, 2);
+        assert_eq!(stats[0].entries, 100);
+        // "key00000" and its timestamp are 16 bytes.
+        assert_eq!(stats[0].key_bytes, 1600);
+        // Small tables fit in a block, and keys share at least "key000".
+        assert_eq!(stats[0].blocks, 2);
+        assert_eq!(stats[0].entries_per_block(), 50.0);
+        assert!(stats[0].avg_key_overlap() > 6.0);
+        // The rest are footers with checksums of indexes.
+        let size: u64 = agate.tables().unwrap().iter().map(|t| t.size).sum();
+        let footers = size - stats[0].data_size - stats[0].index_size;
+        assert!(footers > 0 && footers < 64, "{}", footers);
+        assert!(stats[0].index_to_data_ratio() > 0.0);
+        assert_eq!(stats[1], PrefixStats::default());
+    }
+
+    #[test]
+    fn test_verify_on_open() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        assert!(agate.verify_report().is_none());
+        write_keys(&agate, 0, 50);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 50, 100);
+        agate.flush_memtable(true).unwrap();
+        drop(agate);
+
+        let opts =
+            test_options().with_checksum_verification(ChecksumVerificationMode::OnTableOpen, 2);
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        let report = agate.verify_report().unwrap();
+        assert_eq!(report.tables, 2);
+        assert_eq!(report.bytes, agate.size().unwrap().lsm());
+        assert!(report.corrupted_tables.is_empty());
+        drop(agate);
+
+        // Corrupt a value in the second table, which is not found without
+        // reading the block.
+        let sst = table::new_filename(2, tmp_dir.path());
+        let mut data = fs::read(&sst).unwrap();
+        let pos = data.windows(10).position(|w| w == b"value00075").unwrap();
+        data[pos + 9] ^= 0xff;
+        fs::write(&sst, data).unwrap();
+        Agate::open(test_options(), tmp_dir.path()).unwrap();
+
+        let err = Agate::open(opts, tmp_dir.path()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Corruption, "{:?}", err);
+        assert_eq!(err.context().unwrap().table_id, Some(2));
+    }
+
+    #[test]
+    fn test_open_progress() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 50);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 50, 100);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 100, 110);
+        drop(agate);
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorder = events.clone();
+        let opts = test_options()
+            .with_checksum_verification(ChecksumVerificationMode::OnTableOpen, 2)
+            .with_open_progress(Arc::new(move |progress| {
+                recorder.lock().unwrap().push(progress)
+            }));
+        Agate::open(opts, tmp_dir.path()).unwrap();
+
+        let events = events.lock().unwrap();
+        let mut stages: Vec<_> = events.iter().map(|p| p.stage).collect();
+        stages.dedup();
+        assert_eq!(
+            stages,
+            vec![
+                OpenProgressStage::Manifest,
+                OpenProgressStage::Tables,
+                OpenProgressStage::VerifyTables,
+                OpenProgressStage::Memtables
+            ]
+        );
+        for stage in stages {
+            let progress: Vec<_> = events.iter().filter(|p| p.stage == stage).collect();
+            assert_eq!(progress[0].done, 0);
+            let last = progress.last().unwrap();
+            assert_eq!(last.done, last.total, "{:?}", progress);
+            assert!(progress.windows(2).all(|w| w[0].done < w[1].done));
+            if stage == OpenProgressStage::Tables || stage == OpenProgressStage::VerifyTables {
+                assert_eq!(last.total, 2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_timings() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let stages: Vec<_> = agate
+            .open_timings()
+            .stages
+            .iter()
+            .map(|(s, _)| *s)
+            .collect();
+        assert_eq!(
+            stages,
+            vec![
+                OpenStage::CreateDir,
+                OpenStage::Lock,
+                OpenStage::Identity,
+                OpenStage::KeyRegistry,
+                OpenStage::Manifest,
+                OpenStage::Levels,
+                OpenStage::Memtables,
+                OpenStage::Workers
+            ]
+        );
+        write_keys(&agate, 0, 10);
+        drop(agate);
+
+        let opts = test_options().with_read_only(true);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let timings = agate.open_timings();
+        assert_eq!(timings.stage(OpenStage::CreateDir), None);
+        assert!(timings.stage(OpenStage::Memtables).is_some());
+        assert!(timings.total() >= timings.stage(OpenStage::Memtables).unwrap());
+    }
+
+    #[test]
+    fn test_verify() {
+        use crate::ConsistencyIssue;
+
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        for i in 0..3 {
+            write_keys(&agate, i * 10, i * 10 + 10);
+            agate.flush_memtable(true).unwrap();
+        }
+        assert!(matches!(
+            Agate::verify(tmp_dir.path(), test_options()),
+            Err(Error::Locked(_))
+        ));
+        drop(agate);
+
+        let report = Agate::verify(tmp_dir.path(), test_options()).unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+        assert_eq!(report.checksums.tables, 3);
+
+        let table = |id| table::new_filename(id, tmp_dir.path());
+        fs::copy(table(1), table(100)).unwrap();
+        fs::remove_file(table(2)).unwrap();
+        let mut data = fs::read(table(3)).unwrap();
+        data[0] ^= 0xff;
+        fs::write(table(3), data).unwrap();
+        let report = Agate::verify(tmp_dir.path(), test_options()).unwrap();
+        assert_eq!(report.checksums.corrupted_tables, vec![3]);
+        assert_eq!(report.issues.len(), 3, "{:?}", report);
+        assert_eq!(report.issues[0], ConsistencyIssue::MissingTable { id: 2 });
+        assert_eq!(report.issues[1], ConsistencyIssue::OrphanTable { id: 100 });
+        assert!(matches!(
+            report.issues[2],
+            ConsistencyIssue::CorruptedTable { id: 3, .. }
+        ));
+        // Nothing is removed.
+        assert!(table(100).exists());
+        assert!(table(1).exists());
+    }
+
+    #[test]
+    fn test_rotate_master_key() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let old_key = vec![1; 32];
+        let new_key = vec![2; 32];
+        let opts = |key: &[u8]| {
+            test_options().with_encryption_key(key.to_vec(), Duration::from_secs(3600))
+        };
+        let agate = Agate::open(opts(&old_key), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 10);
+        assert!(matches!(
+            Agate::rotate_master_key(tmp_dir.path(), &old_key, &new_key),
+            Err(Error::Locked(_))
+        ));
+        drop(agate);
+
+        Agate::rotate_master_key(tmp_dir.path(), &old_key, &new_key).unwrap();
+        let err = Agate::open(opts(&old_key), tmp_dir.path()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Config, "{:?}", err);
+        let agate = Agate::open(opts(&new_key), tmp_dir.path()).unwrap();
+        let key = key_with_ts(b"key00005" as &[u8], u64::MAX);
+        assert_eq!(agate.get(&key).unwrap().value, Bytes::from("value00005"));
+    }
+
+    #[test]
+    fn test_set_option() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let clock = Arc::new(crate::ManualClock::new(0));
+        let agate = Agate::open(test_options().with_clock(clock.clone()), tmp_dir.path()).unwrap();
+        let deadline = clock.now() + Duration::from_millis(500);
+        let write = |agate: &Agate, start: u64, end: u64| {
+            let entries = (start..end)
+                .map(|i| {
+                    Entry::new(
+                        key_with_ts(format!("key{:05}", i).as_str(), i + 1),
+                        Bytes::new(),
+                    )
+                })
+                .collect();
+            agate.write_entries_with_deadline(entries, deadline)
+        };
+        write(&agate, 0, 5).unwrap();
+
+        // The second batch needs to wait for one second.
+        agate
+            .set_option(RuntimeOption::WriteRateLimit {
+                bytes_per_sec: 0,
+                ops_per_sec: 5,
+            })
+            .unwrap();
+        write(&agate, 5, 10).unwrap();
+        assert!(matches!(write(&agate, 10, 15), Err(Error::WriteStalled)));
+        agate
+            .set_option(RuntimeOption::WriteRateLimit {
+                bytes_per_sec: 0,
+                ops_per_sec: 0,
+            })
+            .unwrap();
+        write(&agate, 10, 15).unwrap();
+
+        agate.set_option(RuntimeOption::SyncWrites(true)).unwrap();
+        assert!(agate.core.sync_writes.load(Ordering::Relaxed));
+        write(&agate, 15, 20).unwrap();
+
+        // Compactors are restarted with the new number.
+        agate.set_option(RuntimeOption::NumCompactors(1)).unwrap();
+        assert_eq!(agate.compactors.lock().unwrap().count(COMPACTOR_TASK), 1);
+        agate.set_option(RuntimeOption::NumCompactors(0)).unwrap();
+        assert_eq!(agate.compactors.lock().unwrap().count(COMPACTOR_TASK), 0);
+        agate.close(true).unwrap();
+        let agate = Agate::open(test_options().with_read_only(true), tmp_dir.path()).unwrap();
+        assert!(matches!(
+            agate.set_option(RuntimeOption::NumCompactors(1)),
+            Err(Error::ReadOnly)
+        ));
+
+        // Caches can be resized only if they are enabled on open.
+        let block_cache = agate.core.opts.block_cache.clone().unwrap();
+        agate
+            .set_option(RuntimeOption::BlockCacheSize(1 << 10))
+            .unwrap();
+        assert_eq!(block_cache.stats().capacity, 1 << 10);
+        assert!(matches!(
+            agate.set_option(RuntimeOption::IndexCacheSize(1 << 10)),
+            Err(Error::Config(_))
+        ));
+
+        let agate = Agate::open(test_options().with_in_memory(true), "").unwrap();
+        assert!(agate.set_option(RuntimeOption::SyncWrites(true)).is_err());
+    }
+
+    #[test]
+    fn test_sync() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        agate.sync().unwrap();
+        // Tiny memtables make sure there are immutable memtables to sync.
+        for i in 0..300 {
+            let key = key_with_ts(format!("key{:05}", i).as_str(), i + 1);
+            agate
+                .write_entries(vec![Entry::new(key, Bytes::from(vec![0; 100]))])
+                .unwrap();
+            if i % 50 == 0 {
+                agate.sync().unwrap();
+            }
+        }
+        agate.sync().unwrap();
+        drop(agate);
+
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        assert_eq!(
+            agate.get(&key_with_ts("key00299", u64::MAX)).unwrap().value,
+            vec![0; 100]
+        );
+        let mem = Agate::open(test_options().with_in_memory(true), "").unwrap();
+        mem.sync().unwrap();
+    }
+
+    #[test]
+    fn test_read_only() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 20,
+            ..test_options()
+        };
+        let ro_opts = opts.clone().with_read_only(true);
+        assert!(Agate::open(ro_opts.clone(), tmp_dir.path()).is_err());
+
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 100, 200);
+        assert!(Agate::open(ro_opts.clone(), tmp_dir.path()).is_err());
+        agate.close(false).unwrap();
+        let files: Vec<_> = fs::read_dir(tmp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+
+        // Several read-only instances can be opened, and WAL is replayed.
+        let ro1 = Agate::open(ro_opts.clone(), tmp_dir.path()).unwrap();
+        let ro2 = Agate::open(ro_opts, tmp_dir.path()).unwrap();
+        assert!(Agate::open(opts, tmp_dir.path()).is_err());
+        for i in (0..200).step_by(7) {
+            let key = key_with_ts(format!("key{:05}", i).as_str(), u64::MAX);
+            assert_eq!(ro1.get(&key).unwrap().value, format!("value{:05}", i));
+            assert_eq!(ro2.get(&key).unwrap().value, format!("value{:05}", i));
+        }
+        assert!(matches!(
+            ro1.write_entries(vec![Entry::new(key_with_ts("key", 1), Bytes::new())]),
+            Err(Error::ReadOnly)
+        ));
+        let mut txn = ro1.new_transaction(true);
+        txn.set(Bytes::from("key"), Bytes::new()).unwrap();
+        assert!(matches!(txn.commit(), Err(Error::ReadOnly)));
+        assert!(matches!(ro1.flush_memtable(true), Err(Error::ReadOnly)));
+        ro1.close(true).unwrap();
+        drop(ro2);
+
+        // Nothing is changed on disk.
+        let after: Vec<_> = fs::read_dir(tmp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(files.len(), after.len());
+    }
+
+    #[test]
+    fn test_badger_compat() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 20,
+            ..test_options()
+        };
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.close(true).unwrap();
+
+        // Make it look like a directory left by Badger v2, whose MANIFEST
+        // only differs in magic, and there is no memtable or lock file.
+        let manifest_path = tmp_dir.path().join(crate::manifest::MANIFEST_FILENAME);
+        let mut manifest = fs::read(&manifest_path).unwrap();
+        manifest[..8].copy_from_slice(b"Bdgr\0\0\0\x07");
+        fs::write(&manifest_path, manifest).unwrap();
+        for name in fs::read_dir(tmp_dir.path()).unwrap() {
+            let path = name.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if name.ends_with(MEMTABLE_FILE_EXT) || name == lock::LOCK_FILE_NAME {
+                fs::remove_file(&path).unwrap();
+            }
+        }
+
+        assert!(Agate::open(opts.clone().with_read_only(true), tmp_dir.path()).is_err());
+        assert!(matches!(
+            Agate::open(
+                AgateOptions {
+                    badger_compat: true,
+                    ..opts.clone()
+                },
+                tmp_dir.path()
+            ),
+            Err(Error::Config(_))
+        ));
+        let agate = Agate::open(opts.with_badger_compat(), tmp_dir.path()).unwrap();
+        for i in (0..100).step_by(7) {
+            let key = key_with_ts(format!("key{:05}", i).as_str(), u64::MAX);
+            assert_eq!(agate.get(&key).unwrap().value, format!("value{:05}", i));
+        }
+        assert!(!tmp_dir.path().join(lock::LOCK_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_ttl_with_manual_clock() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let clock = Arc::new(crate::ManualClock::new(1000));
+        let opts = AgateOptions {
+            clock: clock.clone(),
+            ..test_options()
+        };
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+
+        let entry = Entry::new(key_with_ts("key", 1), Bytes::from("value"))
+            .with_ttl(Duration::from_secs(10));
+        // TTL starts when the entry is written.
+        clock.advance(Duration::from_secs(5));
+        agate.write_entries(vec![entry]).unwrap();
+        let value = agate.get(&key_with_ts("key", 1)).unwrap();
+        assert_eq!(value.expires_at, 1015);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(agate.get(&key_with_ts("key", 1)).unwrap().value, "value");
+        clock.advance(Duration::from_secs(1));
+        assert!(agate.get(&key_with_ts("key", 1)).unwrap().value.is_empty());
+        let values = agate.get_multi(&[key_with_ts("key", 1)]).unwrap();
+        assert!(values[0].value.is_empty());
+    }
 }
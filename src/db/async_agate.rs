@@ -0,0 +1,200 @@
+use super::*;
+use crate::closer::Closer;
+use crate::future::TaskFuture;
+use crate::iterator::Item;
+use crate::ops::transaction::Transaction;
+
+use std::panic::{self, AssertUnwindSafe};
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Threads running blocking tasks of `AsyncAgate`.
+struct BlockingPool {
+    sender: Option<Sender<Task>>,
+    tasks: Closer,
+}
+
+impl BlockingPool {
+    fn new(num_threads: usize) -> Result<Self> {
+        let (tx, rx) = crossbeam_channel::unbounded::<Task>();
+        let mut tasks = Closer::default();
+        for i in 0..num_threads {
+            let rx = rx.clone();
+            tasks.spawn(format!("async-agate-{}", i), move |_| {
+                for task in rx {
+                    task();
+                }
+            })?;
+        }
+        Ok(Self {
+            sender: Some(tx),
+            tasks,
+        })
+    }
+
+    fn spawn<T: Send + 'static>(
+        &self,
+        task: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> TaskFuture<T> {
+        let (future, done) = TaskFuture::new();
+        let task = Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(task))
+                .unwrap_or_else(|_| Err(Error::CustomError("async task panicked".to_string())));
+            done(result);
+        });
+        // The future resolves to `Error::DBClosed` if the task is dropped.
+        let _ = self.sender.as_ref().unwrap().send(task);
+        future
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        // Threads exit once queued tasks are finished.
+        self.sender.take();
+        let _ = self.tasks.close();
+    }
+}
+
+struct AsyncInner {
+    // Dropped first, so that the database is closed after all tasks are
+    // finished.
+    pool: BlockingPool,
+    agate: Arc<Agate>,
+}
+
+/// Async facade of `Agate`, which doesn't depend on any runtime. Reads and
+/// other calls which may block on file IO are dispatched to a dedicated
+/// pool of threads, and writes are sent to write thread directly, so async
+/// services don't need to wrap every call in something like
+/// `spawn_blocking`.
+///
+/// It can be cloned cheaply. The database is closed when the last clone is
+/// dropped, after queued tasks are finished.
+#[derive(Clone)]
+pub struct AsyncAgate {
+    inner: Arc<AsyncInner>,
+}
+
+impl Agate {
+    /// Convert the database to an async one, which runs blocking tasks with
+    /// `num_threads` threads, at least one.
+    pub fn into_async(self, num_threads: usize) -> Result<AsyncAgate> {
+        // TODO: dispatch reads to io_uring when it's supported by `Env`.
+        Ok(AsyncAgate {
+            inner: Arc::new(AsyncInner {
+                pool: BlockingPool::new(num_threads.max(1))?,
+                agate: Arc::new(self),
+            }),
+        })
+    }
+}
+
+impl AsyncAgate {
+    /// Get the underlying database for calls without async versions.
+    pub fn agate(&self) -> &Agate {
+        &self.inner.agate
+    }
+
+    /// Run `f` with the database in the pool, for calls without async
+    /// versions which may block, like `Agate::flatten`.
+    pub fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Agate) -> Result<T> + Send + 'static,
+    ) -> TaskFuture<T> {
+        let agate = self.inner.agate.clone();
+        self.inner.pool.spawn(move || f(&agate))
+    }
+
+    /// Get the latest version of `key` visible to new transactions, see
+    /// `Transaction::get`.
+    pub fn get(&self, key: Bytes) -> TaskFuture<Item> {
+        self.run(move |agate| agate.new_transaction(false).get(&key))
+    }
+
+    /// Create a transaction. Reads of the transaction block, so they should
+    /// be moved to `run` if data may not be cached.
+    pub fn new_transaction(&self, update: bool) -> Transaction {
+        self.inner.agate.new_transaction(update)
+    }
+
+    /// Commit `txn`, see `Transaction::commit_async`.
+    pub fn commit(&self, txn: Transaction) -> WriteFuture {
+        txn.commit_async()
+    }
+
+    /// Set `key` to `value` in a new transaction.
+    pub fn set(&self, key: Bytes, value: Bytes) -> WriteFuture {
+        let mut txn = self.new_transaction(true);
+        if let Err(err) = txn.set(key, value) {
+            return WriteFuture::ready(Err(err));
+        }
+        txn.commit_async()
+    }
+
+    /// Iterate the latest version of keys with `prefix` visible to new
+    /// transactions in the pool. Items are passed to `f` in batches in the
+    /// order of keys, and iteration stops at the first error of `f`.
+    pub fn iterate(
+        &self,
+        prefix: Bytes,
+        f: impl FnMut(Vec<Item>) -> Result<()> + Send + 'static,
+    ) -> TaskFuture<()> {
+        self.run(move |agate| agate.new_stream().with_prefix(prefix).run(Some, f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::future::block_on;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_async_agate() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        let db = agate.into_async(2).unwrap();
+
+        let futures: Vec<_> = (0..100)
+            .map(|i| {
+                db.set(
+                    Bytes::from(format!("key{:03}", i)),
+                    Bytes::from(i.to_string()),
+                )
+            })
+            .collect();
+        for future in futures {
+            block_on(future).unwrap();
+        }
+        let item = block_on(db.get(Bytes::from("key042"))).unwrap();
+        assert_eq!(item.value(), "42");
+        assert!(matches!(
+            block_on(db.get(Bytes::from("missing"))),
+            Err(Error::KeyNotFound)
+        ));
+
+        let mut txn = db.new_transaction(true);
+        txn.delete(Bytes::from("key000")).unwrap();
+        block_on(db.commit(txn)).unwrap();
+
+        let keys = Arc::new(Mutex::new(vec![]));
+        let collected = keys.clone();
+        block_on(db.iterate(Bytes::from("key0"), move |batch| {
+            let mut keys = collected.lock().unwrap();
+            keys.extend(batch.into_iter().map(|item| item.key().clone()));
+            Ok(())
+        }))
+        .unwrap();
+        let keys = keys.lock().unwrap();
+        assert_eq!(keys.len(), 99);
+        assert_eq!(keys[0], "key001");
+
+        let size = block_on(db.run(|agate| agate.size())).unwrap();
+        assert_eq!(size, db.agate().size().unwrap());
+        let res: Result<()> = block_on(db.run(|_| panic!("boom")));
+        assert!(res.is_err());
+        // The pool is still alive after a panic.
+        block_on(db.get(Bytes::from("key001"))).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use super::*;
+
+use prost::Message;
+use proto::meta::{Kv, KvList};
+use std::io::Write;
+
+/// Write `list` to `writer` prefixed with its length in u64 little endian.
+fn write_kv_list(writer: &mut impl Write, list: &KvList) -> Result<()> {
+    let mut buf = Vec::with_capacity(list.encoded_len());
+    list.encode(&mut buf).unwrap();
+    writer.write_all(&(buf.len() as u64).to_le_bytes())?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+impl Agate {
+    /// Write all versions of keys committed since `since_ts` to `writer`,
+    /// in the format read by `Agate::load`. Returns the timestamp the backup
+    /// is taken at. Passing it plus one as `since_ts` of the next backup
+    /// makes an incremental backup of changes in between.
+    ///
+    /// Keys are read by a `Stream`, where tables whose versions are all
+    /// older than `since_ts` are skipped without being read. Versions older
+    /// than a deletion are not written. In incremental backups, deletions
+    /// and expirations since the last backup are written as delete markers,
+    /// so that loading the full backup and all incremental ones in order
+    /// restores the same data.
+    pub fn backup(&self, mut writer: impl Write, since_ts: u64) -> Result<u64> {
+        let stream = self
+            .new_stream()
+            .with_since_ts(since_ts)
+            .with_all_versions(true)
+            .with_deleted(since_ts > 0);
+        stream.run(
+            |item| {
+                let mut kv = Kv {
+                    key: item.key().to_vec(),
+                    version: item.version(),
+                    ..Default::default()
+                };
+                if item.is_deleted() {
+                    kv.meta = vec![VALUE_DELETE];
+                } else {
+                    kv.value = item.value().to_vec();
+                    kv.user_meta = vec![item.user_meta()];
+                    kv.expires_at = item.expires_at();
+                    kv.meta = vec![0];
+                }
+                Some(kv)
+            },
+            |kv| write_kv_list(&mut writer, &KvList { kv }),
+        )?;
+        writer.flush()?;
+
+        Ok(stream.read_ts())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_options;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_backup_all_versions() {
+        let opts = || test_options().with_managed_txns(true);
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(opts(), tmp_dir.path()).unwrap();
+        let commit = |ts: u64, key: &str, value: Option<&str>| {
+            let mut txn = agate.new_transaction_at(ts - 1, true);
+            let key = Bytes::from(key.to_string());
+            match value {
+                Some(value) => txn.set(key, Bytes::from(value.to_string())).unwrap(),
+                None => txn.delete(key).unwrap(),
+            }
+            txn.commit_at(ts).unwrap();
+        };
+        commit(1, "a", Some("a1"));
+        commit(1, "b", Some("b1"));
+        commit(2, "a", Some("a2"));
+        agate.flush_memtable(true).unwrap();
+        commit(3, "b", None);
+        commit(4, "b", Some("b4"));
+        commit(5, "a", Some("a5"));
+
+        let get = |agate: &Agate, key: &str, ts: u64| {
+            let txn = agate.new_transaction_at(ts, false);
+            match txn.get(&Bytes::from(key.to_string())) {
+                Ok(item) => Some(item.value().clone()),
+                Err(Error::KeyNotFound) => None,
+                Err(err) => panic!("{:?}", err),
+            }
+        };
+        let mut full = vec![];
+        assert_eq!(agate.backup(&mut full, 0).unwrap(), 5);
+        let restore_dir = TempDir::new("agatedb").unwrap();
+        let restored = Agate::open(opts(), restore_dir.path()).unwrap();
+        restored.load(&full[..], 1).unwrap();
+        for ts in 1..=5 {
+            assert_eq!(get(&restored, "a", ts), get(&agate, "a", ts), "{}", ts);
+        }
+        assert_eq!(get(&restored, "b", 4), Some(Bytes::from("b4")));
+        // Versions older than a deletion are not backed up.
+        assert_eq!(get(&restored, "b", 1), None);
+
+        // Incremental backups carry all versions since the last one.
+        commit(6, "a", Some("a6"));
+        commit(7, "a", Some("a7"));
+        commit(7, "b", None);
+        let mut incremental = vec![];
+        assert_eq!(agate.backup(&mut incremental, 6).unwrap(), 7);
+        restored.load(&incremental[..], 1).unwrap();
+        for ts in 1..=7 {
+            assert_eq!(get(&restored, "a", ts), get(&agate, "a", ts), "{}", ts);
+        }
+        assert_eq!(get(&restored, "b", 6), Some(Bytes::from("b4")));
+        assert_eq!(get(&restored, "b", 7), None);
+    }
+}
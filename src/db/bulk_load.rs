@@ -0,0 +1,166 @@
+use super::*;
+use crate::table::builder::Builder;
+use crate::util::same_key;
+
+use std::cmp::Ordering as CmpOrdering;
+
+/// Builds SSTs from sorted key-value pairs and installs them at the last
+/// level directly, bypassing WAL and memtables. It's intended for initial
+/// imports, which can be much larger than memory.
+///
+/// Several loaders can run in parallel, each handling a disjoint range of
+/// keys. Tables built by a loader only become visible after `finish`, and
+/// they are deleted if the loader is dropped without finishing.
+pub struct BulkLoader {
+    core: Arc<Core>,
+    builder: Builder,
+    last_key: Bytes,
+    tables: Vec<Table>,
+}
+
+impl Agate {
+    /// Create a loader for importing data to the last level. Versions of
+    /// loaded keys should be older than any existing data in upper levels.
+    pub fn new_bulk_loader(&self) -> BulkLoader {
+        BulkLoader::new(self.core.clone())
+    }
+}
+
+/// Create a builder of tables at the last level.
+fn new_builder(core: &Core) -> Builder {
+    let level = core.opts.max_levels - 1;
+    Builder::new(build_level_table_options(&core.opts, level))
+}
+
+impl BulkLoader {
+    pub(crate) fn new(core: Arc<Core>) -> Self {
+        Self {
+            builder: new_builder(&core),
+            core,
+            last_key: Bytes::new(),
+            tables: vec![],
+        }
+    }
+
+    /// Add a key-value pair. Keys should contain timestamp, and must be
+    /// added in strictly increasing order.
+    pub fn add(&mut self, key: Bytes, value: Value) -> Result<()> {
+        if self.core.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.core
+            .check_entry_size(key.len().saturating_sub(8), value.value.len())?;
+        if !self.in_order(&key) {
+            return Err(Error::CustomError(format!(
+                "bulk loaded keys out of order: {:?} after {:?}",
+                key, self.last_key
+            )));
+        }
+        // All versions of a key should be kept in the same table.
+        if !self.last_key.is_empty()
+            && !same_key(&key, &self.last_key)
+            && self.builder.reach_capacity(self.core.opts.base_table_size)
+        {
+            self.finish_table()?;
+        }
+
+        self.builder.add(&key, value, 0);
+        self.last_key = key;
+        Ok(())
+    }
+
+    /// Check whether `key` can be added after keys added so far.
+    pub(crate) fn in_order(&self, key: &[u8]) -> bool {
+        self.last_key.is_empty()
+            || self
+                .core
+                .lvctl
+                .comparator()
+                .compare_key(key, &self.last_key)
+                == CmpOrdering::Greater
+    }
+
+    fn finish_table(&mut self) -> Result<()> {
+        let builder = std::mem::replace(&mut self.builder, new_builder(&self.core));
+        let table = self.core.create_table(builder)?;
+        self.tables.push(table);
+        Ok(())
+    }
+
+    /// Build the remaining data and install all tables at the last level.
+    /// Fails if loaded keys overlap with the existing tables there.
+    pub fn finish(self) -> Result<()> {
+        let core = self.core.clone();
+        let tables = self.build()?;
+        core.add_bottom_tables(tables)
+    }
+
+    /// Build the remaining data, and return all tables without installing
+    /// them.
+    pub(crate) fn build(mut self) -> Result<Vec<Table>> {
+        if !self.builder.is_empty() {
+            self.finish_table()?;
+        }
+        Ok(std::mem::take(&mut self.tables))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_options;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_bulk_load() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            base_table_size: 1 << 12,
+            ..test_options()
+        };
+        let load = |agate: &Agate, start: u64, end: u64| {
+            let mut loader = agate.new_bulk_loader();
+            for i in start..end {
+                let key = key_with_ts(format!("key{:05}", i).as_str(), 1);
+                let value = Value::new(Bytes::from(format!("value{:05}", i)));
+                loader.add(key, value)?;
+            }
+            loader.finish()
+        };
+        let check = |agate: &Agate| {
+            assert_eq!(agate.estimate_size(b"", b"z").keys, 2000);
+            for i in (0..2000).step_by(13) {
+                let value = agate
+                    .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                    .unwrap();
+                assert_eq!(value.value, format!("value{:05}", i));
+                assert_eq!(value.version, 1);
+            }
+        };
+
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        // Disjoint ranges can be loaded in parallel.
+        thread::scope(|s| {
+            let handle = s.spawn(|| load(&agate, 1000, 2000));
+            load(&agate, 0, 1000).unwrap();
+            handle.join().unwrap().unwrap();
+        });
+        check(&agate);
+
+        // Overlapping and unordered loads are rejected.
+        assert!(load(&agate, 1500, 2500).is_err());
+        let mut loader = agate.new_bulk_loader();
+        loader
+            .add(key_with_ts("key03000", 1), Value::new(Bytes::new()))
+            .unwrap();
+        assert!(loader
+            .add(key_with_ts("key02999", 1), Value::new(Bytes::new()))
+            .is_err());
+        drop(loader);
+        check(&agate);
+
+        drop(agate);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        check(&agate);
+    }
+}
@@ -0,0 +1,84 @@
+use super::*;
+
+impl Agate {
+    /// Create an openable copy of the database in `dir`, which must not
+    /// exist. Memtables are flushed first, then all tables are hard linked
+    /// into `dir` together with a snapshot of manifest and key registry, so
+    /// the checkpoint costs little space until tables are removed from the
+    /// database. Writes are only blocked by the flush, and the checkpoint
+    /// contains all writes acknowledged before this call.
+    ///
+    /// `dir` must be on the same file system as the database. The copy gets
+    /// a new identity when it's opened.
+    pub fn checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let core = &self.core;
+        if core.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if core.opts.in_memory {
+            return Err(Error::CustomError(
+                "checkpoint is not supported in memory mode".to_string(),
+            ));
+        }
+        let dir = dir.as_ref();
+        let env = core.opts.env.as_ref();
+        if env.exists(dir) {
+            return Err(Error::CustomError(format!(
+                "checkpoint directory {:?} already exists",
+                dir
+            )));
+        }
+
+        core.flush_memtable(true)?;
+        env.create_dir_all(dir)?;
+        core.manifest.checkpoint(dir)?;
+        // Key registry only grows, so it has all data keys of linked tables.
+        core.key_registry.write_to(dir)?;
+        env.sync_dir(dir)?;
+        info!("checkpoint of {:?} is created in {:?}", core.opts.dir, dir);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{test_options, write_keys};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_checkpoint() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = test_options().with_encryption_key(vec![7; 32], Duration::from_secs(3600));
+        let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 50);
+        agate.flush_memtable(true).unwrap();
+        // Keys in memtables are flushed by checkpoint.
+        write_keys(&agate, 50, 60);
+        let checkpoint = tmp_dir.path().join("checkpoint");
+        agate.checkpoint(&checkpoint).unwrap();
+        assert!(agate.checkpoint(&checkpoint).is_err());
+        write_keys(&agate, 60, 70);
+
+        let copy = Agate::open(opts, &checkpoint).unwrap();
+        assert_ne!(copy.identity().uuid, agate.identity().uuid);
+        let get = |agate: &Agate, i: u64| {
+            agate
+                .get(&key_with_ts(format!("key{:05}", i).as_str(), u64::MAX))
+                .unwrap()
+                .value
+        };
+        for i in 0..60 {
+            assert_eq!(get(&copy, i), format!("value{:05}", i));
+        }
+        assert!(get(&copy, 65).is_empty());
+        assert_eq!(get(&agate, 65), "value00065");
+
+        // Both copies can be written independently.
+        write_keys(&copy, 100, 110);
+        copy.flush_memtable(true).unwrap();
+        assert!(get(&agate, 105).is_empty());
+        let copy = crate::test_util::reopen(copy).unwrap();
+        assert_eq!(get(&copy, 105), "value00105");
+    }
+}
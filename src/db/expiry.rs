@@ -0,0 +1,191 @@
+use super::*;
+use crate::table::ITERATOR_NOCACHE;
+
+use std::collections::HashMap;
+
+/// Number of tombstones written in a batch when deleting expired entries.
+const EXPIRY_DELETE_BATCH: usize = 1000;
+
+impl Core {
+    /// Delete the latest versions of keys which have expired, in tables
+    /// which may have expired entries according to their TTL metadata.
+    /// `scanned` records when every table is scanned, so tables whose
+    /// entries have not expired since are skipped. Returns the number of
+    /// entries deleted.
+    ///
+    /// Tombstones are written at the versions of expired entries, so that
+    /// newer versions written meanwhile are never hidden, and no conflict
+    /// with transactions is possible.
+    pub(crate) fn delete_expired(&self, scanned: &mut HashMap<u64, u64>) -> Result<usize> {
+        if self.is_closed() {
+            return Err(Error::DBClosed);
+        }
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let now = self.opts.clock.unix_time();
+        let tables: Vec<Table> = self.lvctl.all_tables().into_iter().flatten().collect();
+        scanned.retain(|id, _| tables.iter().any(|t| t.id() == *id));
+
+        let mut deleted = 0;
+        let mut batch = vec![];
+        for table in tables {
+            let (min, max) = match table.expiry_range() {
+                Some(range) => range,
+                None => continue,
+            };
+            if min > now || scanned.get(&table.id()).is_some_and(|&at| max <= at) {
+                continue;
+            }
+            let mut iter = table.new_iterator(ITERATOR_NOCACHE);
+            iter.rewind();
+            while iter.valid() {
+                let value = iter.value();
+                let version = get_ts(iter.key());
+                if value.is_expired(now) && value.meta & (VALUE_DELETE | VALUE_MERGE_ENTRY) == 0 {
+                    let key = user_key(iter.key());
+                    let latest = with_key_ts(KeyTs::new(key, u64::MAX), |k| self.get_value(k))?;
+                    if latest.version == version && latest.meta & VALUE_DELETE == 0 {
+                        let mut entry = Entry::new(key_with_ts(key, version), Bytes::new());
+                        entry.mark_delete();
+                        batch.push(entry);
+                    }
+                }
+                if batch.len() >= EXPIRY_DELETE_BATCH {
+                    deleted += batch.len();
+                    self.write_entries(std::mem::take(&mut batch), None)?;
+                }
+                iter.next();
+            }
+            scanned.insert(table.id(), now);
+        }
+        if !batch.is_empty() {
+            deleted += batch.len();
+            self.write_entries(batch, None)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Delete expired entries every `expiry_scan_interval` until `signal`
+    /// is closed.
+    pub(crate) fn scan_expired(&self, signal: &Signal) {
+        let mut scanned = HashMap::new();
+        while !signal.wait_timeout(self.opts.expiry_scan_interval) {
+            match self.delete_expired(&mut scanned) {
+                Ok(0) => {}
+                Ok(deleted) => debug!("deleted {} expired entries", deleted),
+                Err(Error::DBClosed) => break,
+                Err(err) => warn!("failed to delete expired entries: {:?}", err),
+            }
+        }
+    }
+}
+
+impl Agate {
+    /// Delete the latest versions of keys which have expired in tables,
+    /// which are otherwise kept until they are compacted. Only tables with
+    /// TTL metadata, written since it's recorded, are scanned. Entries in
+    /// memtables are deleted after they are flushed. Returns the number of
+    /// entries deleted.
+    ///
+    /// It's also run every `AgateOptions::expiry_scan_interval` in
+    /// background.
+    pub fn delete_expired(&self) -> Result<usize> {
+        self.core.delete_expired(&mut HashMap::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_options;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_delete_expired() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let clock = Arc::new(crate::ManualClock::new(1000));
+        let agate = Agate::open(test_options().with_clock(clock.clone()), tmp_dir.path()).unwrap();
+        let key = |i: usize| format!("key{:05}", i);
+        let entries = (0..10)
+            .map(|i| {
+                let entry = Entry::new(key_with_ts(key(i).as_str(), 1), Bytes::from("value"));
+                if i % 2 == 0 {
+                    entry.with_ttl(Duration::from_secs(10))
+                } else {
+                    entry
+                }
+            })
+            .collect();
+        agate.write_entries(entries).unwrap();
+        // The newer version doesn't expire.
+        let entry = Entry::new(key_with_ts(key(2).as_str(), 2), Bytes::from("new"));
+        agate.write_entries(vec![entry]).unwrap();
+        agate.flush_memtable(true).unwrap();
+        let tables = agate.tables().unwrap();
+        let table = agate.core.lvctl.all_tables().swap_remove(0).swap_remove(0);
+        assert_eq!(table.id(), tables[0].id);
+        assert_eq!(table.expiry_range(), Some((1010, 1010)));
+
+        assert_eq!(agate.delete_expired().unwrap(), 0);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(agate.delete_expired().unwrap(), 4);
+        let latest = |i: usize| {
+            let key = key_with_ts(key(i).as_str(), u64::MAX);
+            agate.core.get_value(&key).unwrap()
+        };
+        for i in [0, 4, 6, 8] {
+            let value = latest(i);
+            assert_ne!(value.meta & VALUE_DELETE, 0);
+            assert_eq!(value.version, 1);
+        }
+        assert_eq!(latest(2).value, "new");
+        assert_eq!(latest(1).value, "value");
+
+        // Tombstones are not deleted again, and hide expired entries after
+        // flush as well.
+        assert_eq!(agate.delete_expired().unwrap(), 0);
+        agate.flush_memtable(true).unwrap();
+        assert_eq!(agate.delete_expired().unwrap(), 0);
+        assert_ne!(latest(0).meta & VALUE_DELETE, 0);
+        agate.core.orc.advance_to(2);
+        let mut keys = vec![];
+        agate
+            .snapshot()
+            .scan(b"", b"", |item| {
+                keys.push(item.key().clone());
+                Ok(true)
+            })
+            .unwrap();
+        assert_eq!(
+            keys,
+            [1, 2, 3, 5, 7, 9]
+                .iter()
+                .map(|&i| key(i))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_expiry_scanner() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let clock = Arc::new(crate::ManualClock::new(1000));
+        let opts = test_options()
+            .with_clock(clock.clone())
+            .with_expiry_scan_interval(Duration::from_millis(10));
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        assert_eq!(agate.tasks.count(EXPIRY_SCANNER_TASK), 1);
+        let entry = Entry::new(key_with_ts("key", 1), Bytes::from("value"))
+            .with_ttl(Duration::from_secs(1));
+        agate.write_entries(vec![entry]).unwrap();
+        agate.flush_memtable(true).unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        let start = Instant::now();
+        while agate.core.get_value(&key_with_ts("key", 1)).unwrap().meta & VALUE_DELETE == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        agate.close(false).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+use super::*;
+use crate::table::{ConcatIterator, MergeIterator, TableIterators, ITERATOR_NOCACHE};
+
+impl Agate {
+    /// Merge tables of all levels into the last level, like `badger flatten`.
+    /// All versions are kept. Tables flushed to L0 meanwhile are left
+    /// untouched, and an error is returned if other levels are changed by
+    /// others, like compaction or bulk loading, before the merged tables
+    /// are installed.
+    ///
+    /// Data are rewritten, so it takes as long as bulk loading all tables.
+    pub fn flatten(&self) -> Result<()> {
+        let core = &self.core;
+        if core.is_closed() {
+            return Err(Error::DBClosed);
+        }
+        if core.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let old = core.lvctl.all_tables();
+        let last = old.len() - 1;
+        if old[..last].iter().all(Vec::is_empty) {
+            return Ok(());
+        }
+
+        // Newer data comes first, so that it wins among duplicated keys.
+        let mut iters: Vec<Box<TableIterators>> = vec![];
+        for table in old[0].iter().rev() {
+            iters.push(Box::new(table.new_iterator(ITERATOR_NOCACHE).into()));
+        }
+        for tables in old[1..].iter().filter(|tables| !tables.is_empty()) {
+            let iter = ConcatIterator::from_tables(tables.clone(), ITERATOR_NOCACHE);
+            iters.push(Box::new(iter.into()));
+        }
+        let mut iter = MergeIterator::from_iterators(iters, false, core.comparator());
+        let mut loader = BulkLoader::new(core.clone());
+        iter.rewind();
+        while iter.valid() {
+            loader.add(Bytes::copy_from_slice(iter.key()), iter.value())?;
+            iter.next();
+        }
+        drop(iter);
+
+        let tables = loader.build()?;
+        info!(
+            "flattening {} tables into {} tables at level {}",
+            old.iter().map(Vec::len).sum::<usize>(),
+            tables.len(),
+            last
+        );
+        core.lvctl.flatten_tables(&old, tables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{count_files, test_options, write_keys};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_flatten() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        write_keys(&agate, 0, 100);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 100, 200);
+        // An older version of a flushed key.
+        agate
+            .write_to_lsm(Request {
+                entries: vec![Entry::new(key_with_ts("key00150", 1), Bytes::from("old"))],
+                deadline: None,
+                done: None,
+            })
+            .unwrap();
+        agate.flush_memtable(true).unwrap();
+
+        let info = agate.db_info().unwrap();
+        assert_eq!(info.levels[0].tables.len(), 2);
+        assert_eq!(info.max_version, 200);
+        assert!(info.to_string().contains("[Level 0] 2 tables"));
+
+        agate.flatten().unwrap();
+        let info = agate.db_info().unwrap();
+        let last = info.levels.len() - 1;
+        assert_eq!(info.num_tables(), info.levels[last].tables.len());
+        let key_count: u32 = info.levels[last].tables.iter().map(|t| t.key_count).sum();
+        assert_eq!(key_count, 201);
+        assert_eq!(count_files(tmp_dir.path(), ".sst"), info.num_tables());
+        // Nothing to flatten.
+        agate.flatten().unwrap();
+        assert_eq!(agate.db_info().unwrap().levels, info.levels);
+        drop(agate);
+
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        assert_eq!(agate.levels().unwrap(), info.levels);
+        for i in 0..200 {
+            let key = format!("key{:05}", i);
+            let value = agate.get(&key_with_ts(key.as_str(), u64::MAX)).unwrap();
+            assert_eq!(value.value, format!("value{:05}", i));
+        }
+        let value = agate.get(&key_with_ts("key00150", 1)).unwrap();
+        assert_eq!(value.value, "old");
+
+        assert!(
+            matches!(agate.run_gc(0.5), Err(Error::Config(msg)) if msg == "value log not supported")
+        );
+        assert!(agate.run_gc(1.0).is_err());
+    }
+}
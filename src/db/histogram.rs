@@ -0,0 +1,161 @@
+use super::*;
+
+use std::fmt;
+
+/// Distribution of sizes, with power of two buckets like Badger's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramData {
+    /// upper bounds (exclusive) of all buckets but the last open one
+    pub bounds: Vec<u64>,
+    /// count of values in every bucket, one more than `bounds`
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub sum: u64,
+}
+
+impl HistogramData {
+    /// Create a histogram with buckets bounded by `2^min_exp` to
+    /// `2^max_exp`.
+    fn with_exponents(min_exp: u32, max_exp: u32) -> Self {
+        let bounds: Vec<u64> = (min_exp..=max_exp).map(|exp| 1 << exp).collect();
+        Self {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+            sum: 0,
+        }
+    }
+
+    fn update(&mut self, value: u64) {
+        let idx = self.bounds.partition_point(|bound| *bound <= value);
+        self.counts[idx] += 1;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+}
+
+impl fmt::Display for HistogramData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Total count: {}", self.count)?;
+        if self.count == 0 {
+            return Ok(());
+        }
+        writeln!(f, "Min value: {}", self.min)?;
+        writeln!(f, "Max value: {}", self.max)?;
+        writeln!(f, "Mean: {:.2}", self.mean())?;
+        writeln!(f, "{:>24} {:>9}", "Range", "Count")?;
+        for (idx, count) in self.counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let lower = if idx == 0 { 0 } else { self.bounds[idx - 1] };
+            let upper = match self.bounds.get(idx) {
+                Some(bound) => bound.to_string(),
+                None => "infinity".to_string(),
+            };
+            writeln!(f, "[{:>10}, {:>10}) {:>9}", lower, upper, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sizes of keys and values, returned by `Agate::histogram`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyValueHistogram {
+    /// sizes of user keys
+    pub key_sizes: HistogramData,
+    /// sizes of values, where merge operands are folded
+    pub value_sizes: HistogramData,
+}
+
+impl Default for KeyValueHistogram {
+    fn default() -> Self {
+        Self {
+            key_sizes: HistogramData::with_exponents(1, 16),
+            value_sizes: HistogramData::with_exponents(1, 30),
+        }
+    }
+}
+
+impl fmt::Display for KeyValueHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Histogram of key sizes (in bytes)")?;
+        write!(f, "{}", self.key_sizes)?;
+        writeln!(f)?;
+        writeln!(f, "Histogram of value sizes (in bytes)")?;
+        write!(f, "{}", self.value_sizes)
+    }
+}
+
+impl Agate {
+    /// Get sizes of the latest version of all keys starting with `prefix`,
+    /// like `badger info --histogram`. All keys under the prefix are
+    /// scanned by a `Stream`.
+    pub fn histogram(&self, prefix: &[u8]) -> Result<KeyValueHistogram> {
+        let mut histogram = KeyValueHistogram::default();
+        self.new_stream()
+            .with_prefix(Bytes::copy_from_slice(prefix))
+            .run(
+                |item| Some((item.key().len() as u64, item.value().len() as u64)),
+                |batch| {
+                    for (key_size, value_size) in batch {
+                        histogram.key_sizes.update(key_size);
+                        histogram.value_sizes.update(value_size);
+                    }
+                    Ok(())
+                },
+            )?;
+        Ok(histogram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_options;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_histogram() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction(true);
+        for (key, len) in [("a", 0), ("b1", 1), ("b22", 100), ("b333", 1000)] {
+            txn.set(Bytes::from(key), Bytes::from(vec![0; len]))
+                .unwrap();
+        }
+        txn.commit().unwrap();
+
+        let histogram = agate.histogram(b"b").unwrap();
+        let keys = &histogram.key_sizes;
+        assert_eq!((keys.count, keys.min, keys.max, keys.sum), (3, 2, 4, 9));
+        // [2, 4) and [4, 8)
+        assert_eq!(&keys.counts[..3], &[0, 2, 1]);
+        let values = &histogram.value_sizes;
+        assert_eq!((values.count, values.min, values.max), (3, 1, 1000));
+        assert_eq!(values.counts[0], 1);
+        assert_eq!(values.counts[6], 1);
+        assert_eq!(values.counts[9], 1);
+        assert!(histogram
+            .to_string()
+            .contains("[       512,       1024)         1"));
+
+        let histogram = agate.histogram(b"").unwrap();
+        assert_eq!(histogram.key_sizes.count, 4);
+        assert_eq!(histogram.value_sizes.min, 0);
+        assert_eq!(agate.histogram(b"c").unwrap().key_sizes.count, 0);
+    }
+}
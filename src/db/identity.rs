@@ -0,0 +1,144 @@
+use crate::env::{Env, IoPriority, OpenMode};
+use crate::{Error, Result};
+
+use rand::RngCore;
+use std::io::Write;
+use std::path::Path;
+
+pub(crate) const IDENTITY_FILE_NAME: &str = "IDENTITY";
+const IDENTITY_REWRITE_FILE_NAME: &str = "REWRITE-IDENTITY";
+
+/// Identity of a store. `uuid` is generated when the directory is created,
+/// and `incarnation` is bumped every time it's opened for writing, so tools
+/// like replication and backup can tell if a directory is replaced or has
+/// been written by others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreIdentity {
+    pub uuid: String,
+    pub incarnation: u64,
+}
+
+impl StoreIdentity {
+    /// Create a new identity with a random version 4 UUID.
+    pub(crate) fn generate() -> Self {
+        let mut bytes = [0; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let uuid = format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        );
+        Self {
+            uuid,
+            incarnation: 0,
+        }
+    }
+
+    /// Read identity from `IDENTITY` file in `dir`. Returns `None` if the file
+    /// doesn't exist.
+    pub(crate) fn load(env: &dyn Env, dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(IDENTITY_FILE_NAME);
+        if !env.exists(&path) {
+            return Ok(None);
+        }
+        let content = env.read_file(&path)?;
+        let mut lines = std::str::from_utf8(&content).unwrap_or_default().lines();
+        let uuid = lines.next().filter(|uuid| uuid.len() == 36);
+        let incarnation = lines.next().and_then(|n| n.parse().ok());
+        match (uuid, incarnation) {
+            (Some(uuid), Some(incarnation)) => Ok(Some(Self {
+                uuid: uuid.to_string(),
+                incarnation,
+            })),
+            _ => Err(Error::CustomError(format!(
+                "malformed identity file {}",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Atomically replace `IDENTITY` file in `dir` with current identity.
+    fn persist(&self, env: &dyn Env, dir: &Path) -> Result<()> {
+        let rewrite_path = dir.join(IDENTITY_REWRITE_FILE_NAME);
+        let mut file =
+            env.open_writable(&rewrite_path, OpenMode::Truncate, IoPriority::Foreground)?;
+        writeln!(file, "{}", self.uuid)?;
+        writeln!(file, "{}", self.incarnation)?;
+        file.sync_all()?;
+        drop(file);
+        env.rename(&rewrite_path, &dir.join(IDENTITY_FILE_NAME))?;
+        env.sync_dir(dir)
+    }
+
+    /// Load identity of the store at `dir`, which is created if not exists,
+    /// and bump its incarnation.
+    pub(crate) fn open(env: &dyn Env, dir: &Path) -> Result<Self> {
+        let mut identity = Self::load(env, dir)?.unwrap_or_else(Self::generate);
+        identity.incarnation += 1;
+        identity.persist(env, dir)?;
+        Ok(identity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_options;
+    use crate::env::StdEnv;
+    use crate::Agate;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_store_identity() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        assert_eq!(StoreIdentity::load(&StdEnv, tmp_dir.path()).unwrap(), None);
+
+        let first = StoreIdentity::open(&StdEnv, tmp_dir.path()).unwrap();
+        assert_eq!(first.uuid.len(), 36);
+        assert_eq!(&first.uuid[14..15], "4");
+        assert_eq!(first.incarnation, 1);
+        let second = StoreIdentity::open(&StdEnv, tmp_dir.path()).unwrap();
+        assert_eq!(second.uuid, first.uuid);
+        assert_eq!(second.incarnation, 2);
+        assert_eq!(
+            StoreIdentity::load(&StdEnv, tmp_dir.path()).unwrap(),
+            Some(second)
+        );
+        assert_ne!(StoreIdentity::generate().uuid, first.uuid);
+
+        fs::write(tmp_dir.path().join(IDENTITY_FILE_NAME), "garbage").unwrap();
+        assert!(StoreIdentity::load(&StdEnv, tmp_dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_identity() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let identity = agate.identity().clone();
+        assert_eq!(identity.incarnation, 1);
+        drop(agate);
+
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        assert_eq!(agate.identity().uuid, identity.uuid);
+        assert_eq!(agate.identity().incarnation, 2);
+        drop(agate);
+        // Read-only instances don't bump incarnation.
+        let ro = Agate::open(test_options().with_read_only(true), tmp_dir.path()).unwrap();
+        assert_eq!(ro.identity().incarnation, 2);
+        drop(ro);
+
+        // A replaced directory gets a different identity.
+        let new_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), new_dir.path()).unwrap();
+        assert_ne!(agate.identity().uuid, identity.uuid);
+        let mem = Agate::open(test_options().with_in_memory(true), "").unwrap();
+        assert_eq!(mem.identity().incarnation, 0);
+    }
+}
@@ -0,0 +1,109 @@
+use super::*;
+
+use std::fmt;
+
+/// Summary of a database, returned by `Agate::db_info`. It's displayed in a
+/// format like `badger info`.
+#[derive(Debug, Clone)]
+pub struct DbInfo {
+    pub dir: PathBuf,
+    pub identity: StoreIdentity,
+    pub max_version: u64,
+    pub size: DbSize,
+    pub memory: MemoryUsage,
+    pub levels: Vec<LevelInfo>,
+}
+
+impl DbInfo {
+    /// Number of tables at all levels.
+    pub fn num_tables(&self) -> usize {
+        self.levels.iter().map(|level| level.tables.len()).sum()
+    }
+}
+
+impl fmt::Display for DbInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[Summary]")?;
+        writeln!(f, "Directory: {}", self.dir.display())?;
+        writeln!(
+            f,
+            "Identity: {} (incarnation {})",
+            self.identity.uuid, self.identity.incarnation
+        )?;
+        writeln!(f, "Max version: {}", self.max_version)?;
+        writeln!(f, "Memtables: {} bytes", self.memory.total())?;
+        writeln!(f, "Tables: {} bytes", self.size.lsm())?;
+        writeln!(f, "Value log: {} bytes", self.size.vlog)?;
+        for level in &self.levels {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "[Level {}] {} tables, {} bytes",
+                level.level,
+                level.tables.len(),
+                level.total_size
+            )?;
+            for table in &level.tables {
+                writeln!(
+                    f,
+                    "{:>8} {:>10} bytes {:>8} keys {:?}@{} - {:?}@{}",
+                    table.id,
+                    table.size,
+                    table.key_count,
+                    Bytes::copy_from_slice(user_key(&table.smallest)),
+                    get_ts(&table.smallest),
+                    Bytes::copy_from_slice(user_key(&table.biggest)),
+                    get_ts(&table.biggest)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Agate {
+    /// Get the summary of the database, which collects its identity, sizes
+    /// and tables of all levels.
+    pub fn db_info(&self) -> Result<DbInfo> {
+        Ok(DbInfo {
+            dir: self.core.opts.dir.clone(),
+            identity: self.identity().clone(),
+            max_version: self.max_version()?,
+            size: self.size()?,
+            memory: self.memory_usage()?,
+            levels: self.levels()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{test_options, write_keys};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_levels_info() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        assert!(agate.tables().unwrap().is_empty());
+        write_keys(&agate, 0, 50);
+        agate.flush_memtable(true).unwrap();
+        write_keys(&agate, 50, 100);
+        agate.flush_memtable(true).unwrap();
+
+        let levels = agate.levels().unwrap();
+        assert_eq!(levels.len(), agate.core.opts.max_levels);
+        assert_eq!(levels[0].tables.len(), 2);
+        assert_eq!(levels[0].total_size, agate.size().unwrap().levels[0]);
+        let tables = agate.tables().unwrap();
+        assert_eq!(tables, levels[0].tables);
+        assert_eq!(tables[0].level, 0);
+        assert_eq!(tables[0].smallest, key_with_ts("key00000", 1));
+        assert_eq!(tables[0].biggest, key_with_ts("key00049", 50));
+        assert_eq!(tables[0].key_count, 50);
+        assert_eq!(tables[0].max_version, 50);
+        assert_eq!(tables[0].stale_data_size, 0);
+        assert_eq!(tables[1].max_version, 100);
+    }
+}
@@ -0,0 +1,244 @@
+use super::*;
+
+use prost::Message;
+use proto::meta::{Kv, KvList};
+use std::io::{self, Read};
+
+/// Read the next `KVList` of a backup stream, where every list is prefixed
+/// with its length in u64 little endian, the same as Badger backups.
+/// Returns `None` at the end of the stream.
+fn read_kv_list(reader: &mut impl Read) -> Result<Option<KvList>> {
+    let mut len = [0; 8];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let mut buf = vec![0; u64::from_le_bytes(len) as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(KvList::decode(&buf[..])?))
+}
+
+/// Convert `kv` of a backup to a value. Only deletion and merge flags are
+/// kept, other flags are internal to the database which made the backup.
+pub(super) fn kv_to_value(kv: Kv) -> Value {
+    Value {
+        meta: kv
+            .meta
+            .first()
+            .map_or(0, |m| m & (VALUE_DELETE | VALUE_MERGE_ENTRY)),
+        meta2: 0,
+        user_meta: kv.user_meta.first().copied().unwrap_or(0),
+        expires_at: kv.expires_at,
+        value: Bytes::from(kv.value),
+        version: kv.version,
+    }
+}
+
+/// Groups loaded entries into write batches, and sends them to write thread
+/// with at most `max_pending` batches waiting to be written.
+struct BatchWriter<'a> {
+    core: &'a Core,
+    batch: Vec<Entry>,
+    batch_size: u64,
+    max_pending: usize,
+    pending: usize,
+    results: (Sender<Result<()>>, Receiver<Result<()>>),
+}
+
+impl<'a> BatchWriter<'a> {
+    fn new(core: &'a Core, max_pending: usize) -> Self {
+        Self {
+            core,
+            batch: vec![],
+            batch_size: 0,
+            max_pending: max_pending.max(1),
+            pending: 0,
+            results: crossbeam_channel::unbounded(),
+        }
+    }
+
+    fn add(&mut self, key: Bytes, value: Value) -> Result<()> {
+        let size = (key.len() + value.value.len()) as u64;
+        if self.batch.len() as u64 + 1 > self.core.opts.max_batch_count
+            || self.batch_size + size > self.core.opts.max_batch_size
+        {
+            self.send_batch()?;
+        }
+
+        let mut entry = Entry::new(key, value.value);
+        entry.meta = value.meta;
+        entry.user_meta = value.user_meta;
+        entry.expires_at = value.expires_at;
+        self.batch.push(entry);
+        self.batch_size += size;
+        Ok(())
+    }
+
+    fn wait_one(&mut self) -> Result<()> {
+        self.pending -= 1;
+        match self.results.1.recv() {
+            Ok(result) => result,
+            Err(_) => Err(Error::DBClosed),
+        }
+    }
+
+    fn send_batch(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        if self.pending >= self.max_pending {
+            self.wait_one()?;
+        }
+        let tx = self.results.0.clone();
+        let batch = std::mem::take(&mut self.batch);
+        self.batch_size = 0;
+        self.core.send_to_write_channel(
+            batch,
+            None,
+            Box::new(move |result| {
+                let _ = tx.send(result);
+            }),
+        )?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Send the last batch and wait for all batches to be written.
+    fn finish(mut self) -> Result<()> {
+        let mut result = self.send_batch();
+        while self.pending > 0 {
+            result = result.and(self.wait_one());
+        }
+        result
+    }
+}
+
+impl Agate {
+    /// Restore entries from a backup stream, keeping their versions, user
+    /// metadata and expiration time. Entries are written in batches, and at
+    /// most `max_pending_writes` batches are waiting to be written at a time.
+    ///
+    /// The database is meant to be empty. In that case, entries in sorted
+    /// order are built into tables at the last level directly like
+    /// `BulkLoader`, until an entry comes out of order, after which
+    /// remaining entries are written through memtables. After loading, new
+    /// transactions read and commit after the largest loaded version.
+    ///
+    /// If it fails, entries loaded so far are not rolled back.
+    pub fn load(&self, mut reader: impl Read, max_pending_writes: usize) -> Result<()> {
+        let core = &self.core;
+        if core.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let mut loader = if core.max_version()? == 0 {
+            Some(self.new_bulk_loader())
+        } else {
+            None
+        };
+        let mut writer = BatchWriter::new(core, max_pending_writes);
+        let mut max_version = 0;
+        while let Some(list) = read_kv_list(&mut reader)? {
+            for kv in list.kv {
+                // Markers of stream ends carry no data.
+                if kv.stream_done || kv.key.is_empty() {
+                    continue;
+                }
+                max_version = max_version.max(kv.version);
+                let key = key_with_ts(&kv.key[..], kv.version);
+                let value = kv_to_value(kv);
+                if let Some(l) = &mut loader {
+                    if l.in_order(&key) {
+                        l.add(key, value)?;
+                        continue;
+                    }
+                    loader.take().unwrap().finish()?;
+                }
+                core.check_entry_size(user_key(&key).len(), value.value.len())?;
+                writer.add(key, value)?;
+            }
+        }
+
+        writer.finish()?;
+        if let Some(loader) = loader {
+            loader.finish()?;
+        }
+        core.orc.advance_to(max_version);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_options;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_load() {
+        let kv = |key: &str, version: u64, value: &str, meta: u8, user_meta: u8| Kv {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            user_meta: vec![user_meta],
+            version,
+            meta: vec![meta],
+            ..Default::default()
+        };
+        let backup = |lists: Vec<Vec<Kv>>| {
+            let mut buf = vec![];
+            for kv in lists {
+                let mut data = vec![];
+                KvList { kv }.encode(&mut data).unwrap();
+                buf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                buf.extend_from_slice(&data);
+            }
+            buf
+        };
+        let check = |agate: &Agate| {
+            let txn = agate.new_transaction(false);
+            let item = txn.get(&Bytes::from("a")).unwrap();
+            assert_eq!((item.value(), item.version()), (&Bytes::from("a7"), 7));
+            assert_eq!(item.user_meta(), 3);
+            assert!(txn.get(&Bytes::from("b")).is_err());
+            assert_eq!(
+                txn.get(&Bytes::from("c")).unwrap().value(),
+                &Bytes::from("c2")
+            );
+            let mut txn = agate.new_transaction(true);
+            txn.set(Bytes::from("a"), Bytes::from("new")).unwrap();
+            txn.commit().unwrap();
+            let txn = agate.new_transaction(false);
+            assert_eq!(txn.get(&Bytes::from("a")).unwrap().version(), 8);
+        };
+        let sorted = backup(vec![
+            vec![kv("a", 7, "a7", 0, 3), kv("a", 5, "a5", 0, 0)],
+            vec![kv("b", 6, "", VALUE_DELETE, 0), kv("b", 4, "b4", 0, 0)],
+            vec![kv("c", 2, "c2", VALUE_TXN, 0)],
+        ]);
+
+        // Sorted input into an empty database goes to the last level.
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        agate.load(&sorted[..], 2).unwrap();
+        let infos = agate.levels().unwrap();
+        assert_eq!(infos.last().unwrap().tables.len(), 1);
+        check(&agate);
+
+        // Unsorted input is written through memtables.
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let unsorted = backup(vec![
+            vec![kv("c", 2, "c2", 0, 0), kv("a", 5, "a5", 0, 0)],
+            vec![kv("b", 6, "", VALUE_DELETE, 0), kv("a", 7, "a7", 0, 3)],
+        ]);
+        agate.load(&unsorted[..], 1).unwrap();
+        check(&agate);
+        assert!(agate.load(&sorted[..sorted.len() - 1], 1).is_err());
+    }
+}
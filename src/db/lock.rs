@@ -0,0 +1,201 @@
+use crate::{Error, Result};
+
+use log::error;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub(crate) const LOCK_FILE_NAME: &str = "LOCK";
+
+/// Directories opened for writing in current process. File locks alone are
+/// not enough, as they may be shared by the whole process on some platforms.
+static OPENED_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Registration of a directory in `OPENED_DIRS`, which is removed on drop.
+struct Registration(PathBuf);
+
+impl Registration {
+    fn register(dir: &Path) -> Result<Self> {
+        let dir = fs::canonicalize(dir)?;
+        let mut opened = OPENED_DIRS.lock()?;
+        if opened.contains(&dir) {
+            return Err(Error::Locked(format!(
+                "{} is already opened by another instance in this process",
+                dir.display()
+            )));
+        }
+        opened.push(dir.clone());
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut opened = OPENED_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        opened.retain(|dir| *dir != self.0);
+    }
+}
+
+/// Lock of a database directory, which is released on drop. Exclusive locks
+/// are also registered in current process, so the same directory can't be
+/// opened twice in one process either.
+pub(crate) struct DirLockGuard {
+    path: PathBuf,
+    file: File,
+    _registration: Option<Registration>,
+}
+
+impl DirLockGuard {
+    /// Lock `LOCK` file in `dir` in shared mode, without writing anything.
+    /// Returns `None` if the file doesn't exist, like a directory which was
+    /// not created by agatedb.
+    pub fn acquire_shared(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(LOCK_FILE_NAME);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match file.try_lock_shared() {
+            Ok(()) => Ok(Some(Self {
+                path,
+                file,
+                _registration: None,
+            })),
+            Err(TryLockError::WouldBlock) => Err(Error::Locked(format!(
+                "{} is locked exclusively, the database is being written",
+                path.display()
+            ))),
+            Err(TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+
+    /// Lock `LOCK` file in `dir`, which is created if not exists. The ID
+    /// of current process is written to the file for diagnosis.
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let registration = Registration::register(dir)?;
+        let path = dir.join(LOCK_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::Locked(format!(
+                    "{} is locked, another process may be using the database",
+                    path.display()
+                )));
+            }
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self {
+            path,
+            file,
+            _registration: Some(registration),
+        })
+    }
+}
+
+impl Drop for DirLockGuard {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.unlock() {
+            error!("failed to unlock {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_options;
+    use crate::{Agate, AgateOptions, OpenStage};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_dir_lock() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let guard = DirLockGuard::acquire(tmp_dir.path()).unwrap();
+        let err = DirLockGuard::acquire(&tmp_dir.path().join("."))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains(
+                &fs::canonicalize(tmp_dir.path())
+                    .unwrap()
+                    .display()
+                    .to_string()
+            ),
+            "{}",
+            err
+        );
+        assert!(err.is_retryable());
+        let pid = std::fs::read_to_string(tmp_dir.path().join(LOCK_FILE_NAME)).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+
+        assert!(DirLockGuard::acquire_shared(tmp_dir.path()).is_err());
+
+        drop(guard);
+        let shared = DirLockGuard::acquire_shared(tmp_dir.path()).unwrap();
+        assert!(shared.is_some());
+        assert!(DirLockGuard::acquire_shared(tmp_dir.path())
+            .unwrap()
+            .is_some());
+        assert!(DirLockGuard::acquire(tmp_dir.path()).is_err());
+        drop(shared);
+        DirLockGuard::acquire(tmp_dir.path()).unwrap();
+
+        let empty_dir = TempDir::new("agatedb").unwrap();
+        assert!(DirLockGuard::acquire_shared(empty_dir.path())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_open_lock() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let path = tmp_dir.path().join("nested/db");
+        let agate = Agate::open(test_options(), &path).unwrap();
+        assert!(path.join(LOCK_FILE_NAME).exists());
+        assert!(matches!(
+            Agate::open(test_options(), &path),
+            Err(Error::Open {
+                stage: OpenStage::Lock,
+                ..
+            })
+        ));
+
+        let bypass = Agate::open(
+            AgateOptions {
+                bypass_lock_guard: true,
+                ..test_options()
+            },
+            &path,
+        )
+        .unwrap();
+        drop(bypass);
+
+        // Lock is released on drop.
+        drop(agate);
+        let agate = Agate::open(test_options(), &path).unwrap();
+        drop(agate);
+
+        fs::write(path.join(crate::manifest::MANIFEST_FILENAME), b"garbage").unwrap();
+        assert!(matches!(
+            Agate::open(test_options(), &path),
+            Err(Error::Open {
+                stage: OpenStage::Manifest,
+                ..
+            })
+        ));
+        // Lock is released if open fails.
+        fs::remove_file(path.join(crate::manifest::MANIFEST_FILENAME)).unwrap();
+        Agate::open(test_options(), &path).unwrap();
+    }
+}
@@ -1,12 +1,111 @@
 use super::*;
+use crate::cache::{BlockCache, CachePolicy, IndexCache};
+use crate::clock::{Clock, SystemClock};
+use crate::comparator::UserComparator;
+use crate::entry::Entry;
+use crate::env::Env;
+#[cfg(target_arch = "wasm32")]
+use crate::env::MemEnv;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::env::StdEnv;
+use crate::event::EventListener;
+use crate::memtable::MEMTABLE_VIEW_MAX;
+use crate::merge::MergeOperator;
+use crate::opt::{BloomStrategy, ChecksumAlgorithm, ChecksumVerificationMode, Compression};
+use crate::Error;
+
+use skiplist::MAX_NODE_SIZE;
+
+/// Isolation of transactions, see `AgateOptions::isolation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// Serializable snapshot isolation. Keys read by update transactions
+    /// are tracked, and commits fail with `Error::Conflict` if any of them
+    /// is written by transactions committed after the read timestamp.
+    Serializable,
+    /// Snapshot isolation. Transactions still read consistent snapshots at
+    /// their read timestamps, but reads are not tracked and commits are not
+    /// checked, so concurrent transactions may cause write skew.
+    Snapshot,
+}
+
+/// Long running steps of `Agate::open` which report progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenProgressStage {
+    /// replaying manifest, which is counted as one item
+    Manifest,
+    /// opening tables recorded in manifest
+    Tables,
+    /// verifying checksums of tables with `ChecksumVerificationMode::OnTableOpen`
+    VerifyTables,
+    /// replaying WALs of memtables
+    Memtables,
+}
+
+/// Progress of `Agate::open`, `done` out of `total` items of `stage` are
+/// finished. Each stage starts with `done` being 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenProgress {
+    pub stage: OpenProgressStage,
+    pub done: u64,
+    pub total: u64,
+}
+
+pub type OpenProgressCallback = Arc<dyn Fn(OpenProgress) + Send + Sync>;
+
+/// Options which can be changed by `Agate::set_option` while the database
+/// is running. `value_threshold` is not one of them yet: without value log,
+/// every value is kept in LSM tree whatever the threshold is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeOption {
+    /// See `AgateOptions::write_bytes_per_sec` and `write_ops_per_sec`.
+    WriteRateLimit {
+        bytes_per_sec: u64,
+        ops_per_sec: u64,
+    },
+    /// See `AgateOptions::sync_writes`.
+    SyncWrites(bool),
+    /// See `AgateOptions::block_cache_size`. The cache must be enabled on
+    /// open, and blocks beyond the new size are evicted.
+    BlockCacheSize(u64),
+    /// See `AgateOptions::index_cache_size`. The cache must be enabled on
+    /// open, and indexes beyond the new size are evicted.
+    IndexCacheSize(u64),
+    /// See `AgateOptions::num_compactors`. Running compactions are finished
+    /// before compactors are restarted.
+    NumCompactors(usize),
+}
 
 #[derive(Clone)]
 pub struct AgateOptions {
     pub dir: PathBuf,
     pub value_dir: PathBuf,
-    // TODO: docs
+    /// Keep all data in memory, without WAL, manifest or any other file.
+    /// Path passed to `Agate::open` should be empty in this mode.
     pub in_memory: bool,
+    /// Open an existing database without modifying any file. Writes are
+    /// rejected with `Error::ReadOnly`, and several read-only instances can
+    /// open the same directory at the same time.
+    pub read_only: bool,
+    /// Open a data directory of Badger in read-only mode. Only Badger v2
+    /// tables, which have protobuf indexes, are supported. Tables must not
+    /// be compressed or encrypted, and memtables must have been flushed.
+    /// Values stored in value log can't be read yet.
+    pub badger_compat: bool,
+    /// Don't lock the directory, neither by lock file nor against other
+    /// instances in current process. Caller must make sure the directory is
+    /// not written by others.
+    pub bypass_lock_guard: bool,
     pub sync_writes: bool,
+    /// Isolation of transactions. `Snapshot` saves the cost of tracking
+    /// reads and checking conflicts on commit, for workloads where write
+    /// skew is acceptable.
+    pub isolation: IsolationLevel,
+    /// Timestamps of transactions are supplied by caller, like an external
+    /// timestamp oracle, through `Agate::new_transaction_at` and
+    /// `Transaction::commit_at`. Conflicts are not detected, and old
+    /// versions are only discarded below `Agate::set_discard_ts`.
+    pub managed_txns: bool,
 
     // Memtable options
     pub mem_table_size: u64,
@@ -18,15 +117,129 @@ pub struct AgateOptions {
 
     pub value_threshold: usize,
     pub num_memtables: usize,
+    /// Number of threads flushing immutable memtables. Memtables are built
+    /// into L0 tables in parallel, so a slow flush doesn't hold back the
+    /// following ones, but tables are still added to L0 in the order their
+    /// memtables are rotated.
+    pub num_flush_workers: usize,
+    /// Number of threads compacting levels in background. Compactor 0
+    /// always tries L0 first. Zero disables compaction, after which flushes
+    /// are stalled and retried once L0 is full.
+    pub num_compactors: usize,
 
     pub block_size: usize,
     pub bloom_false_positive: f64,
+    /// Bloom filters of new tables. `WholeKey` builds a single filter of a
+    /// table, which is the smallest. `PerBlock` builds a filter of every
+    /// block, which takes more memory in indexes, but point gets skip reading
+    /// blocks which don't have the key even if the table has. Tables record
+    /// their strategies, so it can be changed across restarts.
+    pub bloom_strategy: BloomStrategy,
+    /// Compression of blocks in new tables. Blocks which don't shrink are
+    /// stored uncompressed.
+    pub compression: Compression,
+    /// Compression of tables written to every level, overriding
+    /// `compression` for levels within it, e.g. not compressing L0 and L1
+    /// keeps flushes and compactions of recent data cheap.
+    pub compression_per_level: Vec<Compression>,
+
+    /// Capacity in bytes of the cache of table blocks, zero disables the
+    /// cache. Blocks read by iterators with `ITERATOR_NOCACHE` are not
+    /// inserted.
+    pub block_cache_size: u64,
+    pub block_cache_policy: CachePolicy,
+    /// Capacity in bytes of the cache of table indexes and bloom filters.
+    /// Zero keeps indexes of all opened tables in memory, otherwise only
+    /// metadata of tables is kept, and evicted indexes are read again on
+    /// demand.
+    pub index_cache_size: u64,
+    /// Open tables without reading their indexes, which are read into index
+    /// cache on first access instead, so opening a huge database doesn't
+    /// read every index up front. Key ranges of tables are taken from
+    /// manifest. It only takes effect with an index cache, and tables
+    /// without key ranges in manifest, like those written by Badger, are
+    /// still opened eagerly.
+    pub lazy_load_table_index: bool,
+    /// Capacity in bytes of the cache of the latest values of user keys,
+    /// which serves point lookups before memtables and tables are searched.
+    /// Written keys are invalidated, so it suits read-mostly workloads with
+    /// small hot sets. Zero disables the cache.
+    pub row_cache_size: u64,
+    /// Pin blocks and indexes of L0 tables in block cache and index cache
+    /// once they are read, so that they are never evicted. Every lookup
+    /// checks all L0 tables. Pinned entries still take up cache capacity.
+    pub pin_l0_tables: bool,
 
     pub num_level_zero_tables: usize,
     pub num_level_zero_tables_stall: usize,
+    /// Adjust the thresholds of L0 by recent ingest rate and bytes of L0
+    /// pending compaction, instead of using the fixed numbers above. While
+    /// tables are flushed fast, compaction is triggered earlier, and flushes
+    /// stall later so that bursts don't block writes immediately. The stall
+    /// threshold is at most doubled.
+    pub adaptive_level_zero: bool,
 
     pub value_log_file_size: u64,
     pub value_log_max_entries: u32,
+
+    /// When to verify checksums of tables. With `OnTableOpen`, all tables are
+    /// verified by `num_verify_workers` threads before open returns.
+    pub checksum_verification_mode: ChecksumVerificationMode,
+    /// Algorithm of checksums of new tables. It's recorded with every
+    /// checksum, so tables written with other algorithms are still readable.
+    pub checksum_algorithm: ChecksumAlgorithm,
+    pub num_verify_workers: usize,
+
+    /// Limits of user writes, throttled by a token bucket which allows
+    /// bursts of one second. Zero means unlimited.
+    pub write_bytes_per_sec: u64,
+    pub write_ops_per_sec: u64,
+
+    /// Folds merge operands written by `Transaction::add`. Required if there
+    /// are merge operands in database.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    /// Order of user keys, which is bytewise if it's not set. It must be
+    /// the same every time the database is opened.
+    pub comparator: Option<Arc<dyn UserComparator>>,
+
+    /// Master key of encryption, which must be 16, 24 or 32 bytes to use
+    /// AES-128/192/256. Empty key disables encryption. The key encrypts data
+    /// keys in key registry, which is rotated every
+    /// `encryption_key_rotation_duration`.
+    ///
+    /// Data keys are not used by data files yet: tables, WALs and MANIFEST
+    /// are still written in plaintext even if the key is set.
+    pub encryption_key: Vec<u8>,
+    pub encryption_key_rotation_duration: Duration,
+
+    /// Clock used for TTL and metrics.
+    pub clock: Arc<dyn Clock>,
+    /// Interval of deleting expired entries in tables in background, see
+    /// `Agate::delete_expired`. Zero disables it, and expired entries are
+    /// only skipped on read.
+    pub expiry_scan_interval: Duration,
+    /// File system where WALs, tables and manifest are stored.
+    pub env: Arc<dyn Env>,
+    /// Write tables of flush and other background jobs with direct IO if
+    /// `env` supports it, so that large writes bypass page cache and don't
+    /// evict hot data of reads. Tables are still read through page cache.
+    pub use_direct_io_for_flush_and_compaction: bool,
+    /// Called when `Agate::open` makes progress, so services can tell
+    /// whether a long open is still going.
+    pub open_progress: Option<OpenProgressCallback>,
+    /// Notified of flushes, table changes and write stalls.
+    pub event_listener: Option<Arc<dyn EventListener>>,
+
+    /// Max size of a single write batch, limited by memtable size.
+    /// This is computed from `mem_table_size` in `fix_options`.
+    pub(crate) max_batch_size: u64,
+    /// Max number of entries in a single write batch.
+    pub(crate) max_batch_count: u64,
+    /// Created in `fix_options` with `block_cache_size`.
+    pub(crate) block_cache: Option<Arc<BlockCache>>,
+    /// Created in `fix_options` with `index_cache_size`.
+    pub(crate) index_cache: Option<Arc<IndexCache>>,
 }
 
 impl Default for AgateOptions {
@@ -43,36 +256,557 @@ impl Default for AgateOptions {
             max_levels: 7,
             // agate options
             num_memtables: 20,
+            num_flush_workers: 1,
+            num_compactors: 4,
             in_memory: false,
+            read_only: false,
+            badger_compat: false,
+            bypass_lock_guard: false,
             sync_writes: false,
+            isolation: IsolationLevel::Serializable,
+            managed_txns: false,
             value_threshold: 1 << 10,
             value_log_file_size: 1 << 30 - 1,
             value_log_max_entries: 1000000,
             block_size: 4 << 10,
             bloom_false_positive: 0.01,
+            bloom_strategy: BloomStrategy::WholeKey,
+            compression: Compression::None,
+            compression_per_level: vec![],
+            block_cache_size: 256 << 20,
+            block_cache_policy: CachePolicy::TinyLfu,
+            index_cache_size: 0,
+            lazy_load_table_index: false,
+            row_cache_size: 0,
+            pin_l0_tables: false,
             num_level_zero_tables: 5,
             num_level_zero_tables_stall: 15,
+            adaptive_level_zero: false,
+
+            checksum_verification_mode: ChecksumVerificationMode::NoVerification,
+            checksum_algorithm: ChecksumAlgorithm::Crc32c,
+            num_verify_workers: 4,
+
+            write_bytes_per_sec: 0,
+            write_ops_per_sec: 0,
+
+            merge_operator: None,
+            comparator: None,
+            encryption_key: vec![],
+            encryption_key_rotation_duration: Duration::from_secs(10 * 24 * 60 * 60),
+            clock: Arc::new(SystemClock),
+            expiry_scan_interval: Duration::ZERO,
+            #[cfg(not(target_arch = "wasm32"))]
+            env: Arc::new(StdEnv),
+            #[cfg(target_arch = "wasm32")]
+            env: Arc::new(MemEnv::new()),
+            use_direct_io_for_flush_and_compaction: false,
+            open_progress: None,
+            event_listener: None,
+
+            max_batch_size: 0,
+            max_batch_count: 0,
+            block_cache: None,
+            index_cache: None,
         }
         // TODO: add other options
     }
 }
 
 impl AgateOptions {
+    pub(crate) fn notify(&self, f: impl FnOnce(&dyn EventListener)) {
+        if let Some(listener) = &self.event_listener {
+            f(listener.as_ref());
+        }
+    }
+
+    pub(crate) fn report_open_progress(&self, stage: OpenProgressStage, done: usize, total: usize) {
+        if let Some(callback) = &self.open_progress {
+            callback(OpenProgress {
+                stage,
+                done: done as u64,
+                total: total as u64,
+            });
+        }
+    }
+
+    /// Compression of tables written to `level`.
+    pub(crate) fn compression_of_level(&self, level: usize) -> Compression {
+        self.compression_per_level
+            .get(level)
+            .copied()
+            .unwrap_or(self.compression)
+    }
+
     pub(crate) fn fix_options(&mut self) -> Result<()> {
         if self.in_memory {
-            // TODO: find a way to check if path is set, if set, then panic with ConfigError
+            if !self.dir.as_os_str().is_empty() || !self.value_dir.as_os_str().is_empty() {
+                return Err(Error::Config(format!(
+                    "in-memory mode doesn't use any directory, got dir {:?}, value_dir {:?}",
+                    self.dir, self.value_dir
+                )));
+            }
             self.sync_writes = false;
         }
+        if self.read_only && self.in_memory {
+            return Err(Error::Config(
+                "read-only mode requires an existing database on disk".to_string(),
+            ));
+        }
+        if self.badger_compat && !self.read_only {
+            return Err(Error::Config(
+                "Badger directory can only be opened in read-only mode".to_string(),
+            ));
+        }
+
+        if self.num_memtables < 2 || self.num_memtables > MEMTABLE_VIEW_MAX {
+            return Err(Error::Config(format!(
+                "num_memtables should be within [2, {}], got {}",
+                MEMTABLE_VIEW_MAX, self.num_memtables
+            )));
+        }
+
+        self.max_batch_size = (15 * self.mem_table_size) / 100;
+        self.max_batch_count = self.max_batch_size / MAX_NODE_SIZE as u64;
+        self.validate()?;
+
+        self.block_cache = if self.block_cache_size > 0 {
+            Some(Arc::new(BlockCache::new(
+                self.block_cache_size as usize,
+                self.block_cache_policy,
+            )))
+        } else {
+            None
+        };
+        self.index_cache = if self.index_cache_size > 0 {
+            Some(Arc::new(IndexCache::new(self.index_cache_size as usize)))
+        } else {
+            None
+        };
 
         Ok(())
     }
 
+    /// Check invariants among options.
+    fn validate(&self) -> Result<()> {
+        let check = |ok: bool, msg: String| if ok { Ok(()) } else { Err(Error::Config(msg)) };
+
+        check(
+            (self.value_threshold as u64) < self.mem_table_size,
+            format!(
+                "value_threshold {} should be less than mem_table_size {}",
+                self.value_threshold, self.mem_table_size
+            ),
+        )?;
+        check(
+            self.base_table_size > 0 && self.base_table_size <= self.base_level_size,
+            format!(
+                "base_table_size {} should be within (0, base_level_size {}]",
+                self.base_table_size, self.base_level_size
+            ),
+        )?;
+        check(
+            self.block_size > 0 && self.block_size as u64 <= self.base_table_size,
+            format!(
+                "block_size {} should be within (0, base_table_size {}]",
+                self.block_size, self.base_table_size
+            ),
+        )?;
+        // A batch must fit in the WAL of a rotated memtable, which holds up
+        // to twice `value_log_file_size`.
+        check(
+            self.max_batch_size < self.value_log_file_size,
+            format!(
+                "max_batch_size {} (15% of mem_table_size) should be less than value_log_file_size {}",
+                self.max_batch_size, self.value_log_file_size
+            ),
+        )?;
+        check(
+            self.max_levels > 0,
+            "max_levels should be greater than 0".to_string(),
+        )?;
+        check(
+            self.level_size_multiplier > 1 && self.table_size_multiplier > 0,
+            format!(
+                "level_size_multiplier {} should be greater than 1, table_size_multiplier {} should be greater than 0",
+                self.level_size_multiplier, self.table_size_multiplier
+            ),
+        )?;
+        check(
+            self.num_flush_workers > 0,
+            "num_flush_workers should be greater than 0".to_string(),
+        )?;
+        check(
+            self.num_level_zero_tables_stall > self.num_level_zero_tables,
+            format!(
+                "num_level_zero_tables_stall {} should be greater than num_level_zero_tables {}",
+                self.num_level_zero_tables_stall, self.num_level_zero_tables
+            ),
+        )?;
+        check(
+            self.bloom_false_positive > 0.0 && self.bloom_false_positive < 1.0,
+            format!(
+                "bloom_false_positive {} should be within (0, 1)",
+                self.bloom_false_positive
+            ),
+        )?;
+        for compression in std::iter::once(&self.compression).chain(&self.compression_per_level) {
+            if let Compression::Zstd { level } = *compression {
+                check(
+                    (1..=22).contains(&level),
+                    format!("ZSTD level {} should be within [1, 22]", level),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Options for small datasets, such as tests and embedded usage.
+    pub fn small() -> Self {
+        Self {
+            mem_table_size: 4 << 20,
+            base_table_size: 1 << 20,
+            base_level_size: 4 << 20,
+            num_memtables: 4,
+            num_level_zero_tables: 4,
+            num_level_zero_tables_stall: 8,
+            ..Default::default()
+        }
+    }
+
+    /// Options for write heavy workloads with plenty of memory. Larger
+    /// memtables and L0 absorb bursts before writes are stalled.
+    pub fn high_throughput() -> Self {
+        Self {
+            mem_table_size: 256 << 20,
+            base_table_size: 8 << 20,
+            base_level_size: 64 << 20,
+            num_memtables: 8,
+            num_level_zero_tables: 10,
+            num_level_zero_tables_stall: 30,
+            block_size: 16 << 10,
+            ..Default::default()
+        }
+    }
+
+    /// Options for environments with limited memory, at the cost of more
+    /// frequent flushes.
+    pub fn low_memory() -> Self {
+        Self {
+            mem_table_size: 2 << 20,
+            base_table_size: 1 << 20,
+            base_level_size: 4 << 20,
+            num_memtables: 2,
+            value_threshold: 256,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_value_dir(mut self, value_dir: impl Into<PathBuf>) -> Self {
+        self.value_dir = value_dir.into();
+        self
+    }
+
+    pub fn with_in_memory(mut self, in_memory: bool) -> Self {
+        self.in_memory = in_memory;
+        self
+    }
+
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Open a data directory of Badger, which also sets read-only mode.
+    pub fn with_badger_compat(mut self) -> Self {
+        self.badger_compat = true;
+        self.read_only = true;
+        self
+    }
+
+    pub fn with_bypass_lock_guard(mut self, bypass_lock_guard: bool) -> Self {
+        self.bypass_lock_guard = bypass_lock_guard;
+        self
+    }
+
+    /// Verify checksums of tables with `mode`, and `workers` threads on open.
+    pub fn with_checksum_verification(
+        mut self,
+        mode: ChecksumVerificationMode,
+        workers: usize,
+    ) -> Self {
+        self.checksum_verification_mode = mode;
+        self.num_verify_workers = workers;
+        self
+    }
+
+    /// Use xxhash64 for faster checksums, or CRC32C for compatibility.
+    pub fn with_checksum_algorithm(mut self, algo: ChecksumAlgorithm) -> Self {
+        self.checksum_algorithm = algo;
+        self
+    }
+
+    pub fn with_sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    pub fn with_isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    pub fn with_managed_txns(mut self, managed_txns: bool) -> Self {
+        self.managed_txns = managed_txns;
+        self
+    }
+
+    pub fn with_mem_table_size(mut self, size: u64) -> Self {
+        self.mem_table_size = size;
+        self
+    }
+
+    pub fn with_num_memtables(mut self, num: usize) -> Self {
+        self.num_memtables = num;
+        self
+    }
+
+    pub fn with_num_flush_workers(mut self, num: usize) -> Self {
+        self.num_flush_workers = num;
+        self
+    }
+
+    pub fn with_num_compactors(mut self, num: usize) -> Self {
+        self.num_compactors = num;
+        self
+    }
+
+    /// Set size of tables at base level, and size of base level itself.
+    pub fn with_base_sizes(mut self, table_size: u64, level_size: u64) -> Self {
+        self.base_table_size = table_size;
+        self.base_level_size = level_size;
+        self
+    }
+
+    /// Set growth of table size and level size at every level after base level.
+    pub fn with_size_multipliers(mut self, table: usize, level: usize) -> Self {
+        self.table_size_multiplier = table;
+        self.level_size_multiplier = level;
+        self
+    }
+
+    pub fn with_max_levels(mut self, max_levels: usize) -> Self {
+        self.max_levels = max_levels;
+        self
+    }
+
+    pub fn with_value_threshold(mut self, threshold: usize) -> Self {
+        self.value_threshold = threshold;
+        self
+    }
+
+    pub fn with_block_size(mut self, size: usize) -> Self {
+        self.block_size = size;
+        self
+    }
+
+    /// Cache table blocks with `size` bytes, zero disables the cache.
+    pub fn with_block_cache(mut self, size: u64, policy: CachePolicy) -> Self {
+        self.block_cache_size = size;
+        self.block_cache_policy = policy;
+        self
+    }
+
+    /// Cache table indexes with `size` bytes, zero keeps all indexes in
+    /// memory.
+    pub fn with_index_cache(mut self, size: u64) -> Self {
+        self.index_cache_size = size;
+        self
+    }
+
+    pub fn with_lazy_load_table_index(mut self, enabled: bool) -> Self {
+        self.lazy_load_table_index = enabled;
+        self
+    }
+
+    /// Cache latest values of user keys with `size` bytes, zero disables
+    /// the cache.
+    pub fn with_row_cache(mut self, size: u64) -> Self {
+        self.row_cache_size = size;
+        self
+    }
+
+    pub fn with_bloom_false_positive(mut self, rate: f64) -> Self {
+        self.bloom_false_positive = rate;
+        self
+    }
+
+    pub fn with_bloom_strategy(mut self, strategy: BloomStrategy) -> Self {
+        self.bloom_strategy = strategy;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_compression_per_level(mut self, compression: Vec<Compression>) -> Self {
+        self.compression_per_level = compression;
+        self
+    }
+
+    /// Set number of L0 tables to trigger compaction, and to stall writes.
+    pub fn with_level_zero_tables(mut self, compaction: usize, stall: usize) -> Self {
+        self.num_level_zero_tables = compaction;
+        self.num_level_zero_tables_stall = stall;
+        self
+    }
+
+    pub fn with_adaptive_level_zero(mut self, adaptive: bool) -> Self {
+        self.adaptive_level_zero = adaptive;
+        self
+    }
+
+    pub fn with_value_log_file_size(mut self, size: u64) -> Self {
+        self.value_log_file_size = size;
+        self
+    }
+
+    pub fn with_value_log_max_entries(mut self, num: u32) -> Self {
+        self.value_log_max_entries = num;
+        self
+    }
+
+    /// Set limits of user writes, zero means unlimited.
+    pub fn with_write_rate_limit(mut self, bytes_per_sec: u64, ops_per_sec: u64) -> Self {
+        self.write_bytes_per_sec = bytes_per_sec;
+        self.write_ops_per_sec = ops_per_sec;
+        self
+    }
+
+    pub fn with_merge_operator(mut self, op: Arc<dyn MergeOperator>) -> Self {
+        self.merge_operator = Some(op);
+        self
+    }
+
+    pub fn with_comparator(mut self, comparator: Arc<dyn UserComparator>) -> Self {
+        self.comparator = Some(comparator);
+        self
+    }
+
+    /// Set master key of key registry, see `encryption_key`. Data files are
+    /// not encrypted yet.
+    pub fn with_encryption_key(mut self, key: Vec<u8>, rotation_duration: Duration) -> Self {
+        self.encryption_key = key;
+        self.encryption_key_rotation_duration = rotation_duration;
+        self
+    }
+
+    pub fn with_direct_io_for_flush_and_compaction(mut self, enabled: bool) -> Self {
+        self.use_direct_io_for_flush_and_compaction = enabled;
+        self
+    }
+
+    pub fn with_env(mut self, env: Arc<dyn Env>) -> Self {
+        self.env = env;
+        self
+    }
+
+    pub fn with_open_progress(mut self, callback: OpenProgressCallback) -> Self {
+        self.open_progress = Some(callback);
+        self
+    }
+
+    pub fn with_event_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.event_listener = Some(listener);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_expiry_scan_interval(mut self, interval: Duration) -> Self {
+        self.expiry_scan_interval = interval;
+        self
+    }
+
+    /// Open database at `path` with the options, which are validated first.
+    pub fn open<P: AsRef<Path>>(self, path: P) -> Result<Agate> {
+        Agate::open(self, path)
+    }
+
     fn skip_vlog(&self, entry: &Entry) -> bool {
         entry.value.len() < self.value_threshold
     }
 
-    fn arena_size(&self) -> u64 {
-        // TODO: take other options into account
-        self.mem_table_size as u64
+    pub(crate) fn arena_size(&self) -> u64 {
+        // Keys and values are not allocated in arena, but a full memtable may
+        // still accept two more batches before it's rotated, as a batch can be
+        // appended to WAL while the previous one is still being inserted.
+        self.mem_table_size + 2 * self.max_batch_count * MAX_NODE_SIZE as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn check(mut opts: AgateOptions) -> Result<()> {
+        opts.fix_options()
+    }
+
+    #[test]
+    fn test_validate_options() {
+        for opts in [
+            AgateOptions::default(),
+            AgateOptions::small(),
+            AgateOptions::high_throughput(),
+            AgateOptions::low_memory(),
+        ] {
+            check(opts).unwrap();
+        }
+
+        let invalid = vec![
+            AgateOptions::small().with_value_threshold(4 << 20),
+            AgateOptions::small().with_base_sizes(8 << 20, 4 << 20),
+            AgateOptions::small().with_base_sizes(0, 4 << 20),
+            AgateOptions::small().with_block_size(2 << 20),
+            AgateOptions::small().with_max_levels(0),
+            AgateOptions::small().with_size_multipliers(2, 1),
+            AgateOptions::small().with_level_zero_tables(5, 5),
+            AgateOptions::small().with_bloom_false_positive(1.0),
+            AgateOptions::small().with_num_memtables(1),
+            AgateOptions::small().with_num_flush_workers(0),
+            AgateOptions::small().with_value_log_file_size(600 << 10),
+            AgateOptions::small().with_compression(Compression::Zstd { level: 0 }),
+            AgateOptions::small().with_compression_per_level(vec![Compression::Zstd { level: 23 }]),
+        ];
+        for opts in invalid {
+            assert!(matches!(check(opts), Err(Error::Config(_))));
+        }
+    }
+
+    #[test]
+    fn test_open_with_builder() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = AgateOptions::small()
+            .with_mem_table_size(1 << 16)
+            .with_sync_writes(true)
+            .open(tmp_dir.path())
+            .unwrap();
+        assert_eq!(agate.core.opts.mem_table_size, 1 << 16);
+        assert!(agate.core.opts.sync_writes);
+        drop(agate);
+
+        assert!(matches!(
+            AgateOptions::small()
+                .with_level_zero_tables(5, 1)
+                .open(tmp_dir.path()),
+            Err(Error::Config(_))
+        ));
     }
 }
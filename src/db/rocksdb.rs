@@ -0,0 +1,134 @@
+use super::*;
+use crate::env::OpenMode;
+use crate::table::rocksdb::{RocksEntry, RocksEntryKind, RocksSstReader, RocksSstWriter};
+
+impl Agate {
+    /// Export the latest version of every key visible to new transactions
+    /// to a new RocksDB SST at `path`, with versions as sequence numbers.
+    /// Merge operands are folded, and values are written without user
+    /// metadata or expiration time. Returns the number of exported keys.
+    ///
+    /// Keys have non-zero sequence numbers, so RocksDB only ingests the SST
+    /// as a DB generated file, see `RocksSstWriter`.
+    pub fn export_rocksdb_sst(&self, path: impl AsRef<Path>) -> Result<u64> {
+        let file = self.core.opts.env.open_writable(
+            path.as_ref(),
+            OpenMode::CreateNew,
+            IoPriority::Background,
+        )?;
+        let mut writer = RocksSstWriter::new(file);
+        self.new_stream().run(
+            |item| {
+                Some(RocksEntry {
+                    user_key: item.key().clone(),
+                    sequence: item.version(),
+                    kind: RocksEntryKind::Value,
+                    value: item.value().clone(),
+                })
+            },
+            |batch| {
+                for entry in &batch {
+                    writer.add(entry)?;
+                }
+                Ok(())
+            },
+        )?;
+        let count = writer.num_entries();
+        writer.finish()?.sync_all()?;
+        Ok(count)
+    }
+
+    /// Import all entries of a RocksDB SST at `path` to the last level like
+    /// `BulkLoader`, with sequence numbers as versions. Entries with
+    /// sequence number 0, like those written by `SstFileWriter`, get the
+    /// global sequence number of the SST or 1. After importing, new
+    /// transactions read and commit after the largest imported version.
+    /// Returns the number of imported entries.
+    ///
+    /// See `RocksSstReader` for supported SSTs.
+    pub fn import_rocksdb_sst(&self, path: impl AsRef<Path>) -> Result<u64> {
+        let data = self.core.opts.env.read_file(path.as_ref())?;
+        let reader = RocksSstReader::new(data)?;
+        let default_version = reader.global_seqno().max(1);
+        let mut loader = self.new_bulk_loader();
+        let mut max_version = 0;
+        let mut count = 0;
+        reader.for_each(|entry| {
+            let version = match entry.sequence {
+                0 => default_version,
+                sequence => sequence,
+            };
+            let mut value = Value::new(entry.value);
+            match entry.kind {
+                RocksEntryKind::Value => {}
+                RocksEntryKind::Deletion => value.meta = VALUE_DELETE,
+                RocksEntryKind::Merge => value.meta = VALUE_MERGE_ENTRY,
+            }
+            max_version = max_version.max(version);
+            count += 1;
+            loader.add(key_with_ts(&entry.user_key[..], version), value)
+        })?;
+        loader.finish()?;
+        self.core.orc.advance_to(max_version);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{test_options, write_keys};
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_rocksdb_sst() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let source = Agate::open(test_options(), tmp_dir.path().join("source")).unwrap();
+        write_keys(&source, 0, 50);
+        source.flush_memtable(true).unwrap();
+        write_keys(&source, 50, 100);
+        source.core.orc.advance_to(100);
+        let path = tmp_dir.path().join("export.sst");
+        assert_eq!(source.export_rocksdb_sst(&path).unwrap(), 100);
+        assert!(source.export_rocksdb_sst(&path).is_err());
+
+        let agate = Agate::open(test_options(), tmp_dir.path().join("target")).unwrap();
+        assert_eq!(agate.import_rocksdb_sst(&path).unwrap(), 100);
+        let txn = agate.new_transaction(false);
+        for i in 0..100 {
+            let item = txn.get(&Bytes::from(format!("key{:05}", i))).unwrap();
+            assert_eq!(item.value(), &Bytes::from(format!("value{:05}", i)));
+            assert_eq!(item.version(), i + 1);
+        }
+        assert_eq!(agate.core.orc.read_ts(), 100);
+
+        // Keys written by `SstFileWriter` have sequence number 0.
+        let mut writer = crate::RocksSstWriter::new(vec![]);
+        for (key, kind) in [
+            ("a", crate::RocksEntryKind::Value),
+            ("b", crate::RocksEntryKind::Deletion),
+        ] {
+            writer
+                .add(&crate::RocksEntry {
+                    user_key: Bytes::from(key),
+                    sequence: 0,
+                    kind,
+                    value: Bytes::from(key),
+                })
+                .unwrap();
+        }
+        let data = writer.finish().unwrap();
+        let reader = crate::RocksSstReader::new(Bytes::from(data.clone())).unwrap();
+        assert!(reader
+            .properties()
+            .contains_key("rocksdb.external_sst_file.version"));
+        let path = tmp_dir.path().join("external.sst");
+        fs::write(&path, data).unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path().join("external")).unwrap();
+        assert_eq!(agate.import_rocksdb_sst(&path).unwrap(), 2);
+        let txn = agate.new_transaction(false);
+        assert_eq!(txn.get(&Bytes::from("a")).unwrap().version(), 1);
+        assert!(txn.get(&Bytes::from("b")).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use super::*;
+use crate::format::{with_key_ts, KeyTs};
+use crate::iterator::{Item, IteratorOptions};
+use crate::table::{MergeIterator, TableIterators};
+
+use std::cmp::Ordering as CmpOrdering;
+use std::sync::atomic::AtomicUsize;
+
+/// Picks keys to be streamed by user keys, before their values are read.
+pub type StreamKeyFilter = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Number of outputs of transform delivered to sink at a time by default.
+const DEFAULT_STREAM_BATCH_SIZE: usize = 1000;
+/// Number of batches buffered for each range waiting to be delivered.
+const STREAM_RANGE_BUFFER: usize = 4;
+
+/// Scans the latest version of keys at a read timestamp with multiple
+/// threads. The key space is split into ranges by table boundaries, and
+/// each range is scanned by its own iterator. Results are delivered to sink
+/// in the order of keys, so a stream can feed backups, exports, or another
+/// database which expects sorted input.
+pub struct Stream {
+    core: Arc<Core>,
+    read_ts: u64,
+    /// `read_ts` tracked by oracle as a reader
+    tracked_ts: Option<u64>,
+    since_ts: u64,
+    prefix: Bytes,
+    num_workers: usize,
+    batch_size: usize,
+    deleted: bool,
+    all_versions: bool,
+    key_filter: Option<StreamKeyFilter>,
+}
+
+impl Agate {
+    /// Create a stream of all keys visible to new transactions. Versions
+    /// visible to it are kept by compaction until it's dropped.
+    pub fn new_stream(&self) -> Stream {
+        let read_ts = self.core.orc.begin_read();
+        Stream {
+            read_ts,
+            tracked_ts: Some(read_ts),
+            core: self.core.clone(),
+            since_ts: 0,
+            prefix: Bytes::new(),
+            num_workers: 8,
+            batch_size: DEFAULT_STREAM_BATCH_SIZE,
+            deleted: false,
+            all_versions: false,
+            key_filter: None,
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Some(ts) = self.tracked_ts.take() {
+            self.core.orc.end_read(ts);
+        }
+    }
+}
+
+impl Core {
+    /// Create an iterator over all versions in memtables and tables picked
+    /// by `opts`.
+    pub(crate) fn new_merged_iterator(
+        &self,
+        opts: &IteratorOptions,
+    ) -> Result<Box<TableIterators>> {
+        // Newer data comes first, so that it wins among duplicated keys.
+        let mut iters: Vec<TableIterators> = vec![];
+        {
+            let mts = self.mts.read()?;
+            iters.push(mts.table_mut().new_iterator(opts.reverse).into());
+            for idx in (0..mts.nums_of_memtable() - 1).rev() {
+                iters.push(mts.table_imm(idx).new_iterator(opts.reverse).into());
+            }
+        }
+        self.lvctl.append_iterators(&mut iters, opts);
+        let iters = iters.into_iter().map(Box::new).collect();
+        Ok(MergeIterator::from_iterators(
+            iters,
+            opts.reverse,
+            self.comparator(),
+        ))
+    }
+}
+
+impl Stream {
+    /// Read keys at `read_ts` instead of the latest committed timestamp.
+    /// It's not tracked as a reader, so caller must make sure versions
+    /// visible to it are not discarded, e.g. by `Agate::set_discard_ts`.
+    pub fn with_read_ts(mut self, read_ts: u64) -> Self {
+        if let Some(ts) = self.tracked_ts.take() {
+            self.core.orc.end_read(ts);
+        }
+        self.read_ts = read_ts;
+        self
+    }
+
+    /// Skip keys whose latest versions are older than `since_ts`. Tables
+    /// with only older versions are not read at all.
+    pub fn with_since_ts(mut self, since_ts: u64) -> Self {
+        self.since_ts = since_ts;
+        self
+    }
+
+    /// Only stream keys starting with `prefix`.
+    pub fn with_prefix(mut self, prefix: Bytes) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Scan ranges with `num_workers` threads.
+    pub fn with_num_workers(mut self, num_workers: usize) -> Self {
+        self.num_workers = num_workers.max(1);
+        self
+    }
+
+    /// Deliver at most `batch_size` outputs to sink at a time.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Also stream keys which are deleted or expired at their latest
+    /// versions, as items with `Item::is_deleted`. It's useful to carry
+    /// deletions in incremental streams.
+    pub fn with_deleted(mut self, deleted: bool) -> Self {
+        self.deleted = deleted;
+        self
+    }
+
+    /// Stream all versions of keys not older than `since_ts` instead of the
+    /// latest one, from newer to older. Versions older than a deletion are
+    /// not streamed, as they are invisible at any timestamp after it.
+    pub fn with_all_versions(mut self, all_versions: bool) -> Self {
+        self.all_versions = all_versions;
+        self
+    }
+
+    /// Only stream keys accepted by `filter`.
+    pub fn with_key_filter(mut self, filter: StreamKeyFilter) -> Self {
+        self.key_filter = Some(filter);
+        self
+    }
+
+    /// Timestamp keys are read at.
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    /// Split keys with the prefix into ranges. Every range is a start key,
+    /// and ends where the next one starts.
+    pub(crate) fn ranges(&self) -> Vec<Bytes> {
+        let c = self.core.comparator();
+        let mut ranges = vec![self.prefix.clone()];
+        for split in self.core.lvctl.key_splits() {
+            if c.compare_user_key(&split, &self.prefix) == CmpOrdering::Greater
+                && split.starts_with(&self.prefix)
+            {
+                ranges.push(split);
+            }
+        }
+        ranges
+    }
+
+    /// Stream the latest version, or all versions, of every key picked by
+    /// key filter. Items are converted by `transform` in worker threads,
+    /// where `None` drops the item, and outputs are delivered to `sink` in
+    /// batches in the order of keys. Streaming stops at the first error of iterators or `sink`.
+    pub fn run<T: Send>(
+        &self,
+        transform: impl Fn(Item) -> Option<T> + Sync,
+        mut sink: impl FnMut(Vec<T>) -> Result<()>,
+    ) -> Result<()> {
+        if self.core.is_closed() {
+            return Err(Error::DBClosed);
+        }
+        let ranges = self.ranges();
+        let next = AtomicUsize::new(0);
+        let (senders, receivers): (Vec<_>, Vec<_>) = ranges
+            .iter()
+            .map(|_| {
+                let (tx, rx) = crossbeam_channel::bounded::<Result<Vec<T>>>(STREAM_RANGE_BUFFER);
+                (Some(tx), rx)
+            })
+            .unzip();
+        // A sender is dropped once its range is scanned, so that sink can
+        // move on to the next range.
+        let senders = Mutex::new(senders);
+
+        thread::scope(|s| {
+            for _ in 0..self.num_workers.min(ranges.len()) {
+                s.spawn(|| loop {
+                    // Ranges are taken in order, so the first unfinished range
+                    // always has a worker, and sink never waits forever.
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    if idx >= ranges.len() {
+                        break;
+                    }
+                    let tx = senders.lock().unwrap()[idx].take().unwrap();
+                    let res =
+                        self.scan_range(&ranges[idx], ranges.get(idx + 1), &transform, |batch| {
+                            tx.send(Ok(batch)).is_ok()
+                        });
+                    match res {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(err) => {
+                            let _ = tx.send(Err(err));
+                            break;
+                        }
+                    }
+                });
+            }
+
+            for rx in receivers {
+                for batch in rx {
+                    sink(batch?)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Scan keys in `[start, end)` and send outputs of `transform` in
+    /// batches by `send`, which returns false if sink is gone. Returns
+    /// false if streaming should stop.
+    fn scan_range<T>(
+        &self,
+        start: &Bytes,
+        end: Option<&Bytes>,
+        transform: &impl Fn(Item) -> Option<T>,
+        mut send: impl FnMut(Vec<T>) -> bool,
+    ) -> Result<bool> {
+        let opts = IteratorOptions {
+            since_ts: self.since_ts,
+            all_versions: true,
+            // Streams read each block once.
+            no_cache: true,
+            ..Default::default()
+        };
+        let mut iter = self.core.new_merged_iterator(&opts)?;
+        let c = self.core.comparator();
+        let now = self.core.clock().unix_time();
+        let discard_ts = self.core.orc.discard_ts();
+        let mut batch = Vec::with_capacity(self.batch_size);
+        with_key_ts(KeyTs::new(start, u64::MAX), |key| iter.seek(key));
+        while iter.valid() {
+            let key = user_key(iter.key());
+            let past_end = end.is_some_and(|end| c.compare_user_key(key, end) != CmpOrdering::Less);
+            if !key.starts_with(&self.prefix) || past_end {
+                break;
+            }
+            let version = get_ts(iter.key());
+            if version > self.read_ts {
+                iter.next();
+                continue;
+            }
+
+            let key = Bytes::copy_from_slice(key);
+            let picked = self.key_filter.as_ref().is_none_or(|filter| filter(&key));
+            while picked && iter.valid() && user_key(iter.key()) == &key[..] {
+                let version = get_ts(iter.key());
+                if version < self.since_ts {
+                    break;
+                }
+                let mut value = iter.value();
+                value.version = version;
+                let mut value = self.core.fold_merge(&key, value, version.checked_sub(1))?;
+                let deleted = value.meta & VALUE_DELETE != 0 || value.is_expired(now);
+                if deleted {
+                    value = Value::new_with_meta(Bytes::new(), VALUE_DELETE, 0);
+                    value.version = version;
+                }
+                if self.deleted || !deleted {
+                    if let Some(output) = transform(Item::new(key.clone(), value)) {
+                        batch.push(output);
+                    }
+                }
+                if batch.len() >= self.batch_size && !send(std::mem::take(&mut batch)) {
+                    return Ok(false);
+                }
+                // Versions older than a deletion, or than the latest one
+                // not newer than discard timestamp, are never read.
+                if !self.all_versions || deleted || version <= discard_ts {
+                    break;
+                }
+                iter.next();
+            }
+
+            while iter.valid() && user_key(iter.key()) == &key[..] {
+                iter.next();
+            }
+        }
+        Ok(batch.is_empty() || send(batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{test_options, write_keys};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_stream() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        // Keys are spread over several tables and memtable.
+        for (start, end) in [(0, 30), (30, 60), (60, 90)] {
+            write_keys(&agate, start, end);
+            agate.flush_memtable(true).unwrap();
+        }
+        write_keys(&agate, 90, 100);
+        agate.core.orc.advance_to(100);
+        let key = |i: u64| Bytes::from(format!("key{:05}", i));
+
+        let stream = agate.new_stream().with_num_workers(4).with_batch_size(7);
+        assert!(stream.ranges().len() >= 3);
+        let mut keys = vec![];
+        stream
+            .run(
+                |item| Some(item.key().clone()),
+                |batch| {
+                    assert!(batch.len() <= 7);
+                    keys.extend(batch);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(keys, (0..100).map(key).collect::<Vec<_>>());
+
+        // Keys can be filtered by prefix, key filter and transform.
+        let mut keys = vec![];
+        agate
+            .new_stream()
+            .with_prefix(Bytes::from("key0004"))
+            .with_key_filter(Arc::new(|key: &[u8]| key != b"key00042"))
+            .run(
+                |item| Some(item.key().clone()).filter(|_| item.version() != 44),
+                |batch| {
+                    keys.extend(batch);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            keys,
+            vec![40, 41, 44, 45, 46, 47, 48, 49]
+                .into_iter()
+                .map(key)
+                .collect::<Vec<_>>()
+        );
+
+        // Stream stops at the first error of sink.
+        let mut batches = 0;
+        let res = agate.new_stream().with_batch_size(10).run(Some, |_| {
+            batches += 1;
+            Err(Error::CustomError("sink".to_string()))
+        });
+        assert!(matches!(res, Err(Error::CustomError(_))));
+        assert_eq!(batches, 1);
+    }
+}
@@ -0,0 +1,194 @@
+use super::load::kv_to_value;
+use super::*;
+
+use proto::meta::KvList;
+use std::collections::{HashMap, HashSet};
+
+/// Writes sorted key-value lists, e.g. outputs of `Stream` or a backup, into
+/// an empty database. Data is built into SSTs at the last level directly,
+/// bypassing WAL, memtables and transactions, which makes restoring much
+/// faster than writing keys one by one.
+///
+/// Entries are grouped into streams by `Kv::stream_id`. Keys of a stream
+/// must be sorted, and different streams must cover disjoint ranges of keys,
+/// but lists of different streams can be interleaved. All tables are
+/// recorded to manifest in one change set by `flush`, and nothing is
+/// visible before that. Tables are deleted if the writer is dropped without
+/// flushing.
+pub struct StreamWriter {
+    core: Arc<Core>,
+    loaders: HashMap<u32, BulkLoader>,
+    done: HashSet<u32>,
+    tables: Vec<Table>,
+    max_version: u64,
+}
+
+impl Agate {
+    /// Create a writer for restoring data by streams. Fails if the database
+    /// is read-only or already holds data.
+    pub fn new_stream_writer(&self) -> Result<StreamWriter> {
+        if self.core.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.core.max_version()? != 0 {
+            return Err(Error::CustomError(
+                "stream writer requires an empty database".to_string(),
+            ));
+        }
+        Ok(StreamWriter {
+            core: self.core.clone(),
+            loaders: HashMap::new(),
+            done: HashSet::new(),
+            tables: vec![],
+            max_version: 0,
+        })
+    }
+}
+
+impl StreamWriter {
+    /// Write entries of `list`. An entry with `stream_done` set finishes its
+    /// stream, after which the stream can't be written anymore.
+    pub fn write(&mut self, list: KvList) -> Result<()> {
+        for kv in list.kv {
+            let stream_id = kv.stream_id;
+            if self.done.contains(&stream_id) {
+                return Err(Error::CustomError(format!(
+                    "stream {} is written after it's done",
+                    stream_id
+                )));
+            }
+            if kv.stream_done {
+                self.done.insert(stream_id);
+                if let Some(loader) = self.loaders.remove(&stream_id) {
+                    self.tables.extend(loader.build()?);
+                }
+                continue;
+            }
+            if kv.key.is_empty() {
+                continue;
+            }
+
+            self.max_version = self.max_version.max(kv.version);
+            let key = key_with_ts(&kv.key[..], kv.version);
+            let core = &self.core;
+            let loader = self
+                .loaders
+                .entry(stream_id)
+                .or_insert_with(|| BulkLoader::new(core.clone()));
+            loader.add(key, kv_to_value(kv))?;
+        }
+        Ok(())
+    }
+
+    /// Build the remaining data and install all tables at the last level.
+    /// After that, new transactions read and commit after the largest
+    /// written version. Fails if streams overlap with each other.
+    pub fn flush(mut self) -> Result<()> {
+        let mut tables = std::mem::take(&mut self.tables);
+        for (_, loader) in self.loaders.drain() {
+            tables.extend(loader.build()?);
+        }
+        self.core.add_bottom_tables(tables)?;
+        self.core.orc.advance_to(self.max_version);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{test_options, write_keys};
+    use proto::meta::Kv;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_stream_writer() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let source = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        write_keys(&source, 0, 100);
+        source.flush_memtable(true).unwrap();
+        source.core.orc.advance_to(100);
+
+        // Two streams of disjoint ranges, whose lists are interleaved.
+        let mut lists: Vec<Vec<Kv>> = vec![vec![], vec![]];
+        source
+            .new_stream()
+            .with_batch_size(10)
+            .run(
+                |item| {
+                    let stream_id = (item.key() >= &Bytes::from("key00050")) as u32;
+                    Some(Kv {
+                        key: item.key().to_vec(),
+                        value: item.value().to_vec(),
+                        version: item.version(),
+                        stream_id,
+                        ..Default::default()
+                    })
+                },
+                |batch| {
+                    for kv in batch {
+                        lists[kv.stream_id as usize].push(kv);
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let done = |stream_id| Kv {
+            stream_id,
+            stream_done: true,
+            ..Default::default()
+        };
+        lists[0].push(done(0));
+
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let mut writer = agate.new_stream_writer().unwrap();
+        let (first, second) = (lists[0].clone(), lists[1].clone());
+        for i in 0..first.len().max(second.len()) {
+            for list in [&first, &second] {
+                if let Some(kv) = list.get(i) {
+                    writer
+                        .write(KvList {
+                            kv: vec![kv.clone()],
+                        })
+                        .unwrap();
+                }
+            }
+        }
+        assert!(writer.write(KvList { kv: vec![done(0)] }).is_err());
+        // Nothing is visible before flush.
+        assert_eq!(agate.max_version().unwrap(), 0);
+        writer.flush().unwrap();
+
+        let check = |agate: &Agate| {
+            assert_eq!(agate.estimate_size(b"", b"z").keys, 100);
+            let txn = agate.new_transaction(false);
+            for i in (0..100).step_by(7) {
+                let item = txn.get(&Bytes::from(format!("key{:05}", i))).unwrap();
+                assert_eq!(item.value(), &Bytes::from(format!("value{:05}", i)));
+                assert_eq!(item.version(), i + 1);
+            }
+        };
+        check(&agate);
+        assert!(agate.new_stream_writer().is_err());
+        let agate = crate::test_util::reopen(agate).unwrap();
+        check(&agate);
+
+        // Overlapping streams are rejected without installing any table.
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let mut writer = agate.new_stream_writer().unwrap();
+        for (stream_id, kv) in first.into_iter().enumerate() {
+            writer
+                .write(KvList {
+                    kv: vec![Kv {
+                        stream_id: stream_id as u32 % 2,
+                        ..kv
+                    }],
+                })
+                .unwrap();
+        }
+        assert!(writer.flush().is_err());
+        assert_eq!(agate.max_version().unwrap(), 0);
+    }
+}
@@ -0,0 +1,238 @@
+use super::*;
+
+use crate::closer::Closer;
+
+use proto::meta::{Kv, KvList};
+use std::sync::atomic::AtomicU64;
+
+/// Number of change lists buffered for a subscriber. Once a subscriber falls
+/// behind by this many lists, writes wait for it to catch up.
+const SUBSCRIBER_BUFFER: usize = 64;
+
+struct Subscriber {
+    id: u64,
+    prefixes: Vec<Bytes>,
+    sender: Sender<KvList>,
+}
+
+impl Subscriber {
+    fn matches(&self, key: &[u8]) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p))
+    }
+}
+
+/// Subscribers of committed changes, which are published by insert thread
+/// in the order of writes.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    next_id: AtomicU64,
+    list: Mutex<Vec<Subscriber>>,
+}
+
+impl Subscribers {
+    /// Pick changes in `entries` for every subscriber. It should be called
+    /// before entries are inserted into memtable, and the result should be
+    /// passed to `deliver` after the insertion.
+    pub(crate) fn collect(&self, entries: &[Entry]) -> Vec<(u64, KvList)> {
+        let list = self.list.lock().unwrap();
+        if list.is_empty() {
+            return vec![];
+        }
+        let mut changes = vec![];
+        for subscriber in list.iter() {
+            let kv: Vec<Kv> = entries
+                .iter()
+                .filter(|e| e.meta & VALUE_FIN_TXN == 0 && subscriber.matches(user_key(&e.key)))
+                .map(|e| Kv {
+                    key: user_key(&e.key).to_vec(),
+                    value: e.value.to_vec(),
+                    user_meta: vec![e.user_meta],
+                    version: get_ts(&e.key),
+                    expires_at: e.expires_at,
+                    meta: vec![e.meta & (VALUE_DELETE | VALUE_MERGE_ENTRY)],
+                    ..Default::default()
+                })
+                .collect();
+            if !kv.is_empty() {
+                changes.push((subscriber.id, KvList { kv }));
+            }
+        }
+        changes
+    }
+
+    /// Send changes to subscribers, waiting for those whose buffers are
+    /// full. Subscribers which are gone are removed.
+    pub(crate) fn deliver(&self, changes: Vec<(u64, KvList)>) {
+        if changes.is_empty() {
+            return;
+        }
+        // Senders are cloned out, so that a slow subscriber doesn't block
+        // others from subscribing or unsubscribing.
+        let senders: Vec<_> = {
+            let list = self.list.lock().unwrap();
+            changes
+                .into_iter()
+                .filter_map(|(id, changes)| {
+                    let subscriber = list.iter().find(|s| s.id == id)?;
+                    Some((id, subscriber.sender.clone(), changes))
+                })
+                .collect()
+        };
+        for (id, sender, changes) in senders {
+            if sender.send(changes).is_err() {
+                self.remove(id);
+            }
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        self.list.lock().unwrap().retain(|s| s.id != id);
+    }
+
+    /// Drop all subscribers, so their threads exit once buffered changes
+    /// are delivered.
+    pub(crate) fn clear(&self) {
+        self.list.lock().unwrap().clear();
+    }
+}
+
+/// Handle of a subscription created by `Agate::subscribe`. Dropping it stops
+/// delivering changes and waits for the callback to return.
+pub struct Subscription {
+    core: Arc<Core>,
+    id: u64,
+    tasks: Closer,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Close the signal first, so that the thread exits and unblocks
+        // writers waiting for room in its buffer.
+        self.tasks.signal().close();
+        self.core.subscribers.remove(self.id);
+        let _ = self.tasks.close();
+    }
+}
+
+impl Agate {
+    /// Call `callback` with changes committed after this call whose keys
+    /// start with any of `prefixes`, or all changes if `prefixes` is empty.
+    /// Changes are delivered in the order of commits from a dedicated
+    /// thread, as lists of entries in the format of `Agate::backup`, where
+    /// deletions have `VALUE_DELETE` in meta.
+    ///
+    /// Changes are queued before commits return. If the callback falls
+    /// behind, commits wait for it, so it must not write to the database
+    /// itself. Delivering stops when the callback returns an error, the
+    /// subscription is dropped, or the database is closed.
+    pub fn subscribe(
+        &self,
+        prefixes: Vec<Bytes>,
+        mut callback: impl FnMut(KvList) -> Result<()> + Send + 'static,
+    ) -> Result<Subscription> {
+        let core = &self.core;
+        if core.is_closed() {
+            return Err(Error::DBClosed);
+        }
+        let (tx, rx) = crossbeam_channel::bounded::<KvList>(SUBSCRIBER_BUFFER);
+        let mut tasks = Closer::default();
+        tasks.spawn("subscriber", move |signal| {
+            for changes in rx {
+                if signal.is_closed() {
+                    break;
+                }
+                if let Err(err) = callback(changes) {
+                    warn!("subscriber stopped: {}", err);
+                    break;
+                }
+            }
+        })?;
+
+        let id = core.subscribers.next_id.fetch_add(1, Ordering::Relaxed);
+        core.subscribers.list.lock().unwrap().push(Subscriber {
+            id,
+            prefixes,
+            sender: tx,
+        });
+        Ok(Subscription {
+            core: core.clone(),
+            id,
+            tasks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_options;
+    use std::thread;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_subscribe() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(test_options(), tmp_dir.path()).unwrap();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let subscription = agate
+            .subscribe(vec![Bytes::from("a"), Bytes::from("c")], move |list| {
+                for kv in list.kv {
+                    tx.send((Bytes::from(kv.key), kv.version, kv.meta[0]))
+                        .unwrap();
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        for (i, key) in ["a1", "b1", "c1", "a2"].iter().enumerate() {
+            let mut txn = agate.new_transaction(true);
+            if i == 3 {
+                txn.delete(Bytes::from("a1")).unwrap();
+            }
+            txn.set(Bytes::from(*key), Bytes::from("v")).unwrap();
+            txn.commit().unwrap();
+        }
+        let mut received: Vec<_> = (0..4).map(|_| rx.recv().unwrap()).collect();
+        // Entries of a transaction are not ordered.
+        received.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        assert_eq!(
+            received,
+            vec![
+                (Bytes::from("a1"), 1, 0),
+                (Bytes::from("c1"), 3, 0),
+                (Bytes::from("a1"), 4, VALUE_DELETE),
+                (Bytes::from("a2"), 4, 0),
+            ]
+        );
+
+        // A blocked subscriber holds off writes, until it's dropped.
+        let (block_tx, block_rx) = crossbeam_channel::bounded::<()>(0);
+        let blocked = agate
+            .subscribe(vec![], move |_| {
+                let _ = block_rx.recv();
+                Ok(())
+            })
+            .unwrap();
+        drop(subscription);
+        let agate = Arc::new(agate);
+        let writer = {
+            let agate = agate.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let mut txn = agate.new_transaction(true);
+                    txn.set(Bytes::from(format!("key{}", i)), Bytes::new())
+                        .unwrap();
+                    txn.commit().unwrap();
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!writer.is_finished());
+        // Others can still subscribe and unsubscribe meanwhile.
+        drop(agate.subscribe(vec![], |_| Ok(())).unwrap());
+        drop(block_tx);
+        drop(blocked);
+        writer.join().unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Traits of key-value storage engines, so that frameworks which are generic
+//! over engines, like raftstore-like layers, can run on top of agatedb.
+//!
+//! Keys and values are plain bytes without timestamps. Writes are applied
+//! in write batches atomically, and reads are served from the latest
+//! committed data or a snapshot.
+
+use crate::iterator::Item;
+use crate::ops::snapshot::Snapshot;
+use crate::{Agate, AgateOptions, Error, Result, Transaction};
+
+use bytes::Bytes;
+use std::path::Path;
+
+/// Point reads.
+pub trait Peekable {
+    /// Get the value of `key`, or `None` if it doesn't exist.
+    fn get_value(&self, key: &[u8]) -> Result<Option<Bytes>>;
+}
+
+/// Range scans.
+pub trait Iterable {
+    /// Call `f` with every key and its value within `[start, end)` in the
+    /// order of keys, until it returns false. An empty `end` means no upper
+    /// bound.
+    fn scan<F>(&self, start: &[u8], end: &[u8], f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>;
+}
+
+/// Writes buffered in a write batch.
+pub trait Mutable {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn delete(&mut self, key: &[u8]) -> Result<()>;
+}
+
+/// Writes applied together by `KvEngine::write`.
+pub trait WriteBatch: Mutable + Send {
+    /// Number of keys written by the batch.
+    fn count(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+}
+
+/// A key-value storage engine.
+pub trait KvEngine: Peekable + Iterable + Send + Sync + Sized + 'static {
+    type Options;
+    type Snapshot: Peekable + Iterable + Send + Sync;
+    type WriteBatch: WriteBatch;
+
+    fn open(opts: Self::Options, path: &Path) -> Result<Self>;
+
+    /// Get a consistent view of the engine, which is not affected by later
+    /// writes.
+    fn snapshot(&self) -> Self::Snapshot;
+
+    fn write_batch(&self) -> Self::WriteBatch;
+
+    /// Apply all writes of `batch` atomically.
+    fn write(&self, batch: Self::WriteBatch) -> Result<()>;
+
+    /// Persist all writes applied so far.
+    fn sync(&self) -> Result<()>;
+}
+
+fn item_value(res: Result<Item>) -> Result<Option<Bytes>> {
+    match res {
+        Ok(item) => Ok(Some(item.value().clone())),
+        Err(Error::KeyNotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl Peekable for Snapshot {
+    fn get_value(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        item_value(self.get(&Bytes::copy_from_slice(key)))
+    }
+}
+
+impl Iterable for Snapshot {
+    fn scan<F>(&self, start: &[u8], end: &[u8], mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        Snapshot::scan(self, start, end, |item| f(item.key(), item.value()))
+    }
+}
+
+impl Peekable for Agate {
+    fn get_value(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        item_value(
+            self.new_transaction(false)
+                .get(&Bytes::copy_from_slice(key)),
+        )
+    }
+}
+
+impl Iterable for Agate {
+    fn scan<F>(&self, start: &[u8], end: &[u8], f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        Iterable::scan(&Agate::snapshot(self), start, end, f)
+    }
+}
+
+/// A write batch is an update transaction. Later writes to the same key in
+/// a batch replace earlier ones.
+impl Mutable for Transaction {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.set(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        Transaction::delete(self, Bytes::copy_from_slice(key))
+    }
+}
+
+impl WriteBatch for Transaction {
+    fn count(&self) -> usize {
+        self.pending_entries().count()
+    }
+}
+
+impl KvEngine for Agate {
+    type Options = AgateOptions;
+    type Snapshot = Snapshot;
+    type WriteBatch = Transaction;
+
+    fn open(opts: AgateOptions, path: &Path) -> Result<Self> {
+        Agate::open(opts, path)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Agate::snapshot(self)
+    }
+
+    fn write_batch(&self) -> Transaction {
+        self.new_transaction(true)
+    }
+
+    fn write(&self, batch: Transaction) -> Result<()> {
+        batch.commit()
+    }
+
+    fn sync(&self) -> Result<()> {
+        Agate::sync(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn check_engine<E: KvEngine>(opts: E::Options, path: &Path) {
+        let engine = E::open(opts, path).unwrap();
+        let mut batch = engine.write_batch();
+        assert!(batch.is_empty());
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            batch.put(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        batch.delete(b"key3").unwrap();
+        assert_eq!(batch.count(), 10);
+        engine.write(batch).unwrap();
+
+        let snapshot = engine.snapshot();
+        let mut batch = engine.write_batch();
+        batch.put(b"key1", b"new").unwrap();
+        batch.delete(b"key2").unwrap();
+        engine.write(batch).unwrap();
+        engine.sync().unwrap();
+
+        assert_eq!(engine.get_value(b"key1").unwrap().unwrap(), "new");
+        assert_eq!(engine.get_value(b"key2").unwrap(), None);
+        assert_eq!(snapshot.get_value(b"key1").unwrap().unwrap(), "key1");
+        assert_eq!(snapshot.get_value(b"key2").unwrap().unwrap(), "key2");
+        assert_eq!(snapshot.get_value(b"key3").unwrap(), None);
+
+        let mut keys = vec![];
+        snapshot
+            .scan(b"key1", b"key6", |key, value| {
+                assert_eq!(key, value);
+                keys.push(key.to_vec());
+                Ok(true)
+            })
+            .unwrap();
+        assert_eq!(keys, vec![b"key1", b"key2", b"key4", b"key5"]);
+
+        let mut pairs = vec![];
+        engine
+            .scan(b"key0", b"", |key, value| {
+                pairs.push((key.to_vec(), value.to_vec()));
+                Ok(pairs.len() < 3)
+            })
+            .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"key0".to_vec(), b"key0".to_vec()),
+                (b"key1".to_vec(), b"new".to_vec()),
+                (b"key4".to_vec(), b"key4".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_agate_engine() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        check_engine::<Agate>(AgateOptions::default(), tmp_dir.path());
+    }
+}
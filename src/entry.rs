@@ -1,21 +1,28 @@
 use bytes::Bytes;
+use std::time::Duration;
 
 const DELETE: u8 = 1 << 0;
 const VALUE_POINTER: u8 = 1 << 1;
+const MERGE_ENTRY: u8 = 1 << 3;
 
 pub struct Entry {
     pub key: Bytes,
     pub value: Bytes,
     pub(crate) meta: u8,
+    pub(crate) meta2: u64,
     pub user_meta: u8,
     pub expires_at: u64,
     pub(crate) version: u64,
+    /// Set by `with_ttl`, `expires_at` is calculated from it when the entry
+    /// is written.
+    pub(crate) ttl: Option<Duration>,
 }
 
 pub struct EntryRef<'a> {
     pub key: &'a [u8],
     pub value: &'a [u8],
     pub(crate) meta: u8,
+    pub(crate) meta2: u64,
     pub user_meta: u8,
     pub expires_at: u64,
     pub(crate) version: u64,
@@ -33,16 +40,46 @@ impl Entry {
             key,
             value,
             meta: 0,
+            meta2: 0,
             user_meta: 0,
             expires_at: 0,
             version: 0,
+            ttl: None,
         }
     }
 
+    /// Set user metadata of the entry, which is returned as is on read.
+    pub fn with_meta(mut self, user_meta: u8) -> Entry {
+        self.user_meta = user_meta;
+        self
+    }
+
+    /// Set the entry to expire `ttl` after it's written, according to the
+    /// clock of database. Expired entries are treated as missing on read.
+    /// The precision is in seconds.
+    pub fn with_ttl(mut self, ttl: Duration) -> Entry {
+        self.ttl = Some(ttl);
+        self
+    }
+
     pub fn mark_delete(&mut self) {
         self.meta |= DELETE;
     }
 
+    pub fn is_deleted(&self) -> bool {
+        self.meta & DELETE != 0
+    }
+
+    pub fn is_merge(&self) -> bool {
+        self.meta & MERGE_ENTRY != 0
+    }
+
+    /// Mark the entry as a merge operand, which is folded with older versions
+    /// by `AgateOptions::merge_operator` on read.
+    pub fn mark_merge(&mut self) {
+        self.meta |= MERGE_ENTRY;
+    }
+
     // TODO: entry encoding will be done later, as current WAL encodes header and key / value separately
     /*
     pub fn encoded_len(&self) -> usize {
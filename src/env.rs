@@ -0,0 +1,542 @@
+mod mem;
+mod priority;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub use mem::MemEnv;
+pub use priority::RateLimitedEnv;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use uring::UringEnv;
+
+use crate::Result;
+
+use bytes::Bytes;
+#[cfg(not(target_arch = "wasm32"))]
+use memmap::{Mmap, MmapMut, MmapOptions};
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+/// How `Env::open_writable` opens a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// open an existing file
+    Existing,
+    /// create a new file, which must not exist before
+    CreateNew,
+    /// create the file if not exists, and truncate it to empty
+    Truncate,
+}
+
+/// Class of IO issued through opened files. Foreground IO serves reads and
+/// commits of users, while background IO comes from flush, compaction and
+/// bulk loading, which can be throttled or deprioritized (e.g. by ionice)
+/// to keep foreground latency low.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    Foreground,
+    Background,
+}
+
+/// File system used by WALs, tables and manifest. The default is `StdEnv`,
+/// which uses std file system and mmap, or `MemEnv` on wasm32. It can be
+/// replaced to inject faults in tests, or to store data somewhere else.
+///
+/// WALs are always written in foreground, so only readable and writable
+/// files are tagged with `IoPriority`.
+pub trait Env: Send + Sync + fmt::Debug {
+    /// Open an existing file for random reads.
+    fn open_readable(&self, path: &Path, priority: IoPriority) -> Result<Box<dyn ReadableFile>>;
+
+    /// Open a file for reading and writing.
+    fn open_writable(
+        &self,
+        path: &Path,
+        mode: OpenMode,
+        priority: IoPriority,
+    ) -> Result<Box<dyn WritableFile>>;
+
+    /// Map a file into memory for reading and writing. If the file doesn't
+    /// exist, it's created with `len` zero bytes and synced together with
+    /// its directory. Returns whether the file is created.
+    fn open_mapped(&self, path: &Path, len: u64) -> Result<(Box<dyn MappedFile>, bool)>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Get names of all files in `dir`.
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>>;
+
+    fn create_dir_all(&self, dir: &Path) -> Result<()>;
+
+    /// Rename `from` to `to`, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Create `to` as a hard link to file `from`.
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()>;
+
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    /// Persist file creations, renames and removals in `dir`.
+    fn sync_dir(&self, dir: &Path) -> Result<()>;
+
+    /// Create a new file at `path` with `data`. If `direct_io` is true and
+    /// it's supported, data bypasses page cache, so that large background
+    /// writes don't evict hot data from it.
+    fn create_file(
+        &self,
+        path: &Path,
+        data: &[u8],
+        priority: IoPriority,
+        direct_io: bool,
+    ) -> Result<()> {
+        let _ = direct_io;
+        let mut file = self.open_writable(path, OpenMode::CreateNew, priority)?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    /// Read the whole file at `path`.
+    fn read_file(&self, path: &Path) -> Result<Bytes> {
+        let file = self.open_readable(path, IoPriority::Foreground)?;
+        file.read_at(0, file.size() as usize)
+    }
+
+    /// Whether files are stored in local file system. Database directories
+    /// are only locked by `LOCK` files in local file system.
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// A file opened by `Env::open_readable`.
+pub trait ReadableFile: Send + Sync {
+    fn size(&self) -> u64;
+
+    /// Read `len` bytes starting at `offset`.
+    fn read_at(&self, offset: usize, len: usize) -> Result<Bytes>;
+
+    /// Read every `(offset, len)` of `reads`, which can be issued together
+    /// by files supporting async IO like `UringEnv`.
+    fn read_batch(&self, reads: &[(usize, usize)]) -> Result<Vec<Bytes>> {
+        reads
+            .iter()
+            .map(|&(offset, len)| self.read_at(offset, len))
+            .collect()
+    }
+}
+
+/// A file opened by `Env::open_writable`.
+pub trait WritableFile: Read + Write + Seek + Send {
+    fn set_len(&mut self, len: u64) -> Result<()>;
+
+    fn sync_all(&mut self) -> Result<()>;
+}
+
+/// A file mapped into memory by `Env::open_mapped`. Length of the mapping
+/// doesn't change even if the file is resized by `set_len`.
+///
+/// Platforms may not allow shrinking a file while it's mapped, like
+/// Windows, in which case the file is truncated once it's unmapped, and
+/// `file_len` returns the length it's going to be.
+pub trait MappedFile: Deref<Target = [u8]> + DerefMut + Send {
+    /// Persist modifications of the mapping.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Get length of the underlying file.
+    fn file_len(&self) -> Result<u64>;
+
+    fn set_len(&mut self, len: u64) -> Result<()>;
+
+    fn sync_all(&mut self) -> Result<()>;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// `Env` backed by std file system, where readable and mapped files are
+/// accessed through mmap.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct StdEnv;
+
+#[cfg(not(target_arch = "wasm32"))]
+struct StdReadableFile {
+    _file: File,
+    mmap: Mmap,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ReadableFile for StdReadableFile {
+    fn size(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Bytes> {
+        if offset + len > self.mmap.len() {
+            return Err(crate::Error::TableRead(format!(
+                "out of range, offset={}, size={}, len={}",
+                offset,
+                len,
+                self.mmap.len()
+            )));
+        }
+        Ok(Bytes::copy_from_slice(&self.mmap[offset..offset + len]))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WritableFile for File {
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        File::set_len(self, len)?;
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        File::sync_all(self)?;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct StdMappedFile {
+    file: File,
+    mmap: MmapMut,
+    /// Length the file is truncated to on drop, as a mapped file can't be
+    /// shrunk on Windows.
+    #[cfg(windows)]
+    pending_len: Option<u64>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Deref for StdMappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DerefMut for StdMappedFile {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.mmap
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl MappedFile for StdMappedFile {
+    fn flush(&mut self) -> Result<()> {
+        self.mmap.flush()?;
+        Ok(())
+    }
+
+    fn file_len(&self) -> Result<u64> {
+        #[cfg(windows)]
+        if let Some(len) = self.pending_len {
+            return Ok(len);
+        }
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        // Shrinking below the mapping fails with `ERROR_USER_MAPPED_FILE`.
+        #[cfg(windows)]
+        {
+            if len < self.mmap.len() as u64 {
+                self.pending_len = Some(len);
+                return Ok(());
+            }
+            self.pending_len = None;
+        }
+        self.file.set_len(len)?;
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+impl Drop for StdMappedFile {
+    fn drop(&mut self) {
+        if let Some(len) = self.pending_len.take() {
+            // Unmap before truncating.
+            self.mmap = match MmapMut::map_anon(1) {
+                Ok(mmap) => mmap,
+                Err(e) => {
+                    log::error!("failed to unmap file before truncating: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = self.file.set_len(len).and_then(|_| self.file.sync_all()) {
+                log::error!("failed to truncate mapped file to {}: {}", len, e);
+            }
+        }
+    }
+}
+
+/// Alignment of offsets, lengths and buffers of direct IO, which is the
+/// logical block size of most devices.
+#[cfg(target_os = "linux")]
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+/// Size of the aligned buffer data is copied to before written with direct
+/// IO.
+#[cfg(target_os = "linux")]
+const DIRECT_IO_BUFFER_SIZE: usize = 1 << 20;
+
+/// Create file `path` with `data` through `O_DIRECT`. Returns `None` if the
+/// file system doesn't support direct IO, like tmpfs, in which case the
+/// file is not created.
+#[cfg(target_os = "linux")]
+fn create_file_direct(path: &Path, data: &[u8]) -> Option<Result<()>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return None,
+        Err(e) => return Some(Err(e.into())),
+    };
+    let res = (|| {
+        let mut vec = vec![0; DIRECT_IO_BUFFER_SIZE + DIRECT_IO_ALIGNMENT];
+        let start = vec.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+        let buf = &mut vec[start..start + DIRECT_IO_BUFFER_SIZE];
+        for chunk in data.chunks(DIRECT_IO_BUFFER_SIZE) {
+            // The last chunk is padded with zeros, which are truncated below.
+            let len = chunk.len().div_ceil(DIRECT_IO_ALIGNMENT) * DIRECT_IO_ALIGNMENT;
+            buf[..chunk.len()].copy_from_slice(chunk);
+            buf[chunk.len()..len].fill(0);
+            file.write_all(&buf[..len])?;
+        }
+        file.set_len(data.len() as u64)?;
+        Ok(())
+    })();
+    Some(res)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Env for StdEnv {
+    fn open_readable(&self, path: &Path, _: IoPriority) -> Result<Box<dyn ReadableFile>> {
+        let file = File::open(path)?;
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        Ok(Box::new(StdReadableFile { _file: file, mmap }))
+    }
+
+    fn open_writable(
+        &self,
+        path: &Path,
+        mode: OpenMode,
+        _: IoPriority,
+    ) -> Result<Box<dyn WritableFile>> {
+        let mut opts = OpenOptions::new();
+        opts.read(true).write(true);
+        match mode {
+            OpenMode::Existing => {}
+            OpenMode::CreateNew => {
+                opts.create_new(true);
+            }
+            OpenMode::Truncate => {
+                opts.create(true).truncate(true);
+            }
+        }
+        Ok(Box::new(opts.open(path)?))
+    }
+
+    fn open_mapped(&self, path: &Path, len: u64) -> Result<(Box<dyn MappedFile>, bool)> {
+        let created = !path.exists();
+        let file = if created {
+            let file = OpenOptions::new()
+                .create_new(true)
+                .read(true)
+                .write(true)
+                .open(path)?;
+            file.set_len(len)?;
+            file.sync_all()?;
+            self.sync_dir(path.parent().unwrap())?;
+            file
+        } else {
+            OpenOptions::new().read(true).write(true).open(path)?
+        };
+        let mmap = unsafe { MmapOptions::new().map_mut(&file)? };
+        let file = StdMappedFile {
+            file,
+            mmap,
+            #[cfg(windows)]
+            pending_len: None,
+        };
+        Ok((Box::new(file), created))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(dir)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(from, to)?;
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::hard_link(from, to)?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[cfg(not(windows))]
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Directories can't be opened as files on Windows, and NTFS journals
+    /// metadata changes like creations and renames by itself.
+    #[cfg(windows)]
+    fn sync_dir(&self, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn create_file(
+        &self,
+        path: &Path,
+        data: &[u8],
+        priority: IoPriority,
+        direct_io: bool,
+    ) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        if direct_io {
+            if let Some(res) = create_file_direct(path, data) {
+                return res;
+            }
+        }
+        let _ = direct_io;
+        let mut file = self.open_writable(path, OpenMode::CreateNew, priority)?;
+        file.write_all(data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::SeekFrom;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_std_env() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let env = StdEnv;
+        let path = tmp_dir.path().join("file");
+
+        let mut file = env
+            .open_writable(&path, OpenMode::CreateNew, IoPriority::Foreground)
+            .unwrap();
+        assert!(env
+            .open_writable(&path, OpenMode::CreateNew, IoPriority::Foreground)
+            .is_err());
+        file.write_all(b"hello world").unwrap();
+        file.set_len(5).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+        assert_eq!(env.read_file(&path).unwrap(), Bytes::from("hello"));
+        let mut file = env
+            .open_writable(&path, OpenMode::Existing, IoPriority::Foreground)
+            .unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b"!").unwrap();
+        drop(file);
+        let file = env.open_readable(&path, IoPriority::Foreground).unwrap();
+        assert_eq!(file.size(), 6);
+        assert_eq!(file.read_at(4, 2).unwrap(), Bytes::from("o!"));
+        assert!(file.read_at(4, 3).is_err());
+        drop(file);
+
+        let new_path = tmp_dir.path().join("renamed");
+        env.rename(&path, &new_path).unwrap();
+        env.sync_dir(tmp_dir.path()).unwrap();
+        assert!(!env.exists(&path));
+        assert_eq!(env.list_dir(tmp_dir.path()).unwrap(), vec!["renamed"]);
+        env.remove_file(&new_path).unwrap();
+
+        let mapped_path = tmp_dir.path().join("mapped");
+        let (mut mapped, created) = env.open_mapped(&mapped_path, 16).unwrap();
+        assert!(created);
+        mapped[..5].copy_from_slice(b"hello");
+        mapped.flush().unwrap();
+        drop(mapped);
+        let (mapped, created) = env.open_mapped(&mapped_path, 16).unwrap();
+        assert!(!created);
+        assert_eq!(&mapped[..5], b"hello");
+        assert_eq!(mapped.file_len().unwrap(), 16);
+    }
+
+    #[test]
+    fn test_std_env_mapped_set_len() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let env = StdEnv;
+        let path = tmp_dir.path().join("mapped");
+        let (mut mapped, _) = env.open_mapped(&path, 16).unwrap();
+        mapped[..5].copy_from_slice(b"hello");
+        mapped.flush().unwrap();
+
+        // The mapping is kept while the file is shrunk.
+        mapped.set_len(5).unwrap();
+        mapped.sync_all().unwrap();
+        assert_eq!(mapped.file_len().unwrap(), 5);
+        assert_eq!(mapped.len(), 16);
+        assert_eq!(&mapped[..5], b"hello");
+        drop(mapped);
+        assert_eq!(env.read_file(&path).unwrap(), Bytes::from("hello"));
+
+        // Growing is applied immediately.
+        let (mut mapped, created) = env.open_mapped(&path, 16).unwrap();
+        assert!(!created);
+        assert_eq!(mapped.len(), 5);
+        mapped.set_len(8).unwrap();
+        assert_eq!(mapped.file_len().unwrap(), 8);
+        drop(mapped);
+        assert_eq!(env.read_file(&path).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn test_std_env_create_file() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let env = StdEnv;
+        // Length of data is not aligned.
+        let data: Vec<u8> = (0..(1 << 20) + 5000).map(|i| i as u8).collect();
+        for (name, direct_io) in [("buffered", false), ("direct", true)] {
+            let path = tmp_dir.path().join(name);
+            env.create_file(&path, &data, IoPriority::Background, direct_io)
+                .unwrap();
+            assert!(env
+                .create_file(&path, b"", IoPriority::Background, direct_io)
+                .is_err());
+            assert_eq!(env.read_file(&path).unwrap(), data);
+        }
+    }
+}
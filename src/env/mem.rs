@@ -0,0 +1,442 @@
+use super::{Env, IoPriority, MappedFile, OpenMode, ReadableFile, WritableFile};
+use crate::{Error, Result};
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+type FileData = Arc<RwLock<Vec<u8>>>;
+
+#[derive(Default)]
+struct FileSystem {
+    dirs: HashSet<PathBuf>,
+    files: HashMap<PathBuf, FileData>,
+}
+
+impl FileSystem {
+    fn file(&self, path: &Path) -> io::Result<FileData> {
+        match self.files.get(path) {
+            Some(data) => Ok(data.clone()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !self.dirs.contains(dir) => {
+                Err(not_found(dir))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+/// `Env` which keeps all files in memory, so it works on targets without
+/// file system, like wasm32, and makes tests independent of disks. Data
+/// survive closing and reopening databases with the same env, but not
+/// dropping the env itself. Clones share the same files.
+///
+/// Syncing is a no-op, and directories are not locked, so a directory must
+/// not be opened by two databases at the same time. Mapped files take
+/// memory of their full length, so WALs, which are mapped with twice of
+/// `value_log_file_size`, should be kept small.
+#[derive(Default, Clone)]
+pub struct MemEnv {
+    fs: Arc<Mutex<FileSystem>>,
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Total bytes of all files.
+    pub fn total_size(&self) -> u64 {
+        let fs = self.fs.lock().unwrap();
+        fs.files
+            .values()
+            .map(|data| data.read().unwrap().len() as u64)
+            .sum()
+    }
+}
+
+impl fmt::Debug for MemEnv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fs = self.fs.lock().unwrap();
+        f.debug_struct("MemEnv")
+            .field("dirs", &fs.dirs.len())
+            .field("files", &fs.files.len())
+            .finish()
+    }
+}
+
+struct MemReadableFile {
+    data: FileData,
+}
+
+impl ReadableFile for MemReadableFile {
+    fn size(&self) -> u64 {
+        self.data.read().unwrap().len() as u64
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Bytes> {
+        let data = self.data.read().unwrap();
+        if offset + len > data.len() {
+            return Err(Error::TableRead(format!(
+                "out of range, offset={}, size={}, len={}",
+                offset,
+                len,
+                data.len()
+            )));
+        }
+        Ok(Bytes::copy_from_slice(&data[offset..offset + len]))
+    }
+}
+
+struct MemWritableFile {
+    data: FileData,
+    pos: u64,
+}
+
+impl Read for MemWritableFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemWritableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.write().unwrap();
+        let start = self.pos as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemWritableFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => {
+                let len = self.data.read().unwrap().len() as u64;
+                len.checked_add_signed(delta)
+            }
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )),
+        }
+    }
+}
+
+impl WritableFile for MemWritableFile {
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.data.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A mapping is a private buffer, which is written back to the file on
+/// flush and drop. Unlike mmap, other handles of the file don't see
+/// modifications of the mapping until then.
+struct MemMappedFile {
+    data: FileData,
+    buf: Vec<u8>,
+}
+
+impl MemMappedFile {
+    fn write_back(&mut self) {
+        let mut data = self.data.write().unwrap();
+        let len = data.len().min(self.buf.len());
+        data[..len].copy_from_slice(&self.buf[..len]);
+    }
+}
+
+impl Deref for MemMappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for MemMappedFile {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl MappedFile for MemMappedFile {
+    fn flush(&mut self) -> Result<()> {
+        self.write_back();
+        Ok(())
+    }
+
+    fn file_len(&self) -> Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.write_back();
+        self.data.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        self.write_back();
+        Ok(())
+    }
+}
+
+impl Drop for MemMappedFile {
+    fn drop(&mut self) {
+        self.write_back();
+    }
+}
+
+impl Env for MemEnv {
+    fn open_readable(&self, path: &Path, _: IoPriority) -> Result<Box<dyn ReadableFile>> {
+        let data = self.fs.lock().unwrap().file(path)?;
+        Ok(Box::new(MemReadableFile { data }))
+    }
+
+    fn open_writable(
+        &self,
+        path: &Path,
+        mode: OpenMode,
+        _: IoPriority,
+    ) -> Result<Box<dyn WritableFile>> {
+        let mut fs = self.fs.lock().unwrap();
+        let data = match mode {
+            OpenMode::Existing => fs.file(path)?,
+            OpenMode::CreateNew => {
+                fs.check_parent(path)?;
+                if fs.files.contains_key(path) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} already exists", path.display()),
+                    )
+                    .into());
+                }
+                fs.files.entry(path.to_path_buf()).or_default().clone()
+            }
+            OpenMode::Truncate => {
+                fs.check_parent(path)?;
+                let data = fs.files.entry(path.to_path_buf()).or_default().clone();
+                data.write().unwrap().clear();
+                data
+            }
+        };
+        Ok(Box::new(MemWritableFile { data, pos: 0 }))
+    }
+
+    fn open_mapped(&self, path: &Path, len: u64) -> Result<(Box<dyn MappedFile>, bool)> {
+        let mut fs = self.fs.lock().unwrap();
+        let created = !fs.files.contains_key(path);
+        if created {
+            fs.check_parent(path)?;
+            let data = vec![0; len as usize];
+            fs.files
+                .insert(path.to_path_buf(), Arc::new(RwLock::new(data)));
+        }
+        let data = fs.file(path)?;
+        let buf = data.read().unwrap().clone();
+        Ok((Box::new(MemMappedFile { data, buf }), created))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let fs = self.fs.lock().unwrap();
+        fs.files.contains_key(path) || fs.dirs.contains(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        let fs = self.fs.lock().unwrap();
+        if !fs.dirs.contains(dir) {
+            return Err(not_found(dir).into());
+        }
+        let files = fs.files.keys();
+        let dirs = fs.dirs.iter();
+        Ok(files
+            .chain(dirs)
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name()?.to_str().map(str::to_string))
+            .collect())
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        for dir in dir.ancestors().filter(|d| !d.as_os_str().is_empty()) {
+            fs.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        fs.check_parent(to)?;
+        let data = fs.files.remove(from).ok_or_else(|| not_found(from))?;
+        fs.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        fs.check_parent(to)?;
+        let data = fs.file(from)?;
+        if fs.files.contains_key(to) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", to.display()),
+            )
+            .into());
+        }
+        fs.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        match fs.files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path).into()),
+        }
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        let fs = self.fs.lock().unwrap();
+        if !fs.dirs.contains(dir) {
+            return Err(not_found(dir).into());
+        }
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Agate, AgateOptions};
+
+    #[test]
+    fn test_mem_env() {
+        let env = MemEnv::new();
+        let dir = Path::new("/db");
+        let path = dir.join("file");
+        assert!(env
+            .open_writable(&path, OpenMode::CreateNew, IoPriority::Foreground)
+            .is_err());
+        env.create_dir_all(dir).unwrap();
+
+        let mut file = env
+            .open_writable(&path, OpenMode::CreateNew, IoPriority::Foreground)
+            .unwrap();
+        assert!(env
+            .open_writable(&path, OpenMode::CreateNew, IoPriority::Foreground)
+            .is_err());
+        file.write_all(b"hello world").unwrap();
+        file.set_len(5).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+        assert_eq!(env.read_file(&path).unwrap(), Bytes::from("hello"));
+        let mut file = env
+            .open_writable(&path, OpenMode::Existing, IoPriority::Foreground)
+            .unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b"!").unwrap();
+        drop(file);
+        let file = env.open_readable(&path, IoPriority::Foreground).unwrap();
+        assert_eq!(file.size(), 6);
+        assert_eq!(file.read_at(4, 2).unwrap(), Bytes::from("o!"));
+        assert!(file.read_at(4, 3).is_err());
+        drop(file);
+
+        let new_path = dir.join("renamed");
+        env.rename(&path, &new_path).unwrap();
+        env.sync_dir(dir).unwrap();
+        assert!(!env.exists(&path));
+        assert_eq!(env.list_dir(dir).unwrap(), vec!["renamed"]);
+        env.hard_link(&new_path, &path).unwrap();
+        env.remove_file(&new_path).unwrap();
+        assert_eq!(env.read_file(&path).unwrap(), Bytes::from("hello!"));
+        env.remove_file(&path).unwrap();
+
+        let mapped_path = dir.join("mapped");
+        let (mut mapped, created) = env.open_mapped(&mapped_path, 16).unwrap();
+        assert!(created);
+        mapped[..5].copy_from_slice(b"hello");
+        mapped.flush().unwrap();
+        drop(mapped);
+        let (mapped, created) = env.open_mapped(&mapped_path, 16).unwrap();
+        assert!(!created);
+        assert_eq!(&mapped[..5], b"hello");
+        assert_eq!(mapped.file_len().unwrap(), 16);
+    }
+
+    #[test]
+    fn test_agate_on_mem_env() {
+        let env = MemEnv::new();
+        let opts = AgateOptions {
+            env: Arc::new(env.clone()),
+            mem_table_size: 1 << 14,
+            value_log_file_size: 1 << 16,
+            ..Default::default()
+        };
+        let agate = Agate::open(opts.clone(), "/db").unwrap();
+        for i in 0..1000 {
+            let mut txn = agate.new_transaction(true);
+            let key = Bytes::from(format!("key{:04}", i));
+            txn.set(key.clone(), key).unwrap();
+            txn.commit().unwrap();
+        }
+        agate.flush_memtable(true).unwrap();
+        assert!(!agate.tables().unwrap().is_empty());
+        drop(agate);
+        assert!(env.total_size() > 0);
+
+        let agate = Agate::open(opts, "/db").unwrap();
+        for i in (0..1000).step_by(97) {
+            let key = Bytes::from(format!("key{:04}", i));
+            let item = agate.new_transaction(false).get(&key).unwrap();
+            assert_eq!(item.value(), &key);
+        }
+        assert_eq!(agate.identity().incarnation, 2);
+    }
+}
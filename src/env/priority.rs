@@ -0,0 +1,242 @@
+use super::{Env, IoPriority, MappedFile, OpenMode, ReadableFile, WritableFile};
+use crate::clock::SystemClock;
+use crate::rate_limiter::RateLimiter;
+use crate::Result;
+
+use bytes::Bytes;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Limits of one IO class, shared by all files opened with the class.
+struct ClassLimiter {
+    bytes_per_sec: u64,
+    limiter: RateLimiter,
+}
+
+impl ClassLimiter {
+    fn new(bytes_per_sec: u64) -> Arc<Self> {
+        Arc::new(Self {
+            bytes_per_sec,
+            limiter: RateLimiter::new(bytes_per_sec, 0, Instant::now()),
+        })
+    }
+
+    /// Block until `bytes` of IO is allowed.
+    fn request(&self, bytes: usize) {
+        // No deadline is given, so it can't fail.
+        let _ = self.limiter.acquire(bytes as u64, 0, &SystemClock, None);
+    }
+}
+
+/// `Env` which throttles reads and writes of files by their `IoPriority`.
+/// Each class has its own token bucket, which is shared by all files opened
+/// through the env, so sharing one env among databases limits them all.
+///
+/// Limiting background IO prevents flush and compaction from eating up disk
+/// bandwidth needed by foreground reads. Envs which want to apply ionice or
+/// other OS level priorities can wrap `StdEnv` in the same way.
+#[derive(Clone)]
+pub struct RateLimitedEnv {
+    inner: Arc<dyn Env>,
+    foreground: Arc<ClassLimiter>,
+    background: Arc<ClassLimiter>,
+}
+
+impl RateLimitedEnv {
+    /// Wrap `inner` with limits of bytes per second for each class, which
+    /// allow bursts of one second. Zero means unlimited.
+    pub fn new(
+        inner: Arc<dyn Env>,
+        foreground_bytes_per_sec: u64,
+        background_bytes_per_sec: u64,
+    ) -> Self {
+        Self {
+            inner,
+            foreground: ClassLimiter::new(foreground_bytes_per_sec),
+            background: ClassLimiter::new(background_bytes_per_sec),
+        }
+    }
+
+    fn limiter(&self, priority: IoPriority) -> Arc<ClassLimiter> {
+        match priority {
+            IoPriority::Foreground => self.foreground.clone(),
+            IoPriority::Background => self.background.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for RateLimitedEnv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitedEnv")
+            .field("inner", &self.inner)
+            .field("foreground_bytes_per_sec", &self.foreground.bytes_per_sec)
+            .field("background_bytes_per_sec", &self.background.bytes_per_sec)
+            .finish()
+    }
+}
+
+struct LimitedReadableFile {
+    inner: Box<dyn ReadableFile>,
+    limiter: Arc<ClassLimiter>,
+}
+
+impl ReadableFile for LimitedReadableFile {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Bytes> {
+        self.limiter.request(len);
+        self.inner.read_at(offset, len)
+    }
+
+    fn read_batch(&self, reads: &[(usize, usize)]) -> Result<Vec<Bytes>> {
+        self.limiter
+            .request(reads.iter().map(|&(_, len)| len).sum());
+        self.inner.read_batch(reads)
+    }
+}
+
+struct LimitedWritableFile {
+    inner: Box<dyn WritableFile>,
+    limiter: Arc<ClassLimiter>,
+}
+
+impl Read for LimitedWritableFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.limiter.request(buf.len());
+        self.inner.read(buf)
+    }
+}
+
+impl Write for LimitedWritableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.limiter.request(buf.len());
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for LimitedWritableFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl WritableFile for LimitedWritableFile {
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        self.inner.sync_all()
+    }
+}
+
+impl Env for RateLimitedEnv {
+    fn open_readable(&self, path: &Path, priority: IoPriority) -> Result<Box<dyn ReadableFile>> {
+        Ok(Box::new(LimitedReadableFile {
+            inner: self.inner.open_readable(path, priority)?,
+            limiter: self.limiter(priority),
+        }))
+    }
+
+    fn open_writable(
+        &self,
+        path: &Path,
+        mode: OpenMode,
+        priority: IoPriority,
+    ) -> Result<Box<dyn WritableFile>> {
+        Ok(Box::new(LimitedWritableFile {
+            inner: self.inner.open_writable(path, mode, priority)?,
+            limiter: self.limiter(priority),
+        }))
+    }
+
+    fn create_file(
+        &self,
+        path: &Path,
+        data: &[u8],
+        priority: IoPriority,
+        direct_io: bool,
+    ) -> Result<()> {
+        self.limiter(priority).request(data.len());
+        self.inner.create_file(path, data, priority, direct_io)
+    }
+    fn open_mapped(&self, path: &Path, len: u64) -> Result<(Box<dyn MappedFile>, bool)> {
+        self.inner.open_mapped(path, len)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        self.inner.list_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        self.inner.sync_dir(dir)
+    }
+
+    fn is_local(&self) -> bool {
+        self.inner.is_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::StdEnv;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_rate_limited_env() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let env = RateLimitedEnv::new(Arc::new(StdEnv), 0, 1000);
+        let write = |name: &str, priority| {
+            let path = tmp_dir.path().join(name);
+            let start = Instant::now();
+            let mut file = env
+                .open_writable(&path, OpenMode::CreateNew, priority)
+                .unwrap();
+            file.write_all(&[0; 750]).unwrap();
+            file.write_all(&[0; 750]).unwrap();
+            start.elapsed()
+        };
+
+        // Foreground IO is unlimited.
+        assert!(write("fg", IoPriority::Foreground) < Duration::from_millis(400));
+        // Background IO waits for the 500 bytes borrowed from the bucket.
+        assert!(write("bg", IoPriority::Background) >= Duration::from_millis(400));
+        let file = env
+            .open_readable(&tmp_dir.path().join("bg"), IoPriority::Background)
+            .unwrap();
+        let start = Instant::now();
+        assert_eq!(file.read_at(0, 100).unwrap().len(), 100);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,248 @@
+use super::{Env, IoPriority, MappedFile, OpenMode, ReadableFile, StdEnv, WritableFile};
+use crate::{Error, Result};
+
+use bytes::Bytes;
+use io_uring::{opcode, types, IoUring};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Number of reads submitted to a ring at once.
+const RING_ENTRIES: u32 = 64;
+
+thread_local! {
+    // Every thread submits reads to its own ring, so reads of different
+    // threads don't contend.
+    static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+}
+
+/// `Env` which reads tables with io_uring on Linux, so that reads of a
+/// batch, like blocks read ahead by iterators, are issued with a single
+/// syscall. Reads go through page cache as `StdEnv` does.
+///
+/// Other files are handled by `StdEnv`. WALs are still written through
+/// mmap, which doesn't issue syscalls for appends.
+#[derive(Debug, Clone, Copy)]
+pub struct UringEnv {
+    inner: StdEnv,
+}
+
+impl UringEnv {
+    /// Create the env, or return an error if io_uring is not supported by
+    /// the kernel.
+    pub fn new() -> Result<Self> {
+        with_ring(|_| Ok(()))?;
+        Ok(Self { inner: StdEnv })
+    }
+}
+
+fn with_ring<T>(f: impl FnOnce(&mut IoUring) -> Result<T>) -> Result<T> {
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.is_none() {
+            *ring = Some(IoUring::new(RING_ENTRIES)?);
+        }
+        f(ring.as_mut().unwrap())
+    })
+}
+
+struct UringReadableFile {
+    file: File,
+    size: u64,
+}
+
+impl UringReadableFile {
+    /// Read every `(offset, len)` of `reads` into `bufs` with `ring`. If
+    /// submitting fails, `broken` is set, as reads may be still in flight.
+    fn read_into(
+        &self,
+        ring: &mut IoUring,
+        reads: &[(usize, usize)],
+        bufs: &mut [Vec<u8>],
+        broken: &mut bool,
+    ) -> Result<()> {
+        let fd = types::Fd(self.file.as_raw_fd());
+        let mut done = vec![0; reads.len()];
+        let mut pending: Vec<usize> = (0..reads.len()).filter(|&i| reads[i].1 > 0).collect();
+        while !pending.is_empty() {
+            let batch = pending.len().min(RING_ENTRIES as usize);
+            {
+                let mut sq = ring.submission();
+                for &i in &pending[..batch] {
+                    let (offset, len) = reads[i];
+                    let buf = &mut bufs[i][done[i]..];
+                    let entry = opcode::Read::new(fd, buf.as_mut_ptr(), (len - done[i]) as u32)
+                        .offset((offset + done[i]) as u64)
+                        .build()
+                        .user_data(i as u64);
+                    // The queue is drained below, so it's never full.
+                    unsafe { sq.push(&entry).unwrap() };
+                }
+            }
+
+            let mut err = None;
+            let mut inflight = batch;
+            while inflight > 0 {
+                let res = (|| {
+                    fail::fail_point!("uring_submit", |_| Err(io::Error::other(
+                        "failpoint uring_submit"
+                    )));
+                    ring.submit_and_wait(inflight)
+                })();
+                match res {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        *broken = true;
+                        return Err(e.into());
+                    }
+                }
+                for cqe in ring.completion() {
+                    inflight -= 1;
+                    let i = cqe.user_data() as usize;
+                    match cqe.result() {
+                        res if res < 0 => err = Some(io::Error::from_raw_os_error(-res)),
+                        0 => err = Some(io::ErrorKind::UnexpectedEof.into()),
+                        res => done[i] += res as usize,
+                    }
+                }
+            }
+            if let Some(err) = err {
+                return Err(err.into());
+            }
+            // Short reads are submitted again for the rest.
+            pending.retain(|&i| done[i] < reads[i].1);
+        }
+        Ok(())
+    }
+}
+
+impl ReadableFile for UringReadableFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Bytes> {
+        Ok(self.read_batch(&[(offset, len)])?.pop().unwrap())
+    }
+
+    fn read_batch(&self, reads: &[(usize, usize)]) -> Result<Vec<Bytes>> {
+        for &(offset, len) in reads {
+            if (offset + len) as u64 > self.size {
+                return Err(Error::TableRead(format!(
+                    "out of range, offset={}, size={}, len={}",
+                    offset, len, self.size
+                )));
+            }
+        }
+        let mut bufs: Vec<_> = reads.iter().map(|&(_, len)| vec![0; len]).collect();
+        let mut broken = false;
+        let res = with_ring(|ring| self.read_into(ring, reads, &mut bufs, &mut broken));
+        if broken {
+            // Reads may be still in flight, so neither buffers nor the ring
+            // can be freed. The ring also holds entries of this batch, so a
+            // new one is created for following reads.
+            for buf in bufs.iter_mut() {
+                std::mem::forget(std::mem::take(buf));
+            }
+            RING.with(|ring| std::mem::forget(ring.borrow_mut().take()));
+        }
+        res?;
+        Ok(bufs.into_iter().map(Bytes::from).collect())
+    }
+}
+
+impl Env for UringEnv {
+    fn open_readable(&self, path: &Path, _: IoPriority) -> Result<Box<dyn ReadableFile>> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Box::new(UringReadableFile { file, size }))
+    }
+
+    fn open_writable(
+        &self,
+        path: &Path,
+        mode: OpenMode,
+        priority: IoPriority,
+    ) -> Result<Box<dyn WritableFile>> {
+        self.inner.open_writable(path, mode, priority)
+    }
+
+    fn create_file(
+        &self,
+        path: &Path,
+        data: &[u8],
+        priority: IoPriority,
+        direct_io: bool,
+    ) -> Result<()> {
+        self.inner.create_file(path, data, priority, direct_io)
+    }
+
+    fn open_mapped(&self, path: &Path, len: u64) -> Result<(Box<dyn MappedFile>, bool)> {
+        self.inner.open_mapped(path, len)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        self.inner.list_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        self.inner.sync_dir(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_uring_env() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let env = UringEnv::new().unwrap();
+        let path = tmp_dir.path().join("file");
+        let data: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let mut file = env
+            .open_writable(&path, OpenMode::CreateNew, IoPriority::Foreground)
+            .unwrap();
+        file.write_all(&data).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+
+        let file = env.open_readable(&path, IoPriority::Foreground).unwrap();
+        assert_eq!(file.size(), data.len() as u64);
+        assert_eq!(file.read_at(10, 5).unwrap(), &data[10..15]);
+        assert!(file.read_at(data.len() - 1, 2).is_err());
+        assert_eq!(env.read_file(&path).unwrap(), data);
+
+        // More reads than entries of a ring.
+        let reads: Vec<_> = (0..200).map(|i| (i * 4000, 100 + i)).collect();
+        let res = file.read_batch(&reads).unwrap();
+        for (&(offset, len), bytes) in reads.iter().zip(res) {
+            assert_eq!(bytes, &data[offset..offset + len]);
+        }
+    }
+}
@@ -1,5 +1,8 @@
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::result;
+use std::sync::{Arc, PoisonError};
 
 use thiserror::Error;
 
@@ -11,9 +14,15 @@ pub enum Error {
     Io(#[source] Box<io::Error>),
     #[error("Empty key")]
     EmptyKey,
-    #[error("Too long: {0}")]
-    TooLong(String),
-    #[error("Invalid checksum")]
+    #[error("Key not found")]
+    KeyNotFound,
+    #[error("{what} is too long: {len} > {limit}")]
+    TooLong {
+        what: &'static str,
+        len: usize,
+        limit: usize,
+    },
+    #[error("Invalid checksum: {0}")]
     InvalidChecksum(String),
     #[error("Invalid filename")]
     InvalidFilename(String),
@@ -29,6 +38,264 @@ pub enum Error {
     LogRead(String),
     #[error("Error when compaction: {0}")]
     CompactionError(String),
+    #[error("{0}")]
+    CustomError(String),
+    #[error("Lock poisoned: {0}")]
+    PoisonError(String),
+    #[error("No room for write")]
+    WriteNoRoom(()),
+    #[error("Txn is too big to fit into one request")]
+    TxnTooBig,
+    /// Keys read by the transaction were written by transactions committed
+    /// after it started, see `IsolationLevel::Serializable`. The
+    /// transaction should be retried.
+    #[error("Transaction conflict, please retry")]
+    Conflict,
+    #[error("Write stalled until deadline")]
+    WriteStalled,
+    #[error("Database is opened in read-only mode")]
+    ReadOnly,
+    #[error("Database is locked: {0}")]
+    Locked(String),
+    /// Flushing memtables stopped after `0`, which fails all later writes
+    /// and flushes.
+    #[error("Memtable flush failed: {0}")]
+    FlushFailed(#[source] Arc<Error>),
+    /// One error returned to several requests, like writes merged into one
+    /// WAL batch.
+    #[error("{0}")]
+    Shared(#[source] Arc<Error>),
+    #[error("Encryption key mismatch")]
+    EncryptionKeyMismatch,
+    #[error("Invalid data key id {0}")]
+    InvalidDataKeyId(u64),
+    /// State is broken and can't be recovered, like a panic happened while
+    /// updating it. The database should be reopened.
+    #[error("Internal error: {0}")]
+    Internal(String),
+    #[error("Background task {name} panicked: {message}")]
+    TaskPanicked { name: String, message: String },
+    #[error("Failed to open database at stage {stage:?}: {source}")]
+    Open {
+        stage: OpenStage,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error("{source} ({context})")]
+    Context {
+        context: ErrorContext,
+        #[source]
+        source: Box<Error>,
+    },
+}
+
+/// Coarse classification of errors, which stays stable when new error
+/// variants are added, so applications can branch on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// data on disk is damaged or can't be decoded
+    Corruption,
+    /// key doesn't exist
+    NotFound,
+    /// transaction conflicts with another one
+    Conflict,
+    /// too much data in one transaction or batch
+    TxnTooBig,
+    /// writes are stalled or memtables are full
+    Stalled,
+    /// database is opened in read-only mode
+    ReadOnly,
+    /// database directory is locked by others
+    Locked,
+    /// database is closed
+    Closed,
+    /// options are invalid
+    Config,
+    /// arguments such as keys are invalid
+    InvalidArgument,
+    /// failure of underlying file system
+    Io,
+    Other,
+}
+
+/// Where an error happens. Fields which are unknown are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub path: Option<PathBuf>,
+    pub offset: Option<u64>,
+    pub table_id: Option<u64>,
+}
+
+impl ErrorContext {
+    pub fn path(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn table(table_id: u64) -> Self {
+        Self {
+            table_id: Some(table_id),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        if let Some(table_id) = self.table_id {
+            write!(f, "table {}", table_id)?;
+            sep = ", ";
+        }
+        if let Some(path) = &self.path {
+            write!(f, "{}path {}", sep, path.display())?;
+            sep = ", ";
+        }
+        if let Some(offset) = self.offset {
+            write!(f, "{}offset {}", sep, offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stages of `Agate::open`, in the order they are executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenStage {
+    /// creating database directories
+    CreateDir,
+    /// acquiring the exclusive directory lock
+    Lock,
+    /// loading and bumping store identity
+    Identity,
+    /// loading data keys of encryption
+    KeyRegistry,
+    /// replaying manifest
+    Manifest,
+    /// opening tables of all levels
+    Levels,
+    /// replaying WALs of memtables
+    Memtables,
+    /// starting background threads
+    Workers,
+}
+
+impl Error {
+    /// Get classification of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Config(_) | Error::EncryptionKeyMismatch => ErrorKind::Config,
+            Error::Io(err) if err.kind() == io::ErrorKind::NotFound => ErrorKind::NotFound,
+            Error::Io(_) => ErrorKind::Io,
+            Error::EmptyKey | Error::TooLong { .. } => ErrorKind::InvalidArgument,
+            Error::KeyNotFound => ErrorKind::NotFound,
+            Error::InvalidChecksum(_)
+            | Error::InvalidFilename(_)
+            | Error::Decode(_)
+            | Error::VarDecode(_)
+            | Error::TableRead(_)
+            | Error::LogRead(_)
+            | Error::InvalidDataKeyId(_) => ErrorKind::Corruption,
+            Error::DBClosed => ErrorKind::Closed,
+            Error::WriteNoRoom(_) | Error::WriteStalled => ErrorKind::Stalled,
+            Error::TxnTooBig => ErrorKind::TxnTooBig,
+            Error::Conflict => ErrorKind::Conflict,
+            Error::ReadOnly => ErrorKind::ReadOnly,
+            Error::Locked(_) => ErrorKind::Locked,
+            Error::CompactionError(_)
+            | Error::CustomError(_)
+            | Error::PoisonError(_)
+            | Error::Internal(_)
+            | Error::TaskPanicked { .. } => ErrorKind::Other,
+            Error::Open { source, .. } | Error::Context { source, .. } => source.kind(),
+            Error::FlushFailed(source) | Error::Shared(source) => source.kind(),
+        }
+    }
+
+    /// Whether the operation may succeed if retried later, like when writes
+    /// are stalled or the directory is locked. Errors like corruption or
+    /// invalid configuration are permanent.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::WriteNoRoom(_) | Error::WriteStalled | Error::Locked(_) | Error::Conflict => {
+                true
+            }
+            Error::Io(err) => !matches!(
+                err.kind(),
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::AlreadyExists
+                    | io::ErrorKind::InvalidInput
+                    | io::ErrorKind::InvalidData
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::Unsupported
+            ),
+            Error::Open { source, .. } | Error::Context { source, .. } => source.is_retryable(),
+            Error::Shared(source) => source.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Get context of the error, if any is attached.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::Context { context, .. } => Some(context),
+            Error::Open { source, .. } => source.context(),
+            Error::FlushFailed(source) | Error::Shared(source) => source.context(),
+            _ => None,
+        }
+    }
+
+    /// Get the underlying error, without context or open stage attached.
+    pub fn root_cause(&self) -> &Error {
+        match self {
+            Error::Open { source, .. } | Error::Context { source, .. } => source.root_cause(),
+            Error::Shared(source) => source.root_cause(),
+            err => err,
+        }
+    }
+
+    /// Attach `context` to the error. If the error has context already,
+    /// only fields which are unknown there are filled.
+    pub(crate) fn with_context(self, context: ErrorContext) -> Error {
+        match self {
+            Error::Context {
+                context: mut inner,
+                source,
+            } => {
+                inner.path = inner.path.or(context.path);
+                inner.offset = inner.offset.or(context.offset);
+                inner.table_id = inner.table_id.or(context.table_id);
+                Error::Context {
+                    context: inner,
+                    source,
+                }
+            }
+            err => Error::Context {
+                context,
+                source: Box::new(err),
+            },
+        }
+    }
+
+    /// Wrap the error with the open stage it fails.
+    pub(crate) fn at_stage(stage: OpenStage) -> impl FnOnce(Error) -> Error {
+        move |source| Error::Open {
+            stage,
+            source: Box::new(source),
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -45,4 +312,11 @@ impl From<prost::DecodeError> for Error {
     }
 }
 
+impl<T> From<PoisonError<T>> for Error {
+    #[inline]
+    fn from(e: PoisonError<T>) -> Error {
+        Error::PoisonError(e.to_string())
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,90 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How a table is added to LSM tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableCreationReason {
+    Flush,
+    BulkLoad,
+    Flatten,
+    Compaction,
+}
+
+/// Memtable flush reported by `EventListener`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushInfo {
+    /// memory held by the memtable
+    pub memtable_size: u64,
+    /// ID of the L0 table, `None` if the flush hasn't finished or the
+    /// memtable is empty
+    pub table_id: Option<u64>,
+    /// time spent in flush, zero if the flush hasn't finished
+    pub duration: Duration,
+}
+
+/// Compaction reported by `EventListener`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionInfo {
+    pub compactor_id: usize,
+    /// level whose tables are compacted
+    pub level: usize,
+    /// level the output tables are added to
+    pub next_level: usize,
+    /// IDs of tables compacted from both levels
+    pub input_tables: Vec<u64>,
+    /// IDs of output tables, empty if the compaction hasn't finished
+    pub output_tables: Vec<u64>,
+    /// time spent in compaction, zero if the compaction hasn't finished
+    pub duration: Duration,
+}
+
+/// Table added to LSM tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableCreationInfo {
+    pub id: u64,
+    pub level: usize,
+    pub size: u64,
+    pub reason: TableCreationReason,
+}
+
+/// Table file removed from disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableDeletionInfo {
+    pub id: u64,
+    pub path: PathBuf,
+}
+
+/// Receives notifications of background work, so that embedders can log or
+/// react to them without polling metrics. All hooks do nothing by default.
+///
+/// Hooks are called synchronously from flusher, compactors, writers or
+/// `Agate::open`, sometimes with internal locks held, so they should return
+/// quickly and must not call back into the database.
+pub trait EventListener: Send + Sync {
+    /// Called before an immutable memtable is flushed to L0.
+    fn on_flush_begin(&self, _info: &FlushInfo) {}
+
+    /// Called after a memtable is flushed and its WAL is removed.
+    fn on_flush_completed(&self, _info: &FlushInfo) {}
+
+    /// Called before tables picked by a compactor are merged.
+    fn on_compaction_begin(&self, _info: &CompactionInfo) {}
+
+    /// Called after output tables of a compaction replace its input tables
+    /// in both levels and manifest. Failed compactions are not reported.
+    fn on_compaction_completed(&self, _info: &CompactionInfo) {}
+
+    /// Called after a table is recorded in manifest.
+    fn on_table_created(&self, _info: &TableCreationInfo) {}
+
+    /// Called after a table file is removed, e.g. when it's left by an
+    /// interrupted flush and not referenced by manifest.
+    fn on_table_deleted(&self, _info: &TableDeletionInfo) {}
+
+    /// Called when a write starts to wait because all memtables are full.
+    fn on_stall_begin(&self) {}
+
+    /// Called when a stalled write gets room or gives up, with the time it
+    /// has been waiting.
+    fn on_stall_end(&self, _stalled: Duration) {}
+}
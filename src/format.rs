@@ -1,22 +1,86 @@
 use bytes::{BufMut, Bytes, BytesMut};
+use std::cell::Cell;
 use std::{ptr, u64};
 
-pub fn key_with_ts(key: impl Into<BytesMut>, ts: u64) -> Bytes {
-    let mut key = key.into();
-    append_ts(&mut key, ts);
-    key.freeze()
+pub fn key_with_ts(key: impl AsRef<[u8]>, ts: u64) -> Bytes {
+    KeyTs::new(key.as_ref(), ts).to_bytes()
 }
 
 /// Append a ts to make this key be the first one within range.
-pub fn key_with_ts_first(key: impl Into<BytesMut>) -> Bytes {
+pub fn key_with_ts_first(key: impl AsRef<[u8]>) -> Bytes {
     key_with_ts(key, std::u64::MAX)
 }
 
 /// Append a ts to make this key be the last one within range.
-pub fn key_with_ts_last(key: impl Into<BytesMut>) -> Bytes {
+pub fn key_with_ts_last(key: impl AsRef<[u8]>) -> Bytes {
     key_with_ts(key, 0)
 }
 
+/// A user key and a ts, which borrows the user key, so that it can be
+/// encoded into a reused buffer instead of a new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyTs<'a> {
+    key: &'a [u8],
+    ts: u64,
+}
+
+impl<'a> KeyTs<'a> {
+    pub fn new(key: &'a [u8], ts: u64) -> Self {
+        Self { key, ts }
+    }
+
+    /// Split an encoded key into user key and ts.
+    pub fn decode(key: &'a [u8]) -> Self {
+        Self::new(user_key(key), get_ts(key))
+    }
+
+    pub fn key(&self) -> &'a [u8] {
+        self.key
+    }
+
+    pub fn ts(&self) -> u64 {
+        self.ts
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.key.len() + 8
+    }
+
+    /// Append the encoded key to `buf`.
+    pub fn encode_to(&self, buf: &mut BytesMut) {
+        buf.reserve(self.encoded_len());
+        buf.extend_from_slice(self.key);
+        append_ts(buf, self.ts);
+    }
+
+    /// Encode the key into a new buffer of the exact size.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.encode_to(&mut buf);
+        buf.freeze()
+    }
+}
+
+thread_local! {
+    static KEY_BUF: Cell<BytesMut> = Cell::new(BytesMut::new());
+}
+
+/// Call `f` with `key` encoded into a thread local buffer, which is reused
+/// by later calls once the encoded key passed to `f` is dropped, so point
+/// reads don't allocate keys. Nested calls fall back to new buffers.
+pub fn with_key_ts<R>(key: KeyTs<'_>, f: impl FnOnce(&Bytes) -> R) -> R {
+    KEY_BUF.with(|cell| {
+        let mut buf = cell.take();
+        buf.clear();
+        key.encode_to(&mut buf);
+        let encoded = buf.split().freeze();
+        let res = f(&encoded);
+        drop(encoded);
+        cell.set(buf);
+        res
+    })
+}
+
 pub fn append_ts(key: &mut BytesMut, ts: u64) {
     key.reserve(8);
     let res = (u64::MAX - ts).to_be();
@@ -52,5 +116,28 @@ mod tests {
     fn test_key_ts() {
         let key = key_with_ts("aaa", 0);
         assert_eq!(get_ts(&key), 0);
+
+        let key_ts = KeyTs::new(b"aaa", 233);
+        let key = key_ts.to_bytes();
+        assert_eq!(key.len(), key_ts.encoded_len());
+        assert_eq!(key, key_with_ts("aaa", 233));
+        assert_eq!(KeyTs::decode(&key), key_ts);
+
+        let mut buf = BytesMut::from("prefix");
+        key_ts.encode_to(&mut buf);
+        assert_eq!(&buf[6..], &key[..]);
+
+        let outer = with_key_ts(key_ts, |encoded| {
+            // Nested calls don't share the buffer.
+            let inner = with_key_ts(KeyTs::new(b"b", 1), Bytes::clone);
+            assert_eq!(encoded, &key);
+            assert_eq!(inner, key_with_ts("b", 1));
+            encoded.clone()
+        });
+        assert_eq!(outer, key);
+        with_key_ts(KeyTs::new(b"cc", 2), |encoded| {
+            assert_eq!(encoded, &key_with_ts("cc", 2));
+        });
+        assert_eq!(outer, key);
     }
 }
@@ -0,0 +1,181 @@
+use crate::{Error, Result};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+struct State<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// A future which resolves to the result of a task once it's finished by
+/// another thread, like a write applied by write thread.
+pub struct TaskFuture<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+/// A future which resolves to the result of a write once it's applied by
+/// write thread.
+pub type WriteFuture = TaskFuture<()>;
+
+/// Callback which completes a `TaskFuture`.
+pub(crate) type TaskCallback<T> = Box<dyn FnOnce(Result<T>) + Send>;
+
+/// Completes the future when dropped. If the task is never handled, for
+/// example the request is discarded when database is closed, the future
+/// resolves to `Error::DBClosed`.
+struct Notifier<T> {
+    state: Arc<Mutex<State<T>>>,
+    result: Option<Result<T>>,
+}
+
+impl<T: Send + 'static> TaskFuture<T> {
+    /// Create a future together with the callback which completes it.
+    pub(crate) fn new() -> (TaskFuture<T>, TaskCallback<T>) {
+        let state = Arc::new(Mutex::new(State {
+            result: None,
+            waker: None,
+        }));
+        let notifier = Notifier {
+            state: state.clone(),
+            result: None,
+        };
+        let done = Box::new(move |result| notifier.notify(result));
+        (TaskFuture { state }, done)
+    }
+}
+
+impl<T> TaskFuture<T> {
+    /// Create a future which is already completed.
+    pub(crate) fn ready(result: Result<T>) -> TaskFuture<T> {
+        let state = State {
+            result: Some(result),
+            waker: None,
+        };
+        TaskFuture {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+}
+
+impl<T> Future for TaskFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Notifier<T> {
+    fn notify(mut self, result: Result<T>) {
+        self.result = Some(result);
+    }
+}
+
+impl<T> Drop for Notifier<T> {
+    fn drop(&mut self) {
+        let result = self.result.take().unwrap_or(Err(Error::DBClosed));
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            state.result = Some(result);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+struct ThreadWaker(std::thread::Thread);
+
+#[cfg(test)]
+impl std::task::Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Poll `f` on current thread until it's ready.
+#[cfg(test)]
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = Box::pin(f);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = f.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::key_with_ts;
+    use crate::{Agate, AgateOptions};
+    use bytes::Bytes;
+    use std::thread;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_write_future() {
+        let (future, done) = WriteFuture::new();
+        let handle = thread::spawn(move || done(Ok(())));
+        block_on(future).unwrap();
+        handle.join().unwrap();
+
+        let (future, done) = WriteFuture::new();
+        drop(done);
+        assert!(matches!(block_on(future), Err(Error::DBClosed)));
+    }
+
+    #[test]
+    fn test_write_async() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 20,
+            ..Default::default()
+        };
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+
+        let futures: Vec<_> = (0..100)
+            .map(|i| {
+                let key = key_with_ts(format!("key{:03}", i).as_str(), 1);
+                agate.put_async(key, Bytes::from(i.to_string()))
+            })
+            .collect();
+        for future in futures {
+            block_on(future).unwrap();
+        }
+        for i in 0..100 {
+            let value = agate
+                .get(&key_with_ts(format!("key{:03}", i).as_str(), 1))
+                .unwrap();
+            assert_eq!(value.value, i.to_string());
+        }
+
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("key"), Bytes::from("value")).unwrap();
+        block_on(txn.commit_async()).unwrap();
+        let item = agate
+            .new_transaction(false)
+            .get(&Bytes::from("key"))
+            .unwrap();
+        assert_eq!(item.value(), "value");
+
+        let too_big = vec![0; 1 << 20];
+        let future = agate.put_async(key_with_ts("big", 1), Bytes::from(too_big));
+        assert!(matches!(block_on(future), Err(Error::TxnTooBig)));
+    }
+}
@@ -0,0 +1,100 @@
+//! Entry points of fuzz targets in `fuzz/`, which decode on-disk formats
+//! from arbitrary bytes. Decoders must return errors instead of panicking
+//! or reading out of bounds on malformed data.
+
+use crate::checksum;
+use crate::format::key_with_ts;
+use crate::iterator_trait::AgateIterator;
+use crate::manifest::ManifestFile;
+use crate::opt::{ChecksumAlgorithm, ChecksumVerificationMode, Options};
+use crate::table::Table;
+use crate::wal::WalIterator;
+use crate::Result;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use prost::Message;
+use proto::meta::{BlockOffset, Checksum, TableIndex};
+use std::io::Cursor;
+
+/// Decode `data` as a WAL, returning the number of entries.
+pub fn decode_wal(data: &[u8]) -> Result<usize> {
+    let mut iter = WalIterator::new(Cursor::new(data));
+    let mut count = 0;
+    while iter.next()?.is_some() {
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Decode `data` as a table and read all entries, returning the number of
+/// entries.
+///
+/// Block checksums are not verified, so that corrupted blocks are decoded.
+/// Index of a random table rarely passes its checksum, see `decode_index`
+/// and `decode_block` for fuzzing them.
+pub fn decode_table(data: &[u8]) -> Result<usize> {
+    let opts = Options {
+        checksum_mode: ChecksumVerificationMode::NoVerification,
+        ..Default::default()
+    };
+    let table = Table::open_in_memory(Bytes::copy_from_slice(data), 1, opts)?;
+    let mut count = 0;
+    let mut iter = table.new_iterator(0);
+    iter.rewind();
+    while iter.valid() {
+        iter.value();
+        count += 1;
+        iter.next();
+    }
+    if let Some(err) = iter.error() {
+        if !err.is_eof() {
+            return Err(crate::Error::TableRead(format!("{:?}", err)));
+        }
+    }
+    iter.seek(table.biggest());
+    table.does_not_have_key(table.smallest(), farmhash::fingerprint32(data));
+    Ok(count)
+}
+
+/// Decode `data` as the index of a table, with a valid footer.
+pub fn decode_index(data: &[u8]) -> Result<usize> {
+    let mut table = BytesMut::from(data);
+    put_footer(&mut table, 0);
+    decode_table(&table)
+}
+
+/// Decode `data` as the only block of a table, with a valid index.
+pub fn decode_block(data: &[u8]) -> Result<usize> {
+    let index = TableIndex {
+        offsets: vec![BlockOffset {
+            key: key_with_ts("", 0).to_vec(),
+            offset: 0,
+            len: data.len() as u32,
+            ..Default::default()
+        }],
+        key_count: 1,
+        ..Default::default()
+    };
+    let mut table = BytesMut::from(data);
+    index.encode(&mut table).unwrap();
+    put_footer(&mut table, data.len());
+    decode_table(&table)
+}
+
+/// Append the footer of an index starting at `index_offset` to `table`.
+fn put_footer(table: &mut BytesMut, index_offset: usize) {
+    let index_len = table.len() - index_offset;
+    let checksum = Checksum {
+        sum: checksum::calculate_checksum(&table[index_offset..], ChecksumAlgorithm::Crc32c),
+        algo: ChecksumAlgorithm::Crc32c as i32,
+    };
+    table.put_u32(index_len as u32);
+    checksum.encode(table).unwrap();
+    table.put_u32(checksum.encoded_len() as u32);
+}
+
+/// Replay `data` as a MANIFEST, returning the number of tables.
+pub fn replay_manifest(data: &[u8]) -> Result<usize> {
+    let (manifest, _) = ManifestFile::replay_manifest_file(Bytes::copy_from_slice(data))?;
+    Ok(manifest.tables.len())
+}
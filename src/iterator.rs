@@ -1,27 +1,285 @@
-use crate::Table;
+use crate::format::{get_ts, key_with_ts, user_key};
+use crate::iterator_trait::AgateIterator;
+use crate::ops::transaction::Transaction;
+use crate::table::TableIterators;
+use crate::value::{Value, VALUE_DELETE};
+use crate::{Result, Table};
 use bytes::Bytes;
+use std::cmp::Ordering;
+
+/// `Item` is returned by reads, carrying value of a key together with
+/// its metadata.
+#[derive(Debug, Clone)]
+pub struct Item {
+    key: Bytes,
+    value: Value,
+}
+
+impl Item {
+    pub(crate) fn new(key: Bytes, value: Value) -> Self {
+        Self { key, value }
+    }
+
+    /// Key without timestamp.
+    pub fn key(&self) -> &Bytes {
+        &self.key
+    }
+
+    pub fn value(&self) -> &Bytes {
+        &self.value.value
+    }
+
+    /// Commit timestamp of the item.
+    pub fn version(&self) -> u64 {
+        self.value.version
+    }
+
+    /// User metadata set with `Entry::with_meta`.
+    pub fn user_meta(&self) -> u8 {
+        self.value.user_meta
+    }
+
+    /// Unix time in seconds when the item expires, 0 if it never expires.
+    pub fn expires_at(&self) -> u64 {
+        self.value.expires_at
+    }
+
+    /// Whether the key is deleted or expired at the version. Only streams
+    /// and iterators of all versions return deleted items, see
+    /// `Stream::with_deleted` and `IteratorOptions::all_versions`.
+    pub fn is_deleted(&self) -> bool {
+        self.value.meta & VALUE_DELETE != 0
+    }
+}
 
 #[derive(Default, Clone)]
 pub struct IteratorOptions {
     pub prefetch_size: usize,
+    /// Read blocks of tables ahead in batches when iterating forward, as
+    /// values are stored in blocks. Blocks read ahead are kept in block
+    /// cache, so it has no effect without block cache or with `no_cache`.
     pub prefetch_values: bool,
+    /// Iterate keys in descending order. Versions of a key, if all of them
+    /// are iterated, come from older to newer then.
     pub reverse: bool,
+    /// Iterate all versions of keys visible at the read timestamp, from
+    /// newer to older, including deletions as items with
+    /// `Item::is_deleted`, instead of the latest version of every key.
     pub all_versions: bool,
+    /// Return items without values, so that values are neither copied nor
+    /// folded from merge operands. Metadata of items is kept.
+    pub key_only: bool,
     pub internal_access: bool,
-    prefix_is_key: bool,
+    /// Whether `prefix` is a whole key, so that bloom filters can skip
+    /// tables without it.
+    pub(crate) prefix_is_key: bool,
+    /// Only iterate keys starting with it. Tables whose key ranges don't
+    /// cover it are not read.
     pub prefix: Bytes,
+    /// Only iterate versions not older than it. Tables whose versions are
+    /// all older are not read.
+    pub since_ts: u64,
+    /// Don't insert blocks read by the iterator into block cache, for scans
+    /// which read every block once.
+    pub no_cache: bool,
 }
 
 impl IteratorOptions {
     /// Check if a table should be included in iterator
-    pub fn pick_table(&self, _table: &Table) -> bool {
+    pub fn pick_table(&self, table: &Table) -> bool {
+        if self.since_ts > 0 && table.max_version() < self.since_ts {
+            return false;
+        }
+        if self.prefix.is_empty() {
+            return true;
+        }
+        // Keys with the prefix are contiguous, and not less than it.
+        let c = table.comparator();
+        let smallest = user_key(table.smallest());
+        let biggest = user_key(table.biggest());
+        if !biggest.starts_with(&self.prefix)
+            && c.compare_user_key(biggest, &self.prefix) == Ordering::Less
+        {
+            return false;
+        }
+        if !smallest.starts_with(&self.prefix)
+            && c.compare_user_key(smallest, &self.prefix) == Ordering::Greater
+        {
+            return false;
+        }
+        if self.prefix_is_key {
+            let hash = farmhash::fingerprint32(&self.prefix);
+            return !table.does_not_have_key(&key_with_ts(&self.prefix, u64::MAX), hash);
+        }
         true
-        // TODO: implement table selection logic
     }
 
     /// Remove unnecessary tables
-    pub fn pick_tables(&self, _tables: &mut Vec<Table>) {
-        return;
-        // TODO: implement table selection logic
+    pub fn pick_tables(&self, tables: &mut Vec<Table>) {
+        tables.retain(|t| self.pick_table(t));
     }
 }
+
+/// Iterator over keys visible to a transaction, created by
+/// `Transaction::new_iterator`. It's not positioned until `rewind` or
+/// `seek` is called.
+pub struct Iterator<'a> {
+    txn: &'a Transaction,
+    iter: Box<TableIterators>,
+    opts: IteratorOptions,
+    now: u64,
+    item: Option<Item>,
+}
+
+impl<'a> Iterator<'a> {
+    pub(crate) fn new(
+        txn: &'a Transaction,
+        iter: Box<TableIterators>,
+        opts: IteratorOptions,
+    ) -> Self {
+        Self {
+            now: txn.core().clock().unix_time(),
+            txn,
+            iter,
+            opts,
+            item: None,
+        }
+    }
+
+    /// Move to the first key with the prefix, or the last one in reverse.
+    pub fn rewind(&mut self) -> Result<()> {
+        if !self.opts.reverse {
+            let prefix = self.opts.prefix.clone();
+            return self.seek(&prefix);
+        }
+        match prefix_successor(&self.opts.prefix) {
+            // The last key before all versions of the successor.
+            Some(end) => self.iter.seek(&key_with_ts(&end, u64::MAX)),
+            None => self.iter.rewind(),
+        }
+        self.find_item()
+    }
+
+    /// Move to the first key not less than `key` with the prefix, or the
+    /// last key not greater than `key` in reverse.
+    pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+        let c = self.txn.core().comparator();
+        let order = c.compare_user_key(key, &self.opts.prefix);
+        let key = if self.opts.reverse {
+            if order == Ordering::Greater && !key.starts_with(&self.opts.prefix) {
+                return self.rewind();
+            }
+            // Versions come from older to newer in reverse.
+            key_with_ts(key, 0)
+        } else if order == Ordering::Less {
+            key_with_ts(&self.opts.prefix, self.txn.read_ts())
+        } else {
+            key_with_ts(key, self.txn.read_ts())
+        };
+        self.iter.seek(&key);
+        self.find_item()
+    }
+
+    /// Move to the next item.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<()> {
+        self.find_item()
+    }
+
+    pub fn valid(&self) -> bool {
+        self.item.is_some()
+    }
+
+    /// Get the current item, which is tracked as read by the transaction.
+    /// It panics if the iterator is not valid.
+    pub fn item(&self) -> &Item {
+        let item = self.item.as_ref().expect("iterator is not valid");
+        self.txn.add_read_key(item.key());
+        item
+    }
+
+    /// Find the next item visible at read timestamp from the current
+    /// position of merged iterator, and move past it.
+    fn find_item(&mut self) -> Result<()> {
+        self.item = None;
+        while self.iter.valid() {
+            let key = user_key(self.iter.key());
+            if !key.starts_with(&self.opts.prefix) {
+                // In reverse, keys after the prefix are skipped until it's
+                // reached.
+                let c = self.txn.core().comparator();
+                if self.opts.reverse
+                    && c.compare_user_key(key, &self.opts.prefix) == Ordering::Greater
+                {
+                    self.iter.next();
+                    continue;
+                }
+                break;
+            }
+
+            let key = Bytes::copy_from_slice(key);
+            let (version, mut value) = match self.next_version(&key) {
+                Some(found) => found,
+                None => continue,
+            };
+            value.version = version;
+            if value.meta & VALUE_DELETE != 0 || value.is_expired(self.now) {
+                if !self.opts.all_versions {
+                    continue;
+                }
+                value = Value::new_with_meta(Bytes::new(), VALUE_DELETE, 0);
+                value.version = version;
+            } else if self.opts.key_only {
+                value.value = Bytes::new();
+            } else {
+                // Pending operands apply to the versions visible at read_ts,
+                // including the one committed at it.
+                let base_ts = if version == self.txn.read_ts() && self.txn.has_pending_write(&key) {
+                    Some(version)
+                } else {
+                    version.checked_sub(1)
+                };
+                value = self.txn.core().fold_merge(&key, value, base_ts)?;
+            }
+            self.item = Some(Item::new(key, value));
+            return Ok(());
+        }
+        Ok(())
+    }
+
+    /// Get the next version of `key` to be returned within the range of
+    /// timestamps, and move past it. Other versions of the key are skipped
+    /// unless all versions are iterated.
+    fn next_version(&mut self, key: &[u8]) -> Option<(u64, Value)> {
+        let (read_ts, since_ts) = (self.txn.read_ts(), self.opts.since_ts);
+        // In reverse, versions come from older to newer, so the latest one
+        // is the last in range.
+        let latest_last = self.opts.reverse && !self.opts.all_versions;
+        let mut found = None;
+        while self.iter.valid() && user_key(self.iter.key()) == key {
+            let version = get_ts(self.iter.key());
+            if version <= read_ts && version >= since_ts {
+                found = Some((version, self.iter.value()));
+            }
+            self.iter.next();
+            if found.is_some() && !latest_last {
+                break;
+            }
+        }
+        if found.is_some() && !self.opts.all_versions {
+            while self.iter.valid() && user_key(self.iter.key()) == key {
+                self.iter.next();
+            }
+        }
+        found
+    }
+}
+
+/// The smallest key greater than all keys with `prefix` in bytewise order,
+/// or `None` if there is no such key.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let pos = prefix.iter().rposition(|b| *b != u8::MAX)?;
+    let mut end = prefix[..=pos].to_vec();
+    end[pos] += 1;
+    Some(end)
+}
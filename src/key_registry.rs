@@ -0,0 +1,427 @@
+use crate::checksum;
+use crate::env::{Env, IoPriority, OpenMode, WritableFile};
+use crate::{AgateOptions, Error, ErrorContext, Result};
+
+use aes::cipher::{NewCipher, StreamCipher};
+use aes::{Aes128Ctr, Aes192Ctr, Aes256Ctr};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use prost::Message;
+use proto::meta::DataKey;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+pub(crate) const KEY_REGISTRY_FILE_NAME: &str = "KEYREGISTRY";
+const KEY_REGISTRY_REWRITE_FILE_NAME: &str = "REWRITE-KEYREGISTRY";
+/// Encrypted with master key at the beginning of the registry, so a wrong
+/// master key can be detected.
+const SANITY_TEXT: &[u8] = b"Hello Agate";
+const IV_SIZE: usize = 16;
+
+/// Check `key` can be used with AES, empty key means no encryption.
+pub(crate) fn validate_key(key: &[u8]) -> Result<()> {
+    if !matches!(key.len(), 0 | 16 | 24 | 32) {
+        return Err(Error::Config(format!(
+            "encryption key must be 16, 24 or 32 bytes for AES-128/192/256, got {} bytes",
+            key.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Encrypt or decrypt `data` in place with AES in counter mode.
+pub(crate) fn xor_block(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<()> {
+    let invalid = || Error::Config(format!("invalid AES key length {}", key.len()));
+    match key.len() {
+        16 => Aes128Ctr::new_from_slices(key, iv)
+            .map_err(|_| invalid())?
+            .apply_keystream(data),
+        24 => Aes192Ctr::new_from_slices(key, iv)
+            .map_err(|_| invalid())?
+            .apply_keystream(data),
+        32 => Aes256Ctr::new_from_slices(key, iv)
+            .map_err(|_| invalid())?
+            .apply_keystream(data),
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+fn generate_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+struct KeyRegistryCore {
+    /// Data keys in plain text.
+    data_keys: HashMap<u64, DataKey>,
+    next_key_id: u64,
+    /// Unix time when the latest data key is created.
+    last_created: i64,
+    file: Option<Box<dyn WritableFile>>,
+}
+
+/// `KeyRegistry` holds data keys used to encrypt WALs, value logs and SSTs.
+/// Data keys are stored in `KEYREGISTRY` file, encrypted with the master key
+/// `AgateOptions::encryption_key`, and a new one is generated every
+/// `encryption_key_rotation_duration`. Keys are referred by ID in files.
+///
+/// File format:
+///
+/// ```text
+/// +----------+------------------------+
+/// | IV (16B) | encrypted SANITY_TEXT  |
+/// +----------+------------------------+
+/// | length (4B) | crc32 (4B) | DataKey | ...
+/// +-------------+------------+---------+
+/// ```
+pub(crate) struct KeyRegistry {
+    opts: AgateOptions,
+    core: Mutex<KeyRegistryCore>,
+}
+
+impl KeyRegistry {
+    /// Open or create the key registry under `opts.dir`. Returns
+    /// `Error::EncryptionKeyMismatch` if `opts.encryption_key` is not the one
+    /// used before.
+    pub fn open(opts: &AgateOptions) -> Result<Self> {
+        validate_key(&opts.encryption_key)?;
+        let mut registry = Self {
+            opts: opts.clone(),
+            core: Mutex::new(KeyRegistryCore {
+                data_keys: HashMap::new(),
+                next_key_id: 1,
+                last_created: 0,
+                file: None,
+            }),
+        };
+        if opts.in_memory {
+            return Ok(registry);
+        }
+
+        let path = opts.dir.join(KEY_REGISTRY_FILE_NAME);
+        let context = || ErrorContext::path(&path);
+        if !opts.env.exists(&path) {
+            if opts.read_only {
+                return Ok(registry);
+            }
+            let file = write_registry(
+                opts.env.as_ref(),
+                &opts.dir,
+                &HashMap::new(),
+                &opts.encryption_key,
+            )
+            .map_err(|e| e.with_context(context()))?;
+            registry.core.get_mut()?.file = Some(file);
+            return Ok(registry);
+        }
+
+        let data = opts
+            .env
+            .read_file(&path)
+            .map_err(|e| e.with_context(context()))?;
+        let (data_keys, valid_len) =
+            read_registry(data, &opts.encryption_key).map_err(|e| e.with_context(context()))?;
+        let core = registry.core.get_mut()?;
+        for key in data_keys.values() {
+            core.next_key_id = core.next_key_id.max(key.key_id + 1);
+            core.last_created = core.last_created.max(key.created_at);
+        }
+        core.data_keys = data_keys;
+        if !opts.read_only {
+            let mut file =
+                opts.env
+                    .open_writable(&path, OpenMode::Existing, IoPriority::Foreground)?;
+            // Drop a half-written key at the end, which is never used.
+            file.set_len(valid_len as u64)?;
+            file.seek(SeekFrom::End(0))?;
+            core.file = Some(file);
+        }
+        Ok(registry)
+    }
+
+    /// Get the data key to encrypt new files with, which is rotated when it's
+    /// older than the rotation duration. Returns `None` if encryption is
+    /// disabled.
+    pub fn latest_data_key(&self) -> Result<Option<DataKey>> {
+        let encryption_key = &self.opts.encryption_key;
+        if encryption_key.is_empty() {
+            return Ok(None);
+        }
+        let now = self.opts.clock.unix_time() as i64;
+        let mut core = self.core.lock()?;
+        let latest_id = core.next_key_id - 1;
+        if let Some(key) = core.data_keys.get(&latest_id) {
+            let rotation_secs = self.opts.encryption_key_rotation_duration.as_secs();
+            if now.saturating_sub(core.last_created) < rotation_secs as i64 {
+                return Ok(Some(key.clone()));
+            }
+        }
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let key = DataKey {
+            key_id: core.next_key_id,
+            data: generate_bytes(encryption_key.len()),
+            iv: generate_bytes(IV_SIZE),
+            created_at: now,
+        };
+        if let Some(file) = core.file.as_mut() {
+            let buf = encode_data_key(&key, encryption_key)?;
+            file.write_all(&buf)?;
+            file.sync_all()?;
+        }
+        core.next_key_id += 1;
+        core.last_created = now;
+        core.data_keys.insert(key.key_id, key.clone());
+        Ok(Some(key))
+    }
+
+    /// Write all data keys to a new registry in `dir`, encrypted with the
+    /// same master key.
+    pub fn write_to(&self, dir: &Path) -> Result<()> {
+        let core = self.core.lock()?;
+        write_registry(
+            self.opts.env.as_ref(),
+            dir,
+            &core.data_keys,
+            &self.opts.encryption_key,
+        )?;
+        Ok(())
+    }
+
+    /// Get data key by ID. ID 0 means the file is not encrypted, in which
+    /// case `None` is returned.
+    pub fn data_key(&self, key_id: u64) -> Result<Option<DataKey>> {
+        if key_id == 0 {
+            return Ok(None);
+        }
+        match self.core.lock()?.data_keys.get(&key_id) {
+            Some(key) => Ok(Some(key.clone())),
+            None => Err(Error::InvalidDataKeyId(key_id)),
+        }
+    }
+}
+
+/// Re-encrypt key registry in `dir` with `new_key`, which must not be opened.
+/// Data keys are kept, so data files don't need to be rewritten. Returns
+/// `Error::EncryptionKeyMismatch` if `old_key` is wrong.
+pub(crate) fn rotate_master_key(
+    env: &dyn Env,
+    dir: &Path,
+    old_key: &[u8],
+    new_key: &[u8],
+) -> Result<()> {
+    validate_key(old_key)?;
+    validate_key(new_key)?;
+    let path = dir.join(KEY_REGISTRY_FILE_NAME);
+    let context = || ErrorContext::path(&path);
+    let data = env
+        .read_file(&path)
+        .map_err(|e| e.with_context(context()))?;
+    let (data_keys, _) = read_registry(data, old_key).map_err(|e| e.with_context(context()))?;
+    write_registry(env, dir, &data_keys, new_key)?;
+    Ok(())
+}
+
+/// Encode `key` as an entry of key registry, with data encrypted by
+/// `encryption_key`.
+fn encode_data_key(key: &DataKey, encryption_key: &[u8]) -> Result<BytesMut> {
+    let mut key = key.clone();
+    if !encryption_key.is_empty() {
+        xor_block(&mut key.data, encryption_key, &key.iv)?;
+    }
+    let mut key_buf = BytesMut::new();
+    key.encode(&mut key_buf).unwrap();
+    let mut buf = BytesMut::with_capacity(8 + key_buf.len());
+    buf.put_u32(key_buf.len() as u32);
+    buf.put_u32(checksum::crc32c(&key_buf));
+    buf.put_slice(&key_buf);
+    Ok(buf)
+}
+
+/// Decode all data keys in key registry, returning them together with the
+/// length of valid data.
+fn read_registry(mut buf: Bytes, encryption_key: &[u8]) -> Result<(HashMap<u64, DataKey>, usize)> {
+    if buf.len() < IV_SIZE + SANITY_TEXT.len() {
+        return Err(Error::CustomError("key registry is truncated".to_string()));
+    }
+    let iv = buf.split_to(IV_SIZE);
+    let mut sanity = buf.split_to(SANITY_TEXT.len()).to_vec();
+    if !encryption_key.is_empty() {
+        xor_block(&mut sanity, encryption_key, &iv)?;
+    }
+    if sanity != SANITY_TEXT {
+        return Err(Error::EncryptionKeyMismatch);
+    }
+
+    let mut data_keys = HashMap::new();
+    let mut offset = IV_SIZE + SANITY_TEXT.len();
+    while buf.remaining() >= 8 {
+        let length = buf.get_u32() as usize;
+        let checksum = buf.get_u32();
+        if buf.remaining() < length {
+            // a half-written key, which should be truncated
+            break;
+        }
+        let data = buf.split_to(length);
+        if checksum::crc32c(&data) != checksum {
+            return Err(Error::InvalidChecksum(
+                "key registry has checksum mismatch".to_string(),
+            ));
+        }
+        let mut key = DataKey::decode(data)?;
+        if !encryption_key.is_empty() {
+            xor_block(&mut key.data, encryption_key, &key.iv)?;
+        }
+        data_keys.insert(key.key_id, key);
+        offset += 8 + length;
+    }
+    Ok((data_keys, offset))
+}
+
+/// Write a new key registry containing `data_keys` encrypted with
+/// `encryption_key`, and atomically replace the old one.
+fn write_registry(
+    env: &dyn Env,
+    dir: &Path,
+    data_keys: &HashMap<u64, DataKey>,
+    encryption_key: &[u8],
+) -> Result<Box<dyn WritableFile>> {
+    let mut buf = BytesMut::new();
+    let iv = generate_bytes(IV_SIZE);
+    let mut sanity = SANITY_TEXT.to_vec();
+    if !encryption_key.is_empty() {
+        xor_block(&mut sanity, encryption_key, &iv)?;
+    }
+    buf.put_slice(&iv);
+    buf.put_slice(&sanity);
+    let mut ids: Vec<_> = data_keys.keys().collect();
+    ids.sort_unstable();
+    for id in ids {
+        buf.put_slice(&encode_data_key(&data_keys[id], encryption_key)?);
+    }
+
+    let rewrite_path = dir.join(KEY_REGISTRY_REWRITE_FILE_NAME);
+    let mut file = env.open_writable(&rewrite_path, OpenMode::Truncate, IoPriority::Foreground)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    drop(file);
+
+    let path = dir.join(KEY_REGISTRY_FILE_NAME);
+    env.rename(&rewrite_path, &path)?;
+    let mut file = env.open_writable(&path, OpenMode::Existing, IoPriority::Foreground)?;
+    file.seek(SeekFrom::End(0))?;
+    env.sync_dir(dir)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::StdEnv;
+    use crate::ManualClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_key_registry() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let clock = Arc::new(ManualClock::new(1000));
+        let mut opts = AgateOptions::default()
+            .with_encryption_key(vec![7; 32], Duration::from_secs(100))
+            .with_clock(clock.clone());
+        opts.dir = tmp_dir.path().to_path_buf();
+
+        let registry = KeyRegistry::open(&opts).unwrap();
+        assert_eq!(registry.data_key(0).unwrap(), None);
+        let first = registry.latest_data_key().unwrap().unwrap();
+        assert_eq!(first.key_id, 1);
+        assert_eq!(first.data.len(), 32);
+        assert_eq!(registry.latest_data_key().unwrap(), Some(first.clone()));
+        // Key is rotated after the duration.
+        clock.advance(Duration::from_secs(100));
+        let second = registry.latest_data_key().unwrap().unwrap();
+        assert_eq!(second.key_id, 2);
+        assert_ne!(second.data, first.data);
+        assert!(registry.data_key(3).is_err());
+        drop(registry);
+
+        // Keys are not stored in plain text.
+        let data = std::fs::read(tmp_dir.path().join(KEY_REGISTRY_FILE_NAME)).unwrap();
+        assert!(!data.windows(32).any(|w| w == &first.data[..]));
+
+        let registry = KeyRegistry::open(&opts).unwrap();
+        assert_eq!(registry.data_key(1).unwrap(), Some(first));
+        assert_eq!(registry.latest_data_key().unwrap(), Some(second));
+        drop(registry);
+
+        let wrong_key = opts
+            .clone()
+            .with_encryption_key(vec![8; 32], Duration::from_secs(100));
+        assert!(matches!(
+            KeyRegistry::open(&wrong_key).err().unwrap(),
+            Error::Context { source, .. } if matches!(*source, Error::EncryptionKeyMismatch)
+        ));
+        let no_key = opts
+            .clone()
+            .with_encryption_key(vec![], Duration::from_secs(100));
+        assert!(KeyRegistry::open(&no_key).is_err());
+        let bad_len = opts.with_encryption_key(vec![7; 10], Duration::from_secs(100));
+        assert!(matches!(
+            KeyRegistry::open(&bad_len).err().unwrap(),
+            Error::Config(_)
+        ));
+    }
+
+    #[test]
+    fn test_rotate_master_key() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let open = |key: &[u8]| {
+            let mut opts =
+                AgateOptions::default().with_encryption_key(key.to_vec(), Duration::from_secs(100));
+            opts.dir = tmp_dir.path().to_path_buf();
+            KeyRegistry::open(&opts)
+        };
+        let old_key = [1; 16];
+        let new_key = [2; 32];
+        let registry = open(&old_key).unwrap();
+        let data_key = registry.latest_data_key().unwrap().unwrap();
+        drop(registry);
+
+        let env = StdEnv;
+        let dir = tmp_dir.path();
+        assert!(matches!(
+            rotate_master_key(&env, dir, &new_key, &old_key).err().unwrap(),
+            Error::Context { source, .. } if matches!(*source, Error::EncryptionKeyMismatch)
+        ));
+        rotate_master_key(&env, dir, &old_key, &new_key).unwrap();
+        assert!(open(&old_key).is_err());
+        let registry = open(&new_key).unwrap();
+        assert_eq!(registry.data_key(1).unwrap(), Some(data_key.clone()));
+        assert_eq!(registry.latest_data_key().unwrap(), Some(data_key));
+        drop(registry);
+
+        // Encryption can be turned off for the registry as well.
+        rotate_master_key(&env, dir, &new_key, &[]).unwrap();
+        assert!(open(&[]).is_ok());
+    }
+
+    #[test]
+    fn test_key_registry_without_encryption() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let registry = KeyRegistry::open(&opts).unwrap();
+        assert_eq!(registry.latest_data_key().unwrap(), None);
+        drop(registry);
+        KeyRegistry::open(&opts).unwrap();
+    }
+}
@@ -1,5 +1,1015 @@
 mod compaction;
 mod handler;
+mod level_zero;
+mod verify;
 
-use compaction::KeyRange;
+pub(crate) use compaction::COMPACTION_INTERVAL;
+use compaction::{get_key_range, get_key_range_single, CompactStatus, KeyRange};
 use handler::LevelHandler;
+use level_zero::LevelZeroController;
+pub(crate) use level_zero::LevelZeroThresholds;
+pub(crate) use verify::check_consistency;
+pub use verify::{ConsistencyIssue, ConsistencyReport, VerifyReport};
+
+use crate::cache::LevelCacheStats;
+use crate::comparator::Comparator;
+use crate::env::IoPriority;
+use crate::event::{TableCreationInfo, TableCreationReason, TableDeletionInfo};
+use crate::format::{get_ts, key_with_ts_first, user_key};
+use crate::iterator::IteratorOptions;
+use crate::manifest::{new_delete_change, new_table_create_change, Manifest, ManifestFile};
+use crate::opt::{build_table_options, ChecksumVerificationMode, Options};
+use crate::table::{self, builder::Builder, new_filename, PrefixStats, TableIterators};
+use crate::util::KeyComparator;
+use crate::value::Value;
+use crate::{AgateOptions, OpenProgressStage, Table};
+use crate::{Error, ErrorContext, Result};
+
+use bytes::Bytes;
+use log::{info, warn};
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::time::Instant;
+
+/// Estimated on-disk usage of a key range.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeEstimate {
+    /// bytes of SST blocks that may contain keys in range
+    pub bytes: u64,
+    /// number of keys (including all versions) in range
+    pub keys: u64,
+}
+
+/// Size of the database, computed from metadata of tables and value log
+/// files instead of walking the file system.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DbSize {
+    /// bytes of tables at every level, starting from L0
+    pub levels: Vec<u64>,
+    /// bytes of value log files, always 0 until value log is implemented
+    pub vlog: u64,
+}
+
+impl DbSize {
+    /// Bytes of tables at all levels.
+    pub fn lsm(&self) -> u64 {
+        self.levels.iter().sum()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.lsm() + self.vlog
+    }
+}
+
+/// Metadata of a table, see `Agate::levels`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableInfo {
+    pub id: u64,
+    pub level: usize,
+    /// smallest key with timestamp
+    pub smallest: Bytes,
+    /// biggest key with timestamp
+    pub biggest: Bytes,
+    /// bytes on disk
+    pub size: u64,
+    /// number of keys, including all versions
+    pub key_count: u32,
+    /// bytes of entries which can be dropped by compaction
+    pub stale_data_size: u32,
+    pub max_version: u64,
+}
+
+/// Metadata of a level and all its tables, see `Agate::levels`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct LevelInfo {
+    pub level: usize,
+    pub total_size: u64,
+    /// tables ordered by smallest key, except in L0, where tables are
+    /// ordered from the oldest to the newest
+    pub tables: Vec<TableInfo>,
+}
+
+pub struct LevelsController {
+    next_file_id: AtomicU64,
+    levels: Vec<Arc<RwLock<LevelHandler>>>,
+    opts: AgateOptions,
+    comparator: Comparator,
+    manifest: Arc<ManifestFile>,
+    /// Result of verifying tables on open, if enabled.
+    verify_report: Option<VerifyReport>,
+    level_zero: Mutex<LevelZeroController>,
+    /// Key ranges and tables being compacted.
+    compact_status: RwLock<CompactStatus>,
+    /// Notified when tables are moved out of L0, which wakes up flushes
+    /// waiting for room there.
+    level_zero_stall: (Mutex<()>, Condvar),
+}
+
+impl LevelsController {
+    /// Create levels and open all tables recorded in manifest.
+    pub fn new(opts: AgateOptions, manifest: Arc<ManifestFile>) -> Result<Self> {
+        assert!(opts.num_level_zero_tables_stall > opts.num_level_zero_tables);
+
+        let levels: Vec<_> = (0..opts.max_levels)
+            .map(|level| Arc::new(RwLock::new(LevelHandler::new(opts.clone(), level))))
+            .collect();
+
+        let mut lvctl = Self {
+            next_file_id: AtomicU64::new(0),
+            compact_status: RwLock::new(CompactStatus::new(levels.len())),
+            levels,
+            comparator: Comparator::new(opts.comparator.clone()),
+            opts,
+            manifest,
+            verify_report: None,
+            level_zero: Mutex::default(),
+            level_zero_stall: (Mutex::new(()), Condvar::new()),
+        };
+
+        if !lvctl.opts.in_memory {
+            lvctl.open_tables()?;
+        }
+
+        Ok(lvctl)
+    }
+
+    fn open_tables(&mut self) -> Result<()> {
+        let manifest = self.manifest.manifest_cloned();
+        revert_to_manifest(&self.opts, &manifest)?;
+
+        let table_opts = build_table_options(&self.opts);
+        let lazy = self.opts.lazy_load_table_index && table_opts.index_cache.is_some();
+        let mut max_file_id = 0;
+        let mut level_tables = vec![vec![]; self.levels.len()];
+
+        let total = manifest.tables.len();
+        self.opts
+            .report_open_progress(OpenProgressStage::Tables, 0, total);
+        let mut result = Ok(());
+        for (i, (id, tm)) in manifest.tables.iter().enumerate() {
+            let level = tm.level as usize;
+            if level >= self.levels.len() {
+                result = Err(Error::CustomError(format!(
+                    "table {} is at level {}, but there are only {} levels",
+                    id,
+                    level,
+                    self.levels.len()
+                )));
+                break;
+            }
+            if tm.key_id != 0 || tm.compression != 0 {
+                result = Err(Error::CustomError(format!(
+                    "table {} is encrypted or compressed, which is not supported",
+                    id
+                )));
+                break;
+            }
+            max_file_id = max_file_id.max(*id);
+            // TODO: verify checksum, encryption
+            let path = new_filename(*id, &self.opts.dir);
+            let res = if lazy && !tm.smallest.is_empty() {
+                Table::open_lazy(
+                    &path,
+                    table_opts.clone(),
+                    tm.smallest.clone(),
+                    tm.biggest.clone(),
+                    tm.max_version,
+                )
+            } else {
+                Table::open(&path, table_opts.clone())
+            };
+            match res {
+                Ok(table) => {
+                    level_tables[level].push(table);
+                    self.opts
+                        .report_open_progress(OpenProgressStage::Tables, i + 1, total);
+                }
+                Err(err) => {
+                    result = Err(err.with_context(ErrorContext::table(*id).with_path(path)));
+                    break;
+                }
+            }
+        }
+
+        if result.is_ok()
+            && matches!(
+                self.opts.checksum_verification_mode,
+                ChecksumVerificationMode::OnTableOpen
+            )
+        {
+            let tables: Vec<_> = level_tables.iter().flatten().cloned().collect();
+            self.opts
+                .report_open_progress(OpenProgressStage::VerifyTables, 0, tables.len());
+            let (report, err) =
+                verify::verify_tables(&tables, self.opts.num_verify_workers, |done| {
+                    self.opts.report_open_progress(
+                        OpenProgressStage::VerifyTables,
+                        done,
+                        tables.len(),
+                    )
+                });
+            info!(
+                "verified {} tables, {} blocks, {} bytes in {:?}, corrupted tables: {:?}",
+                report.tables,
+                report.blocks,
+                report.bytes,
+                report.duration,
+                report.corrupted_tables
+            );
+            self.verify_report = Some(report);
+            if let Some(err) = err {
+                result = Err(err);
+            }
+        }
+
+        if let Err(err) = result {
+            // Tables are deleted on drop by default. Keep them on disk as
+            // they are still referenced by manifest.
+            for table in level_tables.iter().flatten() {
+                table.mark_save();
+            }
+            return Err(err);
+        }
+
+        for (level, tables) in level_tables.into_iter().enumerate() {
+            self.write_level(level).init_tables(tables);
+        }
+
+        self.next_file_id.store(max_file_id + 1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Lock handler of `level` for reading. `LevelHandler` checks arguments
+    /// before modifying anything, so a panic while holding the lock doesn't
+    /// leave it half updated, and poisoned locks are recovered instead of
+    /// failing every following operation.
+    fn read_level(&self, level: usize) -> RwLockReadGuard<'_, LevelHandler> {
+        self.levels[level]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock handler of `level` for writing, see `read_level`.
+    fn write_level(&self, level: usize) -> RwLockWriteGuard<'_, LevelHandler> {
+        self.levels[level]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock status of running compactions for reading. Like level
+    /// handlers, poisoned locks are recovered, so that a panicking compactor
+    /// doesn't fail all following compactions.
+    fn read_compact_status(&self) -> RwLockReadGuard<'_, CompactStatus> {
+        self.compact_status
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock status of running compactions for writing, see
+    /// `read_compact_status`.
+    fn write_compact_status(&self) -> RwLockWriteGuard<'_, CompactStatus> {
+        self.compact_status
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Reserve a file ID for a new SST.
+    pub fn reserve_file_id(&self) -> u64 {
+        self.next_file_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Finish `builder` and create a table with `file_id`, either in memory
+    /// or on disk. Tables are written in background.
+    pub fn create_table(&self, mut builder: Builder, file_id: u64) -> Result<Table> {
+        let table_opts = Options {
+            io_priority: IoPriority::Background,
+            direct_io: self.opts.use_direct_io_for_flush_and_compaction,
+            ..build_table_options(&self.opts)
+        };
+        let data = builder.finish();
+
+        if self.opts.in_memory {
+            Table::open_in_memory(data, file_id, table_opts)
+        } else {
+            Table::create(&new_filename(file_id, &self.opts.dir), data, table_opts)
+        }
+    }
+
+    /// Add a newly flushed table to L0. The table will always be recorded to manifest first.
+    /// If L0 is full, wait for compactors to move tables out of it until
+    /// `deadline`, after which `Error::WriteStalled` is returned and nothing
+    /// is changed.
+    pub fn add_l0_table(&self, table: Table, deadline: Instant) -> Result<()> {
+        self.wait_for_level_zero_room(&table, deadline)?;
+        if !table.is_in_memory() {
+            self.manifest
+                .add_changes(vec![new_table_create_change(&table, 0)])?;
+        }
+
+        let now = self.opts.clock.now();
+        self.lock_level_zero().record_ingest(now, table.size());
+        // Tables are only added to L0 by flushes one at a time, so the room
+        // is still there.
+        self.write_level(0)
+            .try_add_l0_table(table.clone(), usize::MAX);
+        self.notify_table_created(&table, 0, TableCreationReason::Flush);
+
+        Ok(())
+    }
+
+    fn wait_for_level_zero_room(&self, table: &Table, deadline: Instant) -> Result<()> {
+        // Hold the lock while checking, so that notification from compactors
+        // won't be missed between checking and waiting.
+        let mut guard = self
+            .level_zero_stall
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut stalled = false;
+        loop {
+            let stall = self.level_zero_thresholds().stall;
+            if self.read_level(0).tables.len() < stall {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::WriteStalled);
+            }
+            if !stalled {
+                warn!("L0 is full, stalled adding table {}", table.id());
+                stalled = true;
+            }
+            guard = self
+                .level_zero_stall
+                .1
+                .wait_timeout(guard, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Wake up flushes waiting for room in L0.
+    fn notify_level_zero_room(&self) {
+        let _guard = self
+            .level_zero_stall
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.level_zero_stall.1.notify_all();
+    }
+
+    fn lock_level_zero(&self) -> MutexGuard<'_, LevelZeroController> {
+        self.level_zero
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Thresholds of L0 under current load, see
+    /// `AgateOptions::adaptive_level_zero`.
+    pub fn level_zero_thresholds(&self) -> LevelZeroThresholds {
+        let debt = self.read_level(0).total_size;
+        let now = self.opts.clock.now();
+        self.lock_level_zero().thresholds(&self.opts, now, debt)
+    }
+
+    /// Add tables built by bulk loading to the last level. Tables must not
+    /// overlap with each other or with existing tables of the level, and they
+    /// will be recorded to manifest in one change set.
+    pub fn add_bottom_tables(&self, mut tables: Vec<Table>) -> Result<()> {
+        if tables.is_empty() {
+            return Ok(());
+        }
+        let level = self.levels.len() - 1;
+        let mut handler = self.write_level(level);
+
+        let c = &self.comparator;
+        tables.sort_by(|x, y| c.compare_key(x.smallest(), y.smallest()));
+        for pair in tables.windows(2) {
+            if get_key_range_single(&pair[0]).overlaps_with(&get_key_range_single(&pair[1]), c) {
+                return Err(Error::CustomError(format!(
+                    "bulk loaded tables {} and {} overlap",
+                    pair[0].id(),
+                    pair[1].id()
+                )));
+            }
+        }
+        let range = get_key_range(&tables).unwrap();
+        if let Some(table) = handler
+            .tables
+            .iter()
+            .find(|t| get_key_range_single(t).overlaps_with(&range, c))
+        {
+            return Err(Error::CustomError(format!(
+                "bulk loaded tables overlap with table {} at level {}",
+                table.id(),
+                level
+            )));
+        }
+        if self.read_compact_status().overlaps_with(level, &range, c) {
+            return Err(Error::CustomError(format!(
+                "bulk loaded tables overlap with tables being compacted into level {}",
+                level
+            )));
+        }
+
+        fail::fail_point!("bulk_load_before_manifest", |_| Err(Error::CustomError(
+            "failpoint bulk_load_before_manifest".to_string()
+        )));
+        if !self.opts.in_memory {
+            let changes = tables
+                .iter()
+                .map(|t| new_table_create_change(t, level))
+                .collect();
+            self.manifest.add_changes(changes)?;
+        }
+        for table in &tables {
+            self.notify_table_created(table, level, TableCreationReason::BulkLoad);
+        }
+        handler.add_tables(tables);
+
+        Ok(())
+    }
+
+    /// Get tables of every level, starting from L0.
+    pub fn all_tables(&self) -> Vec<Vec<Table>> {
+        (0..self.levels.len())
+            .map(|level| self.read_level(level).tables.clone())
+            .collect()
+    }
+
+    /// Replace tables taken by `all_tables` with `tables` at the last level,
+    /// which should hold the same data. Tables flushed to L0 after taking
+    /// are kept. Returns an error if other levels are changed meanwhile.
+    pub fn flatten_tables(&self, old: &[Vec<Table>], mut tables: Vec<Table>) -> Result<()> {
+        let mut handlers: Vec<_> = (0..self.levels.len())
+            .map(|level| self.write_level(level))
+            .collect();
+        for (level, (handler, old)) in handlers.iter().zip(old).enumerate() {
+            // New tables are only appended to L0.
+            let unchanged = (handler.tables.len() == old.len()
+                || level == 0 && handler.tables.len() > old.len())
+                && handler
+                    .tables
+                    .iter()
+                    .zip(old)
+                    .all(|(x, y)| x.id() == y.id());
+            if !unchanged {
+                return Err(Error::CustomError(format!(
+                    "level {} is changed during flattening",
+                    level
+                )));
+            }
+        }
+
+        let last = self.levels.len() - 1;
+        if !self.opts.in_memory {
+            let mut changes: Vec<_> = old
+                .iter()
+                .flatten()
+                .map(|t| new_delete_change(t.id()))
+                .collect();
+            changes.extend(tables.iter().map(|t| new_table_create_change(t, last)));
+            self.manifest.add_changes(changes)?;
+        }
+        for table in &tables {
+            self.notify_table_created(table, last, TableCreationReason::Flatten);
+        }
+        for (handler, old) in handlers.iter_mut().zip(old) {
+            let removed: Vec<_> = if handler.level == 0 {
+                handler.tables.drain(..old.len()).collect()
+            } else {
+                handler.tables.drain(..).collect()
+            };
+            handler.total_size -= removed.iter().map(Table::size).sum::<u64>();
+        }
+        let c = &self.comparator;
+        tables.sort_by(|x, y| c.compare_key(x.smallest(), y.smallest()));
+        handlers[last].add_tables(tables);
+        drop(handlers);
+        self.notify_level_zero_room();
+
+        Ok(())
+    }
+
+    fn notify_table_created(&self, table: &Table, level: usize, reason: TableCreationReason) {
+        self.opts.notify(|l| {
+            l.on_table_created(&TableCreationInfo {
+                id: table.id(),
+                level,
+                size: table.size(),
+                reason,
+            })
+        });
+    }
+
+    /// Get value of `key` from all levels. `max_value` is the value with the
+    /// highest version found in memtables.
+    pub fn get(&self, key: &Bytes, mut max_value: Value) -> Result<Value> {
+        let version = get_ts(key);
+
+        for level in 0..self.levels.len() {
+            let value = self.read_level(level).get(key)?;
+            if value.value.is_empty() && value.meta == 0 {
+                continue;
+            }
+            if value.version == version {
+                return Ok(value);
+            }
+            if max_value.version < value.version {
+                max_value = value;
+            }
+        }
+
+        Ok(max_value)
+    }
+
+    /// Same as `get`, but looks up `keys[i]` for every `i` in `indices` in a
+    /// batch. `indices` should be sorted by key, and `values` holds values
+    /// with the highest versions found in memtables.
+    pub fn get_multi(&self, keys: &[Bytes], indices: &[usize], values: &mut [Value]) -> Result<()> {
+        let hashes: Vec<u32> = keys
+            .iter()
+            .map(|key| farmhash::fingerprint32(user_key(key)))
+            .collect();
+        let mut pending = indices.to_vec();
+
+        for level in 0..self.levels.len() {
+            // Exact versions are found already, no need to check lower levels.
+            pending.retain(|&i| values[i].version != get_ts(&keys[i]));
+            if pending.is_empty() {
+                break;
+            }
+            self.read_level(level)
+                .get_multi(keys, &hashes, &pending, values)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get user keys which split all tables, in ascending order, so that
+    /// ranges between them can be scanned in parallel.
+    pub(crate) fn key_splits(&self) -> Vec<Bytes> {
+        let mut splits = vec![];
+        for level in 0..self.levels.len() {
+            for table in &self.read_level(level).tables {
+                splits.push(Bytes::copy_from_slice(user_key(table.smallest())));
+            }
+        }
+        let c = &self.comparator;
+        splits.sort_unstable_by(|x, y| c.compare_user_key(x, y));
+        splits.dedup();
+        splits
+    }
+
+    /// Append iterators of tables picked by `opts` in all levels, where
+    /// newer data comes first.
+    pub(crate) fn append_iterators(&self, iters: &mut Vec<TableIterators>, opts: &IteratorOptions) {
+        for level in 0..self.levels.len() {
+            self.read_level(level).append_iterators(iters, opts);
+        }
+    }
+
+    /// Get the max version among all tables.
+    pub fn max_version(&self) -> Result<u64> {
+        let mut max_version = 0;
+        for level in 0..self.levels.len() {
+            for table in &self.read_level(level).tables {
+                max_version = max_version.max(table.max_version());
+            }
+        }
+        Ok(max_version)
+    }
+
+    pub fn verify_report(&self) -> Option<&VerifyReport> {
+        self.verify_report.as_ref()
+    }
+
+    /// Get a snapshot of metadata of all levels.
+    pub fn level_infos(&self) -> Result<Vec<LevelInfo>> {
+        let mut infos = Vec::with_capacity(self.levels.len());
+        for level in 0..self.levels.len() {
+            let handler = self.read_level(level);
+            let tables = handler
+                .tables
+                .iter()
+                .map(|table| TableInfo {
+                    id: table.id(),
+                    level,
+                    smallest: table.smallest().clone(),
+                    biggest: table.biggest().clone(),
+                    size: table.size(),
+                    key_count: table.key_count(),
+                    stale_data_size: table.stale_data_size(),
+                    max_version: table.max_version(),
+                })
+                .collect();
+            infos.push(LevelInfo {
+                level,
+                total_size: handler.total_size,
+                tables,
+            });
+        }
+        Ok(infos)
+    }
+
+    /// Get statistics of prefix compression of tables at every level.
+    pub fn level_prefix_stats(&self) -> Vec<PrefixStats> {
+        (0..self.levels.len())
+            .map(|level| {
+                let mut stats = PrefixStats::default();
+                for table in &self.read_level(level).tables {
+                    stats.merge(&table.prefix_stats());
+                }
+                stats
+            })
+            .collect()
+    }
+
+    /// Get cache lookups of tables at every level.
+    pub fn level_cache_stats(&self) -> Vec<LevelCacheStats> {
+        (0..self.levels.len())
+            .map(|level| {
+                let mut stats = LevelCacheStats {
+                    level,
+                    ..Default::default()
+                };
+                for table in &self.read_level(level).tables {
+                    let (block, index) =
+                        (table.block_cache_counters(), table.index_cache_counters());
+                    stats.block_cache_hits += block.hits();
+                    stats.block_cache_misses += block.misses();
+                    stats.index_cache_hits += index.hits();
+                    stats.index_cache_misses += index.misses();
+                }
+                stats
+            })
+            .collect()
+    }
+
+    /// Read blocks of tables picked by `pick` into block cache, only those
+    /// within `range` if it's set. Returns the number of read blocks.
+    pub fn warm_up(
+        &self,
+        pick: impl Fn(&Table) -> bool,
+        range: Option<(&[u8], &[u8])>,
+    ) -> Result<usize> {
+        let mut tables = vec![];
+        for level in 0..self.levels.len() {
+            let handler = self.read_level(level);
+            tables.extend(handler.tables.iter().filter(|t| pick(t)).cloned());
+        }
+        let mut count = 0;
+        for table in tables {
+            count += table.warm_up(range)?;
+        }
+        Ok(count)
+    }
+
+    pub(crate) fn comparator(&self) -> &Comparator {
+        &self.comparator
+    }
+
+    /// Get total size of tables at every level.
+    pub fn level_sizes(&self) -> Result<Vec<u64>> {
+        Ok((0..self.levels.len())
+            .map(|level| self.read_level(level).total_size)
+            .collect())
+    }
+
+    /// Estimate on-disk size and key count of user keys within `[start, end)`
+    /// by summing up table index metadata across all levels. Entries are never
+    /// scanned, so the result is only an approximation at block granularity.
+    pub fn estimate_size(&self, start: &[u8], end: &[u8]) -> SizeEstimate {
+        let start = key_with_ts_first(start);
+        let end = key_with_ts_first(end);
+
+        let mut estimate = SizeEstimate::default();
+        for level in 0..self.levels.len() {
+            let (bytes, keys) = self.read_level(level).estimate_range(&start, &end);
+            estimate.bytes += bytes;
+            estimate.keys += keys;
+        }
+        estimate
+    }
+}
+
+/// Check that all tables in manifest exist, and delete all SSTs which
+/// are not referenced by manifest unless opened in read-only mode.
+fn revert_to_manifest(opts: &AgateOptions, manifest: &Manifest) -> Result<()> {
+    let (env, dir) = (opts.env.as_ref(), opts.dir.as_path());
+    let sst_ids: HashSet<u64> = env
+        .list_dir(dir)?
+        .iter()
+        .filter_map(|name| table::parse_file_id(name).ok())
+        .collect();
+
+    for id in manifest.tables.keys() {
+        if !sst_ids.contains(id) {
+            return Err(Error::CustomError(format!(
+                "file does not exist for table {}",
+                id
+            )));
+        }
+    }
+
+    if opts.read_only {
+        return Ok(());
+    }
+    for id in sst_ids.difference(&manifest.tables.keys().copied().collect()) {
+        let path = new_filename(*id, dir);
+        warn!(
+            "table file {} not referenced in manifest, deleting",
+            path.display()
+        );
+        env.remove_file(&path)?;
+        opts.notify(|l| l.on_table_deleted(&TableDeletionInfo { id: *id, path }));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::key_with_ts;
+    use crate::table::tests::{build_table_data, get_test_table_options};
+    use crate::Table;
+    use bytes::Bytes;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn build_table(dir: &TempDir, id: u64, prefix: &str, n: usize) -> Table {
+        let kv_pairs = (0..n)
+            .map(|i| {
+                (
+                    Bytes::from(format!("{}{:04}", prefix, i)),
+                    Bytes::from(i.to_string()),
+                )
+            })
+            .collect();
+        let opts = get_test_table_options();
+        let data = build_table_data(kv_pairs, opts.clone());
+        Table::create(&crate::table::new_filename(id, dir.path()), data, opts).unwrap()
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let manifest = Arc::new(ManifestFile::open_or_create_manifest_file(&opts).unwrap());
+        let lvctl = LevelsController::new(opts, manifest).unwrap();
+
+        let t1 = build_table(&tmp_dir, 1, "a", 1000);
+        let t2 = build_table(&tmp_dir, 2, "b", 1000);
+        let (t1_size, t2_size) = (t1.size(), t2.size());
+        lvctl.levels[0].write().unwrap().init_tables(vec![t1]);
+        lvctl.levels[1].write().unwrap().init_tables(vec![t2]);
+
+        let all = lvctl.estimate_size(b"", b"z");
+        assert_eq!(all.keys, 2000);
+        assert!(all.bytes > 0 && all.bytes < t1_size + t2_size);
+
+        let a = lvctl.estimate_size(b"a", b"b");
+        assert_eq!(a.keys, 1000);
+        assert_eq!(a.bytes * 2, all.bytes);
+
+        let half = lvctl.estimate_size(b"b0000", b"b0500");
+        assert!(half.keys > 400 && half.keys < 600);
+        assert!(half.bytes < a.bytes);
+
+        assert_eq!(lvctl.estimate_size(b"c", b"d"), SizeEstimate::default());
+        assert_eq!(
+            lvctl.estimate_size(b"b0500", b"b0500"),
+            SizeEstimate::default()
+        );
+    }
+
+    #[test]
+    fn test_level_targets() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            max_levels: 4,
+            ..Default::default()
+        };
+        let manifest = Arc::new(ManifestFile::open_or_create_manifest_file(&opts).unwrap());
+        let mut lvctl = LevelsController::new(opts, manifest).unwrap();
+
+        // L0 goes to the last level while the database is empty.
+        let targets = lvctl.level_targets();
+        assert_eq!(targets.base_level, 3);
+        assert!(lvctl.pick_compact_levels(0).is_empty());
+
+        let table = build_table(&tmp_dir, 1, "a", 1000);
+        let size = table.size();
+        lvctl.write_level(3).init_tables(vec![table]);
+        lvctl.opts.base_level_size = size / 10;
+        lvctl.opts.base_table_size = 100;
+        let targets = lvctl.level_targets();
+        assert_eq!(targets.base_level, 2);
+        assert_eq!(targets.target_size[1..], [size / 10, size / 10, size]);
+        assert_eq!(targets.file_size[1..], [100, 100, 200]);
+
+        // A level over its target is picked, but not the last level.
+        lvctl
+            .write_level(2)
+            .init_tables(vec![build_table(&tmp_dir, 2, "b", 1000)]);
+        let prios = lvctl.pick_compact_levels(0);
+        assert_eq!(prios.len(), 1);
+        assert_eq!(prios[0].level, 2);
+        assert!(prios[0].score > 1.0);
+    }
+
+    #[test]
+    fn test_discard_score() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            max_levels: 4,
+            ..Default::default()
+        };
+        let manifest = Arc::new(ManifestFile::open_or_create_manifest_file(&opts).unwrap());
+        let lvctl = LevelsController::new(opts, manifest).unwrap();
+        lvctl
+            .write_level(3)
+            .init_tables(vec![build_table(&tmp_dir, 1, "a", 1000)]);
+
+        // Every key has a latest version and 3 stale ones.
+        let mut builder = Builder::new(get_test_table_options());
+        let value = Bytes::from(vec![b'v'; 100]);
+        for i in 0..100 {
+            let key = format!("b{:04}", i);
+            builder.add(&key_with_ts(key.as_str(), 4), Value::new(value.clone()), 0);
+            for ts in (1..4).rev() {
+                let key = key_with_ts(key.as_str(), ts);
+                builder.add_stale_key(&key, Value::new(value.clone()), 0);
+            }
+        }
+        let path = new_filename(2, tmp_dir.path());
+        let table = Table::create(&path, builder.finish(), get_test_table_options()).unwrap();
+        lvctl.write_level(2).init_tables(vec![table]);
+
+        // Stale versions can't be dropped while they may still be read.
+        assert!(lvctl.pick_compact_levels(3).is_empty());
+        let prios = lvctl.pick_compact_levels(4);
+        assert_eq!(prios.len(), 1);
+        assert_eq!(prios[0].level, 2);
+        assert!(prios[0].score > 1.0);
+    }
+
+    #[test]
+    fn test_poisoned_level() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let manifest = Arc::new(ManifestFile::open_or_create_manifest_file(&opts).unwrap());
+        let lvctl = LevelsController::new(opts, manifest).unwrap();
+        lvctl
+            .write_level(1)
+            .init_tables(vec![build_table(&tmp_dir, 1, "a", 10)]);
+
+        std::thread::scope(|s| {
+            let res = s
+                .spawn(|| {
+                    let _handler = lvctl.write_level(1);
+                    panic!("panic while holding level lock");
+                })
+                .join();
+            assert!(res.is_err());
+        });
+        assert!(lvctl.levels[1].is_poisoned());
+
+        let key = key_with_ts(b"a0005" as &[u8], u64::MAX);
+        lvctl.get(&key, Value::default()).unwrap();
+        assert_eq!(lvctl.level_infos().unwrap()[1].tables[0].key_count, 10);
+        lvctl.write_level(1).init_tables(vec![]);
+        assert_eq!(lvctl.level_sizes().unwrap()[1], 0);
+
+        std::thread::scope(|s| {
+            let res = s
+                .spawn(|| {
+                    let _status = lvctl.write_compact_status();
+                    panic!("panic while holding compaction status lock");
+                })
+                .join();
+            assert!(res.is_err());
+        });
+        assert!(lvctl.compact_status.is_poisoned());
+        assert!(!lvctl.compact(0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_level_zero_stall() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            num_level_zero_tables: 1,
+            num_level_zero_tables_stall: 2,
+            ..Default::default()
+        };
+        let manifest = Arc::new(ManifestFile::open_or_create_manifest_file(&opts).unwrap());
+        let lvctl = LevelsController::new(opts, manifest.clone()).unwrap();
+        let now = Instant::now();
+        for id in 1..=2 {
+            lvctl
+                .add_l0_table(build_table(&tmp_dir, id, "a", 10), now)
+                .unwrap();
+        }
+
+        // Nothing is changed if L0 is still full at deadline.
+        let table = build_table(&tmp_dir, 3, "a", 10);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let err = lvctl.add_l0_table(table.clone(), deadline).unwrap_err();
+        assert!(matches!(err, Error::WriteStalled), "{:?}", err);
+        assert_eq!(lvctl.read_level(0).num_tables(), 2);
+        assert_eq!(manifest.manifest_cloned().tables.len(), 2);
+
+        // Waiting flush is woken up once tables are moved out of L0.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                lvctl.write_level(0).init_tables(vec![]);
+                lvctl.notify_level_zero_room();
+            });
+            let deadline = Instant::now() + Duration::from_secs(10);
+            lvctl.add_l0_table(table, deadline).unwrap();
+        });
+        assert_eq!(lvctl.read_level(0).num_tables(), 1);
+    }
+
+    #[test]
+    fn test_get_from_levels() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let manifest = Arc::new(ManifestFile::open_or_create_manifest_file(&opts).unwrap());
+        let lvctl = LevelsController::new(opts, manifest).unwrap();
+        let build = |id: u64, kvs: &[(&str, u64)]| {
+            let opts = get_test_table_options();
+            let mut builder = crate::TableBuilder::new(opts.clone());
+            for (key, ts) in kvs {
+                let value = Bytes::from(format!("{}@{}", key, ts));
+                builder.add(
+                    &key_with_ts(*key, *ts),
+                    Value::new_with_meta(value, 0, 0),
+                    0,
+                );
+            }
+            let path = crate::table::new_filename(id, tmp_dir.path());
+            Table::create(&path, builder.finish(), opts).unwrap()
+        };
+        // Versions of "c" are split across tables.
+        lvctl.write_level(1).init_tables(vec![
+            build(3, &[("c", 3), ("d", 2)]),
+            build(1, &[("a", 1), ("b", 1)]),
+            build(4, &[("f", 1)]),
+            build(2, &[("c", 5), ("c", 4)]),
+        ]);
+        // The newer table of L0 has an older version.
+        lvctl
+            .write_level(0)
+            .init_tables(vec![build(5, &[("x", 3)]), build(6, &[("x", 2)])]);
+
+        let cases = vec![
+            ("0", u64::MAX, ""),
+            ("a", u64::MAX, "a@1"),
+            ("b", u64::MAX, "b@1"),
+            ("c", u64::MAX, "c@5"),
+            ("c", 4, "c@4"),
+            ("c", 3, "c@3"),
+            ("c", 2, ""),
+            ("d", u64::MAX, "d@2"),
+            ("e", u64::MAX, ""),
+            ("f", u64::MAX, "f@1"),
+            ("g", u64::MAX, ""),
+            ("x", u64::MAX, "x@3"),
+            ("x", 2, "x@2"),
+            ("x", 1, ""),
+        ];
+        let mut keys = vec![];
+        for (key, ts, expected) in &cases {
+            let key = key_with_ts(*key, *ts);
+            let value = lvctl.get(&key, Value::default()).unwrap();
+            assert_eq!(value.value, expected, "{:?}", key);
+            keys.push(key);
+        }
+        let indices: Vec<_> = (0..keys.len()).collect();
+        let mut values = vec![Value::default(); keys.len()];
+        lvctl.get_multi(&keys, &indices, &mut values).unwrap();
+        for ((key, ts, expected), value) in cases.iter().zip(&values) {
+            assert_eq!(value.value, expected, "{}@{}", key, ts);
+        }
+    }
+}
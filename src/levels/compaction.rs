@@ -1,13 +1,33 @@
 use std::collections::HashSet;
 
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use bytes::Bytes;
+use log::info;
+
+use super::{LevelHandler, LevelsController};
+use crate::comparator::Comparator;
+use crate::event::{CompactionInfo, TableCreationReason};
+use crate::format::{get_ts, key_with_ts_first, key_with_ts_last, user_key};
+use crate::iterator_trait::AgateIterator;
+use crate::manifest::{new_delete_change, new_table_create_change};
+use crate::opt::build_level_table_options;
+use crate::table::builder::Builder;
+use crate::table::{ConcatIterator, MergeIterator, TableIterators, ITERATOR_NOCACHE};
+use crate::util::{same_key, KeyComparator};
+use crate::value::{VALUE_DELETE, VALUE_MERGE_ENTRY};
+use crate::{Error, Result, Table};
 
-use bytes::{Bytes, BytesMut};
+/// Interval of compactors checking whether any level needs compaction.
+pub(crate) const COMPACTION_INTERVAL: Duration = Duration::from_millis(50);
 
-use super::LevelHandler;
-use crate::format::{key_with_ts_first, key_with_ts_last, user_key};
-use crate::util::{KeyComparator, COMPARATOR};
-use crate::{Error, Result, Table};
+/// Adjusted scores are divided by at least this score of the level below.
+const MIN_ADJUSTED_SCORE: f64 = 0.01;
+
+/// A level is compacted once data that can be discarded takes up this ratio
+/// of its size, even if it's not over its target.
+const DISCARD_RATIO: f64 = 0.5;
 
 /// Represents a range of keys from `left` to `right`
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -18,14 +38,14 @@ pub enum KeyRange {
 }
 
 impl KeyRange {
-    pub fn new(left: Bytes, right: Bytes) -> Self {
+    pub fn new(left: Bytes, right: Bytes, c: &Comparator) -> Self {
         // left must <= right
-        assert!(COMPARATOR.compare_key(&left, &right) != std::cmp::Ordering::Greater);
+        assert!(c.compare_key(&left, &right) != std::cmp::Ordering::Greater);
         Self::Range { left, right }
     }
 
-    /// Extend current key range with another
-    pub fn extend(&self, other: &Self) -> Self {
+    /// Extend current key range with another, where keys are ordered by `c`
+    pub fn extend(&self, other: &Self, c: &Comparator) -> Self {
         use KeyRange::{Empty, Inf, Range};
         match (self, other) {
             (current, Empty) => current.clone(),
@@ -41,28 +61,26 @@ impl KeyRange {
                     right: other_right,
                 },
             ) => {
-                let left =
-                    if COMPARATOR.compare_key(other_left, self_left) == std::cmp::Ordering::Less {
-                        other_left
-                    } else {
-                        self_left
-                    }
-                    .clone();
-                let right = if COMPARATOR.compare_key(other_right, self_right)
-                    == std::cmp::Ordering::Greater
-                {
-                    other_right
+                let left = if c.compare_key(other_left, self_left) == std::cmp::Ordering::Less {
+                    other_left
                 } else {
-                    self_right
+                    self_left
                 }
                 .clone();
+                let right =
+                    if c.compare_key(other_right, self_right) == std::cmp::Ordering::Greater {
+                        other_right
+                    } else {
+                        self_right
+                    }
+                    .clone();
                 Range { left, right }
             }
         }
     }
 
-    /// Check if two key ranges overlap
-    pub fn overlaps_with(&self, other: &Self) -> bool {
+    /// Check if two key ranges overlap, where keys are ordered by `c`
+    pub fn overlaps_with(&self, other: &Self, c: &Comparator) -> bool {
         use KeyRange::{Empty, Inf, Range};
         match (self, other) {
             // If my range is empty, other ranges always overlap with me
@@ -81,11 +99,11 @@ impl KeyRange {
                 },
             ) => {
                 // [other_left, other_right] ... [self_left, self_right]
-                if COMPARATOR.compare_key(other_right, self_left) == std::cmp::Ordering::Less {
+                if c.compare_key(other_right, self_left) == std::cmp::Ordering::Less {
                     return false;
                 }
                 // [self_left, self_right] ... [other_left, other_right]
-                if COMPARATOR.compare_key(self_right, other_left) == std::cmp::Ordering::Less {
+                if c.compare_key(self_right, other_left) == std::cmp::Ordering::Less {
                     return false;
                 }
                 true
@@ -118,8 +136,8 @@ impl LevelCompactStatus {
         prev_ranges_len != self.ranges.len()
     }
 
-    pub fn overlaps_with(&self, dst: &KeyRange) -> bool {
-        self.ranges.iter().any(|r| r.overlaps_with(dst))
+    pub fn overlaps_with(&self, dst: &KeyRange, c: &Comparator) -> bool {
+        self.ranges.iter().any(|r| r.overlaps_with(dst, c))
     }
 }
 
@@ -186,6 +204,15 @@ impl CompactDef {
 }
 
 impl CompactStatus {
+    pub fn new(num_levels: usize) -> Self {
+        Self {
+            levels: (0..num_levels)
+                .map(|_| LevelCompactStatus::default())
+                .collect(),
+            tables: HashSet::new(),
+        }
+    }
+
     pub fn delete(&mut self, compact_def: &CompactDef) {
         let this_level_id = compact_def.this_level_id;
         assert!(
@@ -198,7 +225,6 @@ impl CompactStatus {
 
         this_level.del_size -= compact_def.this_size;
         let mut found = this_level.remove(&compact_def.this_range);
-        drop(this_level);
 
         if !compact_def.next_range.is_empty() {
             let next_level = &mut self.levels[next_level_id];
@@ -220,7 +246,7 @@ impl CompactStatus {
         }
     }
 
-    pub fn compare_and_add(&mut self, compact_def: &CompactDef) -> Result<()> {
+    pub fn compare_and_add(&mut self, compact_def: &CompactDef, c: &Comparator) -> Result<()> {
         let this_level = compact_def.this_level_id;
         assert!(
             this_level < self.levels.len() - 1,
@@ -228,13 +254,13 @@ impl CompactStatus {
         );
 
         let next_level = compact_def.next_level_id;
-        if self.levels[this_level].overlaps_with(&compact_def.this_range) {
+        if self.levels[this_level].overlaps_with(&compact_def.this_range, c) {
             return Err(Error::CompactionError(format!(
                 "{:?} overlap with this level {} {:?}",
                 compact_def.this_range, compact_def.this_level_id, self.levels[this_level].ranges
             )));
         }
-        if self.levels[next_level].overlaps_with(&compact_def.next_range) {
+        if self.levels[next_level].overlaps_with(&compact_def.next_range, c) {
             return Err(Error::CompactionError(format!(
                 "{:?} overlap with next level {} {:?}",
                 compact_def.next_range, compact_def.next_level_id, self.levels[next_level].ranges
@@ -246,9 +272,13 @@ impl CompactStatus {
         self.levels[this_level]
             .ranges
             .push(compact_def.this_range.clone());
-        self.levels[next_level]
-            .ranges
-            .push(compact_def.next_range.clone());
+        // An empty range would overlap with every range, `delete` doesn't
+        // remove it either.
+        if !compact_def.next_range.is_empty() {
+            self.levels[next_level]
+                .ranges
+                .push(compact_def.next_range.clone());
+        }
 
         self.levels[this_level].del_size += compact_def.this_size;
 
@@ -263,9 +293,9 @@ impl CompactStatus {
         Ok(())
     }
 
-    pub fn overlaps_with(&self, level: usize, this: &KeyRange) -> bool {
+    pub fn overlaps_with(&self, level: usize, this: &KeyRange, c: &Comparator) -> bool {
         let this_level = &self.levels[level];
-        this_level.overlaps_with(this)
+        this_level.overlaps_with(this, c)
     }
 }
 
@@ -300,25 +330,23 @@ pub fn get_key_range(tables: &[Table]) -> Option<KeyRange> {
         return None;
     }
 
+    let c = tables[0].comparator();
     let mut smallest = tables[0].smallest();
     let mut biggest = tables[0].biggest();
 
     for i in 1..tables.len() {
-        if COMPARATOR.compare_key(tables[i].smallest(), &smallest) == std::cmp::Ordering::Less {
+        if c.compare_key(tables[i].smallest(), smallest) == std::cmp::Ordering::Less {
             smallest = tables[i].smallest();
         }
-        if COMPARATOR.compare_key(tables[i].biggest(), &biggest) == std::cmp::Ordering::Greater {
+        if c.compare_key(tables[i].biggest(), biggest) == std::cmp::Ordering::Greater {
             biggest = tables[i].biggest();
         }
     }
-    let mut smallest_buf = BytesMut::with_capacity(smallest.len() + 8);
-    let mut biggest_buf = BytesMut::with_capacity(biggest.len() + 8);
-    smallest_buf.extend_from_slice(user_key(&smallest));
-    biggest_buf.extend_from_slice(user_key(&biggest));
     return Some(KeyRange::new(
-        key_with_ts_first(smallest_buf),
+        key_with_ts_first(user_key(smallest)),
         // the appended key will be `<biggest_key><u64::MAX>`.
-        key_with_ts_last(biggest_buf),
+        key_with_ts_last(user_key(biggest)),
+        c,
     ));
 }
 
@@ -326,86 +354,602 @@ pub fn get_key_range_single(table: &Table) -> KeyRange {
     get_key_range(std::slice::from_ref(table)).unwrap()
 }
 
+impl LevelsController {
+    /// Run a compaction of the level which needs it the most. Compactor 0
+    /// always tries L0 first, so that flushes are not stalled by compactions
+    /// of other levels. Versions not newer than `discard_ts` are visible to
+    /// no reads except the newest one of every key, so older ones are
+    /// dropped. Returns whether a compaction is done.
+    pub fn compact(&self, compactor_id: usize, discard_ts: u64) -> Result<bool> {
+        if self.levels.len() < 2 {
+            return Ok(false);
+        }
+        let mut prios = self.pick_compact_levels(discard_ts);
+        if compactor_id == 0 {
+            if let Some(pos) = prios.iter().position(|p| p.level == 0) {
+                let prio = prios.remove(pos);
+                prios.insert(0, prio);
+            }
+        }
+        for prio in prios {
+            if prio.adjusted < 1.0 && !(compactor_id == 0 && prio.level == 0) {
+                break;
+            }
+            if self.do_compact(compactor_id, prio, discard_ts)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Get target sizes of levels and sizes of their tables. Targets are
+    /// computed backwards from the size of the last level, and L0 is
+    /// compacted into the base level, which is the first level whose target
+    /// is not greater than `base_level_size`, or the last empty level below
+    /// it. So data goes to lower levels directly while the database is small.
+    pub(crate) fn level_targets(&self) -> Targets {
+        let num_levels = self.levels.len();
+        let base_size = self.opts.base_level_size;
+        let mut targets = Targets {
+            base_level: 0,
+            target_size: vec![0; num_levels],
+            file_size: vec![0; num_levels],
+        };
+
+        let mut db_size = self.read_level(num_levels - 1).total_size;
+        for level in (1..num_levels).rev() {
+            let target = db_size.max(base_size);
+            targets.target_size[level] = target;
+            if targets.base_level == 0 && target <= base_size {
+                targets.base_level = level;
+            }
+            db_size /= self.opts.level_size_multiplier as u64;
+        }
+        targets.base_level = targets.base_level.max(1);
+
+        let mut table_size = self.opts.base_table_size;
+        for level in 0..num_levels {
+            targets.file_size[level] = if level == 0 {
+                self.opts.mem_table_size
+            } else if level <= targets.base_level {
+                table_size
+            } else {
+                table_size *= self.opts.table_size_multiplier as u64;
+                table_size
+            };
+        }
+
+        for level in targets.base_level + 1..num_levels - 1 {
+            if self.read_level(level).total_size > 0 {
+                break;
+            }
+            targets.base_level = level;
+        }
+        let base = targets.base_level;
+        if base < num_levels - 1
+            && self.read_level(base).total_size == 0
+            && self.read_level(base + 1).total_size < targets.target_size[base + 1]
+        {
+            targets.base_level += 1;
+        }
+        targets
+    }
+
+    /// Get levels whose scores are at least 1, in descending order of
+    /// adjusted scores. Score of L0 is its number of tables against the
+    /// compaction threshold, and scores of other levels are their sizes not
+    /// being compacted against their targets, or their discardable stale
+    /// data against `DISCARD_RATIO` if that's higher. A level is adjusted
+    /// down by the score of the level below it, so that the lower level is
+    /// compacted first if it's fuller, instead of being filled further.
+    ///
+    /// This is only the table half of discard based scoring: value log
+    /// discard stats are not taken into account, as there is no value log
+    /// yet, so levels referencing mostly discarded value log files are not
+    /// boosted.
+    pub(crate) fn pick_compact_levels(&self, discard_ts: u64) -> Vec<CompactionPriority> {
+        let targets = self.level_targets();
+        let l0_trigger = self.level_zero_thresholds().compaction;
+        let sizes: Vec<_> = (0..self.levels.len())
+            .map(|level| {
+                let handler = self.read_level(level);
+                (
+                    handler.num_tables(),
+                    handler.total_size,
+                    handler.discardable_size(discard_ts),
+                )
+            })
+            .collect();
+
+        let status = self.read_compact_status();
+        let mut prios: Vec<_> = sizes
+            .iter()
+            .enumerate()
+            .map(|(level, &(num_tables, total_size, discardable))| {
+                let score = if level == 0 {
+                    num_tables as f64 / l0_trigger as f64
+                } else {
+                    let size = total_size.saturating_sub(status.levels[level].del_size);
+                    let discard_score = if total_size == 0 {
+                        0.0
+                    } else {
+                        discardable as f64 / total_size as f64 / DISCARD_RATIO
+                    };
+                    (size as f64 / targets.target_size[level] as f64).max(discard_score)
+                };
+                CompactionPriority {
+                    level,
+                    score,
+                    adjusted: score,
+                    drop_prefixes: vec![],
+                    targets: targets.clone(),
+                }
+            })
+            .collect();
+        drop(status);
+
+        let mut prev = 0;
+        for level in targets.base_level..prios.len() {
+            if prios[prev].adjusted >= 1.0 {
+                let below = if prios[level].score >= MIN_ADJUSTED_SCORE {
+                    prios[level].adjusted
+                } else {
+                    MIN_ADJUSTED_SCORE
+                };
+                prios[prev].adjusted /= below;
+            }
+            prev = level;
+        }
+
+        // The last level is never compacted.
+        prios.pop();
+        prios.retain(|p| p.score >= 1.0);
+        prios.sort_by(|x, y| y.adjusted.partial_cmp(&x.adjusted).unwrap());
+        prios
+    }
+
+    /// Compact the level of `prio` into the next one, or into the base
+    /// level for L0. Returns false if no tables can be picked, as they are
+    /// being compacted by others.
+    fn do_compact(
+        &self,
+        compactor_id: usize,
+        prio: CompactionPriority,
+        discard_ts: u64,
+    ) -> Result<bool> {
+        let level = prio.level;
+        let next_level = if level == 0 {
+            prio.targets.base_level
+        } else {
+            level + 1
+        };
+        let targets = prio.targets.clone();
+        let mut cd = CompactDef::new(
+            compactor_id,
+            self.levels[level].clone(),
+            level,
+            self.levels[next_level].clone(),
+            next_level,
+            prio,
+            targets,
+        );
+        let filled = if level == 0 {
+            self.fill_tables_l0(&mut cd)
+        } else {
+            self.fill_tables(&mut cd, discard_ts)
+        };
+        if !filled {
+            return Ok(false);
+        }
+
+        let res = self.run_compact_def(&cd, discard_ts);
+        self.write_compact_status().delete(&cd);
+        res.map(|_| true)
+    }
+
+    /// Pick the oldest tables of L0 which overlap with each other, and the
+    /// tables they overlap with in the base level. Newer L0 tables are kept,
+    /// so they still shadow the compacted data.
+    fn fill_tables_l0(&self, cd: &mut CompactDef) -> bool {
+        let c = &self.comparator;
+        // Levels are locked while tables are picked and registered, so that
+        // no table is added to the ranges meanwhile.
+        let this = self.read_level(cd.this_level_id);
+        let next = self.read_level(cd.next_level_id);
+
+        let mut range = KeyRange::Empty;
+        for table in &this.tables {
+            let table_range = get_key_range_single(table);
+            if !range.overlaps_with(&table_range, c) {
+                break;
+            }
+            range = range.extend(&table_range, c);
+            cd.top.push(table.clone());
+        }
+        if cd.top.is_empty() {
+            return false;
+        }
+        cd.this_range = range;
+        cd.this_size = cd.top.iter().map(Table::size).sum();
+
+        let (left, right) = next.overlapping_tables(&cd.this_range);
+        cd.bot = next.tables[left..right].to_vec();
+        cd.next_range = match get_key_range(&cd.bot) {
+            Some(range) => range,
+            None => cd.this_range.clone(),
+        };
+        self.write_compact_status().compare_and_add(cd, c).is_ok()
+    }
+
+    /// Pick a table of a level other than L0, and the tables it overlaps
+    /// with in the next level. Tables with more stale data that can be
+    /// discarded at `discard_ts` are preferred, then ones with more stale
+    /// data, then older ones.
+    fn fill_tables(&self, cd: &mut CompactDef, discard_ts: u64) -> bool {
+        let c = &self.comparator;
+        let this = self.read_level(cd.this_level_id);
+        let next = self.read_level(cd.next_level_id);
+
+        let mut tables = this.tables.clone();
+        tables.sort_by(|x, y| {
+            let discardable = |t: &Table| {
+                if t.max_version() <= discard_ts {
+                    t.stale_data_size()
+                } else {
+                    0
+                }
+            };
+            discardable(y)
+                .cmp(&discardable(x))
+                .then(y.stale_data_size().cmp(&x.stale_data_size()))
+                .then(x.max_version().cmp(&y.max_version()))
+        });
+        let mut status = self.write_compact_status();
+        for table in tables {
+            let range = get_key_range_single(&table);
+            if status.overlaps_with(cd.this_level_id, &range, c) {
+                continue;
+            }
+            let (left, right) = next.overlapping_tables(&range);
+            cd.bot = next.tables[left..right].to_vec();
+            cd.next_range = match get_key_range(&cd.bot) {
+                Some(range) => range,
+                None => range.clone(),
+            };
+            cd.this_range = range;
+            cd.this_size = table.size();
+            cd.top = vec![table];
+            if status.compare_and_add(cd, c).is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Merge tables of `cd` into new tables, and replace them in both levels
+    /// and manifest atomically.
+    fn run_compact_def(&self, cd: &CompactDef, discard_ts: u64) -> Result<()> {
+        let start = self.opts.clock.now();
+        let mut info = CompactionInfo {
+            compactor_id: cd.compactor_id,
+            level: cd.this_level_id,
+            next_level: cd.next_level_id,
+            input_tables: cd.all_tables().iter().map(Table::id).collect(),
+            output_tables: vec![],
+            duration: Duration::from_secs(0),
+        };
+        self.opts.notify(|l| l.on_compaction_begin(&info));
+        let tables = self.compact_build_tables(cd, discard_ts)?;
+
+        let mut this = self.write_level(cd.this_level_id);
+        let mut next = self.write_level(cd.next_level_id);
+        // Levels may be replaced meanwhile, e.g. by flattening.
+        if !this.has_tables(&cd.top) || !next.has_tables(&cd.bot) {
+            return Err(Error::CompactionError(format!(
+                "tables of level {} or {} are changed during compaction",
+                cd.this_level_id, cd.next_level_id
+            )));
+        }
+        fail::fail_point!("compaction_before_manifest", |_| Err(Error::CustomError(
+            "failpoint compaction_before_manifest".to_string()
+        )));
+        if !self.opts.in_memory {
+            let mut changes: Vec<_> = cd
+                .all_tables()
+                .iter()
+                .map(|t| new_delete_change(t.id()))
+                .collect();
+            changes.extend(
+                tables
+                    .iter()
+                    .map(|t| new_table_create_change(t, cd.next_level_id)),
+            );
+            self.manifest.add_changes(changes)?;
+        }
+        for table in &tables {
+            self.notify_table_created(table, cd.next_level_id, TableCreationReason::Compaction);
+        }
+        next.replace_tables(&cd.bot, &tables)?;
+        this.delete_tables(&cd.top)?;
+        drop((this, next));
+        if cd.this_level_id == 0 {
+            self.notify_level_zero_room();
+        }
+
+        info.output_tables = tables.iter().map(Table::id).collect();
+        info.duration = self.opts.clock.now().saturating_duration_since(start);
+        info!(
+            "compactor {} compacted {} tables at level {} and {} tables at level {} into {} tables in {:?}",
+            cd.compactor_id,
+            cd.top.len(),
+            cd.this_level_id,
+            cd.bot.len(),
+            cd.next_level_id,
+            tables.len(),
+            info.duration
+        );
+        self.opts.notify(|l| l.on_compaction_completed(&info));
+        Ok(())
+    }
+
+    /// Merge entries of tables of `cd`, newer ones first, and build them
+    /// into tables of the target size of the next level. All versions of a
+    /// key are kept in the same table.
+    fn compact_build_tables(&self, cd: &CompactDef, discard_ts: u64) -> Result<Vec<Table>> {
+        let mut iters: Vec<Box<TableIterators>> = vec![];
+        if cd.this_level_id == 0 {
+            for table in cd.top.iter().rev() {
+                iters.push(Box::new(table.new_iterator(ITERATOR_NOCACHE).into()));
+            }
+        } else {
+            let iter = ConcatIterator::from_tables(cd.top.clone(), ITERATOR_NOCACHE);
+            iters.push(Box::new(iter.into()));
+        }
+        if !cd.bot.is_empty() {
+            let iter = ConcatIterator::from_tables(cd.bot.clone(), ITERATOR_NOCACHE);
+            iters.push(Box::new(iter.into()));
+        }
+        let mut iter = MergeIterator::from_iterators(iters, false, &self.comparator);
+
+        // Tombstones can only be dropped if no lower level has the keys.
+        let has_overlap = self.check_overlap(&cd.all_tables(), cd.next_level_id + 1);
+        let now = self.opts.clock.unix_time();
+        let file_size = cd.targets.file_size[cd.next_level_id];
+        let table_opts = build_level_table_options(&self.opts, cd.next_level_id);
+        let new_builder = || Builder::new(table_opts.clone());
+        let mut builder = new_builder();
+        let mut tables = vec![];
+        let mut last_key = Bytes::new();
+        let mut skip_older = false;
+        iter.rewind();
+        while iter.valid() {
+            let key = Bytes::copy_from_slice(iter.key());
+            let value = iter.value();
+            iter.next();
+
+            let is_latest = !same_key(&key, &last_key);
+            if is_latest {
+                if !last_key.is_empty() && builder.reach_capacity(file_size) {
+                    let builder = std::mem::replace(&mut builder, new_builder());
+                    tables.push(self.create_table(builder, self.reserve_file_id())?);
+                }
+                last_key = key.clone();
+                skip_older = false;
+            } else if skip_older {
+                continue;
+            }
+
+            // Merge operands need older versions to be folded with.
+            if get_ts(&key) <= discard_ts && value.meta & VALUE_MERGE_ENTRY == 0 {
+                skip_older = true;
+                let is_deleted = value.meta & VALUE_DELETE != 0 || value.is_expired(now);
+                if is_deleted && !has_overlap {
+                    continue;
+                }
+            }
+            // TODO: set vlog_len when value log is implemented
+            if is_latest {
+                builder.add(&key, value, 0);
+            } else {
+                builder.add_stale_key(&key, value, 0);
+            }
+        }
+        drop(iter);
+        if !builder.is_empty() {
+            tables.push(self.create_table(builder, self.reserve_file_id())?);
+        }
+        Ok(tables)
+    }
+
+    /// Whether any table at or below `level` overlaps with `tables`.
+    fn check_overlap(&self, tables: &[Table], level: usize) -> bool {
+        let range = match get_key_range(tables) {
+            Some(range) => range,
+            None => return false,
+        };
+        (level..self.levels.len()).any(|level| {
+            let (left, right) = self.read_level(level).overlapping_tables(&range);
+            right > left
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::format::key_with_ts;
+    use crate::table::tests::{build_table_data, get_test_table_options};
+    use crate::AgateOptions;
+
+    fn build_table(id: u64, keys: &[&'static str]) -> Table {
+        let opts = get_test_table_options();
+        let kv_pairs = keys
+            .iter()
+            .map(|k| (Bytes::from_static(k.as_bytes()), Bytes::from("v")))
+            .collect();
+        Table::open_in_memory(build_table_data(kv_pairs, opts.clone()), id, opts).unwrap()
+    }
+
+    fn compact_def(this_level: usize, top: Vec<Table>, bot: Vec<Table>) -> CompactDef {
+        let handler = |level| {
+            Arc::new(RwLock::new(LevelHandler::new(
+                AgateOptions::default(),
+                level,
+            )))
+        };
+        let prios = CompactionPriority {
+            level: this_level,
+            score: 1.0,
+            adjusted: 1.0,
+            drop_prefixes: vec![],
+            targets: Targets::new(),
+        };
+        let mut cd = CompactDef::new(
+            0,
+            handler(this_level),
+            this_level,
+            handler(this_level + 1),
+            this_level + 1,
+            prios,
+            Targets::new(),
+        );
+        cd.this_range = get_key_range(&top).unwrap();
+        cd.next_range = get_key_range(&bot).unwrap_or_else(|| cd.this_range.clone());
+        cd.this_size = top.iter().map(Table::size).sum();
+        cd.top = top;
+        cd.bot = bot;
+        cd
+    }
+
+    #[test]
+    fn test_compact_status() {
+        let c = Comparator::default();
+        let mut status = CompactStatus::new(4);
+        let cd1 = compact_def(1, vec![build_table(1, &["a", "c"])], vec![]);
+        let cd2 = compact_def(
+            1,
+            vec![build_table(2, &["d", "f"])],
+            vec![build_table(3, &["e", "g"])],
+        );
+        status.compare_and_add(&cd1, &c).unwrap();
+        status.compare_and_add(&cd2, &c).unwrap();
+        assert_eq!(status.tables.len(), 3);
+        assert_eq!(status.levels[1].del_size, cd1.this_size + cd2.this_size);
+        let key = |k: &str| key_with_ts(k, 0);
+        let range = |k| KeyRange::new(key(k), key(k), &c);
+        assert!(status.overlaps_with(1, &range("b"), &c));
+        assert!(!status.overlaps_with(1, &range("h"), &c));
+        // The range is also kept in the next level, even without tables.
+        assert!(status.overlaps_with(2, &range("b"), &c));
+
+        // Ranges being compacted can't be picked again, in either level.
+        let cd3 = compact_def(1, vec![build_table(4, &["b"])], vec![]);
+        assert!(status.compare_and_add(&cd3, &c).is_err());
+        let cd4 = compact_def(2, vec![build_table(5, &["g"])], vec![]);
+        assert!(status.compare_and_add(&cd4, &c).is_err());
+        assert_eq!(status.tables.len(), 3);
+
+        status.delete(&cd1);
+        status.compare_and_add(&cd3, &c).unwrap();
+        status.delete(&cd2);
+        status.compare_and_add(&cd4, &c).unwrap();
+        status.delete(&cd3);
+        status.delete(&cd4);
+        assert!(status.tables.is_empty());
+        assert!(status
+            .levels
+            .iter()
+            .all(|l| l.ranges.is_empty() && l.del_size == 0));
+    }
 
     #[test]
     fn test_keyrange_non_overlap() {
+        let c = Comparator::default();
         let k1 = KeyRange::new(
             Bytes::from_static(b"000000000000"),
             Bytes::from_static(b"dddd00000000"),
+            &c,
         );
         let k2 = KeyRange::new(
             Bytes::from_static(b"eeee00000000"),
             Bytes::from_static(b"ffff00000000"),
+            &c,
         );
 
         assert_eq!(
-            k1.extend(&k2),
+            k1.extend(&k2, &c),
             KeyRange::new(
                 Bytes::from_static(b"000000000000"),
-                Bytes::from_static(b"ffff00000000")
+                Bytes::from_static(b"ffff00000000"),
+                &c
             )
         );
 
         assert_eq!(
-            k2.extend(&k1),
+            k2.extend(&k1, &c),
             KeyRange::new(
                 Bytes::from_static(b"000000000000"),
-                Bytes::from_static(b"ffff00000000")
+                Bytes::from_static(b"ffff00000000"),
+                &c
             )
         );
 
-        assert!(!k1.overlaps_with(&k2));
-        assert!(!k2.overlaps_with(&k1));
+        assert!(!k1.overlaps_with(&k2, &c));
+        assert!(!k2.overlaps_with(&k1, &c));
     }
 
     #[test]
     fn test_keyrange_overlap() {
+        let c = Comparator::default();
         let k1 = KeyRange::new(
             Bytes::from_static(b"000000000000"),
             Bytes::from_static(b"eeee00000000"),
+            &c,
         );
         let k2 = KeyRange::new(
             Bytes::from_static(b"dddd00000000"),
             Bytes::from_static(b"ffff00000000"),
+            &c,
         );
 
         assert_eq!(
-            k1.extend(&k2),
+            k1.extend(&k2, &c),
             KeyRange::new(
                 Bytes::from_static(b"000000000000"),
-                Bytes::from_static(b"ffff00000000")
+                Bytes::from_static(b"ffff00000000"),
+                &c
             )
         );
 
         assert_eq!(
-            k2.extend(&k1),
+            k2.extend(&k1, &c),
             KeyRange::new(
                 Bytes::from_static(b"000000000000"),
-                Bytes::from_static(b"ffff00000000")
+                Bytes::from_static(b"ffff00000000"),
+                &c
             )
         );
 
-        assert!(k1.overlaps_with(&k2));
-        assert!(k2.overlaps_with(&k1));
+        assert!(k1.overlaps_with(&k2, &c));
+        assert!(k2.overlaps_with(&k1, &c));
     }
 
     #[test]
     fn test_keyrange_inf() {
+        let c = Comparator::default();
         let k1 = KeyRange::Inf;
         let k2 = KeyRange::new(
             Bytes::from_static(b"dddd00000000"),
             Bytes::from_static(b"ffff00000000"),
+            &c,
         );
 
-        assert_eq!(k1.extend(&k2), KeyRange::Inf);
-        assert_eq!(k2.extend(&k1), KeyRange::Inf);
-        assert_eq!(k1.extend(&KeyRange::Empty), k1);
-        assert_eq!(k2.extend(&KeyRange::Empty), k2);
-        assert!(!KeyRange::Inf.overlaps_with(&KeyRange::Empty));
-        assert!(KeyRange::Empty.overlaps_with(&KeyRange::Inf));
-        assert!(KeyRange::Empty.overlaps_with(&KeyRange::Empty));
+        assert_eq!(k1.extend(&k2, &c), KeyRange::Inf);
+        assert_eq!(k2.extend(&k1, &c), KeyRange::Inf);
+        assert_eq!(k1.extend(&KeyRange::Empty, &c), k1);
+        assert_eq!(k2.extend(&KeyRange::Empty, &c), k2);
+        assert!(!KeyRange::Inf.overlaps_with(&KeyRange::Empty, &c));
+        assert!(KeyRange::Empty.overlaps_with(&KeyRange::Inf, &c));
+        assert!(KeyRange::Empty.overlaps_with(&KeyRange::Empty, &c));
     }
 }
@@ -2,14 +2,23 @@
 #![allow(unused_variables)]
 
 use super::KeyRange;
+use crate::comparator::Comparator;
+use crate::format::{get_ts, user_key};
+use crate::iterator::IteratorOptions;
+use crate::table::{
+    ConcatIterator, TableIterators, ITERATOR_NOCACHE, ITERATOR_PREFETCH, ITERATOR_REVERSED,
+};
+use crate::util::{same_key, KeyComparator};
 use crate::value::Value;
-use crate::Result;
-use crate::{iterator::IteratorOptions, table::TableIterators};
+use crate::AgateIterator;
 use crate::{AgateOptions, Table};
+use crate::{Error, Result};
 use bytes::Bytes;
+use std::cmp::Ordering;
 
 pub struct LevelHandler {
     opts: AgateOptions,
+    comparator: Comparator,
     pub level: usize,
     pub tables: Vec<Table>,
     pub total_size: u64,
@@ -28,6 +37,7 @@ impl Drop for LevelHandler {
 impl LevelHandler {
     pub fn new(opts: AgateOptions, level: usize) -> Self {
         Self {
+            comparator: Comparator::new(opts.comparator.clone()),
             opts,
             level,
             tables: vec![],
@@ -35,8 +45,31 @@ impl LevelHandler {
         }
     }
 
-    pub fn try_add_l0_table(&mut self, table: Table) -> bool {
-        unimplemented!()
+    /// Add a table to L0. Returns false if L0 is stalled, which is when it
+    /// has `stall` tables already.
+    pub fn try_add_l0_table(&mut self, table: Table, stall: usize) -> bool {
+        assert_eq!(self.level, 0);
+        if self.tables.len() >= stall {
+            return false;
+        }
+
+        if self.opts.pin_l0_tables {
+            table.pin();
+        }
+        self.total_size += table.size();
+        self.tables.push(table);
+
+        true
+    }
+
+    /// Total stale data of tables which can be dropped by compactions at
+    /// `discard_ts`, that is, tables with no versions newer than it.
+    pub fn discardable_size(&self, discard_ts: u64) -> u64 {
+        self.tables
+            .iter()
+            .filter(|t| t.max_version() <= discard_ts)
+            .map(|t| t.stale_data_size() as u64)
+            .sum()
     }
 
     pub fn num_tables(&self) -> usize {
@@ -47,27 +80,230 @@ impl LevelHandler {
         unimplemented!()
     }
 
+    /// Get value of `key` from tables of this level. The value with
+    /// the highest version not greater than `key`'s version is returned.
     pub fn get(&self, key: &Bytes) -> Result<Value> {
-        unimplemented!()
+        let hash = farmhash::fingerprint32(user_key(key));
+        let mut max_value = Value::default();
+        self.get_multi(
+            std::slice::from_ref(key),
+            &[hash],
+            &[0],
+            std::slice::from_mut(&mut max_value),
+        )?;
+        Ok(max_value)
+    }
+
+    /// Look up `keys[i]` for every `i` in `indices`, which should be sorted
+    /// by key. `hashes` are fingerprints of user keys. The value found is
+    /// stored to `values[i]` if it's newer than the existing one.
+    ///
+    /// Every table is searched with a single iterator in key order, so a
+    /// block is only read once for adjacent keys.
+    pub fn get_multi(
+        &self,
+        keys: &[Bytes],
+        hashes: &[u32],
+        indices: &[usize],
+        values: &mut [Value],
+    ) -> Result<()> {
+        if self.level == 0 {
+            // Newer tables are at the end of L0, and they win among the same
+            // versions, like what iterators do. The highest version not
+            // greater than the key's among all tables is kept.
+            for table in self.tables.iter().rev() {
+                self.get_from_table(table, keys, hashes, indices, values)?;
+            }
+            return Ok(());
+        }
+
+        // Tables are sorted and don't overlap in other levels, so a key can
+        // only be found in the first table whose biggest key is not less
+        // than it. Versions of a user key split across tables are ordered
+        // the same way.
+        let c = &self.comparator;
+        let mut start = 0;
+        while start < indices.len() {
+            let key = &keys[indices[start]];
+            let idx = self
+                .tables
+                .partition_point(|t| c.compare_key(t.biggest(), key) == Ordering::Less);
+            let table = match self.tables.get(idx) {
+                Some(table) => table,
+                // Following keys are greater.
+                None => break,
+            };
+            let end = start
+                + indices[start..].partition_point(|&i| {
+                    c.compare_key(&keys[i], table.biggest()) != Ordering::Greater
+                });
+            self.get_from_table(table, keys, hashes, &indices[start..end], values)?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    /// Look up keys of `indices` in `table` like `get_multi`.
+    fn get_from_table(
+        &self,
+        table: &Table,
+        keys: &[Bytes],
+        hashes: &[u32],
+        indices: &[usize],
+        values: &mut [Value],
+    ) -> Result<()> {
+        let smallest = user_key(table.smallest());
+        let biggest = user_key(table.biggest());
+        let mut iter = None;
+
+        for &i in indices {
+            let key = &keys[i];
+            let key_no_ts = user_key(key);
+            if self.comparator.compare_user_key(key_no_ts, smallest) == Ordering::Less
+                || self.comparator.compare_user_key(key_no_ts, biggest) == Ordering::Greater
+                || table.does_not_have_key(key, hashes[i])
+            {
+                continue;
+            }
+
+            let it = iter.get_or_insert_with(|| table.new_iterator(0));
+            it.seek(key);
+            if !it.valid() {
+                if let Some(err) = it.error() {
+                    if !err.is_eof() {
+                        return Err(Error::TableRead(format!(
+                            "error when seeking table {}: {:?}",
+                            table.id(),
+                            err
+                        )));
+                    }
+                }
+                continue;
+            }
+
+            if same_key(key, it.key()) {
+                let version = get_ts(it.key());
+                if version > values[i].version {
+                    values[i] = it.value();
+                    values[i].version = version;
+                }
+            }
+        }
+
+        Ok(())
     }
 
+    /// Get the range `[left, right)` of tables overlapping with `kr`. It's
+    /// only meaningful for levels other than L0, where tables are sorted.
     pub fn overlapping_tables(&self, kr: &KeyRange) -> (usize, usize) {
-        unimplemented!()
+        let (left, right) = match kr {
+            KeyRange::Range { left, right } => (left, right),
+            KeyRange::Inf => return (0, self.tables.len()),
+            KeyRange::Empty => return (0, 0),
+        };
+        let c = &self.comparator;
+        let start = self
+            .tables
+            .partition_point(|t| c.compare_key(t.biggest(), left) == Ordering::Less);
+        let end = self
+            .tables
+            .partition_point(|t| c.compare_key(t.smallest(), right) != Ordering::Greater);
+        (start, end.max(start))
     }
 
+    /// Whether all `tables` are in this level.
+    pub fn has_tables(&self, tables: &[Table]) -> bool {
+        tables
+            .iter()
+            .all(|t| self.tables.iter().any(|x| x.id() == t.id()))
+    }
+
+    /// Remove `to_del` and add `to_add` to a level other than L0, which is
+    /// done by compaction into this level.
     pub fn replace_tables(&mut self, to_del: &[Table], to_add: &[Table]) -> Result<()> {
-        unimplemented!()
+        assert_ne!(self.level, 0);
+        self.delete_tables(to_del)?;
+        self.add_tables(to_add.to_vec());
+        Ok(())
     }
 
+    /// Remove `to_del` from this level, keeping the order of the rest.
     pub fn delete_tables(&mut self, to_del: &[Table]) -> Result<()> {
-        unimplemented!()
+        if !self.has_tables(to_del) {
+            return Err(Error::CompactionError(format!(
+                "tables {:?} are not found in level {}",
+                to_del.iter().map(Table::id).collect::<Vec<_>>(),
+                self.level
+            )));
+        }
+        self.tables
+            .retain(|t| !to_del.iter().any(|x| x.id() == t.id()));
+        self.total_size -= to_del.iter().map(Table::size).sum::<u64>();
+        Ok(())
     }
 
     pub fn init_tables(&mut self, tables: Vec<Table>) {
-        unimplemented!()
+        self.total_size = tables.iter().map(|t| t.size()).sum();
+        self.tables = tables;
+
+        if self.level == 0 {
+            // key range of tables in L0 may overlap, sort them by file ID
+            self.tables.sort_by_key(|t| t.id());
+            if self.opts.pin_l0_tables {
+                self.tables.iter().for_each(Table::pin);
+            }
+        } else {
+            let c = &self.comparator;
+            self.tables
+                .sort_by(|x, y| c.compare_key(x.smallest(), y.smallest()));
+        }
     }
 
+    /// Add tables to a level other than L0. Tables should not overlap with
+    /// existing ones.
+    pub fn add_tables(&mut self, tables: Vec<Table>) {
+        assert_ne!(self.level, 0);
+        self.total_size += tables.iter().map(|t| t.size()).sum::<u64>();
+        self.tables.extend(tables);
+        let c = &self.comparator;
+        self.tables
+            .sort_by(|x, y| c.compare_key(x.smallest(), y.smallest()));
+    }
+
+    /// Estimate on-disk size and number of keys within `[start, end)` among
+    /// all tables of this level.
+    pub fn estimate_range(&self, start: &[u8], end: &[u8]) -> (u64, u64) {
+        self.tables
+            .iter()
+            .map(|t| t.estimate_range(start, end))
+            .fold((0, 0), |(size, keys), (s, k)| (size + s, keys + k))
+    }
+
+    /// Append iterators of tables picked by `opts`, where newer data comes
+    /// first.
     pub(crate) fn append_iterators(&self, iters: &mut Vec<TableIterators>, opts: &IteratorOptions) {
-        unimplemented!()
+        let mut opt = if opts.reverse { ITERATOR_REVERSED } else { 0 };
+        if opts.no_cache {
+            opt |= ITERATOR_NOCACHE;
+        }
+        if opts.prefetch_values {
+            opt |= ITERATOR_PREFETCH;
+        }
+        if self.level == 0 {
+            // Newer tables are at the end of L0.
+            for table in self.tables.iter().rev() {
+                if opts.pick_table(table) {
+                    iters.push(table.new_iterator(opt).into());
+                }
+            }
+            return;
+        }
+
+        let mut tables = self.tables.clone();
+        opts.pick_tables(&mut tables);
+        if !tables.is_empty() {
+            iters.push(ConcatIterator::from_tables(tables, opt).into());
+        }
     }
 }
@@ -0,0 +1,117 @@
+//! Thresholds of L0 adapted to recent load, see
+//! `AgateOptions::adaptive_level_zero`.
+
+use crate::AgateOptions;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Ingest rate is measured by tables added to L0 within the window.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Numbers of L0 tables to trigger compaction and to stall flushes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelZeroThresholds {
+    pub compaction: usize,
+    pub stall: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct LevelZeroController {
+    /// time and size of tables added to L0 within `RATE_WINDOW`
+    recent: VecDeque<(Instant, u64)>,
+    recent_bytes: u64,
+}
+
+impl LevelZeroController {
+    pub fn record_ingest(&mut self, now: Instant, bytes: u64) {
+        self.recent.push_back((now, bytes));
+        self.recent_bytes += bytes;
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(at, bytes)) = self.recent.front() {
+            if now.saturating_duration_since(at) <= RATE_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+            self.recent_bytes -= bytes;
+        }
+    }
+
+    /// Bytes added to L0 per second recently.
+    pub fn ingest_rate(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        self.recent_bytes as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    /// Thresholds under current ingest rate, where `debt` is bytes of L0
+    /// tables pending compaction.
+    ///
+    /// While tables arrive fast, compaction is triggered earlier to keep up
+    /// with flushes, and L0 absorbs the burst by stalling later. The stall
+    /// threshold is raised by tables expected to be flushed in the next
+    /// second, at most doubled, and only until the debt reaches twice the
+    /// bytes of L0 at the configured stall threshold.
+    pub fn thresholds(
+        &mut self,
+        opts: &AgateOptions,
+        now: Instant,
+        debt: u64,
+    ) -> LevelZeroThresholds {
+        let base = LevelZeroThresholds {
+            compaction: opts.num_level_zero_tables,
+            stall: opts.num_level_zero_tables_stall,
+        };
+        if !opts.adaptive_level_zero {
+            return base;
+        }
+
+        let table_size = opts.mem_table_size.max(1);
+        let burst = (self.ingest_rate(now) / table_size as f64).ceil() as usize;
+        let compaction = base
+            .compaction
+            .saturating_sub(burst)
+            .max(1)
+            .min(base.compaction);
+        let max_debt = 2 * base.stall as u64 * table_size;
+        let headroom = (max_debt.saturating_sub(debt) / table_size) as usize;
+        let stall = base.stall + burst.min(headroom).min(base.stall);
+        LevelZeroThresholds { compaction, stall }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thresholds(compaction: usize, stall: usize) -> LevelZeroThresholds {
+        LevelZeroThresholds { compaction, stall }
+    }
+
+    #[test]
+    fn test_level_zero_thresholds() {
+        let mut opts = AgateOptions::default().with_level_zero_tables(5, 10);
+        opts.mem_table_size = 1 << 20;
+        let mut ctl = LevelZeroController::default();
+        let start = Instant::now();
+        ctl.record_ingest(start, 100 << 20);
+        assert_eq!(ctl.thresholds(&opts, start, 0), thresholds(5, 10));
+
+        opts.adaptive_level_zero = true;
+        // 10MB per second, 10 tables per second.
+        assert_eq!(ctl.ingest_rate(start), (10 << 20) as f64);
+        assert_eq!(ctl.thresholds(&opts, start, 0), thresholds(1, 20));
+        // Stall is raised less while debt grows.
+        assert_eq!(ctl.thresholds(&opts, start, 15 << 20), thresholds(1, 15));
+        assert_eq!(ctl.thresholds(&opts, start, 30 << 20), thresholds(1, 10));
+
+        // A slow ingest only changes thresholds slightly.
+        let later = start + RATE_WINDOW * 2;
+        ctl.record_ingest(later, 1 << 20);
+        assert_eq!(ctl.thresholds(&opts, later, 0), thresholds(4, 11));
+        let idle = later + RATE_WINDOW * 2;
+        assert_eq!(ctl.thresholds(&opts, idle, 0), thresholds(5, 10));
+    }
+}
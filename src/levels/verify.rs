@@ -0,0 +1,226 @@
+use super::compaction::get_key_range_single;
+use crate::iterator_trait::AgateIterator;
+use crate::manifest::ManifestFile;
+use crate::opt::build_table_options;
+use crate::table::{self, new_filename, ITERATOR_NOCACHE};
+use crate::util::KeyComparator;
+use crate::value::VALUE_POINTER;
+use crate::{AgateOptions, Error, ErrorContext, Result, Table};
+
+use bytes::Bytes;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Summary of verifying checksums of tables.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// number of tables verified
+    pub tables: usize,
+    /// number of blocks verified
+    pub blocks: usize,
+    /// bytes of tables verified
+    pub bytes: u64,
+    /// IDs of tables failed to verify, in ascending order
+    pub corrupted_tables: Vec<u64>,
+    pub duration: Duration,
+}
+
+/// Verify checksums of all blocks of `tables` with `workers` threads. The
+/// error of the first corrupted table is returned together with the report.
+/// `on_progress` is called with the number of tables verified so far.
+pub(crate) fn verify_tables(
+    tables: &[Table],
+    workers: usize,
+    on_progress: impl Fn(usize) + Sync,
+) -> (VerifyReport, Option<Error>) {
+    let start = Instant::now();
+    let next = AtomicUsize::new(0);
+    let report = Mutex::new(VerifyReport::default());
+    let first_error = Mutex::new(None);
+
+    thread::scope(|s| {
+        for _ in 0..workers.max(1).min(tables.len()) {
+            s.spawn(|| {
+                while let Some(table) = tables.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let res = table.verify_checksum();
+                    let mut report = report.lock().unwrap();
+                    report.tables += 1;
+                    report.blocks += table.offsets_length();
+                    report.bytes += table.size();
+                    if let Err(err) = res {
+                        report.corrupted_tables.push(table.id());
+                        first_error.lock().unwrap().get_or_insert_with(|| {
+                            err.with_context(ErrorContext::table(table.id()))
+                        });
+                    }
+                    on_progress(report.tables);
+                }
+            });
+        }
+    });
+
+    let mut report = report.into_inner().unwrap();
+    report.corrupted_tables.sort_unstable();
+    report.duration = start.elapsed();
+    (report, first_error.into_inner().unwrap())
+}
+
+/// Problem found by `Agate::verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsistencyIssue {
+    /// table recorded in manifest has no file
+    MissingTable { id: u64 },
+    /// table file is not recorded in manifest, it's removed on next open
+    OrphanTable { id: u64 },
+    /// table is recorded at a level beyond `max_levels`
+    InvalidLevel { id: u64, level: usize },
+    /// table can't be opened, or fails checksum verification
+    CorruptedTable { id: u64, error: String },
+    /// smallest key of table is larger than its biggest key
+    InvalidKeyRange { id: u64 },
+    /// key ranges of two tables at a level other than L0 overlap
+    OverlappingTables { level: usize, left: u64, right: u64 },
+    /// value pointer which can't be resolved, as there is no value log
+    DanglingValuePointer { id: u64, key: Bytes },
+}
+
+/// Result of checking a closed database by `Agate::verify`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// summary of verifying checksums of all tables in manifest
+    pub checksums: VerifyReport,
+    /// all problems found, ordered by the kind of check
+    pub issues: Vec<ConsistencyIssue>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Check database at `opts.dir` without modifying anything. Only failures of
+/// reading manifest or listing files are returned as errors, other problems
+/// are collected in the report.
+pub(crate) fn check_consistency(opts: &AgateOptions) -> Result<ConsistencyReport> {
+    let mut opts = opts.clone();
+    opts.read_only = true;
+    let manifest = ManifestFile::open_or_create_manifest_file(&opts)?.manifest_cloned();
+    let sst_ids: HashSet<u64> = opts
+        .env
+        .list_dir(&opts.dir)?
+        .iter()
+        .filter_map(|name| table::parse_file_id(name).ok())
+        .collect();
+
+    let mut report = ConsistencyReport::default();
+    let mut ids: Vec<_> = manifest.tables.keys().copied().collect();
+    ids.sort_unstable();
+    let mut orphans: Vec<_> = sst_ids
+        .iter()
+        .filter(|id| !manifest.tables.contains_key(id))
+        .copied()
+        .collect();
+    orphans.sort_unstable();
+
+    let table_opts = build_table_options(&opts);
+    let mut levels = vec![vec![]; opts.max_levels];
+    for id in ids {
+        let tm = &manifest.tables[&id];
+        let level = tm.level as usize;
+        if !sst_ids.contains(&id) {
+            report.issues.push(ConsistencyIssue::MissingTable { id });
+            continue;
+        }
+        if level >= opts.max_levels {
+            report
+                .issues
+                .push(ConsistencyIssue::InvalidLevel { id, level });
+            continue;
+        }
+        if tm.key_id != 0 || tm.compression != 0 {
+            report.issues.push(ConsistencyIssue::CorruptedTable {
+                id,
+                error: "table is encrypted or compressed, which is not supported".to_string(),
+            });
+            continue;
+        }
+        match Table::open(&new_filename(id, &opts.dir), table_opts.clone()) {
+            Ok(table) => {
+                // Opened tables are deleted on drop by default.
+                table.mark_save();
+                levels[level].push(table);
+            }
+            Err(err) => report.issues.push(ConsistencyIssue::CorruptedTable {
+                id,
+                error: err.to_string(),
+            }),
+        }
+    }
+    report.issues.extend(
+        orphans
+            .into_iter()
+            .map(|id| ConsistencyIssue::OrphanTable { id }),
+    );
+
+    let tables: Vec<_> = levels.iter().flatten().cloned().collect();
+    let (checksums, _) = verify_tables(&tables, opts.num_verify_workers, |_| {});
+    for id in &checksums.corrupted_tables {
+        let table = tables.iter().find(|t| t.id() == *id).unwrap();
+        let error = table
+            .verify_checksum()
+            .err()
+            .map_or_else(|| "checksum mismatch".to_string(), |err| err.to_string());
+        report
+            .issues
+            .push(ConsistencyIssue::CorruptedTable { id: *id, error });
+    }
+    let corrupted: HashSet<u64> = checksums.corrupted_tables.iter().copied().collect();
+    report.checksums = checksums;
+
+    let c = &table_opts.comparator;
+    for (level, tables) in levels.iter_mut().enumerate() {
+        tables.retain(|t| !corrupted.contains(&t.id()));
+        for table in tables.iter() {
+            if c.compare_key(table.smallest(), table.biggest()) == CmpOrdering::Greater {
+                report
+                    .issues
+                    .push(ConsistencyIssue::InvalidKeyRange { id: table.id() });
+            }
+        }
+        if level == 0 {
+            continue;
+        }
+        tables.sort_by(|x, y| c.compare_key(x.smallest(), y.smallest()));
+        for pair in tables.windows(2) {
+            if get_key_range_single(&pair[0]).overlaps_with(&get_key_range_single(&pair[1]), c) {
+                report.issues.push(ConsistencyIssue::OverlappingTables {
+                    level,
+                    left: pair[0].id(),
+                    right: pair[1].id(),
+                });
+            }
+        }
+    }
+
+    // TODO: resolve pointers once value log is implemented.
+    for table in levels.iter().flatten() {
+        let mut iter = table.new_iterator(ITERATOR_NOCACHE);
+        iter.rewind();
+        while iter.valid() {
+            if iter.value().meta & VALUE_POINTER != 0 {
+                report.issues.push(ConsistencyIssue::DanglingValuePointer {
+                    id: table.id(),
+                    key: Bytes::copy_from_slice(iter.key()),
+                });
+            }
+            iter.next();
+        }
+    }
+
+    Ok(report)
+}
@@ -1,30 +1,87 @@
 #![allow(dead_code)]
 
+mod allocator;
 mod bloom;
+mod buffer_pool;
+mod cache;
 mod checksum;
+mod clock;
+mod closer;
+mod comparator;
 mod db;
+pub mod engine;
 mod entry;
+mod env;
 mod error;
+mod event;
 mod format;
+#[cfg(feature = "async")]
+mod future;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
 mod iterator;
 mod iterator_trait;
+mod key_registry;
 mod levels;
+mod manifest;
 mod memtable;
+mod merge;
+mod metrics;
 mod ops;
 mod opt;
+mod rate_limiter;
 mod table;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 mod util;
 mod value;
 mod wal;
 
-pub use format::{get_ts, key_with_ts};
-pub use opt::ChecksumVerificationMode;
+pub use allocator::AllocatorStats;
+pub use cache::{BlockCache, CachePolicy, CacheStats, CacheStatus, IndexCache, LevelCacheStats};
+pub use format::{get_ts, key_with_ts, with_key_ts, KeyTs};
 pub use opt::Options as TableOptions;
+pub use opt::{BloomStrategy, ChecksumAlgorithm, ChecksumVerificationMode, Compression};
 pub use table::builder::Builder as TableBuilder;
-pub use table::Table;
+pub use table::rocksdb::{
+    RocksEntry, RocksEntryKind, RocksSstReader, RocksSstWriter, ROCKSDB_MAX_SEQUENCE,
+};
+pub use table::{PrefixStats, Table};
 pub use value::Value;
 
-pub use db::{Agate, AgateOptions};
-pub use error::{Error, Result};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use comparator::{BytewiseComparator, Comparator, UserComparator};
+#[cfg(feature = "async")]
+pub use db::AsyncAgate;
+pub use db::{
+    Agate, AgateOptions, BulkLoader, DbInfo, HistogramData, IsolationLevel, KeyValueHistogram,
+    OpenProgress, OpenProgressCallback, OpenProgressStage, OpenTimings, RuntimeOption,
+    StoreIdentity, Stream, StreamKeyFilter, StreamWriter, Subscription,
+};
+pub use entry::Entry;
+#[cfg(not(target_arch = "wasm32"))]
+pub use env::StdEnv;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use env::UringEnv;
+pub use env::{
+    Env, IoPriority, MappedFile, MemEnv, OpenMode, RateLimitedEnv, ReadableFile, WritableFile,
+};
+pub use error::{Error, ErrorContext, ErrorKind, OpenStage, Result};
+pub use event::{
+    CompactionInfo, EventListener, FlushInfo, TableCreationInfo, TableCreationReason,
+    TableDeletionInfo,
+};
+#[cfg(feature = "async")]
+pub use future::{TaskFuture, WriteFuture};
+pub use iterator::{Item, Iterator, IteratorOptions};
 pub use iterator_trait::AgateIterator;
+pub use levels::{
+    ConsistencyIssue, ConsistencyReport, DbSize, LevelInfo, SizeEstimate, TableInfo, VerifyReport,
+};
+pub use memtable::MemoryUsage;
+pub use merge::{MergeOperator, U64AddOperator};
+pub use metrics::MetricsSnapshot;
+pub use ops::snapshot::Snapshot;
+pub use ops::transaction::Transaction;
+pub use ops::write_batch::WriteBatch;
 pub use skiplist::Skiplist;
@@ -0,0 +1,599 @@
+use crate::checksum;
+use crate::env::{Env, IoPriority, OpenMode, WritableFile};
+use crate::table::Table;
+use crate::AgateOptions;
+use crate::{Error, ErrorContext, Result};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use prost::Message;
+use proto::meta::{
+    manifest_change::Operation as ManifestChangeOp, ManifestChange, ManifestChangeSet,
+};
+use std::collections::{HashMap, HashSet};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const MANIFEST_FILENAME: &str = "MANIFEST";
+const MANIFEST_REWRITE_FILENAME: &str = "MANIFEST-REWRITE";
+const MANIFEST_DELETIONS_REWRITE_THRESHOLD: usize = 10000;
+const MANIFEST_DELETIONS_RATIO: usize = 10;
+
+const MAGIC_TEXT: &[u8; 4] = b"Agat";
+const MAGIC_VERSION: u32 = 1;
+/// MANIFEST of Badger shares the same layout, only with different magic.
+const BADGER_MAGIC_TEXT: &[u8; 4] = b"Bdgr";
+const BADGER_MAGIC_VERSIONS: &[u32] = &[7, 8];
+
+/// `LevelManifest` contains information about LSM tree levels.
+#[derive(Default, Clone, Debug)]
+pub struct LevelManifest {
+    pub tables: HashSet<u64>,
+}
+
+/// `TableManifest` contains information about a specific table
+/// in the LSM tree.
+#[derive(Default, Clone, Debug)]
+pub struct TableManifest {
+    pub level: u8,
+    pub key_id: u64,
+    /// compression algorithm, only set by Badger as it's not supported yet
+    pub compression: u32,
+    /// key range and max version of the table, empty if they are not
+    /// recorded, like tables created by Badger
+    pub smallest: Bytes,
+    pub biggest: Bytes,
+    pub max_version: u64,
+}
+
+/// `Manifest` represents the contents of the MANIFEST file.
+///
+/// The MANIFEST file describes the startup state of the db -- all LSM files
+/// and what level they're at. It consists of a sequence of `ManifestChangeSet`
+/// objects. Each `ManifestChangeSet` is applied atomically.
+#[derive(Default, Clone, Debug)]
+pub struct Manifest {
+    pub levels: Vec<LevelManifest>,
+    pub tables: HashMap<u64, TableManifest>,
+    /// number of changes (creations and deletions) in MANIFEST file,
+    /// used for deciding whether to rewrite the file.
+    pub creations: usize,
+    pub deletions: usize,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn as_changes(&self) -> Vec<ManifestChange> {
+        self.tables
+            .iter()
+            .map(|(id, tm)| ManifestChange {
+                smallest: tm.smallest.to_vec(),
+                biggest: tm.biggest.to_vec(),
+                max_version: tm.max_version,
+                ..new_create_change(*id, tm.level as usize, tm.key_id)
+            })
+            .collect()
+    }
+
+    fn apply_change(&mut self, change: &ManifestChange) -> Result<()> {
+        match ManifestChangeOp::from_i32(change.op) {
+            Some(ManifestChangeOp::Create) => {
+                if change.level > u8::MAX as u32 {
+                    return Err(Error::CustomError(format!(
+                        "MANIFEST invalid, table {} has level {}",
+                        change.id, change.level
+                    )));
+                }
+                if self.tables.contains_key(&change.id) {
+                    return Err(Error::CustomError(format!(
+                        "MANIFEST invalid, table {} exists",
+                        change.id
+                    )));
+                }
+                self.tables.insert(
+                    change.id,
+                    TableManifest {
+                        level: change.level as u8,
+                        key_id: change.key_id,
+                        compression: change.compression,
+                        smallest: Bytes::copy_from_slice(&change.smallest),
+                        biggest: Bytes::copy_from_slice(&change.biggest),
+                        max_version: change.max_version,
+                    },
+                );
+                while self.levels.len() <= change.level as usize {
+                    self.levels.push(LevelManifest::default());
+                }
+                self.levels[change.level as usize].tables.insert(change.id);
+                self.creations += 1;
+            }
+            Some(ManifestChangeOp::Delete) => {
+                let tm = self.tables.remove(&change.id).ok_or_else(|| {
+                    Error::CustomError(format!("MANIFEST removes non-existing table {}", change.id))
+                })?;
+                self.levels[tm.level as usize].tables.remove(&change.id);
+                self.deletions += 1;
+            }
+            None => {
+                return Err(Error::CustomError(format!(
+                    "MANIFEST file has invalid manifest change op {}",
+                    change.op
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_change_set(&mut self, change_set: &ManifestChangeSet) -> Result<()> {
+        for change in &change_set.changes {
+            self.apply_change(change)?;
+        }
+        Ok(())
+    }
+}
+
+struct ManifestFileCore {
+    file: Option<Box<dyn WritableFile>>,
+    manifest: Manifest,
+    /// Set when a failed append can't be undone, so the file may not match
+    /// `manifest` anymore.
+    poisoned: bool,
+}
+
+/// `ManifestFile` holds the file pointer (and other info) about the manifest file,
+/// which is a log file we append to.
+pub struct ManifestFile {
+    env: Arc<dyn Env>,
+    directory: PathBuf,
+    deletions_rewrite_threshold: usize,
+    core: Mutex<ManifestFileCore>,
+}
+
+impl ManifestFile {
+    /// Open or create the MANIFEST file under `opts.dir`.
+    pub fn open_or_create_manifest_file(opts: &AgateOptions) -> Result<Self> {
+        if opts.in_memory {
+            return Ok(Self {
+                env: opts.env.clone(),
+                directory: PathBuf::new(),
+                deletions_rewrite_threshold: 0,
+                core: Mutex::new(ManifestFileCore {
+                    file: None,
+                    manifest: Manifest::new(),
+                    poisoned: false,
+                }),
+            });
+        }
+        if opts.read_only {
+            return Self::open_read_only(opts.env.clone(), &opts.dir, opts.badger_compat);
+        }
+        Self::help_open_or_create_manifest_file(
+            opts.env.clone(),
+            &opts.dir,
+            MANIFEST_DELETIONS_REWRITE_THRESHOLD,
+        )
+    }
+
+    /// Replay MANIFEST file without modifying it. Changes are only applied
+    /// in memory afterwards. If `badger_compat` is true, MANIFEST written by
+    /// Badger is accepted as well.
+    fn open_read_only(env: Arc<dyn Env>, dir: &Path, badger_compat: bool) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILENAME);
+        let context = || ErrorContext::path(&path);
+        let data = env
+            .read_file(&path)
+            .map_err(|e| e.with_context(context()))?;
+        let (manifest, _) = Self::replay_manifest_file_with(data, badger_compat)
+            .map_err(|e| e.with_context(context()))?;
+        Ok(Self {
+            env,
+            directory: dir.to_path_buf(),
+            deletions_rewrite_threshold: 0,
+            core: Mutex::new(ManifestFileCore {
+                file: None,
+                manifest,
+                poisoned: false,
+            }),
+        })
+    }
+
+    fn help_open_or_create_manifest_file(
+        env: Arc<dyn Env>,
+        dir: impl AsRef<Path>,
+        deletions_threshold: usize,
+    ) -> Result<Self> {
+        let path = dir.as_ref().join(MANIFEST_FILENAME);
+
+        if !env.exists(&path) {
+            let manifest = Manifest::new();
+            let (file, creations) = Self::help_rewrite(env.as_ref(), dir.as_ref(), &manifest)?;
+            assert_eq!(creations, 0);
+            return Ok(Self {
+                env,
+                directory: dir.as_ref().to_path_buf(),
+                deletions_rewrite_threshold: deletions_threshold,
+                core: Mutex::new(ManifestFileCore {
+                    file: Some(file),
+                    manifest,
+                    poisoned: false,
+                }),
+            });
+        }
+
+        let data = env
+            .read_file(&path)
+            .map_err(|e| e.with_context(ErrorContext::path(&path)))?;
+        let (manifest, trunc_offset) = Self::replay_manifest_file(data)
+            .map_err(|e| e.with_context(ErrorContext::path(&path)))?;
+
+        let mut file = env.open_writable(&path, OpenMode::Existing, IoPriority::Foreground)?;
+
+        // Truncate file so we don't have a half-written entry at the end.
+        file.set_len(trunc_offset as u64)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            env,
+            directory: dir.as_ref().to_path_buf(),
+            deletions_rewrite_threshold: deletions_threshold,
+            core: Mutex::new(ManifestFileCore {
+                file: Some(file),
+                manifest,
+                poisoned: false,
+            }),
+        })
+    }
+
+    /// Write a new MANIFEST file containing all tables in `manifest`,
+    /// and atomically replace the old one.
+    fn help_rewrite(
+        env: &dyn Env,
+        dir: &Path,
+        manifest: &Manifest,
+    ) -> Result<(Box<dyn WritableFile>, usize)> {
+        let rewrite_path = dir.join(MANIFEST_REWRITE_FILENAME);
+
+        let mut fp =
+            env.open_writable(&rewrite_path, OpenMode::Truncate, IoPriority::Foreground)?;
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(MAGIC_TEXT);
+        buf.put_u32(MAGIC_VERSION);
+
+        let creations = manifest.tables.len();
+        let changes = manifest.as_changes();
+        let set = ManifestChangeSet { changes };
+
+        let mut change_buf = BytesMut::new();
+        set.encode(&mut change_buf).unwrap();
+        buf.put_u32(change_buf.len() as u32);
+        buf.put_u32(checksum::crc32c(&change_buf));
+        buf.put_slice(&change_buf);
+
+        fp.write_all(&buf)?;
+        fp.sync_all()?;
+        drop(fp);
+
+        let manifest_path = dir.join(MANIFEST_FILENAME);
+        env.rename(&rewrite_path, &manifest_path)?;
+
+        let mut fp =
+            env.open_writable(&manifest_path, OpenMode::Existing, IoPriority::Foreground)?;
+        fp.seek(SeekFrom::End(0))?;
+        env.sync_dir(dir)?;
+
+        Ok((fp, creations))
+    }
+
+    /// Read all change sets from MANIFEST file, returning the manifest and
+    /// the offset of the end of last valid change set.
+    pub(crate) fn replay_manifest_file(data: Bytes) -> Result<(Manifest, usize)> {
+        Self::replay_manifest_file_with(data, false)
+    }
+
+    fn replay_manifest_file_with(data: Bytes, badger_compat: bool) -> Result<(Manifest, usize)> {
+        let mut buf = data;
+
+        if buf.len() < 8 {
+            return Err(Error::CustomError("MANIFEST has bad magic".to_string()));
+        }
+        let magic = buf.split_to(4);
+        let version = buf.get_u32();
+        if badger_compat && magic[..] == BADGER_MAGIC_TEXT[..] {
+            if !BADGER_MAGIC_VERSIONS.contains(&version) {
+                return Err(Error::CustomError(format!(
+                    "Badger MANIFEST has unsupported version: {} (we support {:?})",
+                    version, BADGER_MAGIC_VERSIONS
+                )));
+            }
+        } else if magic[..] != MAGIC_TEXT[..] {
+            return Err(Error::CustomError("MANIFEST has bad magic".to_string()));
+        } else if version != MAGIC_VERSION {
+            return Err(Error::CustomError(format!(
+                "MANIFEST has unsupported version: {} (we support {})",
+                version, MAGIC_VERSION
+            )));
+        }
+
+        let mut manifest = Manifest::new();
+        let mut offset = 8;
+
+        while buf.remaining() >= 8 {
+            let length = buf.get_u32() as usize;
+            let checksum = buf.get_u32();
+            if buf.remaining() < length {
+                // a half-written change set, which should be truncated
+                break;
+            }
+            let data = buf.split_to(length);
+            if checksum::crc32c(&data) != checksum {
+                return Err(Error::InvalidChecksum(
+                    "MANIFEST has checksum mismatch".to_string(),
+                ));
+            }
+            let change_set = ManifestChangeSet::decode(data)?;
+            manifest.apply_change_set(&change_set)?;
+            offset += 8 + length;
+        }
+
+        Ok((manifest, offset))
+    }
+
+    /// Append changes to MANIFEST file atomically. In-memory manifest is
+    /// only updated after the changes are synced, so a failed call can be
+    /// retried.
+    pub fn add_changes(&self, changes: Vec<ManifestChange>) -> Result<()> {
+        // In-memory manifest may be ahead of file if a panic happened while
+        // appending, so it can't be used anymore.
+        let mut core = self.core.lock().map_err(|_| {
+            Error::Internal("manifest is poisoned by a panic during update".to_string())
+        })?;
+        if core.poisoned {
+            return Err(Error::Internal(
+                "manifest is poisoned by a failed update".to_string(),
+            ));
+        }
+        let set = ManifestChangeSet { changes };
+        let mut manifest = core.manifest.clone();
+        manifest.apply_change_set(&set)?;
+        if core.file.is_none() {
+            // in-memory mode, nothing to persist
+            core.manifest = manifest;
+            return Ok(());
+        }
+
+        if manifest.deletions > self.deletions_rewrite_threshold
+            && manifest.deletions
+                > MANIFEST_DELETIONS_RATIO * (manifest.creations - manifest.deletions)
+        {
+            core.manifest = manifest;
+            if let Err(e) = self.rewrite(&mut core) {
+                // Either the old or the new MANIFEST is on disk now.
+                core.poisoned = true;
+                return Err(e);
+            }
+            return Ok(());
+        }
+
+        let mut change_buf = BytesMut::new();
+        set.encode(&mut change_buf).unwrap();
+        let mut buf = BytesMut::with_capacity(8 + change_buf.len());
+        buf.put_u32(change_buf.len() as u32);
+        buf.put_u32(checksum::crc32c(&change_buf));
+        buf.put_slice(&change_buf);
+        let file = core.file.as_mut().unwrap();
+        let res = file.write_all(&buf).map_err(Error::from).and_then(|_| {
+            fail::fail_point!("manifest_before_sync", |_| Err(Error::Io(Box::new(
+                std::io::Error::other("failpoint manifest_before_sync")
+            ))));
+            file.sync_all()
+        });
+        if let Err(e) = res {
+            // The change set may be partially written, drop it by rewriting
+            // the file from the unchanged in-memory manifest.
+            if self.rewrite(&mut core).is_err() {
+                core.poisoned = true;
+            }
+            return Err(e);
+        }
+        core.manifest = manifest;
+        Ok(())
+    }
+
+    fn rewrite(&self, core: &mut ManifestFileCore) -> Result<()> {
+        // drop current file first, as it will be replaced
+        core.file.take();
+        let (file, creations) =
+            Self::help_rewrite(self.env.as_ref(), &self.directory, &core.manifest)?;
+        core.file = Some(file);
+        core.manifest.creations = creations;
+        core.manifest.deletions = 0;
+        Ok(())
+    }
+
+    /// Hard link all tables in manifest into `dir`, and write a MANIFEST
+    /// recording them there. Manifest is locked meanwhile, so no table is
+    /// added or removed in between.
+    pub fn checkpoint(&self, dir: &Path) -> Result<()> {
+        let core = self.core.lock().map_err(|_| {
+            Error::Internal("manifest is poisoned by a panic during update".to_string())
+        })?;
+        for id in core.manifest.tables.keys() {
+            let from = crate::table::new_filename(*id, &self.directory);
+            let to = crate::table::new_filename(*id, dir);
+            self.env
+                .hard_link(&from, &to)
+                .map_err(|e| e.with_context(ErrorContext::path(&from)))?;
+        }
+        Self::help_rewrite(self.env.as_ref(), dir, &core.manifest)?;
+        Ok(())
+    }
+
+    /// Get a copy of current manifest.
+    pub fn manifest_cloned(&self) -> Manifest {
+        self.core.lock().unwrap().manifest.clone()
+    }
+}
+
+pub fn new_create_change(id: u64, level: usize, key_id: u64) -> ManifestChange {
+    ManifestChange {
+        id,
+        op: ManifestChangeOp::Create as i32,
+        level: level as u32,
+        key_id,
+        // unused fields
+        encryption_algo: 0,
+        compression: 0,
+        smallest: vec![],
+        biggest: vec![],
+        max_version: 0,
+    }
+}
+
+/// Create change of adding `table` to `level`, which records key range and
+/// max version of the table.
+pub fn new_table_create_change(table: &Table, level: usize) -> ManifestChange {
+    ManifestChange {
+        smallest: table.smallest().to_vec(),
+        biggest: table.biggest().to_vec(),
+        max_version: table.max_version(),
+        // TODO: encryption
+        ..new_create_change(table.id(), level, 0)
+    }
+}
+
+pub fn new_delete_change(id: u64) -> ManifestChange {
+    ManifestChange {
+        id,
+        op: ManifestChangeOp::Delete as i32,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::StdEnv;
+    use std::fs::{self, OpenOptions};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_manifest_replay() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        let mf = ManifestFile::open_or_create_manifest_file(&opts).unwrap();
+        mf.add_changes(vec![new_create_change(1, 0, 0), new_create_change(2, 1, 0)])
+            .unwrap();
+        mf.add_changes(vec![new_create_change(3, 0, 0), new_delete_change(1)])
+            .unwrap();
+        assert!(mf.add_changes(vec![new_delete_change(1)]).is_err());
+        drop(mf);
+
+        let mf = ManifestFile::open_or_create_manifest_file(&opts).unwrap();
+        let manifest = mf.manifest_cloned();
+        assert_eq!(manifest.tables.len(), 2);
+        assert_eq!(manifest.tables[&2].level, 1);
+        assert_eq!(manifest.levels[0].tables.len(), 1);
+        assert!(manifest.levels[0].tables.contains(&3));
+    }
+
+    #[test]
+    fn test_manifest_truncated() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        let mf = ManifestFile::open_or_create_manifest_file(&opts).unwrap();
+        mf.add_changes(vec![new_create_change(1, 0, 0)]).unwrap();
+        mf.add_changes(vec![new_create_change(2, 0, 0)]).unwrap();
+        drop(mf);
+
+        // simulate a half-written change set
+        let path = tmp_dir.path().join(MANIFEST_FILENAME);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let mf = ManifestFile::open_or_create_manifest_file(&opts).unwrap();
+        assert_eq!(mf.manifest_cloned().tables.len(), 1);
+        mf.add_changes(vec![new_create_change(3, 0, 0)]).unwrap();
+        drop(mf);
+
+        let mf = ManifestFile::open_or_create_manifest_file(&opts).unwrap();
+        let manifest = mf.manifest_cloned();
+        assert_eq!(manifest.tables.len(), 2);
+        assert!(manifest.tables.contains_key(&3));
+    }
+
+    #[test]
+    fn test_manifest_invalid_level() {
+        let mut manifest = Manifest::new();
+        let mut change = new_create_change(1, 0, 0);
+        change.level = u32::MAX;
+        assert!(manifest.apply_change(&change).is_err());
+        assert!(manifest.levels.is_empty());
+    }
+
+    #[test]
+    fn test_manifest_poisoned() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mf =
+            ManifestFile::help_open_or_create_manifest_file(Arc::new(StdEnv), tmp_dir.path(), 10)
+                .unwrap();
+        mf.add_changes(vec![new_create_change(1, 0, 0)]).unwrap();
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _core = mf.core.lock().unwrap();
+            panic!("panic while updating manifest");
+        }));
+        assert!(res.is_err());
+        assert!(matches!(
+            mf.add_changes(vec![new_create_change(2, 0, 0)]),
+            Err(Error::Internal(_))
+        ));
+    }
+
+    #[test]
+    fn test_manifest_rewrite() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let mf =
+            ManifestFile::help_open_or_create_manifest_file(Arc::new(StdEnv), tmp_dir.path(), 10)
+                .unwrap();
+        for i in 0..100 {
+            mf.add_changes(vec![new_create_change(i, 0, 0)]).unwrap();
+            mf.add_changes(vec![new_delete_change(i)]).unwrap();
+        }
+        let change = ManifestChange {
+            smallest: b"a".to_vec(),
+            biggest: b"z".to_vec(),
+            max_version: 10,
+            ..new_create_change(100, 1, 0)
+        };
+        mf.add_changes(vec![change]).unwrap();
+        drop(mf);
+
+        let mf =
+            ManifestFile::help_open_or_create_manifest_file(Arc::new(StdEnv), tmp_dir.path(), 10)
+                .unwrap();
+        let manifest = mf.manifest_cloned();
+        assert_eq!(manifest.tables.len(), 1);
+        assert!(manifest.deletions < 100);
+        assert!(manifest.levels[1].tables.contains(&100));
+        // Key ranges are kept by rewriting.
+        let tm = &manifest.tables[&100];
+        assert_eq!((&tm.smallest[..], &tm.biggest[..]), (&b"a"[..], &b"z"[..]));
+        assert_eq!(tm.max_version, 10);
+    }
+}
@@ -1,31 +1,85 @@
-use crate::util::Comparator;
-use crate::value::Value;
-use crate::wal::Wal;
+use crate::comparator::Comparator;
+use crate::entry::Entry;
+use crate::format::get_ts;
+use crate::iterator_trait::AgateIterator;
+use crate::value::{Value, VALUE_FIN_TXN, VALUE_TXN};
+use crate::wal::{Wal, WalIterator};
 use crate::AgateOptions;
 use crate::Result;
 use bytes::Bytes;
-use skiplist::Skiplist;
+use skiplist::{Hint, IterRef, Skiplist};
 use std::collections::VecDeque;
 use std::mem::{self, ManuallyDrop, MaybeUninit};
 
+use std::io::Cursor;
+use std::path::Path;
 use std::ptr;
-use std::sync::Mutex;
+use std::sync::{Arc, Condvar, Mutex};
 
-const MEMTABLE_VIEW_MAX: usize = 20;
+pub(crate) const MEMTABLE_VIEW_MAX: usize = 20;
 
-/// MemTableCore guards WAL and max_version.
+/// Memory held by memtables, in bytes.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// allocated from skiplist arena, mostly by skiplist nodes
+    pub arena: u64,
+    /// keys and values inserted into skiplist, which are stored out of arena
+    pub data: u64,
+    /// keys and values appended to WAL but not inserted into skiplist yet
+    pub pending: u64,
+    /// buffers for encoding WAL entries
+    pub wal_buffer: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.arena + self.data + self.pending + self.wal_buffer
+    }
+
+    fn add(&mut self, other: &MemoryUsage) {
+        self.arena += other.arena;
+        self.data += other.data;
+        self.pending += other.pending;
+        self.wal_buffer += other.wal_buffer;
+    }
+}
+
+fn entry_value(entry: &Entry) -> Value {
+    let mut value = Value::new_with_meta(entry.value.clone(), entry.meta, entry.user_meta);
+    value.meta2 = entry.meta2;
+    value.expires_at = entry.expires_at;
+    value
+}
+
+fn entry_size(key: &[u8], value: &Value) -> u64 {
+    (key.len() + value.encoded_size() as usize) as u64
+}
+
+/// MemTableCore guards max_version and states of insertion.
 /// These data will only be modified on memtable put.
-/// Therefore, separating wal and max_version enables
+/// Therefore, separating them from skiplist enables
 /// concurrent read/write of MemTable.
 struct MemTableCore {
-    wal: Option<Wal>,
     max_version: u64,
+    /// sum of key and value size inserted into skiplist
+    data_size: u64,
+    /// sum of key and value size appended to WAL but not inserted yet
+    pending_size: u64,
+    /// number of batches appended to WAL but not inserted into skiplist yet
+    unapplied: usize,
+    /// splice of last insertion, making ordered writes cheaper
+    hint: Hint,
 }
 
 pub struct MemTable {
     pub(crate) skl: Skiplist<Comparator>,
     opt: AgateOptions,
+    /// WAL is guarded separately, so that appending a batch to WAL doesn't
+    /// block inserting previous batch into skiplist.
+    wal: Mutex<Option<Wal>>,
     core: Mutex<MemTableCore>,
+    /// Notified when all appended batches are inserted into skiplist.
+    applied: Condvar,
 }
 
 impl MemTable {
@@ -33,23 +87,279 @@ impl MemTable {
         Self {
             skl,
             opt,
+            wal: Mutex::new(wal),
             core: Mutex::new(MemTableCore {
-                wal,
                 max_version: 0,
+                data_size: 0,
+                pending_size: 0,
+                unapplied: 0,
+                hint: Hint::default(),
             }),
+            applied: Condvar::new(),
         }
     }
 
+    /// Replay WAL of memtable into skiplist. This should be called once
+    /// when an existing memtable is opened. Entries of a transaction are
+    /// only applied if the transaction is finished in WAL.
     pub fn update_skip_list(&self) -> Result<()> {
-        unimplemented!()
+        if let Some(ref mut wal) = *self.wal.lock().unwrap() {
+            let mut it = wal.iter()?;
+            self.replay(&mut it)?;
+            // Unfinished transaction will be overwritten by following writes.
+            let end = it.valid_end();
+            wal.set_write_at(end);
+        }
+        Ok(())
     }
 
-    pub fn put(&self, _key: Bytes, _value: Value) -> Result<()> {
-        unimplemented!()
+    /// Replay WAL file at `path` into skiplist without modifying the file.
+    /// The memtable should be created without WAL.
+    pub(crate) fn replay_read_only(&self, path: &Path) -> Result<()> {
+        let data = self.opt.env.read_file(path)?;
+        self.replay(&mut WalIterator::new(Cursor::new(&data[..])))
+    }
+
+    fn replay(&self, it: &mut WalIterator) -> Result<()> {
+        let mut core = self.core.lock().unwrap();
+        let mut txn = vec![];
+        while let Some(entry) = it.next()? {
+            let v = Value {
+                value: Bytes::copy_from_slice(entry.value),
+                meta: entry.meta,
+                meta2: entry.meta2,
+                user_meta: entry.user_meta,
+                expires_at: entry.expires_at,
+                version: 0,
+            };
+            let key = Bytes::copy_from_slice(entry.key);
+            if entry.meta & VALUE_TXN != 0 {
+                txn.push((key, v));
+                continue;
+            }
+            if entry.meta & VALUE_FIN_TXN == 0 {
+                txn.push((key, v));
+            }
+            for (key, v) in txn.drain(..) {
+                let ts = get_ts(&key);
+                if ts > core.max_version {
+                    core.max_version = ts;
+                }
+                core.data_size += entry_size(&key, &v);
+                self.skl.put_with_hint(key, v, &mut core.hint);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write entry into WAL (if any) and then into skiplist.
+    /// `key` should be a key with timestamp.
+    pub fn put(&self, key: Bytes, value: Value) -> Result<()> {
+        let entry = Entry {
+            key,
+            value: value.value,
+            meta: value.meta,
+            meta2: value.meta2,
+            user_meta: value.user_meta,
+            expires_at: value.expires_at,
+            version: 0,
+            ttl: None,
+        };
+        self.write_batch(vec![entry])?;
+        Ok(())
+    }
+
+    /// Write a batch of entries into WAL (if any) with a single write, and
+    /// then into skiplist. Keys of entries should contain timestamp. Returns
+    /// the number of bytes written to WAL.
+    pub(crate) fn write_batch(&self, entries: Vec<Entry>) -> Result<usize> {
+        let wal_bytes = self.append_wal(&entries)?;
+        self.insert_batch(entries);
+        Ok(wal_bytes)
+    }
+
+    /// Append a batch of entries into WAL (if any) with a single write. The
+    /// entries are not visible until `insert_batch` is called with them.
+    /// Returns the number of bytes written to WAL.
+    pub(crate) fn append_wal(&self, entries: &[Entry]) -> Result<usize> {
+        let mut wal_bytes = 0;
+        if let Some(ref mut wal) = *self.wal.lock().unwrap() {
+            // If WAL exceeds opts.value_log_file_size, we'll force flush the memtable.
+            wal_bytes = wal.write_entries(entries)?;
+        }
+
+        let mut core = self.core.lock().unwrap();
+        core.unapplied += 1;
+        for entry in entries {
+            if entry.meta & VALUE_FIN_TXN != 0 {
+                continue;
+            }
+            core.pending_size += entry_size(&entry.key, &entry_value(entry));
+        }
+        Ok(wal_bytes)
+    }
+
+    /// Insert a batch of entries appended by `append_wal` into skiplist.
+    /// Batches should be inserted in the same order as they are appended.
+    pub(crate) fn insert_batch(&self, entries: Vec<Entry>) {
+        let mut guard = self.core.lock().unwrap();
+        let core = &mut *guard;
+        for entry in entries {
+            let ts = get_ts(&entry.key);
+            if ts > core.max_version {
+                core.max_version = ts;
+            }
+            if entry.meta & VALUE_FIN_TXN != 0 {
+                // Transaction marker is only needed in WAL.
+                continue;
+            }
+            let value = entry_value(&entry);
+            let size = entry_size(&entry.key, &value);
+            core.pending_size -= size;
+            core.data_size += size;
+
+            self.skl.put_with_hint(entry.key, value, &mut core.hint);
+        }
+
+        core.unapplied -= 1;
+        if core.unapplied == 0 {
+            self.applied.notify_all();
+        }
+    }
+
+    /// Block until all batches appended to WAL are inserted into skiplist.
+    pub(crate) fn wait_applied(&self) {
+        let mut core = self.core.lock().unwrap();
+        while core.unapplied > 0 {
+            core = self.applied.wait(core).unwrap();
+        }
     }
 
     pub fn sync_wal(&self) -> Result<()> {
-        unimplemented!()
+        if let Some(ref mut wal) = *self.wal.lock().unwrap() {
+            wal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Give the encoding buffer of WAL back to the buffer pool, when no more
+    /// entries will be written to the memtable.
+    pub(crate) fn release_wal_buffer(&self) {
+        if let Some(ref mut wal) = *self.wal.lock().unwrap() {
+            wal.release_buffer();
+        }
+    }
+
+    /// Get value of `key` from memtable. `key` should be a key with timestamp,
+    /// and the first version not greater than the timestamp will be returned.
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        self.skl.get(key).map(|v| {
+            let mut value = Value::default();
+            value.decode(v);
+            value
+        })
+    }
+
+    pub fn max_version(&self) -> u64 {
+        self.core.lock().unwrap().max_version
+    }
+
+    /// Get memory held by memtable.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let wal_buffer = match *self.wal.lock().unwrap() {
+            Some(ref wal) => wal.buffer_size() as u64,
+            None => 0,
+        };
+        let core = self.core.lock().unwrap();
+        MemoryUsage {
+            arena: self.skl.mem_size() as u64,
+            data: core.data_size,
+            pending: core.pending_size,
+            wal_buffer,
+        }
+    }
+
+    /// Returns `true` if memtable should be rotated and flushed to disk.
+    /// Whether WAL of the memtable has room for a batch of `size` bytes, see
+    /// `Wal::has_room`.
+    pub(crate) fn wal_has_room(&self, size: usize) -> bool {
+        match *self.wal.lock().unwrap() {
+            Some(ref wal) => wal.has_room(size),
+            None => true,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        if let Some(ref wal) = *self.wal.lock().unwrap() {
+            if wal.should_flush() {
+                return true;
+            }
+        }
+        self.memory_usage().total() >= self.opt.mem_table_size
+    }
+
+    /// Remove WAL of memtable. This should only be called after data
+    /// in memtable has been persisted to an SST.
+    pub(crate) fn delete_wal(&self) -> Result<()> {
+        if let Some(wal) = self.wal.lock().unwrap().take() {
+            wal.close_and_remove()?;
+        }
+        Ok(())
+    }
+
+    /// Get an iterator over all entries in memtable.
+    pub fn new_iterator(&self, reversed: bool) -> MemTableIterator {
+        MemTableIterator {
+            inner: self.skl.iter(),
+            reversed,
+        }
+    }
+}
+
+/// `MemTableIterator` iterates a skiplist and decodes values
+/// so that it could be merged with table iterators.
+pub struct MemTableIterator {
+    inner: IterRef<Skiplist<Comparator>, Comparator>,
+    reversed: bool,
+}
+
+impl AgateIterator for MemTableIterator {
+    fn next(&mut self) {
+        if !self.reversed {
+            self.inner.next();
+        } else {
+            self.inner.prev();
+        }
+    }
+
+    fn rewind(&mut self) {
+        if !self.reversed {
+            self.inner.seek_to_first();
+        } else {
+            self.inner.seek_to_last();
+        }
+    }
+
+    fn seek(&mut self, key: &Bytes) {
+        if !self.reversed {
+            self.inner.seek(key);
+        } else {
+            self.inner.seek_for_prev(key);
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        self.inner.key()
+    }
+
+    fn value(&self) -> Value {
+        let mut value = Value::default();
+        value.decode(self.inner.value());
+        value
+    }
+
+    fn valid(&self) -> bool {
+        self.inner.valid()
     }
 }
 
@@ -75,12 +385,12 @@ impl Drop for MemTablesView {
 }
 
 pub struct MemTables {
-    mutable: MemTable,
-    immutable: VecDeque<MemTable>,
+    mutable: Arc<MemTable>,
+    immutable: VecDeque<Arc<MemTable>>,
 }
 
 impl MemTables {
-    pub(crate) fn new(mutable: MemTable, immutable: VecDeque<MemTable>) -> Self {
+    pub(crate) fn new(mutable: Arc<MemTable>, immutable: VecDeque<Arc<MemTable>>) -> Self {
         Self { mutable, immutable }
     }
 
@@ -101,7 +411,278 @@ impl MemTables {
     }
 
     /// Get mutable memtable
-    pub fn table_mut(&self) -> &MemTable {
+    pub fn table_mut(&self) -> &Arc<MemTable> {
         &self.mutable
     }
+
+    /// Get immutable memtable at `idx`, where memtables are ordered from
+    /// the oldest to the newest.
+    pub fn table_imm(&self, idx: usize) -> &Arc<MemTable> {
+        &self.immutable[idx]
+    }
+
+    /// Get number of all memtables, including the mutable one.
+    pub fn nums_of_memtable(&self) -> usize {
+        self.immutable.len() + 1
+    }
+
+    /// Make current mutable memtable immutable, and use `memtable` as the
+    /// new mutable one.
+    pub(crate) fn use_new_table(&mut self, memtable: Arc<MemTable>) {
+        let old_mt = std::mem::replace(&mut self.mutable, memtable);
+        self.immutable.push_back(old_mt);
+    }
+
+    /// Get memory held by all memtables.
+    /// Get the max version among all memtables.
+    pub fn max_version(&self) -> u64 {
+        self.immutable
+            .iter()
+            .map(|mt| mt.max_version())
+            .fold(self.mutable.max_version(), u64::max)
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = self.mutable.memory_usage();
+        for mt in &self.immutable {
+            usage.add(&mt.memory_usage());
+        }
+        usage
+    }
+
+    /// Remove the oldest immutable memtable, which has been flushed.
+    pub(crate) fn pop_imm(&mut self) -> Option<Arc<MemTable>> {
+        self.immutable.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{key_with_ts, user_key};
+    use crate::value::VALUE_DELETE;
+    use tempdir::TempDir;
+
+    fn new_memtable(wal: Option<Wal>, opts: AgateOptions) -> MemTable {
+        let skl = Skiplist::with_capacity(Comparator::default(), 1 << 20);
+        MemTable::new(skl, wal, opts)
+    }
+
+    #[test]
+    fn test_memtable_put_get() {
+        let mt = new_memtable(None, AgateOptions::default());
+        for ts in 1..=3 {
+            let value = Value::new(Bytes::from(format!("value{}", ts)));
+            mt.put(key_with_ts("key", ts), value).unwrap();
+        }
+        assert_eq!(mt.max_version(), 3);
+        assert_eq!(mt.get(&key_with_ts("key", 2)).unwrap().value, "value2");
+        assert_eq!(mt.get(&key_with_ts("key", 10)).unwrap().value, "value3");
+        assert!(mt.get(&key_with_ts("key", 0)).is_none());
+        assert!(mt.get(&key_with_ts("key2", 3)).is_none());
+    }
+
+    #[test]
+    fn test_memtable_iterator() {
+        let mt = new_memtable(None, AgateOptions::default());
+        for i in 0..100 {
+            let value = Value::new(Bytes::from(i.to_string()));
+            mt.put(key_with_ts(format!("key{:03}", i).as_str(), 1), value)
+                .unwrap();
+        }
+
+        let mut it = mt.new_iterator(false);
+        it.rewind();
+        let mut count = 0;
+        while it.valid() {
+            assert_eq!(user_key(it.key()), format!("key{:03}", count).as_bytes());
+            assert_eq!(it.value().value, count.to_string());
+            count += 1;
+            it.next();
+        }
+        assert_eq!(count, 100);
+
+        let mut it = mt.new_iterator(true);
+        it.seek(&key_with_ts("key050", 1));
+        assert_eq!(it.value().value, "50");
+        it.next();
+        assert_eq!(it.value().value, "49");
+        it.rewind();
+        assert_eq!(it.value().value, "99");
+    }
+
+    #[test]
+    fn test_memtable_memory_usage() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 4096,
+            value_log_file_size: 1 << 20,
+            ..Default::default()
+        };
+        let wal = Wal::open(tmp_dir.path().join("00001.mem"), opts.clone()).unwrap();
+        let mt = new_memtable(Some(wal), opts);
+        let empty = mt.memory_usage();
+
+        let entries: Vec<_> = (0..10)
+            .map(|i| {
+                Entry::new(
+                    key_with_ts(format!("key{:02}", i).as_str(), 1),
+                    Bytes::from("value"),
+                )
+            })
+            .collect();
+        mt.append_wal(&entries).unwrap();
+        let appended = mt.memory_usage();
+        assert_eq!(appended.data, 0);
+        // key with timestamp takes 13 bytes, and encoded value takes 8 bytes
+        assert_eq!(appended.pending, 10 * (13 + 8));
+        assert!(appended.wal_buffer > 0);
+        assert_eq!(appended.arena, empty.arena);
+
+        mt.insert_batch(entries);
+        let inserted = mt.memory_usage();
+        assert_eq!(inserted.data, appended.pending);
+        assert_eq!(inserted.pending, 0);
+        assert!(inserted.arena > empty.arena);
+        assert!(!mt.is_full());
+
+        // Node overhead counts towards memtable size as well.
+        for i in 10.. {
+            mt.put(
+                key_with_ts(format!("key{:02}", i).as_str(), 1),
+                Value::new(Bytes::new()),
+            )
+            .unwrap();
+            if mt.is_full() {
+                break;
+            }
+        }
+        let full = mt.memory_usage();
+        assert!(full.total() >= 4096);
+        assert!(full.data < 4096);
+    }
+
+    #[test]
+    fn test_memtable_replay_wal() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            value_log_file_size: 4096,
+            ..Default::default()
+        };
+        let path = tmp_dir.path().join("00001.mem");
+
+        let mt = new_memtable(
+            Some(Wal::open(path.clone(), opts.clone()).unwrap()),
+            opts.clone(),
+        );
+        for i in 1..=10 {
+            let value = Value::new(Bytes::from(i.to_string()));
+            mt.put(key_with_ts(format!("key{:02}", i).as_str(), i), value)
+                .unwrap();
+        }
+        mt.sync_wal().unwrap();
+        drop(mt);
+
+        let mt = new_memtable(
+            Some(Wal::open(path.clone(), opts.clone()).unwrap()),
+            opts.clone(),
+        );
+        mt.update_skip_list().unwrap();
+        assert_eq!(mt.max_version(), 10);
+        assert_eq!(mt.skl.len(), 10);
+        mt.put(key_with_ts("key11", 11), Value::new(Bytes::from("11")))
+            .unwrap();
+        drop(mt);
+
+        // new entries should be appended after replayed ones
+        let mt = new_memtable(Some(Wal::open(path, opts.clone()).unwrap()), opts);
+        mt.update_skip_list().unwrap();
+        assert_eq!(mt.max_version(), 11);
+        assert_eq!(mt.get(&key_with_ts("key05", 5)).unwrap().value, "5");
+        assert_eq!(mt.get(&key_with_ts("key11", 11)).unwrap().value, "11");
+    }
+
+    #[test]
+    fn test_memtable_replay_unfinished_txn() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            value_log_file_size: 4096,
+            ..Default::default()
+        };
+        let path = tmp_dir.path().join("00001.mem");
+
+        let new_entry = |key: &str, ts: u64, meta: u8| {
+            let mut entry = Entry::new(key_with_ts(key, ts), Bytes::from(key.to_owned()));
+            entry.meta = meta;
+            entry
+        };
+
+        let mt = new_memtable(
+            Some(Wal::open(path.clone(), opts.clone()).unwrap()),
+            opts.clone(),
+        );
+        mt.write_batch(vec![
+            new_entry("a", 1, VALUE_TXN),
+            new_entry("b", 1, VALUE_TXN),
+            new_entry("txn", 1, VALUE_FIN_TXN),
+            new_entry("c", 2, 0),
+            new_entry("d", 3, VALUE_TXN),
+        ])
+        .unwrap();
+        // transaction marker is not written to skiplist
+        assert_eq!(mt.skl.len(), 4);
+        drop(mt);
+
+        let mt = new_memtable(
+            Some(Wal::open(path.clone(), opts.clone()).unwrap()),
+            opts.clone(),
+        );
+        mt.update_skip_list().unwrap();
+        assert_eq!(mt.skl.len(), 3);
+        assert_eq!(mt.max_version(), 2);
+        assert!(mt.get(&key_with_ts("d", 3)).is_none());
+
+        // unfinished transaction is overwritten
+        mt.put(key_with_ts("e", 4), Value::new(Bytes::from("e")))
+            .unwrap();
+        drop(mt);
+        let mt = new_memtable(Some(Wal::open(path, opts.clone()).unwrap()), opts);
+        mt.update_skip_list().unwrap();
+        assert_eq!(mt.skl.len(), 4);
+        assert_eq!(mt.get(&key_with_ts("e", 4)).unwrap().value, "e");
+    }
+
+    #[test]
+    fn test_memtable_replay_meta2() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            value_log_file_size: 4096,
+            ..Default::default()
+        };
+        let path = tmp_dir.path().join("00001.mem");
+
+        let mt = new_memtable(
+            Some(Wal::open(path.clone(), opts.clone()).unwrap()),
+            opts.clone(),
+        );
+        let mut entry = Entry::new(key_with_ts("a", 1), Bytes::from("a"));
+        entry.meta = VALUE_DELETE;
+        entry.meta2 = 1 << 33;
+        mt.write_batch(vec![
+            entry,
+            Entry::new(key_with_ts("b", 2), Bytes::from("b")),
+        ])
+        .unwrap();
+        assert_eq!(mt.get(&key_with_ts("a", 1)).unwrap().meta2, 1 << 33);
+        drop(mt);
+
+        let mt = new_memtable(Some(Wal::open(path, opts.clone()).unwrap()), opts);
+        mt.update_skip_list().unwrap();
+        let value = mt.get(&key_with_ts("a", 1)).unwrap();
+        assert_eq!(value.meta, VALUE_DELETE);
+        assert_eq!(value.meta2, 1 << 33);
+        assert_eq!(value.value, "a");
+        let value = mt.get(&key_with_ts("b", 2)).unwrap();
+        assert_eq!((value.meta, value.meta2), (0, 0));
+    }
 }
@@ -0,0 +1,52 @@
+use bytes::Bytes;
+
+use std::convert::TryFrom;
+
+/// Folds merge operands written by `Transaction::add` into values. Operands
+/// are recorded as is, and folded with older versions on read, so writers
+/// updating the same key don't have to read it first.
+pub trait MergeOperator: Send + Sync {
+    /// Fold `operand` into `existing` value of `key`, which is `None` if
+    /// there is no older value, or it's deleted or expired.
+    ///
+    /// Operands of a key may be folded together before the value they apply
+    /// to is known, so the operation should be associative.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Bytes;
+}
+
+/// Treats values and operands as little-endian `u64` and adds them up, which
+/// makes counters. Malformed values are treated as 0.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct U64AddOperator;
+
+impl U64AddOperator {
+    fn decode(value: &[u8]) -> u64 {
+        <[u8; 8]>::try_from(value)
+            .map(u64::from_le_bytes)
+            .unwrap_or(0)
+    }
+}
+
+impl MergeOperator for U64AddOperator {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Bytes {
+        let sum = existing
+            .map_or(0, Self::decode)
+            .wrapping_add(Self::decode(operand));
+        Bytes::copy_from_slice(&sum.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_u64_add_operator() {
+        let op = U64AddOperator;
+        let v = |n: u64| Bytes::copy_from_slice(&n.to_le_bytes());
+        assert_eq!(op.merge(b"k", None, &v(3)), v(3));
+        assert_eq!(op.merge(b"k", Some(&v(3)), &v(4)), v(7));
+        assert_eq!(op.merge(b"k", Some(b"bad"), &v(4)), v(4));
+        assert_eq!(op.merge(b"k", Some(&v(u64::MAX)), &v(2)), v(1));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::allocator::AllocatorStats;
+use crate::cache::hit_ratio;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters of a database instance. All counters are cumulative since the
+/// database is opened.
+#[derive(Default)]
+pub struct Metrics {
+    num_puts: AtomicU64,
+    num_deletes: AtomicU64,
+    num_write_batches: AtomicU64,
+    num_batched_entries: AtomicU64,
+    max_batch_entries: AtomicU64,
+    wal_bytes: AtomicU64,
+    num_memtable_flushes: AtomicU64,
+    memtable_flush_micros: AtomicU64,
+    write_stall_micros: AtomicU64,
+    write_throttle_micros: AtomicU64,
+}
+
+/// A point-in-time copy of `Metrics`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// number of entries written which are not deletions
+    pub num_puts: u64,
+    /// number of deletion markers written
+    pub num_deletes: u64,
+    /// number of batches written to memtable, each batch may contain
+    /// entries of several grouped commits
+    pub num_write_batches: u64,
+    /// number of entries in all written batches
+    pub num_batched_entries: u64,
+    /// number of entries in the largest batch
+    pub max_batch_entries: u64,
+    /// bytes appended to WAL
+    pub wal_bytes: u64,
+    /// number of memtables flushed to L0
+    pub num_memtable_flushes: u64,
+    /// total time spent on flushing memtables
+    pub memtable_flush_duration: Duration,
+    /// total time writers are blocked because memtables are full
+    pub write_stall_duration: Duration,
+    /// total time writers are throttled by write rate limits
+    pub write_throttle_duration: Duration,
+    /// number of block lookups served by block cache
+    pub block_cache_hits: u64,
+    /// number of block lookups which read SSTs
+    pub block_cache_misses: u64,
+    /// number of blocks evicted from block cache
+    pub block_cache_evictions: u64,
+    /// bytes of cached blocks, which is not cumulative
+    pub block_cache_bytes: u64,
+    /// number of index lookups served by index cache
+    pub index_cache_hits: u64,
+    /// number of index lookups which read SSTs
+    pub index_cache_misses: u64,
+    /// number of indexes evicted from index cache
+    pub index_cache_evictions: u64,
+    /// bytes of cached indexes, which is not cumulative
+    pub index_cache_bytes: u64,
+    /// number of L0 tables to trigger compaction, which is not cumulative
+    pub level_zero_compaction_trigger: usize,
+    /// number of L0 tables to stall flushes, which is not cumulative
+    pub level_zero_stall_trigger: usize,
+    /// memory usage of the whole process reported by the global allocator,
+    /// only available with `jemalloc` feature
+    pub allocator: Option<AllocatorStats>,
+}
+
+impl MetricsSnapshot {
+    /// Ratio of block lookups served by block cache.
+    pub fn block_cache_hit_ratio(&self) -> f64 {
+        hit_ratio(self.block_cache_hits, self.block_cache_misses)
+    }
+
+    /// Ratio of index lookups served by index cache.
+    pub fn index_cache_hit_ratio(&self) -> f64 {
+        hit_ratio(self.index_cache_hits, self.index_cache_misses)
+    }
+}
+
+impl Metrics {
+    pub(crate) fn record_write_batch(&self, puts: u64, deletes: u64, wal_bytes: u64) {
+        self.num_puts.fetch_add(puts, Ordering::Relaxed);
+        self.num_deletes.fetch_add(deletes, Ordering::Relaxed);
+        self.num_write_batches.fetch_add(1, Ordering::Relaxed);
+        self.num_batched_entries
+            .fetch_add(puts + deletes, Ordering::Relaxed);
+        self.max_batch_entries
+            .fetch_max(puts + deletes, Ordering::Relaxed);
+        self.wal_bytes.fetch_add(wal_bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_memtable_flush(&self, duration: Duration) {
+        self.num_memtable_flushes.fetch_add(1, Ordering::Relaxed);
+        self.memtable_flush_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write_stall(&self, duration: Duration) {
+        self.write_stall_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write_throttle(&self, duration: Duration) {
+        self.write_throttle_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            num_puts: self.num_puts.load(Ordering::Relaxed),
+            num_deletes: self.num_deletes.load(Ordering::Relaxed),
+            num_write_batches: self.num_write_batches.load(Ordering::Relaxed),
+            num_batched_entries: self.num_batched_entries.load(Ordering::Relaxed),
+            max_batch_entries: self.max_batch_entries.load(Ordering::Relaxed),
+            wal_bytes: self.wal_bytes.load(Ordering::Relaxed),
+            num_memtable_flushes: self.num_memtable_flushes.load(Ordering::Relaxed),
+            memtable_flush_duration: Duration::from_micros(
+                self.memtable_flush_micros.load(Ordering::Relaxed),
+            ),
+            write_stall_duration: Duration::from_micros(
+                self.write_stall_micros.load(Ordering::Relaxed),
+            ),
+            write_throttle_duration: Duration::from_micros(
+                self.write_throttle_micros.load(Ordering::Relaxed),
+            ),
+            ..Default::default()
+        }
+    }
+}
@@ -1,3 +1,4 @@
-mod oracle;
-mod snapshot;
-mod transaction;
+pub(crate) mod oracle;
+pub(crate) mod snapshot;
+pub(crate) mod transaction;
+pub(crate) mod write_batch;
@@ -1,24 +1,167 @@
+use std::collections::{BTreeMap, HashSet};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Fingerprints of keys written by a commit, kept while transactions
+/// reading before it are running.
+struct CommittedTxn {
+    ts: u64,
+    conflict_keys: HashSet<u64>,
+}
+
+/// State of conflict detection, see `IsolationLevel::Serializable`.
+#[derive(Default)]
+struct ConflictTracker {
+    committed_txns: Vec<CommittedTxn>,
+    /// read timestamps of running update transactions, and their numbers
+    running: BTreeMap<u64, usize>,
+}
 
 pub struct Oracle {
     next_txn_ts: AtomicU64,
+    /// All commits not newer than this timestamp are written, so they are
+    /// visible to new transactions.
+    done_commit_ts: AtomicU64,
+    /// Timestamps are supplied by caller, and so is `discard_ts`.
+    managed: bool,
     discard_ts: AtomicU64,
+    /// read timestamps of running transactions and streams, and their
+    /// numbers, which hold off discarding versions visible to them
+    reads: Mutex<BTreeMap<u64, usize>>,
+    /// Commit timestamps are allocated and sent to write thread under this
+    /// lock, so that commits are written in the order of their timestamps.
+    pub(crate) write_lock: Mutex<()>,
+    conflicts: Mutex<ConflictTracker>,
 }
 
 impl Oracle {
+    /// Create an oracle which allocates timestamps after `max_version`.
+    pub fn new(max_version: u64, managed: bool) -> Self {
+        Self {
+            next_txn_ts: AtomicU64::new(max_version + 1),
+            done_commit_ts: AtomicU64::new(max_version),
+            managed,
+            discard_ts: AtomicU64::new(0),
+            reads: Mutex::default(),
+            write_lock: Mutex::new(()),
+            conflicts: Mutex::default(),
+        }
+    }
+
     pub fn read_ts(&self) -> u64 {
-        self.next_txn_ts.load(Ordering::SeqCst) - 1
+        self.done_commit_ts.load(Ordering::SeqCst)
+    }
+
+    /// Get read timestamp of a reader, whose visible versions are kept
+    /// until `end_read` is called with it.
+    pub fn begin_read(&self) -> u64 {
+        // `discard_ts` is computed under the same lock, so that it's never
+        // above the timestamp of a new reader.
+        let mut reads = self.reads.lock().unwrap();
+        let read_ts = self.read_ts();
+        *reads.entry(read_ts).or_default() += 1;
+        read_ts
+    }
+
+    pub fn end_read(&self, read_ts: u64) {
+        remove_ts(&mut self.reads.lock().unwrap(), read_ts);
+    }
+
+    /// Get read timestamp of an update transaction whose conflicts are
+    /// detected. It's also tracked as a reader. `end_txn` must be called
+    /// with it once the transaction is committed or dropped.
+    pub fn begin_txn(&self) -> u64 {
+        // Commits are cleaned up under the same lock, so that those after
+        // the read timestamp are kept.
+        let mut conflicts = self.conflicts.lock().unwrap();
+        let read_ts = self.begin_read();
+        *conflicts.running.entry(read_ts).or_default() += 1;
+        read_ts
+    }
+
+    pub fn end_txn(&self, read_ts: u64) {
+        remove_ts(&mut self.conflicts.lock().unwrap().running, read_ts);
+        self.end_read(read_ts);
     }
 
-    pub fn next_ts(&self) -> u64 {
-        self.next_txn_ts.load(Ordering::SeqCst)
+    /// Whether any of `reads` is written by commits after `read_ts`. It
+    /// must be called under `write_lock`, before the commit is tracked by
+    /// `track_commit`.
+    pub fn has_conflict(&self, read_ts: u64, reads: &[u64]) -> bool {
+        let conflicts = self.conflicts.lock().unwrap();
+        conflicts
+            .committed_txns
+            .iter()
+            .filter(|txn| txn.ts > read_ts)
+            .any(|txn| reads.iter().any(|key| txn.conflict_keys.contains(key)))
     }
 
-    pub fn increment_next_ts(&self) {
-        self.next_txn_ts.fetch_add(1, Ordering::SeqCst);
+    /// Remember `conflict_keys` written at `commit_ts`, and forget commits
+    /// which can't conflict with running or new transactions, which are
+    /// those not newer than any read timestamp.
+    pub fn track_commit(&self, commit_ts: u64, conflict_keys: HashSet<u64>) {
+        let mut conflicts = self.conflicts.lock().unwrap();
+        let mut watermark = self.read_ts();
+        if let Some(&ts) = conflicts.running.keys().next() {
+            watermark = watermark.min(ts);
+        }
+        conflicts.committed_txns.retain(|txn| txn.ts > watermark);
+        conflicts.committed_txns.push(CommittedTxn {
+            ts: commit_ts,
+            conflict_keys,
+        });
+    }
+
+    /// Number of commits kept to detect conflicts.
+    pub(crate) fn tracked_commits(&self) -> usize {
+        self.conflicts.lock().unwrap().committed_txns.len()
+    }
+
+    /// Allocate a new commit timestamp. A timestamp is never reused, even if
+    /// the commit fails.
+    pub fn new_commit_ts(&self) -> u64 {
+        self.next_txn_ts.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Mark the commit at `commit_ts` as written, whether it succeeds or not.
+    pub fn done_commit(&self, commit_ts: u64) {
+        self.done_commit_ts.fetch_max(commit_ts, Ordering::SeqCst);
+    }
+
+    /// Make commits up to `version` visible, and allocate following commit
+    /// timestamps after it. It's used when data with versions is written
+    /// without transactions.
+    pub(crate) fn advance_to(&self, version: u64) {
+        let _guard = self.write_lock.lock().unwrap();
+        self.next_txn_ts.fetch_max(version + 1, Ordering::SeqCst);
+        self.done_commit_ts.fetch_max(version, Ordering::SeqCst);
     }
 
     pub fn set_discard_ts(&self, discard_ts: u64) {
         self.discard_ts.store(discard_ts, Ordering::SeqCst);
     }
+
+    /// Versions not newer than this timestamp are only read at their newest
+    /// one of every key, so compaction drops older ones. Unless timestamps
+    /// are managed by caller, it's the oldest read timestamp of running
+    /// readers, or the newest commit timestamp if there is none, like
+    /// `discardAtOrBelow` of badger.
+    pub fn discard_ts(&self) -> u64 {
+        if self.managed {
+            return self.discard_ts.load(Ordering::SeqCst);
+        }
+        let reads = self.reads.lock().unwrap();
+        let done = self.read_ts();
+        reads.keys().next().map_or(done, |&ts| ts.min(done))
+    }
+}
+
+/// Decrease the number of `ts` in `counts`, removing it once it's 0.
+fn remove_ts(counts: &mut BTreeMap<u64, usize>, ts: u64) {
+    if let Some(count) = counts.get_mut(&ts) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&ts);
+        }
+    }
 }
@@ -1,17 +1,99 @@
-use crate::db::Agate;
+use crate::db::{Agate, Core};
+use crate::format::{get_ts, user_key, with_key_ts, KeyTs};
+use crate::iterator::{Item, Iterator, IteratorOptions};
+use crate::iterator_trait::AgateIterator;
+use crate::ops::transaction::Transaction;
+use crate::value::VALUE_DELETE;
+use crate::Result;
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::sync::Arc;
 
+/// Read-only view of the database at a read timestamp, which is not
+/// affected by later writes.
 pub struct Snapshot {
-    read_ts: u64,
-    agate: Agate,
+    core: Arc<Core>,
+    txn: Transaction,
+}
+
+impl Agate {
+    /// Create a snapshot of all data visible to new transactions. With
+    /// `AgateOptions::managed_txns`, it reads at the newest commit timestamp.
+    pub fn snapshot(&self) -> Snapshot {
+        let txn = if self.core.opts().managed_txns {
+            self.new_transaction_at(self.core.orc.read_ts(), false)
+        } else {
+            self.new_transaction(false)
+        };
+        Snapshot {
+            core: self.core.clone(),
+            txn,
+        }
+    }
 }
 
 impl Snapshot {
-    /*
-    pub fn get(&self, key: &mut [u8]) -> Result<Option<Bytes>> {
-        if key.is_empty() {
-            return Err(Error::EmptyKey);
+    pub fn read_ts(&self) -> u64 {
+        self.txn.read_ts()
+    }
+
+    /// Get the latest version of `key` visible to the snapshot, see
+    /// `Transaction::get`.
+    pub fn get(&self, key: &Bytes) -> Result<Item> {
+        self.txn.get(key)
+    }
+
+    /// Create an iterator over keys visible to the snapshot, see
+    /// `Transaction::new_iterator`.
+    pub fn new_iterator(&self, opts: &IteratorOptions) -> Result<Iterator<'_>> {
+        self.txn.new_iterator(opts)
+    }
+
+    /// Call `f` with the latest version of every key within `[start, end)`
+    /// in the order of keys, until it returns false. An empty `end` means
+    /// no upper bound. Deleted and expired keys are skipped.
+    pub fn scan(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        mut f: impl FnMut(Item) -> Result<bool>,
+    ) -> Result<()> {
+        let opts = IteratorOptions {
+            all_versions: true,
+            ..Default::default()
+        };
+        let mut iter = self.core.new_merged_iterator(&opts)?;
+        let now = self.core.clock().unix_time();
+        let read_ts = self.read_ts();
+        let c = self.core.comparator();
+        with_key_ts(KeyTs::new(start, read_ts), |key| iter.seek(key));
+        while iter.valid() {
+            let key = user_key(iter.key());
+            if !end.is_empty() && c.compare_user_key(key, end) != Ordering::Less {
+                break;
+            }
+            let version = get_ts(iter.key());
+            if version > read_ts {
+                iter.next();
+                continue;
+            }
+
+            let key = Bytes::copy_from_slice(key);
+            let mut value = iter.value();
+            value.version = version;
+            let value = self.core.fold_merge(&key, value, version.checked_sub(1))?;
+            if value.meta & VALUE_DELETE == 0
+                && !value.is_expired(now)
+                && !f(Item::new(key.clone(), value))?
+            {
+                return Ok(());
+            }
+
+            iter.next();
+            while iter.valid() && user_key(iter.key()) == &key[..] {
+                iter.next();
+            }
         }
-        self.agate.get_with_ts(key, self.read_ts)
+        Ok(())
     }
-    */
 }
@@ -1,55 +1,965 @@
-use crate::db::Agate;
+use crate::comparator::Comparator;
+use crate::db::{Agate, Core, IsolationLevel};
 use crate::entry::Entry;
+use crate::format::{key_with_ts, with_key_ts, KeyTs};
+#[cfg(feature = "async")]
+use crate::future::WriteFuture;
+use crate::iterator::{Item, Iterator, IteratorOptions};
+use crate::iterator_trait::AgateIterator;
+use crate::table::MergeIterator;
+use crate::util::KeyComparator;
+use crate::value::{Value, WriteCallback, VALUE_DELETE};
 use crate::{Error, Result};
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
-const MAX_KEY_LENGTH: usize = 65000;
+/// Key of the entry which marks the end of a transaction in WAL.
+pub(crate) const TXN_KEY: &[u8] = b"!badger!txn";
 
 pub struct Transaction {
     read_ts: u64,
     commit_ts: u64,
 
     update: bool,
+    /// Whether reads are tracked and checked for conflicts on commit, see
+    /// `IsolationLevel`.
+    detect_conflicts: bool,
+    /// Whether `read_ts` is tracked by oracle as a reader, so that versions
+    /// visible to it are not discarded by compaction.
+    track_read: bool,
+    /// fingerprints of keys read from database
+    reads: Mutex<Vec<u64>>,
     pending_writes: HashMap<Bytes, Entry>,
-    agate: Agate,
+    core: Arc<Core>,
 }
 
 impl Agate {
+    /// Create a transaction reading at the latest committed timestamp.
+    /// With `AgateOptions::managed_txns`, it can't be committed, see
+    /// `new_transaction_at`.
     pub fn new_transaction(&self, update: bool) -> Transaction {
+        let orc = &self.core.orc;
+        let detect_conflicts = update && self.core.opts().isolation == IsolationLevel::Serializable;
+        let read_ts = if detect_conflicts {
+            orc.begin_txn()
+        } else {
+            orc.begin_read()
+        };
         Transaction {
-            read_ts: 0,
+            read_ts,
             commit_ts: 0,
             update,
+            detect_conflicts,
+            track_read: !detect_conflicts,
+            reads: Mutex::default(),
             pending_writes: HashMap::default(),
-            agate: self.clone(),
+            core: self.core.clone(),
+        }
+    }
+
+    /// Create a transaction reading at `read_ts` supplied by caller, which
+    /// must be committed by `Transaction::commit_at`. Only available with
+    /// `AgateOptions::managed_txns`, and conflicts are never detected.
+    pub fn new_transaction_at(&self, read_ts: u64, update: bool) -> Transaction {
+        Transaction {
+            read_ts,
+            commit_ts: 0,
+            update,
+            detect_conflicts: false,
+            track_read: false,
+            reads: Mutex::default(),
+            pending_writes: HashMap::default(),
+            core: self.core.clone(),
+        }
+    }
+
+    /// Allow compaction to discard versions older than the newest one not
+    /// newer than `discard_ts` of every key. Caller must make sure no
+    /// transaction reads below it. Only available with
+    /// `AgateOptions::managed_txns`.
+    pub fn set_discard_ts(&self, discard_ts: u64) -> Result<()> {
+        if !self.core.opts().managed_txns {
+            return Err(Error::Config(
+                "set_discard_ts can only be used with managed_txns".to_string(),
+            ));
+        }
+        self.core.orc.set_discard_ts(discard_ts);
+        Ok(())
+    }
+}
+
+impl Transaction {
+    /// Create an update transaction whose reads are never checked for
+    /// conflicts, for writes which don't depend on reads like write batches.
+    pub(crate) fn new_blind(core: Arc<Core>) -> Transaction {
+        Transaction {
+            read_ts: core.orc.read_ts(),
+            commit_ts: 0,
+            update: true,
+            detect_conflicts: false,
+            track_read: false,
+            reads: Mutex::default(),
+            pending_writes: HashMap::default(),
+            core,
         }
     }
 }
 
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.detect_conflicts {
+            self.core.orc.end_txn(self.read_ts);
+        } else if self.track_read {
+            self.core.orc.end_read(self.read_ts);
+        }
+    }
+}
+
+fn key_fingerprint(key: &[u8]) -> u64 {
+    farmhash::fingerprint64(key)
+}
+
 impl Transaction {
+    /// Timestamp at which the transaction reads.
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
     pub fn set(&mut self, key: Bytes, value: Bytes) -> Result<()> {
         self.modify(Entry::new(key, value))
     }
 
+    /// Add an entry with options like TTL to pending writes.
+    pub fn set_entry(&mut self, e: Entry) -> Result<()> {
+        self.modify(e)
+    }
+
     pub fn delete(&mut self, key: Bytes) -> Result<()> {
         let mut e = Entry::new(key, Bytes::new());
         e.mark_delete();
         self.modify(e)
     }
 
+    /// Record `delta` as a merge operand of `key`, which is folded with older
+    /// versions by `AgateOptions::merge_operator` on read. Unlike updating
+    /// `key` after reading it, concurrent adds don't depend on each other.
+    pub fn add(&mut self, key: Bytes, delta: Bytes) -> Result<()> {
+        let op = self.core.merge_operator()?;
+        let e = match self.pending_writes.remove(&key) {
+            Some(prev) if prev.meta & VALUE_DELETE != 0 => {
+                Entry::new(key.clone(), op.merge(&key, None, &delta))
+            }
+            // Operands are associative, so a pending operand can absorb delta.
+            Some(mut prev) => {
+                prev.value = op.merge(&key, Some(&prev.value), &delta);
+                prev
+            }
+            None => {
+                let mut e = Entry::new(key, delta);
+                e.mark_merge();
+                e
+            }
+        };
+        self.modify(e)
+    }
+
+    /// Iterate over writes of the transaction which are not committed yet,
+    /// in the order of keys.
+    pub fn pending_entries(&self) -> impl std::iter::Iterator<Item = &Entry> {
+        let mut entries: Vec<_> = self.pending_writes.values().collect();
+        let c = self.core.comparator();
+        entries.sort_by(|a, b| c.compare_user_key(&a.key, &b.key));
+        entries.into_iter()
+    }
+
+    /// Get the latest version of `key` visible to the transaction, including
+    /// pending writes of the transaction itself.
+    pub fn get(&self, key: &Bytes) -> Result<Item> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+
+        let value = match self.pending_writes.get(key) {
+            Some(e) => {
+                let mut value = Value::new_with_meta(e.value.clone(), e.meta, e.user_meta);
+                value.meta2 = e.meta2;
+                value.expires_at = e.expires_at;
+                value.version = self.read_ts;
+                // Pending operand applies to the versions visible at read_ts.
+                self.core.fold_merge(&key[..], value, Some(self.read_ts))?
+            }
+            None => {
+                self.add_read_key(key);
+                let key_ts = KeyTs::new(key, self.read_ts);
+                let value = with_key_ts(key_ts, |key| self.core.get(key))?;
+                // Missing values are returned with version 0.
+                if value.version == 0 {
+                    return Err(Error::KeyNotFound);
+                }
+                value
+            }
+        };
+
+        if value.meta & VALUE_DELETE != 0 || value.is_expired(self.core.clock().unix_time()) {
+            return Err(Error::KeyNotFound);
+        }
+
+        Ok(Item::new(key.clone(), value))
+    }
+
+    /// Create an iterator over keys visible to the transaction, including
+    /// its pending writes, see `IteratorOptions`.
+    pub fn new_iterator(&self, opts: &IteratorOptions) -> Result<Iterator<'_>> {
+        let mut iter = self.core.new_merged_iterator(opts)?;
+        // Pending writes come first, so that they win over committed
+        // versions at the read timestamp.
+        if let Some(pending) = self.new_pending_writes_iterator(&opts.prefix, opts.reverse) {
+            iter = MergeIterator::from_iterators(
+                vec![Box::new(pending.into()), iter],
+                opts.reverse,
+                self.core.comparator(),
+            );
+        }
+        Ok(Iterator::new(self, iter, opts.clone()))
+    }
+
+    fn new_pending_writes_iterator(
+        &self,
+        prefix: &[u8],
+        reversed: bool,
+    ) -> Option<PendingWritesIterator> {
+        let c = self.core.comparator();
+        let mut entries: Vec<_> = self
+            .pending_writes
+            .values()
+            .filter(|e| e.key.starts_with(prefix))
+            .map(|e| {
+                let mut value = Value::new_with_meta(e.value.clone(), e.meta, e.user_meta);
+                value.meta2 = e.meta2;
+                value.expires_at = e.expires_at;
+                (key_with_ts(&e.key, self.read_ts), value)
+            })
+            .collect();
+        if entries.is_empty() {
+            return None;
+        }
+        entries.sort_by(|(a, _), (b, _)| c.compare_key(a, b));
+        Some(PendingWritesIterator {
+            entries,
+            idx: 0,
+            reversed,
+            comparator: c.clone(),
+        })
+    }
+
+    pub(crate) fn has_pending_write(&self, key: &[u8]) -> bool {
+        self.pending_writes.contains_key(key)
+    }
+
+    pub(crate) fn core(&self) -> &Arc<Core> {
+        &self.core
+    }
+
+    /// Track `key` as read, so that the commit conflicts with others
+    /// writing it since the read timestamp.
+    pub(crate) fn add_read_key(&self, key: &[u8]) {
+        if self.detect_conflicts {
+            self.reads.lock().unwrap().push(key_fingerprint(key));
+        }
+    }
+
+    /// Write all pending writes atomically at a new commit timestamp. Fails
+    /// with `Error::Config` if `AgateOptions::managed_txns` is set.
+    pub fn commit(mut self) -> Result<()> {
+        self.commit_and_wait(None)
+    }
+
+    /// Write all pending writes atomically at `commit_ts` supplied by
+    /// caller, see `Agate::new_transaction_at`. Fails with `Error::Config`
+    /// unless `AgateOptions::managed_txns` is set.
+    pub fn commit_at(mut self, commit_ts: u64) -> Result<()> {
+        self.commit_and_wait(Some(commit_ts))
+    }
+
+    fn commit_and_wait(&mut self, commit_ts: Option<u64>) -> Result<()> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.commit_and_send(
+            commit_ts,
+            Box::new(move |result| {
+                let _ = tx.send(result);
+            }),
+        )?;
+        match rx.recv() {
+            Ok(result) => result,
+            Err(_) => Err(Error::DBClosed),
+        }
+    }
+
+    /// Same as `commit`, but returns a future which resolves once all pending
+    /// writes are applied, instead of blocking current thread.
+    #[cfg(feature = "async")]
+    pub fn commit_async(mut self) -> WriteFuture {
+        let (future, done) = WriteFuture::new();
+        if let Err(err) = self.commit_and_send(None, done) {
+            return WriteFuture::ready(Err(err));
+        }
+        future
+    }
+
+    /// Same as `commit`, but calls `done` once all pending writes are
+    /// applied instead of waiting for them.
+    pub(crate) fn commit_with_callback(mut self, done: WriteCallback) -> Result<()> {
+        self.commit_and_send(None, done)
+    }
+
+    /// Send pending writes to write thread at `commit_ts`, or a new commit
+    /// timestamp if it's not supplied. The commit becomes visible to new
+    /// transactions before `done` is called.
+    fn commit_and_send(&mut self, commit_ts: Option<u64>, done: WriteCallback) -> Result<()> {
+        match (commit_ts, self.core.opts().managed_txns) {
+            (None, true) => {
+                return Err(Error::Config(
+                    "commit can't be used with managed_txns, use commit_at instead".to_string(),
+                ))
+            }
+            (Some(_), false) => {
+                return Err(Error::Config(
+                    "commit_at can only be used with managed_txns".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        if self.pending_writes.is_empty() {
+            done(Ok(()));
+            return Ok(());
+        }
+
+        let orc = &self.core.orc;
+        let _guard = orc.write_lock.lock()?;
+        if self.detect_conflicts {
+            let reads = self.reads.get_mut().unwrap();
+            if orc.has_conflict(self.read_ts, reads) {
+                return Err(Error::Conflict);
+            }
+        }
+        self.commit_ts = commit_ts.unwrap_or_else(|| orc.new_commit_ts());
+        if self.detect_conflicts {
+            let conflict_keys: HashSet<_> = self
+                .pending_writes
+                .keys()
+                .map(|k| key_fingerprint(k))
+                .collect();
+            orc.track_commit(self.commit_ts, conflict_keys);
+        }
+        let entries = self.pending_writes.drain().map(|(_, e)| e).collect();
+        let entries = Core::txn_batch(entries, self.commit_ts)?;
+        let (core, commit_ts) = (self.core.clone(), self.commit_ts);
+        self.core.send_to_write_channel(
+            entries,
+            None,
+            Box::new(move |result| {
+                core.orc.done_commit(commit_ts);
+                done(result);
+            }),
+        )
+    }
+
     fn modify(&mut self, e: Entry) -> Result<()> {
         if e.key.is_empty() {
             return Err(Error::EmptyKey);
         }
-        if e.key.len() > MAX_KEY_LENGTH {
-            return Err(Error::TooLong(format!(
-                "key's length > {}: {:?}..",
-                MAX_KEY_LENGTH,
-                &e.key[..MAX_KEY_LENGTH]
-            )));
-        }
+        self.core.check_entry_size(e.key.len(), e.value.len())?;
         self.pending_writes.insert(e.key.clone(), e);
         Ok(())
     }
 }
+
+/// Iterates pending writes of a transaction at its read timestamp, so that
+/// they can be merged with data in the database.
+pub struct PendingWritesIterator {
+    /// keys with timestamps and values, sorted by keys
+    entries: Vec<(Bytes, Value)>,
+    /// position in `entries`, which is out of range once the iterator
+    /// moves past either end
+    idx: usize,
+    reversed: bool,
+    comparator: Comparator,
+}
+
+impl AgateIterator for PendingWritesIterator {
+    fn next(&mut self) {
+        if !self.reversed {
+            self.idx += 1;
+        } else {
+            self.idx = self.idx.wrapping_sub(1);
+        }
+    }
+
+    fn rewind(&mut self) {
+        if !self.reversed {
+            self.idx = 0;
+        } else {
+            self.idx = self.entries.len().wrapping_sub(1);
+        }
+    }
+
+    fn seek(&mut self, key: &Bytes) {
+        let c = &self.comparator;
+        if !self.reversed {
+            self.idx = self
+                .entries
+                .partition_point(|(k, _)| c.compare_key(k, key) == Ordering::Less);
+        } else {
+            self.idx = self
+                .entries
+                .partition_point(|(k, _)| c.compare_key(k, key) != Ordering::Greater)
+                .wrapping_sub(1);
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.entries[self.idx].0
+    }
+
+    fn value(&self) -> Value {
+        self.entries[self.idx].1.clone()
+    }
+
+    fn valid(&self) -> bool {
+        self.idx < self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MAX_KEY_LENGTH;
+    use crate::format::key_with_ts;
+    use crate::wal::{CRC_SIZE, MAX_HEADER_SIZE};
+    use crate::AgateOptions;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_txn_user_meta() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 20,
+            value_log_file_size: 1 << 20,
+            ..Default::default()
+        };
+        {
+            let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+            let mut txn = agate.new_transaction(true);
+            let entry = Entry::new(Bytes::from("key1"), Bytes::from("value1")).with_meta(7);
+            txn.set_entry(entry).unwrap();
+            txn.set(Bytes::from("key2"), Bytes::from("value2")).unwrap();
+            // pending writes are visible to the transaction itself
+            assert_eq!(txn.get(&Bytes::from("key1")).unwrap().user_meta(), 7);
+            txn.commit().unwrap();
+
+            let mut txn = agate.new_transaction(true);
+            txn.delete(Bytes::from("key2")).unwrap();
+            assert!(matches!(
+                txn.get(&Bytes::from("key2")),
+                Err(Error::KeyNotFound)
+            ));
+            txn.commit().unwrap();
+
+            let txn = agate.new_transaction(false);
+            let item = txn.get(&Bytes::from("key1")).unwrap();
+            assert_eq!(item.value(), "value1");
+            assert_eq!(item.user_meta(), 7);
+            assert_eq!(item.version(), 1);
+            assert!(matches!(
+                txn.get(&Bytes::from("key2")),
+                Err(Error::KeyNotFound)
+            ));
+            assert!(matches!(
+                txn.get(&Bytes::from("key3")),
+                Err(Error::KeyNotFound)
+            ));
+            agate.flush_memtable(true).unwrap();
+        }
+
+        // user meta is persisted in tables, and timestamps continue after reopen
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let txn = agate.new_transaction(false);
+        let item = txn.get(&Bytes::from("key1")).unwrap();
+        assert_eq!(item.key(), "key1");
+        assert_eq!(item.user_meta(), 7);
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("key1"), Bytes::from("value3")).unwrap();
+        txn.commit().unwrap();
+        let item = agate
+            .new_transaction(false)
+            .get(&Bytes::from("key1"))
+            .unwrap();
+        assert_eq!(item.value(), "value3");
+        assert_eq!(item.user_meta(), 0);
+        assert_eq!(item.version(), 3);
+    }
+
+    #[test]
+    fn test_txn_pending_entries() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction(true);
+        assert_eq!(txn.pending_entries().count(), 0);
+
+        for key in &["key3", "key1", "key2"] {
+            txn.set(Bytes::from(*key), Bytes::from(format!("{}-value", key)))
+                .unwrap();
+        }
+        txn.delete(Bytes::from("key0")).unwrap();
+        txn.set(Bytes::from("key1"), Bytes::from("new-value"))
+            .unwrap();
+
+        let entries: Vec<_> = txn
+            .pending_entries()
+            .map(|e| (e.key.clone(), e.value.clone(), e.is_deleted()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (Bytes::from("key0"), Bytes::new(), true),
+                (Bytes::from("key1"), Bytes::from("new-value"), false),
+                (Bytes::from("key2"), Bytes::from("key2-value"), false),
+                (Bytes::from("key3"), Bytes::from("key3-value"), false),
+            ]
+        );
+
+        // Pending entries can be augmented before commit.
+        let index: Vec<_> = txn
+            .pending_entries()
+            .filter(|e| !e.is_deleted())
+            .map(|e| Bytes::from(format!("index/{:?}", e.value)))
+            .collect();
+        for key in index {
+            txn.set(key, Bytes::new()).unwrap();
+        }
+        assert_eq!(txn.pending_entries().count(), 7);
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_txn_add() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 20,
+            value_log_file_size: 1 << 20,
+            merge_operator: Some(Arc::new(crate::U64AddOperator)),
+            ..Default::default()
+        };
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let n = |n: u64| Bytes::copy_from_slice(&n.to_le_bytes());
+        let key = Bytes::from("counter");
+        let add = |delta: u64| {
+            let mut txn = agate.new_transaction(true);
+            txn.add(key.clone(), n(delta)).unwrap();
+            txn.commit().unwrap();
+        };
+        let read = || {
+            agate
+                .new_transaction(false)
+                .get(&key)
+                .unwrap()
+                .value()
+                .clone()
+        };
+
+        let mut txn = agate.new_transaction(true);
+        txn.add(key.clone(), n(1)).unwrap();
+        txn.add(key.clone(), n(2)).unwrap();
+        assert_eq!(txn.get(&key).unwrap().value(), &n(3));
+        txn.commit().unwrap();
+        add(5);
+        assert_eq!(read(), n(8));
+
+        // Operands are folded across memtables and tables.
+        agate.flush_memtable(true).unwrap();
+        add(10);
+        assert_eq!(read(), n(18));
+        let values = agate
+            .get_multi(&[key_with_ts(&key[..], u64::MAX), key_with_ts(&key[..], 2)])
+            .unwrap();
+        assert_eq!(values[0].value, n(18));
+        assert_eq!(values[1].value, n(8));
+
+        // Pending operand applies to committed value.
+        let mut txn = agate.new_transaction(true);
+        txn.add(key.clone(), n(2)).unwrap();
+        assert_eq!(txn.get(&key).unwrap().value(), &n(20));
+        drop(txn);
+
+        // Operands don't go beyond a complete value or a deletion.
+        let mut txn = agate.new_transaction(true);
+        txn.set(key.clone(), n(100)).unwrap();
+        txn.commit().unwrap();
+        add(1);
+        assert_eq!(read(), n(101));
+        let mut txn = agate.new_transaction(true);
+        txn.delete(key.clone()).unwrap();
+        txn.add(key.clone(), n(4)).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(read(), n(4));
+        let mut txn = agate.new_transaction(true);
+        txn.delete(key.clone()).unwrap();
+        txn.commit().unwrap();
+        add(7);
+        assert_eq!(read(), n(7));
+    }
+
+    #[test]
+    fn test_txn_conflict() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        let (k1, k2) = (Bytes::from("key1"), Bytes::from("key2"));
+        let mut txn = agate.new_transaction(true);
+        txn.set(k1.clone(), Bytes::from("value")).unwrap();
+        txn.commit().unwrap();
+
+        // Write skew: both transactions read the key written by the other.
+        let mut txn1 = agate.new_transaction(true);
+        let mut txn2 = agate.new_transaction(true);
+        txn1.get(&k1).unwrap();
+        assert!(txn2.get(&k2).is_err());
+        txn1.set(k2.clone(), Bytes::from("value1")).unwrap();
+        txn2.set(k1.clone(), Bytes::from("value2")).unwrap();
+        txn2.commit().unwrap();
+        let err = txn1.commit().unwrap_err();
+        assert!(matches!(err, Error::Conflict));
+        assert!(err.is_retryable());
+
+        // Blind writes and reads of pending writes don't conflict.
+        let mut txn1 = agate.new_transaction(true);
+        let mut txn2 = agate.new_transaction(true);
+        txn1.set(k1.clone(), Bytes::from("value1")).unwrap();
+        txn1.get(&k1).unwrap();
+        txn2.set(k1.clone(), Bytes::from("value2")).unwrap();
+        txn2.commit().unwrap();
+        txn1.commit().unwrap();
+        assert!(agate.new_transaction(false).get(&k2).is_err());
+
+        // Commits are forgotten once no transaction reads before them.
+        assert!(agate.core.orc.tracked_commits() > 0);
+        let mut txn = agate.new_transaction(true);
+        txn.set(k2, Bytes::new()).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(agate.core.orc.tracked_commits(), 1);
+    }
+
+    #[test]
+    fn test_txn_iterator() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction(true);
+        for key in &["a", "b", "c"] {
+            txn.set(Bytes::from(*key), Bytes::from(format!("{}1", key)))
+                .unwrap();
+        }
+        txn.commit().unwrap();
+        agate.flush_memtable(true).unwrap();
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("a"), Bytes::from("a2")).unwrap();
+        txn.delete(Bytes::from("b")).unwrap();
+        txn.commit().unwrap();
+
+        let collect = |txn: &Transaction, opts: IteratorOptions| {
+            let mut iter = txn.new_iterator(&opts).unwrap();
+            iter.rewind().unwrap();
+            let mut items = vec![];
+            while iter.valid() {
+                let item = iter.item();
+                let key = String::from_utf8(item.key().to_vec()).unwrap();
+                items.push(if item.is_deleted() {
+                    format!("{}@{} deleted", key, item.version())
+                } else {
+                    let value = String::from_utf8(item.value().to_vec()).unwrap();
+                    format!("{}@{}={}", key, item.version(), value)
+                });
+                iter.next().unwrap();
+            }
+            items
+        };
+        // Pending writes are visible, and win over committed versions.
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("c"), Bytes::from("c3")).unwrap();
+        txn.set(Bytes::from("d"), Bytes::from("d3")).unwrap();
+        assert_eq!(
+            collect(&txn, IteratorOptions::default()),
+            vec!["a@2=a2", "c@2=c3", "d@2=d3"]
+        );
+        let opts = IteratorOptions {
+            all_versions: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            collect(&txn, opts),
+            vec![
+                "a@2=a2",
+                "a@1=a1",
+                "b@2 deleted",
+                "b@1=b1",
+                "c@2=c3",
+                "c@1=c1",
+                "d@2=d3"
+            ]
+        );
+        let opts = IteratorOptions {
+            key_only: true,
+            prefix: Bytes::from("c"),
+            ..Default::default()
+        };
+        assert_eq!(collect(&txn, opts), vec!["c@2="]);
+        let opts = IteratorOptions {
+            since_ts: 2,
+            all_versions: true,
+            ..Default::default()
+        };
+        let reader = agate.new_transaction(false);
+        assert_eq!(collect(&reader, opts), vec!["a@2=a2", "b@2 deleted"]);
+        let mut iter = reader.new_iterator(&IteratorOptions::default()).unwrap();
+        iter.seek(b"b").unwrap();
+        assert_eq!(iter.item().key(), &Bytes::from("c"));
+        iter.next().unwrap();
+        assert!(!iter.valid());
+
+        // Keys iterated are read by the transaction.
+        let mut txn2 = agate.new_transaction(true);
+        txn2.set(Bytes::from("a"), Bytes::from("a4")).unwrap();
+        txn2.commit().unwrap();
+        assert_eq!(collect(&txn, IteratorOptions::default()).len(), 3);
+        assert!(matches!(txn.commit(), Err(Error::Conflict)));
+    }
+
+    #[test]
+    fn test_txn_iterator_reverse() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction(true);
+        for key in &["a", "b", "ba", "c"] {
+            txn.set(Bytes::from(*key), Bytes::from(format!("{}1", key)))
+                .unwrap();
+        }
+        txn.commit().unwrap();
+        agate.flush_memtable(true).unwrap();
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("ba"), Bytes::from("ba2")).unwrap();
+        txn.delete(Bytes::from("c")).unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("bb"), Bytes::from("bb3")).unwrap();
+        let collect = |opts: IteratorOptions, seek: Option<&str>| {
+            let opts = IteratorOptions {
+                reverse: true,
+                ..opts
+            };
+            let mut iter = txn.new_iterator(&opts).unwrap();
+            match seek {
+                Some(key) => iter.seek(key.as_bytes()).unwrap(),
+                None => iter.rewind().unwrap(),
+            }
+            let mut items = vec![];
+            while iter.valid() {
+                let item = iter.item();
+                let key = String::from_utf8(item.key().to_vec()).unwrap();
+                items.push(if item.is_deleted() {
+                    format!("{}@{} deleted", key, item.version())
+                } else {
+                    let value = String::from_utf8(item.value().to_vec()).unwrap();
+                    format!("{}@{}={}", key, item.version(), value)
+                });
+                iter.next().unwrap();
+            }
+            items
+        };
+        assert_eq!(
+            collect(IteratorOptions::default(), None),
+            vec!["bb@2=bb3", "ba@2=ba2", "b@1=b1", "a@1=a1"]
+        );
+        let opts = IteratorOptions {
+            all_versions: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            collect(opts, None),
+            vec![
+                "c@1=c1",
+                "c@2 deleted",
+                "bb@2=bb3",
+                "ba@1=ba1",
+                "ba@2=ba2",
+                "b@1=b1",
+                "a@1=a1"
+            ]
+        );
+        let opts = IteratorOptions {
+            since_ts: 2,
+            ..Default::default()
+        };
+        assert_eq!(collect(opts, None), vec!["bb@2=bb3", "ba@2=ba2"]);
+
+        // Seeking in reverse moves to the last key not greater than it.
+        let prefix = || IteratorOptions {
+            prefix: Bytes::from("b"),
+            ..Default::default()
+        };
+        let all = vec!["bb@2=bb3", "ba@2=ba2", "b@1=b1"];
+        assert_eq!(collect(prefix(), None), all);
+        assert_eq!(collect(prefix(), Some("z")), all);
+        assert_eq!(collect(prefix(), Some("bab")), &all[1..]);
+        assert_eq!(collect(prefix(), Some("ba")), &all[1..]);
+        assert!(collect(prefix(), Some("a")).is_empty());
+        assert_eq!(
+            collect(IteratorOptions::default(), Some("b")),
+            vec!["b@1=b1", "a@1=a1"]
+        );
+    }
+
+    #[test]
+    fn test_txn_snapshot_isolation() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions::default().with_isolation(IsolationLevel::Snapshot);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let (k1, k2) = (Bytes::from("key1"), Bytes::from("key2"));
+
+        let mut txn1 = agate.new_transaction(true);
+        let mut txn2 = agate.new_transaction(true);
+        assert!(txn1.get(&k1).is_err());
+        assert!(txn2.get(&k2).is_err());
+        txn1.set(k2.clone(), Bytes::from("value1")).unwrap();
+        txn2.set(k1.clone(), Bytes::from("value2")).unwrap();
+        txn2.commit().unwrap();
+        // Reads still see the snapshot at read timestamp.
+        assert!(txn1.get(&k1).is_err());
+        txn1.commit().unwrap();
+
+        let txn = agate.new_transaction(false);
+        assert_eq!(txn.get(&k1).unwrap().value(), "value2");
+        assert_eq!(txn.get(&k2).unwrap().value(), "value1");
+        assert_eq!(agate.core.orc.tracked_commits(), 0);
+    }
+
+    #[test]
+    fn test_txn_managed() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions::default().with_managed_txns(true);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let key = Bytes::from("key");
+
+        let mut txn = agate.new_transaction_at(5, true);
+        txn.set(key.clone(), Bytes::from("value10")).unwrap();
+        txn.commit_at(10).unwrap();
+
+        // Overlapping transactions don't conflict, and the versions are
+        // decided by caller.
+        let mut txn1 = agate.new_transaction_at(10, true);
+        let mut txn2 = agate.new_transaction_at(10, true);
+        assert_eq!(txn1.get(&key).unwrap().value(), "value10");
+        txn1.set(key.clone(), Bytes::from("value30")).unwrap();
+        txn2.set(key.clone(), Bytes::from("value20")).unwrap();
+        txn1.commit_at(30).unwrap();
+        txn2.commit_at(20).unwrap();
+        assert_eq!(agate.core.orc.tracked_commits(), 0);
+
+        for (read_ts, value) in [(15, "value10"), (25, "value20"), (35, "value30")] {
+            let txn = agate.new_transaction_at(read_ts, false);
+            assert_eq!(txn.get(&key).unwrap().value(), value);
+        }
+        assert!(agate.new_transaction_at(9, false).get(&key).is_err());
+        assert_eq!(agate.snapshot().read_ts(), 30);
+
+        agate.set_discard_ts(25).unwrap();
+        assert_eq!(agate.core.orc.discard_ts(), 25);
+    }
+
+    #[test]
+    fn test_txn_managed_new_transaction() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions::default().with_managed_txns(true);
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("key"), Bytes::from("value")).unwrap();
+        assert!(matches!(
+            txn.commit(),
+            Err(Error::Config(msg)) if msg.contains("use commit_at instead")
+        ));
+        let mut batch = agate.new_write_batch();
+        batch.set(Bytes::from("key"), Bytes::from("value")).unwrap();
+        assert!(matches!(batch.flush(), Err(Error::Config(_))));
+
+        // And the other way around.
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction_at(1, true);
+        txn.set(Bytes::from("key"), Bytes::from("value")).unwrap();
+        assert!(matches!(txn.commit_at(2), Err(Error::Config(_))));
+        assert!(matches!(agate.set_discard_ts(1), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_txn_add_without_merge_operator() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction(true);
+        assert!(matches!(
+            txn.add(Bytes::from("key"), Bytes::from("1")),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_txn_too_long() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            mem_table_size: 1 << 19,
+            value_log_file_size: 1 << 17,
+            ..Default::default()
+        };
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction(true);
+        let long_key = Bytes::from(vec![b'k'; MAX_KEY_LENGTH + 1]);
+        assert!(matches!(
+            txn.set(long_key.clone(), Bytes::new()),
+            Err(Error::TooLong {
+                what: "key",
+                len,
+                limit: MAX_KEY_LENGTH,
+            }) if len == MAX_KEY_LENGTH + 1
+        ));
+        // The value, together with key, timestamp and WAL framing, must fit
+        // in an empty WAL.
+        let value_limit = (2 << 17) - (3 + 8 + 2 * MAX_HEADER_SIZE + CRC_SIZE);
+        let long_value = Bytes::from(vec![0; value_limit + 1]);
+        assert!(matches!(
+            txn.set(Bytes::from("key"), long_value.clone()),
+            Err(Error::TooLong {
+                what: "value",
+                len,
+                limit,
+            }) if len == limit + 1 && limit == value_limit
+        ));
+        txn.set(Bytes::from(vec![b'k'; MAX_KEY_LENGTH]), Bytes::new())
+            .unwrap();
+        txn.commit().unwrap();
+
+        // Writes bypassing transactions are checked as well.
+        let entry = Entry::new(long_key, Bytes::new());
+        let err = agate.apply(vec![entry], 1).unwrap_err();
+        assert!(
+            matches!(err, Error::TooLong { what: "key", .. }),
+            "{:?}",
+            err
+        );
+        let entry = Entry::new(key_with_ts(&b"key"[..], 1), long_value);
+        let err = agate.write_entries(vec![entry]).unwrap_err();
+        assert!(
+            matches!(err, Error::TooLong { what: "value", .. }),
+            "{:?}",
+            err
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::db::{Agate, Core};
+use crate::entry::Entry;
+use crate::ops::transaction::{Transaction, TXN_KEY};
+use crate::{Error, Result};
+
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of chunks being written at the same time, more chunks wait for
+/// them to finish.
+const MAX_PENDING_CHUNKS: usize = 16;
+
+/// Bytes added to every entry by its timestamp.
+const TS_SIZE: u64 = 8;
+
+/// Buffers writes and commits them in chunks of transactions as large as
+/// the write path accepts, without tracking reads or checking conflicts.
+/// Every chunk is committed atomically at its own timestamp, but the batch
+/// as a whole is not, so it suits bulk ingestion rather than updates which
+/// must be applied together.
+///
+/// Chunks are written in background while more entries are added. Errors
+/// of writing them are returned by later calls or by `flush`. Writes are
+/// lost if the batch is dropped without being flushed.
+pub struct WriteBatch {
+    core: Arc<Core>,
+    txn: Transaction,
+    count: u64,
+    size: u64,
+    pending: usize,
+    results: (Sender<Result<()>>, Receiver<Result<()>>),
+}
+
+impl Agate {
+    /// Create a batch for writing many keys with little overhead, see
+    /// `WriteBatch`. With `AgateOptions::managed_txns`, its commits fail
+    /// with `Error::Config`.
+    pub fn new_write_batch(&self) -> WriteBatch {
+        WriteBatch {
+            txn: Transaction::new_blind(self.core.clone()),
+            core: self.core.clone(),
+            count: 0,
+            size: 0,
+            pending: 0,
+            results: crossbeam_channel::unbounded(),
+        }
+    }
+}
+
+impl WriteBatch {
+    pub fn set(&mut self, key: Bytes, value: Bytes) -> Result<()> {
+        self.set_entry(Entry::new(key, value))
+    }
+
+    /// Set `key` to `value`, which expires `ttl` after it's written.
+    pub fn set_entry_with_ttl(&mut self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+        self.set_entry(Entry::new(key, value).with_ttl(ttl))
+    }
+
+    pub fn delete(&mut self, key: Bytes) -> Result<()> {
+        let mut e = Entry::new(key, Bytes::new());
+        e.mark_delete();
+        self.set_entry(e)
+    }
+
+    /// Add an entry with options like TTL. The current chunk is committed
+    /// first if the entry doesn't fit in it.
+    pub fn set_entry(&mut self, e: Entry) -> Result<()> {
+        let size = e.key.len() as u64 + TS_SIZE + e.value.len() as u64;
+        if self.count > 0 && !self.fits(size) {
+            self.commit_chunk()?;
+        }
+        self.txn.set_entry(e)?;
+        self.count += 1;
+        self.size += size;
+        Ok(())
+    }
+
+    /// Whether an entry of `size` bytes can be added to the current chunk,
+    /// leaving room for the entry finishing the transaction.
+    fn fits(&self, size: u64) -> bool {
+        let opts = self.core.opts();
+        // Commit timestamp is encoded in decimal in the value.
+        let fin_size = TXN_KEY.len() as u64 + TS_SIZE + 20;
+        self.count + 2 <= opts.max_batch_count && self.size + size + fin_size <= opts.max_batch_size
+    }
+
+    fn commit_chunk(&mut self) -> Result<()> {
+        if self.pending >= MAX_PENDING_CHUNKS {
+            self.wait_one()?;
+        }
+        let txn = std::mem::replace(&mut self.txn, Transaction::new_blind(self.core.clone()));
+        self.count = 0;
+        self.size = 0;
+        let tx = self.results.0.clone();
+        txn.commit_with_callback(Box::new(move |result| {
+            let _ = tx.send(result);
+        }))?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn wait_one(&mut self) -> Result<()> {
+        self.pending -= 1;
+        match self.results.1.recv() {
+            Ok(result) => result,
+            Err(_) => Err(Error::DBClosed),
+        }
+    }
+
+    /// Commit the remaining entries, and wait for all chunks to be written.
+    /// Returns the first error of writing them.
+    pub fn flush(mut self) -> Result<()> {
+        let mut result = self.commit_chunk();
+        while self.pending > 0 {
+            result = result.and(self.wait_one());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AgateOptions, ManualClock};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_write_batch() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let clock = Arc::new(ManualClock::new(1000));
+        let opts = AgateOptions {
+            mem_table_size: 1 << 16,
+            value_log_file_size: 1 << 20,
+            ..Default::default()
+        }
+        .with_clock(clock.clone());
+        let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+        let key = |i: usize| Bytes::from(format!("key{:05}", i));
+
+        let read_ts = agate.core.orc.read_ts();
+        let mut batch = agate.new_write_batch();
+        for i in 0..1000 {
+            batch.set(key(i), Bytes::from(vec![b'v'; 100])).unwrap();
+        }
+        batch.delete(key(0)).unwrap();
+        batch
+            .set_entry_with_ttl(key(1), Bytes::from("ttl"), Duration::from_secs(10))
+            .unwrap();
+        batch.flush().unwrap();
+        // Entries are split into many transactions.
+        assert!(agate.core.orc.read_ts() >= read_ts + 10);
+
+        let txn = agate.new_transaction(false);
+        assert!(matches!(txn.get(&key(0)), Err(Error::KeyNotFound)));
+        assert_eq!(txn.get(&key(1)).unwrap().value(), "ttl");
+        for i in 2..1000 {
+            assert_eq!(txn.get(&key(i)).unwrap().value().len(), 100);
+        }
+        clock.advance(Duration::from_secs(10));
+        assert!(txn.get(&key(1)).is_err());
+
+        // Entries too large for a transaction fail on flush.
+        let mut batch = agate.new_write_batch();
+        batch.set(key(0), Bytes::from(vec![0; 1 << 15])).unwrap();
+        assert!(matches!(batch.flush(), Err(Error::TxnTooBig)));
+    }
+}
@@ -1,3 +1,13 @@
+use crate::cache::{BlockCache, IndexCache};
+use crate::comparator::Comparator;
+use crate::env::{Env, IoPriority};
+use crate::AgateOptions;
+
+use std::sync::Arc;
+
+pub use proto::meta::checksum::Algorithm as ChecksumAlgorithm;
+pub use proto::meta::table_index::BloomStrategy;
+
 #[derive(Debug, Clone)]
 pub struct Options {
     /// size of each block inside SST
@@ -6,9 +16,48 @@ pub struct Options {
     pub block_size: usize,
     /// false positive probability of bloom filter
     pub bloom_false_positive: f64,
+    /// how keys are indexed by bloom filters
+    pub bloom_strategy: BloomStrategy,
+    /// compression of blocks
+    pub compression: Compression,
     /// checksum mode
     pub checksum_mode: ChecksumVerificationMode,
+    /// algorithm of checksums of blocks and index
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// file system where SSTs are stored
+    pub env: Arc<dyn Env>,
+    /// priority of writing SSTs, reads are always in foreground
+    pub io_priority: IoPriority,
+    /// write SSTs with direct IO, see `Env::create_file`
+    pub direct_io: bool,
+    /// cache of blocks shared by tables of a database
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// cache of indexes shared by tables of a database, indexes are kept
+    /// by tables if it's not set
+    pub index_cache: Option<Arc<IndexCache>>,
+    /// order of keys in SST
+    pub comparator: Comparator,
 }
+
+impl Default for Options {
+    fn default() -> Self {
+        build_table_options(&AgateOptions::default())
+    }
+}
+
+/// Compression of blocks in SSTs. Every block records how it's compressed,
+/// so tables written with other compressions are still readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Snappy,
+    /// ZSTD at `level`, which should be within [1, 22]. Higher levels
+    /// compress better but slower.
+    Zstd {
+        level: i32,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub enum ChecksumVerificationMode {
     NoVerification,
@@ -18,4 +67,35 @@ pub enum ChecksumVerificationMode {
     // OnTableAndBlockRead indicates checksum should be verified
     // on SSTable opening and on every block read.
     OnTableAndBlockRead,
+    // OnTableOpen indicates checksum of all SSTables should be verified
+    // in parallel when database is opened, see `Agate::verify_report`.
+    OnTableOpen,
+}
+
+/// Build options of SSTs from options of DB.
+pub fn build_table_options(opts: &AgateOptions) -> Options {
+    Options {
+        table_size: opts.base_table_size,
+        block_size: opts.block_size,
+        bloom_false_positive: opts.bloom_false_positive,
+        bloom_strategy: opts.bloom_strategy,
+        compression: opts.compression,
+        checksum_mode: opts.checksum_verification_mode.clone(),
+        checksum_algorithm: opts.checksum_algorithm,
+        env: opts.env.clone(),
+        io_priority: IoPriority::Foreground,
+        direct_io: false,
+        block_cache: opts.block_cache.clone(),
+        index_cache: opts.index_cache.clone(),
+        comparator: Comparator::new(opts.comparator.clone()),
+    }
+}
+
+/// Build options of SSTs written to `level`, whose compression may be
+/// overridden by `AgateOptions::compression_per_level`.
+pub fn build_level_table_options(opts: &AgateOptions, level: usize) -> Options {
+    Options {
+        compression: opts.compression_of_level(level),
+        ..build_table_options(opts)
+    }
 }
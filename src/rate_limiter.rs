@@ -0,0 +1,175 @@
+use crate::clock::Clock;
+use crate::{Error, Result};
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled at `rate` tokens per second, which holds at most
+/// one second worth of tokens. Tokens can be borrowed, so a request larger
+/// than the bucket still goes through after waiting long enough.
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last_refill {
+            let elapsed = (now - self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+            self.last_refill = now;
+        }
+    }
+
+    /// Time to wait until `n` tokens taken now are paid off.
+    fn wait_for(&self, n: u64) -> Duration {
+        let deficit = n as f64 - self.tokens;
+        if deficit <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(deficit / self.rate as f64)
+        }
+    }
+
+    fn take(&mut self, n: u64) {
+        self.tokens -= n as f64;
+    }
+}
+
+fn new_bucket(rate: u64, now: Instant) -> Option<TokenBucket> {
+    if rate == 0 {
+        None
+    } else {
+        Some(TokenBucket::new(rate, now))
+    }
+}
+
+/// Limits user writes by bytes and operations per second, so that background
+/// jobs can catch up under sustained overload.
+pub(crate) struct RateLimiter {
+    bytes: Mutex<Option<TokenBucket>>,
+    ops: Mutex<Option<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter. Zero rate means unlimited.
+    pub fn new(bytes_per_sec: u64, ops_per_sec: u64, now: Instant) -> Self {
+        Self {
+            bytes: Mutex::new(new_bucket(bytes_per_sec, now)),
+            ops: Mutex::new(new_bucket(ops_per_sec, now)),
+        }
+    }
+
+    /// Change rates of the limiter. Buckets whose rate changes are refilled.
+    pub fn set_rates(&self, bytes_per_sec: u64, ops_per_sec: u64, now: Instant) -> Result<()> {
+        for (bucket, rate) in &[(&self.bytes, bytes_per_sec), (&self.ops, ops_per_sec)] {
+            let mut bucket = bucket.lock()?;
+            if bucket.as_ref().map_or(0, |b| b.rate) != *rate {
+                *bucket = new_bucket(*rate, now);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take tokens for a write, and returns how long the writer should wait
+    /// before proceeding. Nothing is taken and `WriteStalled` is returned if
+    /// the wait lasts beyond `deadline`.
+    fn acquire_at(
+        &self,
+        bytes: u64,
+        ops: u64,
+        now: Instant,
+        deadline: Option<Instant>,
+    ) -> Result<Duration> {
+        let mut bytes_bucket = self.bytes.lock()?;
+        let mut ops_bucket = self.ops.lock()?;
+        let mut buckets: Vec<_> = vec![(bytes_bucket.as_mut(), bytes), (ops_bucket.as_mut(), ops)]
+            .into_iter()
+            .filter_map(|(bucket, n)| bucket.map(|b| (b, n)))
+            .collect();
+
+        let mut wait = Duration::from_secs(0);
+        for (bucket, n) in &mut buckets {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_for(*n));
+        }
+        if matches!(deadline, Some(deadline) if now + wait > deadline) {
+            return Err(Error::WriteStalled);
+        }
+        for (bucket, n) in &mut buckets {
+            bucket.take(*n);
+        }
+
+        Ok(wait)
+    }
+
+    /// Block until a write of `bytes` and `ops` is allowed.
+    pub fn acquire(
+        &self,
+        bytes: u64,
+        ops: u64,
+        clock: &dyn Clock,
+        deadline: Option<Instant>,
+    ) -> Result<Duration> {
+        let wait = self.acquire_at(bytes, ops, clock.now(), deadline)?;
+        if wait > Duration::from_secs(0) {
+            std::thread::sleep(wait);
+        }
+        Ok(wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let limiter = RateLimiter::new(1000, 10, now);
+
+        // Bursts within one second worth of tokens are not throttled.
+        assert_eq!(limiter.acquire_at(600, 1, now, None).unwrap(), ms(0));
+        assert_eq!(limiter.acquire_at(600, 1, now, None).unwrap(), ms(200));
+        // Borrowed tokens are paid off as time goes.
+        assert_eq!(
+            limiter.acquire_at(100, 1, now + ms(200), None).unwrap(),
+            ms(100)
+        );
+        // Ops are limited independently.
+        let now = now + ms(2000);
+        assert_eq!(limiter.acquire_at(0, 15, now, None).unwrap(), ms(500));
+
+        // Nothing is taken if deadline can't be met.
+        let now = now + ms(500);
+        assert!(matches!(
+            limiter.acquire_at(1500, 1, now, Some(now + ms(100))),
+            Err(Error::WriteStalled)
+        ));
+        assert_eq!(
+            limiter
+                .acquire_at(1500, 1, now, Some(now + ms(500)))
+                .unwrap(),
+            ms(500)
+        );
+
+        let unlimited = RateLimiter::new(0, 0, now);
+        assert_eq!(unlimited.acquire_at(u64::MAX, 1, now, None).unwrap(), ms(0));
+
+        // Rates can be changed at runtime.
+        unlimited.set_rates(1000, 0, now).unwrap();
+        assert_eq!(unlimited.acquire_at(1500, 1, now, None).unwrap(), ms(500));
+        limiter.set_rates(0, 0, now).unwrap();
+        assert_eq!(limiter.acquire_at(u64::MAX, 100, now, None).unwrap(), ms(0));
+    }
+}
@@ -1,33 +1,41 @@
 pub(crate) mod builder;
+mod compression;
 pub mod concat_iterator;
 mod iterator;
 pub mod merge_iterator;
+pub(crate) mod rocksdb;
 
 pub use concat_iterator::ConcatIterator;
 pub use merge_iterator::{Iterators as TableIterators, MergeIterator};
 pub type TableIterator = TableRefIterator<Arc<TableInner>>;
 
 use crate::bloom::Bloom;
+use crate::cache::CacheCounters;
 use crate::checksum;
+use crate::comparator::Comparator;
+use crate::env::{Env, IoPriority, ReadableFile};
 use crate::iterator_trait::AgateIterator;
-use crate::opt::{ChecksumVerificationMode, Options};
+use crate::opt::{BloomStrategy, ChecksumVerificationMode, Options};
+use crate::util::{self, KeyComparator};
 use crate::Error;
+use crate::ErrorContext;
 use crate::Result;
 
-use iterator::{TableRefIterator, ITERATOR_NOCACHE, ITERATOR_REVERSED};
+use compression::NO_COMPRESSION;
+use iterator::TableRefIterator;
+pub(crate) use iterator::{ITERATOR_NOCACHE, ITERATOR_PREFETCH, ITERATOR_REVERSED};
 
 use bytes::{Buf, Bytes};
-use memmap::{Mmap, MmapOptions};
 use prost::Message;
 use proto::meta::{BlockOffset, Checksum, TableIndex};
-use std::fs;
-use std::io::Write;
+use std::cmp::Ordering;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
 
 /// MmapFile stores SST data. `File` refers to a file on disk,
 /// and `Memory` refers to data in memory.
@@ -35,8 +43,7 @@ mod tests;
 enum MmapFile {
     File {
         name: PathBuf,
-        file: fs::File,
-        mmap: Mmap,
+        file: Box<dyn ReadableFile>,
     },
     Memory {
         data: Bytes,
@@ -54,13 +61,16 @@ impl MmapFile {
         }
     }
 
-    pub fn open(path: &Path, file: std::fs::File) -> Result<Self> {
-        let mmap = unsafe { MmapOptions::new().map(&file)? };
-        Ok(MmapFile::File {
-            file,
-            mmap,
-            name: path.to_path_buf(),
-        })
+    pub fn open(path: &Path, env: &dyn Env) -> Result<(Self, u64)> {
+        let file = env.open_readable(path, IoPriority::Foreground)?;
+        let size = file.size();
+        Ok((
+            MmapFile::File {
+                file,
+                name: path.to_path_buf(),
+            },
+            size,
+        ))
     }
 }
 
@@ -81,19 +91,127 @@ pub struct TableInner {
     checksum: Bytes,
     /// estimated size, only used on encryption or compression
     estimated_size: u32,
-    /// index of SST
-    index: TableIndex,
+    /// index of SST, or `None` if it's kept in index cache
+    index: Option<Arc<TableIndex>>,
+    /// metadata in index, which is set once index is read
+    meta: OnceLock<IndexMeta>,
+    max_version: u64,
     /// start position of index
     index_start: usize,
     /// length of index
     index_len: usize,
-    /// true if there's bloom filter in table
-    has_bloom_filter: bool,
+    /// checksum of index
+    index_checksum: Checksum,
     /// table options
     opts: Options,
     /// by default, when `TableInner` is dropped, the SST file will be
     /// deleted. By setting this to true, it won't be deleted.
     save_after_close: AtomicBool,
+    /// lookups of this table in block cache and index cache
+    block_cache_counters: CacheCounters,
+    index_cache_counters: CacheCounters,
+    /// whether blocks and index are pinned in caches
+    pinned: AtomicBool,
+}
+
+/// Metadata in index which is always kept in memory once index is read,
+/// even if index is evicted from index cache.
+#[derive(Clone, Copy)]
+struct IndexMeta {
+    num_blocks: usize,
+    key_count: u32,
+    stale_data_size: u32,
+    /// true if there's bloom filter in table
+    has_bloom_filter: bool,
+    /// whether bloom filters are built for blocks or the whole table
+    bloom_strategy: BloomStrategy,
+    key_bytes: u64,
+    key_overlap_bytes: u64,
+    data_size: u64,
+    min_expires_at: u64,
+    max_expires_at: u64,
+    /// whether blocks end with their compression types
+    has_compression_type: bool,
+}
+
+impl IndexMeta {
+    fn new(index: &TableIndex) -> Self {
+        let bloom_strategy =
+            BloomStrategy::from_i32(index.bloom_strategy).unwrap_or(BloomStrategy::WholeKey);
+        let has_bloom_filter = match bloom_strategy {
+            BloomStrategy::WholeKey => !index.bloom_filter.is_empty(),
+            BloomStrategy::PerBlock => index.offsets.iter().any(|o| !o.bloom_filter.is_empty()),
+        };
+        Self {
+            num_blocks: index.offsets.len(),
+            key_count: index.key_count,
+            stale_data_size: index.stale_data_size,
+            has_bloom_filter,
+            bloom_strategy,
+            key_bytes: index.key_bytes,
+            key_overlap_bytes: index.key_overlap_bytes,
+            data_size: index.data_size,
+            min_expires_at: index.min_expires_at,
+            max_expires_at: index.max_expires_at,
+            has_compression_type: index.has_compression_type,
+        }
+    }
+}
+
+/// Statistics of prefix compression of tables, computed by table builder,
+/// see `Agate::prefix_stats`. Keys in a block are stored as differences
+/// from the first key of the block, so they can be used to tune
+/// `block_size`.
+///
+/// Tables written before statistics were recorded are not counted.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixStats {
+    pub tables: usize,
+    pub blocks: u64,
+    pub entries: u64,
+    /// total length of keys with timestamps
+    pub key_bytes: u64,
+    /// bytes of keys shared with first keys of their blocks, which are not
+    /// stored
+    pub key_overlap_bytes: u64,
+    /// bytes of blocks
+    pub data_size: u64,
+    /// bytes of indexes
+    pub index_size: u64,
+}
+
+impl PrefixStats {
+    pub fn merge(&mut self, other: &PrefixStats) {
+        self.tables += other.tables;
+        self.blocks += other.blocks;
+        self.entries += other.entries;
+        self.key_bytes += other.key_bytes;
+        self.key_overlap_bytes += other.key_overlap_bytes;
+        self.data_size += other.data_size;
+        self.index_size += other.index_size;
+    }
+
+    /// Average bytes of a key saved by prefix compression.
+    pub fn avg_key_overlap(&self) -> f64 {
+        ratio(self.key_overlap_bytes, self.entries)
+    }
+
+    pub fn entries_per_block(&self) -> f64 {
+        ratio(self.entries, self.blocks)
+    }
+
+    /// Bytes of indexes per byte of blocks.
+    pub fn index_to_data_ratio(&self) -> f64 {
+        ratio(self.index_size, self.data_size)
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
 }
 
 /// Table is simply an Arc to its internal TableInner structure.
@@ -112,47 +230,49 @@ impl AsRef<TableInner> for TableInner {
 }
 
 impl TableInner {
+    fn new(file: MmapFile, table_size: usize, id: u64, opts: Options) -> TableInner {
+        TableInner {
+            file,
+            table_size,
+            smallest: Bytes::new(),
+            biggest: Bytes::new(),
+            id,
+            checksum: Bytes::new(),
+            estimated_size: 0,
+            index: None,
+            meta: OnceLock::new(),
+            max_version: 0,
+            index_start: 0,
+            index_len: 0,
+            index_checksum: Checksum::default(),
+            opts,
+            save_after_close: AtomicBool::new(false),
+            block_cache_counters: CacheCounters::default(),
+            index_cache_counters: CacheCounters::default(),
+            pinned: AtomicBool::new(false),
+        }
+    }
+
     /// Create an SST from bytes data generated with table builder
     fn create(path: &Path, data: Bytes, opts: Options) -> Result<TableInner> {
-        let mut f = fs::OpenOptions::new()
-            .create_new(true)
-            .read(true)
-            .write(true)
-            .open(path)?;
-        f.write_all(&data)?;
-        // TODO: pass file object directly to open and sync write
-        drop(f);
-        Self::open(path, opts)
+        opts.env
+            .create_file(path, &data, opts.io_priority, opts.direct_io)?;
+        // TODO: sync write
+        let env = opts.env.clone();
+        Self::open(path, opts).inspect_err(|_| {
+            // Remove the file, so that creating it can be retried.
+            let _ = env.remove_file(path);
+        })
     }
 
     /// Open an existing SST on disk
     fn open(path: &Path, opts: Options) -> Result<TableInner> {
         use ChecksumVerificationMode::*;
 
-        let f = fs::OpenOptions::new()
-            .read(true)
-            .write(false)
-            .create(false)
-            .open(path)?;
         let file_name = path.file_name().unwrap().to_str().unwrap();
         let id = parse_file_id(file_name)?;
-        let meta = f.metadata()?;
-        let table_size = meta.len();
-        let mut inner = TableInner {
-            file: MmapFile::open(path, f)?,
-            table_size: table_size as usize,
-            smallest: Bytes::new(),
-            biggest: Bytes::new(),
-            id,
-            checksum: Bytes::new(),
-            estimated_size: 0,
-            index: TableIndex::default(),
-            index_start: 0,
-            index_len: 0,
-            opts,
-            has_bloom_filter: false,
-            save_after_close: AtomicBool::new(false),
-        };
+        let (file, table_size) = MmapFile::open(path, opts.env.as_ref())?;
+        let mut inner = TableInner::new(file, table_size as usize, id, opts);
         inner.init_biggest_and_smallest()?;
 
         if matches!(inner.opts.checksum_mode, OnTableAndBlockRead | OnTableRead) {
@@ -167,21 +287,7 @@ impl TableInner {
         use ChecksumVerificationMode::*;
 
         let table_size = data.len();
-        let mut inner = TableInner {
-            file: MmapFile::Memory { data },
-            opts,
-            table_size,
-            id,
-            smallest: Bytes::new(),
-            biggest: Bytes::new(),
-            checksum: Bytes::new(),
-            estimated_size: 0,
-            index: TableIndex::default(),
-            index_start: 0,
-            index_len: 0,
-            has_bloom_filter: false,
-            save_after_close: AtomicBool::new(false),
-        };
+        let mut inner = TableInner::new(MmapFile::Memory { data }, table_size, id, opts);
         inner.init_biggest_and_smallest()?;
 
         if matches!(inner.opts.checksum_mode, OnTableAndBlockRead | OnTableRead) {
@@ -191,9 +297,30 @@ impl TableInner {
         Ok(inner)
     }
 
+    /// Open an existing SST on disk with its key range and max version,
+    /// which only reads the footer. Index is read on first access, and is
+    /// kept in index cache.
+    fn open_lazy(
+        path: &Path,
+        opts: Options,
+        smallest: Bytes,
+        biggest: Bytes,
+        max_version: u64,
+    ) -> Result<TableInner> {
+        assert!(opts.index_cache.is_some());
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let id = parse_file_id(file_name)?;
+        let (file, table_size) = MmapFile::open(path, opts.env.as_ref())?;
+        let mut inner = TableInner::new(file, table_size as usize, id, opts);
+        inner.init_footer()?;
+        inner.smallest = smallest;
+        inner.biggest = biggest;
+        inner.max_version = max_version;
+        Ok(inner)
+    }
+
     fn init_biggest_and_smallest(&mut self) -> Result<()> {
-        let ko = self.init_index()?;
-        self.smallest = Bytes::from(ko.key.clone());
+        self.smallest = self.init_index()?;
         let mut it = TableRefIterator::new(&self, ITERATOR_REVERSED | ITERATOR_NOCACHE);
         it.rewind();
         if !it.valid() {
@@ -206,39 +333,56 @@ impl TableInner {
         Ok(())
     }
 
-    fn init_index(&mut self) -> Result<&BlockOffset> {
+    /// Read position and checksum of index from footer.
+    fn init_footer(&mut self) -> Result<()> {
+        let back = |pos: usize, len: usize| {
+            pos.checked_sub(len)
+                .ok_or_else(|| Error::TableRead("footer is out of range".to_string()))
+        };
         let mut read_pos = self.table_size;
 
         // read checksum length from last 4 bytes
-        read_pos -= 4;
+        read_pos = back(read_pos, 4)?;
         let mut buf = self.read(read_pos, 4)?;
         let checksum_len = buf.get_u32() as usize;
 
         // read checksum
-        read_pos -= checksum_len;
+        read_pos = back(read_pos, checksum_len)?;
         let buf = self.read(read_pos, checksum_len)?;
         let chksum = Checksum::decode(buf)?;
 
         // read index size from footer
-        read_pos -= 4;
+        read_pos = back(read_pos, 4)?;
         let mut buf = self.read(read_pos, 4)?;
         self.index_len = buf.get_u32() as usize;
 
-        // read index
-        read_pos -= self.index_len;
+        // position of index
+        read_pos = back(read_pos, self.index_len)?;
         self.index_start = read_pos;
-        let data = self.read(read_pos, self.index_len)?;
-        checksum::verify_checksum(&data, &chksum)?;
-
-        self.index = self.read_table_index()?;
+        self.index_checksum = chksum;
 
         // TODO: compression
         self.estimated_size = self.table_size as u32;
+        Ok(())
+    }
 
-        // bloom filter
-        self.has_bloom_filter = !self.index.bloom_filter.is_empty();
+    /// Read index and its metadata, returns the first key of table.
+    fn init_index(&mut self) -> Result<Bytes> {
+        self.init_footer()?;
+        let index = self.read_table_index()?;
+        self.max_version = index.max_version;
+        let _ = self.meta.set(IndexMeta::new(&index));
 
-        Ok(&self.index.offsets[0])
+        let smallest = match index.offsets.first() {
+            Some(offset) => Bytes::from(offset.key.clone()),
+            None => return Err(Error::TableRead("index has no blocks".to_string())),
+        };
+        let index = Arc::new(index);
+        match &self.opts.index_cache {
+            Some(cache) => cache.insert(self.id, index, self.index_len, false),
+            None => self.index = Some(index),
+        }
+        Ok(smallest)
     }
 
     // split the table into at least (n - 1) ranges (when n >= blocks) based on block offsets
@@ -247,13 +391,17 @@ impl TableInner {
             return vec![];
         }
 
-        let offset_length = self.offsets_length();
+        let index = match self.fetch_index() {
+            Ok(index) => index,
+            Err(_) => return vec![],
+        };
+        let offset_length = index.offsets.len();
         let jump = (offset_length / n).max(1);
 
         let mut result = vec![];
 
         for i in (0..offset_length).step_by(jump) {
-            let block = self.offsets(i).unwrap();
+            let block = &index.offsets[i];
             if block.key.starts_with(&prefix) {
                 result.push(Bytes::copy_from_slice(&block.key))
             }
@@ -262,48 +410,134 @@ impl TableInner {
         result
     }
 
-    fn fetch_index(&self) -> &TableIndex {
-        return &self.index;
+    /// Get index of table, which is read from SST again if it's evicted
+    /// from index cache.
+    fn fetch_index(&self) -> Result<Arc<TableIndex>> {
         // TODO: encryption
+        if let Some(index) = &self.index {
+            return Ok(index.clone());
+        }
+        let cache = self.opts.index_cache.as_ref().unwrap();
+        let index = cache.get(self.id);
+        self.index_cache_counters.record(index.is_some());
+        if let Some(index) = index {
+            return Ok(index);
+        }
+        let index = Arc::new(self.read_table_index()?);
+        let _ = self.meta.set(IndexMeta::new(&index));
+        cache.insert(self.id, index.clone(), self.index_len, self.is_pinned());
+        Ok(index)
+    }
+
+    /// Get metadata in index, which reads index if it's not read yet.
+    fn meta(&self) -> Result<IndexMeta> {
+        if let Some(meta) = self.meta.get() {
+            return Ok(*meta);
+        }
+        let index = self.fetch_index()?;
+        Ok(*self.meta.get_or_init(|| IndexMeta::new(&index)))
+    }
+
+    /// Get number of blocks, see `meta`.
+    fn num_blocks(&self) -> Result<usize> {
+        Ok(self.meta()?.num_blocks)
     }
 
     fn offsets_length(&self) -> usize {
-        self.fetch_index().offsets.len()
+        self.num_blocks().unwrap_or(0)
     }
 
-    fn offsets(&self, idx: usize) -> Option<&BlockOffset> {
-        self.fetch_index().offsets.get(idx)
+    fn offsets(&self, idx: usize) -> Option<BlockOffset> {
+        self.fetch_index().ok()?.offsets.get(idx).cloned()
     }
 
-    fn block(&self, idx: usize, _use_cache: bool) -> Result<Arc<Block>> {
-        use ChecksumVerificationMode::*;
+    /// Get a block from block cache, or read it from SST. The block is
+    /// inserted into cache only if `use_cache` is true, so that scans
+    /// which read blocks once don't pollute cache. Errors carry the table
+    /// id and the offset of the block.
+    fn block(&self, idx: usize, use_cache: bool) -> Result<Arc<Block>> {
+        self.read_block(idx, use_cache).map_err(|err| {
+            let mut context = ErrorContext::table(self.id);
+            if let Some(offset) = self.offsets(idx) {
+                context = context.with_offset(offset.offset as u64);
+            }
+            err.with_context(context)
+        })
+    }
 
-        // TODO: support cache
+    fn read_block(&self, idx: usize, use_cache: bool) -> Result<Arc<Block>> {
         if idx >= self.offsets_length() {
             return Err(Error::TableRead("block out of index".to_string()));
         }
-        let block_offset = self
-            .offsets(idx)
+        if let Some(cache) = &self.opts.block_cache {
+            let blk = cache.get(self.id, idx);
+            self.block_cache_counters.record(blk.is_some());
+            if let Some(blk) = blk {
+                return Ok(blk);
+            }
+        }
+        let index = self.fetch_index()?;
+        let block_offset = index
+            .offsets
+            .get(idx)
             .ok_or_else(|| Error::TableRead(format!("failed to get offset block {}", idx)))?;
 
         let offset = block_offset.offset as usize;
         let data = self.read(offset, block_offset.len as usize)?;
+        self.decode_block(idx, offset, data, use_cache)
+    }
+
+    /// Parse block `idx` read from `offset`, and insert it into block cache
+    /// if `use_cache` is true.
+    fn decode_block(
+        &self,
+        idx: usize,
+        offset: usize,
+        data: Bytes,
+        use_cache: bool,
+    ) -> Result<Arc<Block>> {
+        use ChecksumVerificationMode::*;
 
+        if data.len() < 8 {
+            return Err(Error::TableRead(format!("block {} is too small", idx)));
+        }
         let mut read_pos = data.len() - 4; // first read checksum length
         let checksum_len = (&data[read_pos..read_pos + 4]).get_u32() as usize;
 
-        if checksum_len > data.len() {
+        if checksum_len > read_pos - 4 {
             return Err(Error::TableRead("invalid checksum length".to_string()));
         }
 
         // read checksum
         read_pos -= checksum_len;
-        let checksum = data.slice(read_pos..read_pos + checksum_len);
+        let mut checksum = data.slice(read_pos..read_pos + checksum_len);
+
+        // The checksum is calculated for actual data + entry index + index length,
+        // followed by compression type if the table has it.
+        let mut data = data.slice(..read_pos);
+        if self.meta()?.has_compression_type {
+            read_pos -= 1;
+            let ty = data[read_pos];
+            if ty != NO_COMPRESSION {
+                // Compressed blocks are verified before they are decompressed.
+                let chksum = Checksum::decode(checksum)?;
+                checksum::verify_checksum(&data, &chksum)?;
+                checksum = Bytes::new();
+                data = Bytes::from(compression::decompress(&data[..read_pos], ty)?);
+                read_pos = data.len();
+            }
+        }
+        if read_pos < 4 {
+            return Err(Error::TableRead(format!("block {} is too small", idx)));
+        }
 
         // read num entries
         read_pos -= 4;
         let num_entries = (&data[read_pos..read_pos + 4]).get_u32() as usize;
 
+        if num_entries > read_pos / 4 {
+            return Err(Error::TableRead("invalid number of entries".to_string()));
+        }
         let entries_index_start = read_pos - num_entries * 4;
         let entries_index_end = entries_index_start + num_entries * 4;
 
@@ -313,10 +547,6 @@ impl TableInner {
             entry_offsets.push(entry_offsets_ptr.get_u32_le());
         }
 
-        // Drop checksum and checksum length.
-        // The checksum is calculated for actual data + entry index + index length
-        let data = data.slice(..read_pos + 4);
-
         let blk = Arc::new(Block {
             offset,
             entries_index_start,
@@ -330,16 +560,101 @@ impl TableInner {
             blk.verify_checksum()?;
         }
 
+        if use_cache {
+            if let Some(cache) = &self.opts.block_cache {
+                cache.insert(self.id, idx, blk.clone(), self.is_pinned());
+            }
+        }
         Ok(blk)
     }
 
+    /// Read blocks in `blocks` which are not in block cache in one batch,
+    /// and insert them into block cache. Returns the number of read blocks.
+    fn prefetch_blocks(&self, blocks: Range<usize>) -> Result<usize> {
+        let (cache, file) = match (&self.opts.block_cache, &self.file) {
+            (Some(cache), MmapFile::File { file, .. }) => (cache, file),
+            // Blocks can't be kept, or they are in memory already.
+            _ => return Ok(0),
+        };
+        let index = self.fetch_index()?;
+        let missing: Vec<_> = blocks
+            .filter(|&idx| idx < index.offsets.len() && !cache.contains(self.id, idx))
+            .collect();
+        let reads: Vec<_> = missing
+            .iter()
+            .map(|&idx| {
+                let offset = &index.offsets[idx];
+                (offset.offset as usize, offset.len as usize)
+            })
+            .collect();
+        let data = file.read_batch(&reads)?;
+        for ((&idx, &(offset, _)), data) in missing.iter().zip(&reads).zip(data) {
+            self.decode_block(idx, offset, data, true).map_err(|err| {
+                err.with_context(ErrorContext::table(self.id).with_offset(offset as u64))
+            })?;
+        }
+        Ok(missing.len())
+    }
+
+    /// Estimate on-disk size and number of keys within `[start, end)`.
+    ///
+    /// Only block offsets in index are used, so no block will be read.
+    /// Every block which may contain a key in range is counted as a whole,
+    /// and key count is distributed to blocks in proportion to their size.
+    fn estimate_range(&self, start: &[u8], end: &[u8]) -> (u64, u64) {
+        // A table whose index can't be read is estimated as empty.
+        let index = match self.fetch_index() {
+            Ok(index) => index,
+            Err(_) => return (0, 0),
+        };
+        let offsets = &index.offsets;
+        let blocks = self.block_range(offsets, start, end);
+        if blocks.is_empty() {
+            return (0, 0);
+        }
+
+        let blocks_size: u64 = offsets.iter().map(|o| o.len as u64).sum();
+        let size: u64 = offsets[blocks].iter().map(|o| o.len as u64).sum();
+        let keys = (self.key_count() as u64 * size)
+            .checked_div(blocks_size)
+            .unwrap_or(0);
+        (size, keys)
+    }
+
+    /// Get indices of blocks which may contain keys within `[start, end)`.
+    fn block_range(&self, offsets: &[BlockOffset], start: &[u8], end: &[u8]) -> Range<usize> {
+        let c = &self.opts.comparator;
+        if offsets.is_empty()
+            || c.compare_key(start, end) != Ordering::Less
+            || c.compare_key(start, &self.biggest) == Ordering::Greater
+            || c.compare_key(end, &self.smallest) != Ordering::Greater
+        {
+            return 0..0;
+        }
+
+        // the last block whose base key <= start
+        let first = util::search(offsets.len(), |idx| {
+            c.compare_key(&offsets[idx].key, start) == Ordering::Greater
+        })
+        .saturating_sub(1);
+        // the first block whose base key >= end
+        let last = util::search(offsets.len(), |idx| {
+            c.compare_key(&offsets[idx].key, end) != Ordering::Less
+        });
+        first..last.max(first)
+    }
+
+    fn is_pinned(&self) -> bool {
+        self.pinned.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
     fn index_key(&self) -> u64 {
         self.id
     }
 
     /// Get number of keys in SST
     pub fn key_count(&self) -> u32 {
-        self.fetch_index().key_count
+        self.meta().map_or(0, |meta| meta.key_count)
     }
 
     /// Get size of index
@@ -347,9 +662,12 @@ impl TableInner {
         self.index_len
     }
 
-    /// Get size of bloom filter
+    /// Get size of bloom filters, including filters of blocks
     pub fn bloom_filter_size(&self) -> usize {
-        self.fetch_index().bloom_filter.len()
+        self.fetch_index().map_or(0, |index| {
+            let blocks: usize = index.offsets.iter().map(|o| o.bloom_filter.len()).sum();
+            index.bloom_filter.len() + blocks
+        })
     }
 
     /// Get size of SST
@@ -381,32 +699,84 @@ impl TableInner {
         self.id
     }
 
+    /// Check whether the table doesn't have user key of `hash` by the
+    /// bloom filter of table. Always returns false for tables with bloom
+    /// filters of blocks, see `does_not_have_key`.
     pub fn does_not_have(&self, hash: u32) -> bool {
-        if self.has_bloom_filter {
-            let index = self.fetch_index();
-            let bloom = Bloom::new(&index.bloom_filter);
-            !bloom.may_contain(hash)
-        } else {
-            false
+        if !self.has_bloom_filter() || self.bloom_strategy() != BloomStrategy::WholeKey {
+            return false;
         }
+        // The key may exist if bloom filter can't be read.
+        match self.fetch_index() {
+            Ok(index) => !Bloom::new(&index.bloom_filter).may_contain(hash),
+            Err(_) => false,
+        }
+    }
+
+    /// Same as `does_not_have`, but also checks bloom filter of the block
+    /// where `key` would be found by seeking, if the table has filters of
+    /// blocks. `hash` is the fingerprint of user key of `key`.
+    pub fn does_not_have_key(&self, key: &[u8], hash: u32) -> bool {
+        if !self.has_bloom_filter() || self.bloom_strategy() != BloomStrategy::PerBlock {
+            return self.does_not_have(hash);
+        }
+        let index = match self.fetch_index() {
+            Ok(index) => index,
+            Err(_) => return false,
+        };
+        let offsets = &index.offsets;
+        let c = &self.opts.comparator;
+        // the last block whose base key <= key
+        let idx = util::search(offsets.len(), |idx| {
+            c.compare_key(&offsets[idx].key, key) == Ordering::Greater
+        })
+        .saturating_sub(1);
+        // Older versions may be in following blocks, which start with the
+        // same user key then.
+        if offsets
+            .get(idx + 1)
+            .is_some_and(|next| c.same_key(&next.key, key))
+        {
+            return false;
+        }
+        let filter = &offsets[idx].bloom_filter;
+        !filter.is_empty() && !Bloom::new(filter).may_contain(hash)
     }
 
     pub fn has_bloom_filter(&self) -> bool {
-        self.has_bloom_filter
+        self.meta().is_ok_and(|meta| meta.has_bloom_filter)
+    }
+
+    fn bloom_strategy(&self) -> BloomStrategy {
+        self.meta()
+            .map_or(BloomStrategy::WholeKey, |meta| meta.bloom_strategy)
     }
 
     pub(crate) fn read_table_index(&self) -> Result<TableIndex> {
         let data = self.read(self.index_start, self.index_len)?;
+        let context = || ErrorContext::table(self.id).with_offset(self.index_start as u64);
+        // Verify index when it's read for the first time.
+        if self.meta.get().is_none() {
+            checksum::verify_checksum(&data, &self.index_checksum)
+                .map_err(|err| err.with_context(context()))?;
+        }
         // TODO: prefetch
-        let result = Message::decode(data)?;
+        let result: TableIndex =
+            Message::decode(data).map_err(|err| Error::from(err).with_context(context()))?;
+        // Keys are compared with timestamps when seeking blocks.
+        if result.offsets.iter().any(|o| o.key.len() < 8) {
+            return Err(Error::TableRead(format!(
+                "index of table {} has keys without timestamps",
+                self.id
+            )));
+        }
         Ok(result)
     }
 
     fn verify_checksum(&self) -> Result<()> {
         use ChecksumVerificationMode::*;
 
-        let table_index = self.fetch_index();
-        for i in 0..table_index.offsets.len() {
+        for i in 0..self.num_blocks()? {
             // When using OnBlockRead or OnTableAndBlockRead, we do not need to verify block
             // checksum now. But we still need to check if there is an encoding error in block.
             let block = self.block(i, true)?;
@@ -435,18 +805,7 @@ impl TableInner {
                     Ok(data.slice(offset..offset + size))
                 }
             }
-            MmapFile::File { mmap, .. } => {
-                if offset + size > mmap.len() {
-                    Err(Error::TableRead(format!(
-                        "out of range, offset={}, size={}, len={}",
-                        offset,
-                        size,
-                        mmap.len()
-                    )))
-                } else {
-                    Ok(Bytes::copy_from_slice(&mmap[offset..offset + size]))
-                }
-            }
+            MmapFile::File { file, .. } => file.read_at(offset, size),
             MmapFile::None => unreachable!(),
         }
     }
@@ -456,26 +815,56 @@ impl TableInner {
     }
 
     fn max_version(&self) -> u64 {
-        unimplemented!()
-        // self.fetch_index()?.max_version()
+        self.max_version
+    }
+
+    fn stale_data_size(&self) -> u32 {
+        self.meta().map_or(0, |meta| meta.stale_data_size)
+    }
+
+    fn expiry_range(&self) -> Option<(u64, u64)> {
+        self.meta()
+            .ok()
+            .filter(|meta| meta.min_expires_at != 0)
+            .map(|meta| (meta.min_expires_at, meta.max_expires_at))
+    }
+
+    fn prefix_stats(&self) -> PrefixStats {
+        match self.meta() {
+            Ok(meta) if meta.data_size > 0 => PrefixStats {
+                tables: 1,
+                blocks: meta.num_blocks as u64,
+                entries: meta.key_count as u64,
+                key_bytes: meta.key_bytes,
+                key_overlap_bytes: meta.key_overlap_bytes,
+                data_size: meta.data_size,
+                index_size: self.index_len as u64,
+            },
+            _ => PrefixStats::default(),
+        }
     }
 }
 
 impl Drop for TableInner {
     fn drop(&mut self) {
-        if let MmapFile::File { file, mmap, name } =
-            std::mem::replace(&mut self.file, MmapFile::None)
-        {
-            drop(mmap);
+        if let MmapFile::File { file, name } = std::mem::replace(&mut self.file, MmapFile::None) {
             // It is possible that table is opened in read-only mode,
             // so we cannot set_len.
             // file.set_len(0).unwrap();
+            drop(file);
             if !self
                 .save_after_close
                 .load(std::sync::atomic::Ordering::SeqCst)
             {
-                drop(file);
-                fs::remove_file(&name).unwrap();
+                if let Some(cache) = &self.opts.block_cache {
+                    // No block is cached if index is never read.
+                    let num_blocks = self.meta.get().map_or(0, |meta| meta.num_blocks);
+                    cache.remove_table(self.id, num_blocks);
+                }
+                if let Some(cache) = &self.opts.index_cache {
+                    cache.remove(self.id);
+                }
+                self.opts.env.remove_file(&name).unwrap();
             }
         }
     }
@@ -493,7 +882,7 @@ pub struct Block {
 }
 
 impl Block {
-    fn size(&self) -> u64 {
+    pub(crate) fn size(&self) -> u64 {
         3 * std::mem::size_of::<usize>() as u64
             + self.data.len() as u64
             + self.checksum.len() as u64
@@ -501,12 +890,16 @@ impl Block {
     }
 
     fn verify_checksum(&self) -> Result<()> {
+        // Checksums of compressed blocks are verified on read.
+        if self.checksum.is_empty() {
+            return Ok(());
+        }
         let chksum = prost::Message::decode(self.checksum.clone())?;
         checksum::verify_checksum(&self.data, &chksum)
     }
 }
 
-fn parse_file_id(name: &str) -> Result<u64> {
+pub(crate) fn parse_file_id(name: &str) -> Result<u64> {
     if !name.ends_with(".sst") {
         return Err(Error::InvalidFilename(name.to_string()));
     }
@@ -531,6 +924,26 @@ impl Table {
         })
     }
 
+    /// Open an existing SST on disk without reading its index, see
+    /// `AgateOptions::lazy_load_table_index`. `opts` must have index cache.
+    pub fn open_lazy(
+        path: &Path,
+        opts: Options,
+        smallest: Bytes,
+        biggest: Bytes,
+        max_version: u64,
+    ) -> Result<Table> {
+        Ok(Table {
+            inner: Arc::new(TableInner::open_lazy(
+                path,
+                opts,
+                smallest,
+                biggest,
+                max_version,
+            )?),
+        })
+    }
+
     /// Open an existing SST from data in memory
     pub fn open_in_memory(data: Bytes, id: u64, opts: Options) -> Result<Table> {
         Ok(Table {
@@ -544,7 +957,7 @@ impl Table {
     }
 
     /// Get all block offsets
-    pub(crate) fn offsets(&self, idx: usize) -> Option<&BlockOffset> {
+    pub(crate) fn offsets(&self, idx: usize) -> Option<BlockOffset> {
         self.inner.offsets(idx)
     }
 
@@ -553,6 +966,17 @@ impl Table {
         self.inner.block(block_pos, use_cache)
     }
 
+    /// Estimate on-disk size and number of keys within `[start, end)`,
+    /// where both bounds are keys with timestamp.
+    pub(crate) fn estimate_range(&self, start: &[u8], end: &[u8]) -> (u64, u64) {
+        self.inner.estimate_range(start, end)
+    }
+
+    /// Get number of keys in SST
+    pub fn key_count(&self) -> u32 {
+        self.inner.key_count()
+    }
+
     /// Get an iterator to this table
     pub fn new_iterator(&self, opt: usize) -> TableIterator {
         TableRefIterator::new(self.inner.clone(), opt)
@@ -563,6 +987,32 @@ impl Table {
         self.inner.max_version()
     }
 
+    /// Verify checksums of all blocks, regardless of checksum mode.
+    pub(crate) fn verify_checksum(&self) -> Result<()> {
+        for idx in 0..self.offsets_length() {
+            self.inner.block(idx, false)?.verify_checksum()?;
+        }
+        Ok(())
+    }
+
+    /// Get bytes of entries which can be dropped by compaction
+    pub fn stale_data_size(&self) -> u32 {
+        self.inner.stale_data_size()
+    }
+
+    /// Get the earliest and the latest expiration time of entries with TTL,
+    /// or `None` if there are none or the table is written before they are
+    /// recorded.
+    pub fn expiry_range(&self) -> Option<(u64, u64)> {
+        self.inner.expiry_range()
+    }
+
+    /// Get statistics of prefix compression, which are empty if they are
+    /// not recorded in the table.
+    pub fn prefix_stats(&self) -> PrefixStats {
+        self.inner.prefix_stats()
+    }
+
     pub fn has_bloom_filter(&self) -> bool {
         self.inner.has_bloom_filter()
     }
@@ -571,6 +1021,10 @@ impl Table {
         self.inner.does_not_have(hash)
     }
 
+    pub fn does_not_have_key(&self, key: &[u8], hash: u32) -> bool {
+        self.inner.does_not_have_key(key, hash)
+    }
+
     /// Get size of SST
     pub fn size(&self) -> u64 {
         self.inner.size()
@@ -589,10 +1043,63 @@ impl Table {
         self.inner.smallest()
     }
 
+    /// Get the comparator which orders keys of the table.
+    pub fn comparator(&self) -> &Comparator {
+        &self.inner.opts.comparator
+    }
+
     pub fn is_in_memory(&self) -> bool {
         self.inner.is_in_memory()
     }
 
+    pub(crate) fn block_cache_counters(&self) -> &CacheCounters {
+        &self.inner.block_cache_counters
+    }
+
+    pub(crate) fn index_cache_counters(&self) -> &CacheCounters {
+        &self.inner.index_cache_counters
+    }
+
+    /// Pin blocks and index of this table in caches once they are read,
+    /// including those already cached.
+    pub(crate) fn pin(&self) {
+        let inner = &self.inner;
+        inner
+            .pinned
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        if let Some(cache) = &inner.opts.block_cache {
+            let num_blocks = inner.meta.get().map_or(0, |meta| meta.num_blocks);
+            cache.pin_table(inner.id, num_blocks);
+        }
+        if let Some(cache) = &inner.opts.index_cache {
+            cache.pin(inner.id);
+        }
+    }
+
+    /// Read blocks which may contain keys within `[start, end)` into
+    /// block cache, or all blocks if `range` is `None`, where keys contain
+    /// timestamps. Returns the number of read blocks.
+    pub(crate) fn warm_up(&self, range: Option<(&[u8], &[u8])>) -> Result<usize> {
+        let blocks = match range {
+            Some((start, end)) => {
+                let index = self.inner.fetch_index()?;
+                self.inner.block_range(&index.offsets, start, end)
+            }
+            None => 0..self.offsets_length(),
+        };
+        let count = blocks.len();
+        self.prefetch_blocks(blocks)?;
+        Ok(count)
+    }
+
+    /// Read blocks in `blocks` which are not cached in one batch into block
+    /// cache. Returns the number of read blocks.
+    pub(crate) fn prefetch_blocks(&self, blocks: Range<usize>) -> Result<usize> {
+        self.inner
+            .prefetch_blocks(blocks)
+            .map_err(|err| err.with_context(ErrorContext::table(self.id())))
+    }
+
     pub fn mark_save(&self) {
         self.inner
             .save_after_close
@@ -1,12 +1,14 @@
+use super::compression::{self, NO_COMPRESSION};
 use crate::bloom::Bloom;
-use crate::format::user_key;
-use crate::opt::Options;
+use crate::buffer_pool;
+use crate::format::{get_ts, user_key};
+use crate::opt::{BloomStrategy, Compression, Options};
 use crate::value::Value;
 use crate::{checksum, util};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use prost::Message;
-use proto::meta::{checksum::Algorithm as ChecksumAlg, BlockOffset, Checksum, TableIndex};
+use proto::meta::{BlockOffset, Checksum, TableIndex};
 
 /// Entry header stores the difference between current key and block base key.
 /// `overlap` is the common prefix of key and base key, and diff is the length
@@ -40,8 +42,15 @@ pub struct Builder {
     entry_offsets: Vec<u32>,
     table_index: TableIndex,
     key_hashes: Vec<u32>,
+    /// index of the first key hash of current block in `key_hashes`
+    block_hashes_start: usize,
     options: Options,
     max_version: u64,
+    stale_data_size: u32,
+    key_bytes: u64,
+    key_overlap_bytes: u64,
+    min_expires_at: u64,
+    max_expires_at: u64,
 }
 
 impl Builder {
@@ -49,14 +58,20 @@ impl Builder {
     pub fn new(options: Options) -> Builder {
         Builder {
             // approximately 16MB index + table size
-            buf: BytesMut::with_capacity((16 << 20) + options.table_size as usize),
+            buf: buffer_pool::take((16 << 20) + options.table_size as usize),
             table_index: TableIndex::default(),
             key_hashes: Vec::with_capacity(1024),
+            block_hashes_start: 0,
             base_key: Bytes::new(),
             base_offset: 0,
             entry_offsets: vec![],
             options,
             max_version: 0,
+            stale_data_size: 0,
+            key_bytes: 0,
+            key_overlap_bytes: 0,
+            min_expires_at: 0,
+            max_expires_at: 0,
         }
     }
 
@@ -71,7 +86,16 @@ impl Builder {
 
     fn add_helper(&mut self, key: &Bytes, v: Value, vlog_len: u32) {
         self.key_hashes.push(farmhash::fingerprint32(user_key(key)));
-        // TODO: check ts
+        let version = get_ts(key);
+        if version > self.max_version {
+            self.max_version = version;
+        }
+        if v.expires_at != 0 {
+            if self.min_expires_at == 0 || v.expires_at < self.min_expires_at {
+                self.min_expires_at = v.expires_at;
+            }
+            self.max_expires_at = self.max_expires_at.max(v.expires_at);
+        }
         let diff_key = if self.base_key.is_empty() {
             self.base_key = key.clone();
             key
@@ -84,6 +108,8 @@ impl Builder {
             overlap: (key.len() - diff_key.len()) as u16,
             diff: diff_key.len() as u16,
         };
+        self.key_bytes += key.len() as u64;
+        self.key_overlap_bytes += h.overlap as u64;
         assert!(self.buf.len() <= u32::MAX as usize);
         self.entry_offsets
             .push(self.buf.len() as u32 - self.base_offset);
@@ -105,6 +131,9 @@ impl Builder {
             self.buf.put_u32_le(*offset);
         }
         self.buf.put_u32(self.entry_offsets.len() as u32);
+        if self.options.compression != Compression::None {
+            self.compress_block();
+        }
 
         let cs = self.build_checksum(&self.buf[self.base_offset as usize..]);
         self.write_checksum(cs);
@@ -112,15 +141,49 @@ impl Builder {
         self.add_block_to_index();
     }
 
+    /// Compress current block, and append its compression type, which is
+    /// covered by the checksum of the block. The block is kept as is if
+    /// compression doesn't shrink it.
+    fn compress_block(&mut self) {
+        let start = self.base_offset as usize;
+        match compression::compress(&self.buf[start..], self.options.compression) {
+            Some((data, ty)) => {
+                self.buf.truncate(start);
+                self.buf.put_slice(&data);
+                self.buf.put_u8(ty);
+            }
+            None => self.buf.put_u8(NO_COMPRESSION),
+        }
+    }
+
     fn add_block_to_index(&mut self) {
+        let bloom_filter = match self.options.bloom_strategy {
+            BloomStrategy::PerBlock => {
+                self.build_bloom(&self.key_hashes[self.block_hashes_start..])
+            }
+            BloomStrategy::WholeKey => vec![],
+        };
+        self.block_hashes_start = self.key_hashes.len();
         let block = BlockOffset {
             key: self.base_key.to_vec(),
             offset: self.base_offset,
             len: self.buf.len() as u32 - self.base_offset,
+            bloom_filter,
         };
         self.table_index.offsets.push(block);
     }
 
+    /// Build bloom filter of `key_hashes`, which is empty if bloom filters
+    /// are disabled.
+    fn build_bloom(&self, key_hashes: &[u32]) -> Vec<u8> {
+        if self.options.bloom_false_positive <= 0.0 {
+            return vec![];
+        }
+        let bits_per_key =
+            Bloom::bloom_bits_per_key(key_hashes.len(), self.options.bloom_false_positive);
+        Bloom::build_from_key_hashes(key_hashes, bits_per_key).to_vec()
+    }
+
     fn should_finish_block(&self, key: &[u8], value: &Value) -> bool {
         if self.entry_offsets.is_empty() {
             return false;
@@ -151,6 +214,14 @@ impl Builder {
         self.add_helper(key, value, vlog_len);
     }
 
+    /// Same as `add`, but also counts the entry as stale data, which can be
+    /// dropped by compaction. Tables with more stale data are preferred when
+    /// picking tables to compact.
+    pub fn add_stale_key(&mut self, key: &Bytes, value: Value, vlog_len: u32) {
+        self.stale_data_size += (key.len() + value.value.len()) as u32 + vlog_len + 4;
+        self.add(key, value, vlog_len);
+    }
+
     /// Check if entries reach its capacity
     pub fn reach_capacity(&self, capacity: u64) -> bool {
         let block_size = self.buf.len() as u32 + // length of buffer
@@ -170,21 +241,28 @@ impl Builder {
         if self.buf.is_empty() {
             return Bytes::new();
         }
-        let mut bytes = BytesMut::new();
         // TODO: move boundaries and build index if we need to encrypt or compress
-        if self.options.bloom_false_positive > 0.0 {
-            let bits_per_key =
-                Bloom::bloom_bits_per_key(self.key_hashes.len(), self.options.bloom_false_positive);
-            let bloom = Bloom::build_from_key_hashes(&self.key_hashes, bits_per_key);
-            self.table_index.bloom_filter = bloom.to_vec();
+        if self.options.bloom_strategy == BloomStrategy::WholeKey {
+            self.table_index.bloom_filter = self.build_bloom(&self.key_hashes);
         }
+        self.table_index.bloom_strategy = self.options.bloom_strategy as i32;
+        self.table_index.key_count = self.key_hashes.len() as u32;
+        self.table_index.max_version = self.max_version;
+        self.table_index.stale_data_size = self.stale_data_size;
+        self.table_index.key_bytes = self.key_bytes;
+        self.table_index.key_overlap_bytes = self.key_overlap_bytes;
+        self.table_index.min_expires_at = self.min_expires_at;
+        self.table_index.max_expires_at = self.max_expires_at;
+        self.table_index.has_compression_type = self.options.compression != Compression::None;
         // append index to buffer
-        self.table_index.encode(&mut bytes).unwrap();
-        assert!(bytes.len() < u32::MAX as usize);
-        self.buf.put_slice(&bytes);
-        self.buf.put_u32(bytes.len() as u32);
+        let index_offset = self.buf.len();
+        self.table_index.data_size = index_offset as u64;
+        self.table_index.encode(&mut self.buf).unwrap();
+        let index_len = self.buf.len() - index_offset;
+        assert!(index_len < u32::MAX as usize);
+        self.buf.put_u32(index_len as u32);
         // append checksum
-        let cs = self.build_checksum(&bytes);
+        let cs = self.build_checksum(&self.buf[index_offset..index_offset + index_len]);
         self.write_checksum(cs);
         // TODO: eliminate clone if we do not need builder any more after finish
         self.buf.clone().freeze()
@@ -192,25 +270,30 @@ impl Builder {
 
     fn build_checksum(&self, data: &[u8]) -> Checksum {
         Checksum {
-            sum: checksum::calculate_checksum(data, ChecksumAlg::Crc32c),
-            algo: ChecksumAlg::Crc32c as i32,
+            sum: checksum::calculate_checksum(data, self.options.checksum_algorithm),
+            algo: self.options.checksum_algorithm as i32,
         }
     }
 
     fn write_checksum(&mut self, checksum: Checksum) {
-        let mut res = BytesMut::new();
-        checksum.encode(&mut res).unwrap();
-        let len = res.len();
+        let len = checksum.encoded_len();
         assert!(len < u32::MAX as usize);
-        self.buf.put_slice(&res);
+        checksum.encode(&mut self.buf).unwrap();
         self.buf.put_u32(len as u32);
     }
 }
+
+impl Drop for Builder {
+    fn drop(&mut self) {
+        // Tables are usually built one after another by the same thread.
+        buffer_pool::give_back(std::mem::take(&mut self.buf));
+    }
+}
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::table::tests::build_test_table;
-    use crate::table::Table;
+    use crate::table::{PrefixStats, Table};
     use crate::AgateIterator;
     use crate::{format::key_with_ts, ChecksumVerificationMode};
     use tempdir::TempDir;
@@ -225,6 +308,7 @@ mod tests {
             bloom_false_positive: 0.01,
             table_size: 30 << 20,
             checksum_mode: crate::opt::ChecksumVerificationMode::OnTableAndBlockRead,
+            ..Default::default()
         };
 
         let mut builder = Builder::new(opts.clone());
@@ -257,23 +341,74 @@ mod tests {
             assert_eq!(block_first_keys[i], idx.offsets[i].key);
         }
 
-        // TODO: support max_version
-        // assert_eq!(TEST_KEYS_COUNT, table.max_version());
+        assert_eq!(TEST_KEYS_COUNT as u64, table.max_version());
     }
 
-    fn test_with_bloom_filter(with_blooms: bool) {
+    #[test]
+    fn test_prefix_stats() {
+        let opts = Options {
+            block_size: 1024,
+            ..Default::default()
+        };
+        let mut builder = Builder::new(opts.clone());
+        let (mut base_key, mut overlap) = (Bytes::new(), 0);
+        for i in 0..1000 {
+            let k = key_with_ts(format!("{:016x}", i).as_str(), 0);
+            let vs = Value::new(Bytes::from(i.to_string()));
+            // Base keys are stored as a whole.
+            if i == 0 || builder.should_finish_block(&k, &vs) {
+                base_key = k.clone();
+            } else {
+                overlap += k.len() - util::bytes_diff(&base_key, &k).len();
+            }
+            builder.add(&k, vs, 0);
+        }
+        let data = builder.finish();
+        let table = Table::open_in_memory(data.clone(), 1, opts).unwrap();
+
+        let stats = table.prefix_stats();
+        assert_eq!(stats.tables, 1);
+        assert_eq!(stats.blocks, table.offsets_length() as u64);
+        assert!(stats.blocks > 1);
+        assert_eq!(stats.entries, 1000);
+        assert_eq!(stats.key_bytes, 24 * 1000);
+        assert_eq!(stats.key_overlap_bytes, overlap as u64);
+        assert_eq!(stats.index_size, table.inner.index_size() as u64);
+        let index = table.inner.read_table_index().unwrap();
+        assert_eq!(
+            stats.data_size,
+            index.offsets.iter().map(|o| o.len as u64).sum()
+        );
+
+        let mut merged = stats;
+        merged.merge(&stats);
+        assert_eq!(merged.tables, 2);
+        assert_eq!(merged.avg_key_overlap(), stats.avg_key_overlap());
+        assert_eq!(merged.entries_per_block(), stats.entries_per_block());
+        assert_eq!(PrefixStats::default().index_to_data_ratio(), 0.0);
+    }
+
+    fn test_with_bloom_filter(with_blooms: bool, strategy: BloomStrategy) {
         let key_prefix = b"p";
         let key_count = 1000;
         let opts = Options {
-            block_size: 0,
+            block_size: if strategy == BloomStrategy::PerBlock {
+                1024
+            } else {
+                0
+            },
             bloom_false_positive: if with_blooms { 0.01 } else { 0.0 },
+            bloom_strategy: strategy,
             table_size: 0,
             checksum_mode: ChecksumVerificationMode::OnTableRead,
+            ..Default::default()
         };
 
         let table = build_test_table(key_prefix, key_count, opts);
 
         assert_eq!(table.has_bloom_filter(), with_blooms);
+        let index = table.inner.read_table_index().unwrap();
+        assert_eq!(index.bloom_strategy, strategy as i32);
 
         let mut it = table.new_iterator(0);
         let mut count = 0;
@@ -282,15 +417,63 @@ mod tests {
             count += 1;
             let hash = farmhash::fingerprint32(user_key(it.key()));
             assert!(!table.does_not_have(hash));
+            assert!(!table.does_not_have_key(it.key(), hash));
             it.next();
         }
         assert_eq!(key_count, count);
+
+        // Missing keys within the key range are filtered out by blooms.
+        let filtered = (0..key_count)
+            .filter(|i| {
+                let key = key_with_ts(format!("p{:04}x", i).as_str(), 0);
+                table.does_not_have_key(&key, farmhash::fingerprint32(user_key(&key)))
+            })
+            .count();
+        if with_blooms {
+            assert!(filtered > key_count * 9 / 10, "{}", filtered);
+        } else {
+            assert_eq!(filtered, 0);
+        }
     }
 
     #[test]
     fn test_bloom_filter() {
-        test_with_bloom_filter(false);
-        test_with_bloom_filter(true);
+        for strategy in [BloomStrategy::WholeKey, BloomStrategy::PerBlock] {
+            test_with_bloom_filter(false, strategy);
+            test_with_bloom_filter(true, strategy);
+        }
+    }
+
+    #[test]
+    fn test_per_block_bloom_filter() {
+        let opts = Options {
+            block_size: 1024,
+            bloom_false_positive: 0.01,
+            bloom_strategy: BloomStrategy::PerBlock,
+            ..Default::default()
+        };
+        // Versions of a key span several blocks.
+        let mut builder = Builder::new(opts.clone());
+        for i in 0..1000 {
+            let key = key_with_ts(format!("key{:02}", i / 100).as_str(), 1000 - i as u64);
+            builder.add(&key, Value::new(Bytes::from(i.to_string())), 0);
+        }
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let table = Table::create(&tmp_dir.path().join("1.sst"), builder.finish(), opts).unwrap();
+        let index = table.inner.read_table_index().unwrap();
+        assert!(index.bloom_filter.is_empty());
+        assert!(index.offsets.len() > 10);
+        assert!(index.offsets.iter().all(|o| !o.bloom_filter.is_empty()));
+        assert!(table.inner.bloom_filter_size() > 0);
+
+        for i in 0..10 {
+            let user_key = format!("key{:02}", i);
+            let hash = farmhash::fingerprint32(user_key.as_bytes());
+            for ts in [0, 950 - i * 100, u64::MAX] {
+                let key = key_with_ts(user_key.as_str(), ts);
+                assert!(!table.does_not_have_key(&key, hash), "{} {}", i, ts);
+            }
+        }
     }
 
     #[test]
@@ -300,6 +483,7 @@ mod tests {
             block_size: 0,
             table_size: 0,
             checksum_mode: crate::opt::ChecksumVerificationMode::NoVerification,
+            ..Default::default()
         };
 
         let mut b = Builder::new(opt);
@@ -0,0 +1,59 @@
+//! Compression of blocks in SSTs. Blocks of tables written with compression
+//! enabled end with their compression types, see `Builder::finish_block`.
+
+use crate::opt::Compression;
+use crate::{Error, Result};
+
+pub(crate) const NO_COMPRESSION: u8 = 0;
+pub(crate) const SNAPPY_COMPRESSION: u8 = 1;
+pub(crate) const ZSTD_COMPRESSION: u8 = 2;
+
+/// Compress `data`, returning the compressed data and its compression type.
+/// Returns `None` if it fails or doesn't shrink `data`, so that the block is
+/// stored uncompressed.
+pub(crate) fn compress(data: &[u8], compression: Compression) -> Option<(Vec<u8>, u8)> {
+    let (compressed, ty) = match compression {
+        Compression::None => return None,
+        Compression::Snappy => (
+            snap::raw::Encoder::new().compress_vec(data).ok()?,
+            SNAPPY_COMPRESSION,
+        ),
+        Compression::Zstd { level } => (zstd::bulk::compress(data, level).ok()?, ZSTD_COMPRESSION),
+    };
+    if compressed.len() >= data.len() {
+        return None;
+    }
+    Some((compressed, ty))
+}
+
+/// Decompress `data` compressed with compression type `ty`.
+pub(crate) fn decompress(data: &[u8], ty: u8) -> Result<Vec<u8>> {
+    let res = match ty {
+        SNAPPY_COMPRESSION => snap::raw::Decoder::new()
+            .decompress_vec(data)
+            .map_err(|err| err.to_string()),
+        ZSTD_COMPRESSION => zstd::stream::decode_all(data).map_err(|err| err.to_string()),
+        _ => Err(format!("unknown compression type {}", ty)),
+    };
+    res.map_err(|err| Error::TableRead(format!("failed to decompress block: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_decompress() {
+        let data = b"aaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbcccccccccccccccc".repeat(16);
+        for compression in [Compression::Snappy, Compression::Zstd { level: 3 }] {
+            let (compressed, ty) = compress(&data, compression).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(decompress(&compressed, ty).unwrap(), data);
+            assert!(decompress(&compressed[..compressed.len() - 1], ty).is_err());
+        }
+        assert!(compress(&data, Compression::None).is_none());
+        // Incompressible data is stored as is.
+        assert!(compress(b"abcdefgh", Compression::Snappy).is_none());
+        assert!(decompress(&data, 3).is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use super::iterator::ITERATOR_REVERSED;
 use super::{AgateIterator, Table, TableIterator};
-use crate::util::{KeyComparator, COMPARATOR};
+use crate::util::KeyComparator;
 use crate::value::Value;
 
 use bytes::Bytes;
@@ -94,7 +94,8 @@ impl AgateIterator for ConcatIterator {
         let idx;
         if self.opt & ITERATOR_REVERSED == 0 {
             idx = crate::util::search(self.tables.len(), |idx| {
-                COMPARATOR.compare_key(self.tables[idx].biggest(), key) != Less
+                let table = &self.tables[idx];
+                table.comparator().compare_key(table.biggest(), key) != Less
             });
             if idx >= self.tables.len() {
                 self.cur = None;
@@ -103,7 +104,8 @@ impl AgateIterator for ConcatIterator {
         } else {
             let n = self.tables.len();
             let ridx = crate::util::search(self.tables.len(), |idx| {
-                COMPARATOR.compare_key(self.tables[n - 1 - idx].smallest(), key) != Greater
+                let table = &self.tables[n - 1 - idx];
+                table.comparator().compare_key(table.smallest(), key) != Greater
             });
             if ridx >= self.tables.len() {
                 self.cur = None;
@@ -1,7 +1,8 @@
 use super::builder::{Header, HEADER_SIZE};
 use super::{Block, TableInner};
+use crate::comparator::Comparator;
 use crate::iterator_trait::AgateIterator;
-use crate::util::{self, KeyComparator, COMPARATOR};
+use crate::util::{self, KeyComparator};
 use crate::value::Value;
 use crate::Error;
 use bytes::{Bytes, BytesMut};
@@ -42,7 +43,6 @@ enum SeekPos {
 }
 
 /// Block iterator iterates on an SST block
-// TODO: support custom comparator
 struct BlockIterator {
     /// current index of iterator
     idx: usize,
@@ -62,12 +62,15 @@ struct BlockIterator {
     perv_overlap: u16,
     /// iterator error in last operation
     err: Option<IteratorError>,
+    /// order of keys in the table
+    comparator: Comparator,
 }
 
 impl BlockIterator {
-    pub fn new(block: Arc<Block>) -> Self {
+    pub fn new(block: Arc<Block>, comparator: Comparator) -> Self {
         let data = block.data.slice(..block.entries_index_start);
         Self {
+            comparator,
             block,
             err: None,
             base_key: Bytes::new(),
@@ -104,15 +107,35 @@ impl BlockIterator {
         }
 
         self.err = None;
-        let start_offset = self.entry_offsets()[i] as u32;
+        if let Err(err) = self.decode_entry(i) {
+            // Key is rebuilt from base key by the next decode.
+            self.perv_overlap = 0;
+            self.key.clear();
+            self.err = Some(err.into());
+        }
+    }
+
+    /// Decode entry `i` into `key` and `val`. Returns an error instead of
+    /// panicking if the block is corrupted.
+    fn decode_entry(&mut self, i: usize) -> crate::Result<()> {
+        let offset = self.block.offset;
+        let corrupted = |what: &str| {
+            Error::TableRead(format!("block at offset {} is corrupted: {}", offset, what))
+        };
+        let start_offset = self.entry_offsets()[i] as usize;
 
         if self.base_key.is_empty() {
+            if self.data.len() < HEADER_SIZE {
+                return Err(corrupted("base key is out of range"));
+            }
             let mut base_header = Header::default();
             base_header.decode(&mut self.data.slice(..));
+            let base_end = HEADER_SIZE + base_header.diff as usize;
+            if base_end > self.data.len() {
+                return Err(corrupted("base key is out of range"));
+            }
             // TODO: combine this decode with header decode to avoid slice ptr copy
-            self.base_key = self
-                .data
-                .slice(HEADER_SIZE..HEADER_SIZE + base_header.diff as usize);
+            self.base_key = self.data.slice(HEADER_SIZE..base_end);
         }
 
         let end_offset = if self.idx + 1 == self.entry_offsets().len() {
@@ -120,10 +143,17 @@ impl BlockIterator {
         } else {
             self.entry_offsets()[self.idx + 1] as usize
         };
+        if start_offset + HEADER_SIZE > end_offset || end_offset > self.data.len() {
+            return Err(corrupted("entry is out of range"));
+        }
 
-        let mut entry_data = self.data.slice(start_offset as usize..end_offset as usize);
+        let mut entry_data = self.data.slice(start_offset..end_offset);
         let mut header = Header::default();
         header.decode(&mut entry_data);
+        if header.overlap as usize > self.base_key.len() || header.diff as usize > entry_data.len()
+        {
+            return Err(corrupted("key is out of range"));
+        }
 
         // TODO: merge this truncate with the following key truncate
         if header.overlap > self.perv_overlap {
@@ -138,6 +168,14 @@ impl BlockIterator {
         self.key.truncate(header.overlap as usize);
         self.key.extend_from_slice(diff_key);
         self.val = entry_data.slice(header.diff as usize..);
+        // Keys without timestamps can't be compared.
+        if self.key.len() < 8 {
+            return Err(corrupted("key has no timestamp"));
+        }
+        if !Value::is_valid_encoding(&self.val) {
+            return Err(corrupted("value is truncated"));
+        }
+        Ok(())
     }
 
     /// Check if last operation of iterator is error
@@ -165,10 +203,8 @@ impl BlockIterator {
                 return false;
             }
             self.set_idx(idx);
-            match COMPARATOR.compare_key(&self.key, &key) {
-                Less => false,
-                _ => true,
-            }
+            // Stop at corrupted entries, whose errors are reported below.
+            self.err.is_some() || self.comparator.compare_key(&self.key, key) != Less
         });
 
         self.set_idx(found_entry_idx);
@@ -213,6 +249,12 @@ impl BlockIterator {
 // TODO: use `bitfield` if there are too many variants
 pub const ITERATOR_REVERSED: usize = 1 << 1;
 pub const ITERATOR_NOCACHE: usize = 1 << 2;
+/// Read blocks ahead in batches when iterating forward, see
+/// `IteratorOptions::prefetch_values`.
+pub const ITERATOR_PREFETCH: usize = 1 << 3;
+
+/// Number of blocks read together by iterators with `ITERATOR_PREFETCH`.
+const PREFETCH_BLOCKS: usize = 8;
 
 /// An iterator over SST.
 ///
@@ -255,22 +297,45 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
         self.opt & ITERATOR_NOCACHE == 0
     }
 
+    /// Read block `bpos` and blocks after it in one batch if it's not
+    /// cached, for forward iterators with `ITERATOR_PREFETCH`.
+    fn prefetch(&self) {
+        if self.opt & ITERATOR_PREFETCH == 0 || self.opt & ITERATOR_REVERSED != 0 {
+            return;
+        }
+        let table = self.table.as_ref();
+        if let Some(cache) = &table.opts.block_cache {
+            if self.use_cache() && !cache.contains(table.id, self.bpos) {
+                // Errors are returned when the block is read again.
+                let _ = table.prefetch_blocks(self.bpos..self.bpos + PREFETCH_BLOCKS);
+            }
+        }
+    }
+
     fn get_block_iterator(&mut self, block: Arc<Block>) -> &mut BlockIterator {
         if let Some(ref mut iter) = self.block_iterator {
             iter.set_block(block);
             return iter;
         }
-        self.block_iterator = Some(BlockIterator::new(block));
+        let comparator = self.table.as_ref().opts.comparator.clone();
+        self.block_iterator = Some(BlockIterator::new(block, comparator));
         self.block_iterator.as_mut().unwrap()
     }
 
     pub fn seek_to_first(&mut self) {
-        let num_blocks = self.table.as_ref().offsets_length();
+        let num_blocks = match self.table.as_ref().num_blocks() {
+            Ok(num_blocks) => num_blocks,
+            Err(err) => {
+                self.err = Some(err.into());
+                return;
+            }
+        };
         if num_blocks == 0 {
             self.err = Some(IteratorError::EOF);
             return;
         }
         self.bpos = 0;
+        self.prefetch();
         match self.table.as_ref().block(self.bpos, self.use_cache()) {
             Ok(block) => {
                 let block_iterator = self.get_block_iterator(block);
@@ -282,7 +347,13 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
     }
 
     pub fn seek_to_last(&mut self) {
-        let num_blocks = self.table.as_ref().offsets_length();
+        let num_blocks = match self.table.as_ref().num_blocks() {
+            Ok(num_blocks) => num_blocks,
+            Err(err) => {
+                self.err = Some(err.into());
+                return;
+            }
+        };
         if num_blocks == 0 {
             self.err = Some(IteratorError::EOF);
             return;
@@ -300,6 +371,22 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
 
     fn seek_helper(&mut self, block_idx: usize, key: &Bytes) {
         self.bpos = block_idx;
+        // Reuse current block if it's the target, so that seeking sorted keys
+        // within the same block won't read and decode it again.
+        let offset = self
+            .table
+            .as_ref()
+            .offsets(block_idx)
+            .map(|o| o.offset as usize);
+        if let Some(ref mut iter) = self.block_iterator {
+            // Data is cleared once iterator moves out of the block.
+            if !iter.data.is_empty() && Some(iter.block.offset) == offset {
+                iter.seek(key, SeekPos::Origin);
+                self.err = iter.err.clone();
+                return;
+            }
+        }
+        self.prefetch();
         match self.table.as_ref().block(self.bpos, self.use_cache()) {
             Ok(block) => {
                 let block_iterator = self.get_block_iterator(block);
@@ -316,13 +403,16 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
             self.reset();
         }
 
-        let idx = util::search(self.table.as_ref().offsets_length(), |idx| {
-            use std::cmp::Ordering::*;
-            let block_offset = self.table.as_ref().offsets(idx).unwrap();
-            match COMPARATOR.compare_key(&block_offset.key, &key) {
-                Greater => true,
-                _ => false,
+        let index = match self.table.as_ref().fetch_index() {
+            Ok(index) => index,
+            Err(err) => {
+                self.err = Some(err.into());
+                return;
             }
+        };
+        let comparator = &self.table.as_ref().opts.comparator;
+        let idx = util::search(index.offsets.len(), |idx| {
+            comparator.compare_key(&index.offsets[idx].key, key) == std::cmp::Ordering::Greater
         });
 
         if idx == 0 {
@@ -366,6 +456,7 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
         }
 
         if BlockIterator::is_ready(&self.block_iterator) {
+            self.prefetch();
             match self.table.as_ref().block(self.bpos, self.use_cache()) {
                 Ok(block) => {
                     let block_iterator = self.get_block_iterator(block);
@@ -3,8 +3,11 @@ use enum_dispatch::enum_dispatch;
 
 use super::concat_iterator::ConcatIterator;
 use super::TableIterator;
+use crate::comparator::Comparator;
 use crate::iterator_trait::AgateIterator;
-use crate::util::{KeyComparator, COMPARATOR};
+use crate::memtable::MemTableIterator;
+use crate::ops::transaction::PendingWritesIterator;
+use crate::util::KeyComparator;
 use crate::Value;
 
 /// `Iterators` includes all iterator types for AgateDB.
@@ -15,6 +18,8 @@ pub enum Iterators {
     MergeIterator(MergeIterator),
     ConcatIterator(ConcatIterator),
     TableIterator(TableIterator),
+    MemTableIterator(MemTableIterator),
+    PendingWritesIterator(PendingWritesIterator),
     #[cfg(test)]
     VecIterator(tests::VecIterator),
 }
@@ -29,6 +34,7 @@ pub struct MergeIterator {
     is_left_small: bool,
     reverse: bool,
     current_key: BytesMut,
+    comparator: Comparator,
 }
 
 /// `IteratorNode` buffers the iterator key in its own struct, to
@@ -125,7 +131,10 @@ impl MergeIterator {
             return;
         }
 
-        match COMPARATOR.compare_key(&self.smaller().key, &self.bigger().key) {
+        match self
+            .comparator
+            .compare_key(&self.smaller().key, &self.bigger().key)
+        {
             Equal => {
                 self.right.next();
                 if !self.is_left_small {
@@ -157,7 +166,12 @@ impl MergeIterator {
     /// Construct a single merge iterator from multiple iterators
     ///
     /// If the iterator emits elements in descending order, set `reverse` to true.
-    pub fn from_iterators(mut iters: Vec<Box<Iterators>>, reverse: bool) -> Box<Iterators> {
+    /// Keys of all iterators must be ordered by `comparator`.
+    pub fn from_iterators(
+        mut iters: Vec<Box<Iterators>>,
+        reverse: bool,
+        comparator: &Comparator,
+    ) -> Box<Iterators> {
         match iters.len() {
             0 => panic!("no element in iters"),
             1 => iters.pop().unwrap(),
@@ -170,6 +184,7 @@ impl MergeIterator {
                     right: IteratorNode::new(right),
                     is_left_small: true,
                     current_key: BytesMut::new(),
+                    comparator: comparator.clone(),
                 }))
             }
             _ => {
@@ -178,10 +193,11 @@ impl MergeIterator {
                 let left = iters;
                 Box::new(Iterators::from(MergeIterator {
                     reverse,
-                    left: IteratorNode::new(Self::from_iterators(left, reverse)),
-                    right: IteratorNode::new(Self::from_iterators(right, reverse)),
+                    left: IteratorNode::new(Self::from_iterators(left, reverse, comparator)),
+                    right: IteratorNode::new(Self::from_iterators(right, reverse, comparator)),
                     is_left_small: true,
                     current_key: BytesMut::new(),
+                    comparator: comparator.clone(),
                 }))
             }
         }
@@ -261,9 +277,9 @@ mod tests {
             let found_entry_idx = crate::util::search(self.vec.len(), |idx| {
                 use std::cmp::Ordering::*;
                 if self.reversed {
-                    COMPARATOR.compare_key(&self.vec[idx], &key) != Greater
+                    Comparator::default().compare_key(&self.vec[idx], key) != Greater
                 } else {
-                    COMPARATOR.compare_key(&self.vec[idx], &key) != Less
+                    Comparator::default().compare_key(&self.vec[idx], key) != Less
                 }
             });
             self.pos = found_entry_idx;
@@ -379,13 +395,15 @@ mod tests {
 
         let iter_a = Box::new(Iterators::from(VecIterator::new(a, false)));
         let iter_b = Box::new(Iterators::from(VecIterator::new(b, false)));
-        let merge_iter = MergeIterator::from_iterators(vec![iter_a, iter_b], false);
+        let merge_iter =
+            MergeIterator::from_iterators(vec![iter_a, iter_b], false, &Comparator::default());
 
         check_sequence(merge_iter, 0xfff);
 
         let iter_a = Box::new(Iterators::from(VecIterator::new(rev_a, true)));
         let iter_b = Box::new(Iterators::from(VecIterator::new(rev_b, true)));
-        let merge_iter = MergeIterator::from_iterators(vec![iter_a, iter_b], true);
+        let merge_iter =
+            MergeIterator::from_iterators(vec![iter_a, iter_b], true, &Comparator::default());
         check_reverse_sequence(merge_iter, 0xfff);
     }
 
@@ -412,13 +430,19 @@ mod tests {
             .map(|vec| Box::new(Iterators::from(VecIterator::new(vec, false))))
             .collect();
 
-        check_sequence(MergeIterator::from_iterators(iters, false), 0xfff);
+        check_sequence(
+            MergeIterator::from_iterators(iters, false, &Comparator::default()),
+            0xfff,
+        );
 
         let rev_iters: Vec<Box<Iterators>> = rev_vecs
             .into_iter()
             .map(|vec| Box::new(Iterators::from(VecIterator::new(vec, true))))
             .collect();
 
-        check_reverse_sequence(MergeIterator::from_iterators(rev_iters, true), 0xfff);
+        check_reverse_sequence(
+            MergeIterator::from_iterators(rev_iters, true, &Comparator::default()),
+            0xfff,
+        );
     }
 }
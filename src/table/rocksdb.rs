@@ -0,0 +1,748 @@
+use crate::checksum;
+use crate::{Error, Result};
+
+use bytes::{Buf, BufMut, Bytes};
+use prost::encoding::{decode_varint, encode_varint};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+const BLOCK_BASED_TABLE_MAGIC: u64 = 0x88e2_41b7_85f4_cff7;
+const LEGACY_BLOCK_BASED_TABLE_MAGIC: u64 = 0xdb47_7524_8b80_fb57;
+/// Format version of written tables. Versions after it only change how
+/// compressed blocks and indexes are encoded.
+const FORMAT_VERSION: u32 = 2;
+/// Version 6 moves handles out of footer, which is not supported yet.
+const MAX_FORMAT_VERSION: u32 = 5;
+
+const BLOCK_SIZE: usize = 4 * 1024;
+const BLOCK_RESTART_INTERVAL: usize = 16;
+/// One byte compression type followed by four bytes checksum.
+const BLOCK_TRAILER_SIZE: u64 = 5;
+const MAX_BLOCK_HANDLE_LEN: usize = 20;
+/// Checksum type, metaindex and index handles with padding, format version
+/// and magic.
+const FOOTER_LEN: usize = 1 + 2 * MAX_BLOCK_HANDLE_LEN + 4 + 8;
+const LEGACY_FOOTER_LEN: usize = 2 * MAX_BLOCK_HANDLE_LEN + 8;
+const NO_COMPRESSION: u8 = 0;
+const NO_CHECKSUM: u8 = 0;
+const CRC32C_CHECKSUM: u8 = 1;
+const CRC_MASK_DELTA: u32 = 0xa282_ead8;
+
+const TYPE_DELETION: u8 = 0x0;
+const TYPE_VALUE: u8 = 0x1;
+const TYPE_MERGE: u8 = 0x2;
+const TYPE_SINGLE_DELETION: u8 = 0x7;
+
+const PROPERTIES_BLOCK: &[u8] = b"rocksdb.properties";
+const RANGE_DEL_BLOCK: &[u8] = b"rocksdb.range_del";
+const PROP_INDEX_TYPE: &str = "rocksdb.block.based.table.index.type";
+const PROP_INDEX_DELTA_ENCODED: &str = "rocksdb.index.value.is.delta.encoded";
+const PROP_EXTERNAL_SST_VERSION: &str = "rocksdb.external_sst_file.version";
+const PROP_GLOBAL_SEQNO: &str = "rocksdb.external_sst_file.global_seqno";
+/// Version of external SSTs written by `SstFileWriter`, whose entries all
+/// have sequence number 0 until a global one is assigned by ingestion.
+const EXTERNAL_SST_VERSION: u32 = 2;
+
+/// The largest sequence number of RocksDB. Sequence numbers share 8 bytes
+/// with value types in internal keys.
+pub const ROCKSDB_MAX_SEQUENCE: u64 = (1 << 56) - 1;
+
+/// Type of a RocksDB entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RocksEntryKind {
+    Value,
+    /// Both deletions and single deletions.
+    Deletion,
+    Merge,
+}
+
+/// An entry of RocksDB SST, where `sequence` plays the role of version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RocksEntry {
+    pub user_key: Bytes,
+    pub sequence: u64,
+    pub kind: RocksEntryKind,
+    pub value: Bytes,
+}
+
+fn mask_crc(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(CRC_MASK_DELTA)
+}
+
+/// Compare internal keys, which are user keys followed by sequence numbers
+/// and types in descending order.
+fn compare_internal_key(a: &[u8], b: &[u8]) -> Ordering {
+    let (a_key, mut a_trailer) = a.split_at(a.len() - 8);
+    let (b_key, mut b_trailer) = b.split_at(b.len() - 8);
+    a_key
+        .cmp(b_key)
+        .then_with(|| b_trailer.get_u64_le().cmp(&a_trailer.get_u64_le()))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+impl BlockHandle {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        encode_varint(self.offset, buf);
+        encode_varint(self.size, buf);
+    }
+
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        Ok(Self {
+            offset: decode_varint(buf)?,
+            size: decode_varint(buf)?,
+        })
+    }
+
+    /// Offset of the block right after this one.
+    fn next_offset(&self) -> u64 {
+        self.offset + self.size + BLOCK_TRAILER_SIZE
+    }
+}
+
+/// Builds blocks of prefix compressed entries, with a full key every
+/// `restart_interval` entries for binary search.
+struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    restart_interval: usize,
+    counter: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    fn new(restart_interval: usize) -> Self {
+        Self {
+            buf: vec![],
+            restarts: vec![0],
+            restart_interval,
+            counter: 0,
+            last_key: vec![],
+        }
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.counter < self.restart_interval {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            self.restarts.push(self.buf.len() as u32);
+            self.counter = 0;
+            0
+        };
+        encode_varint(shared as u64, &mut self.buf);
+        encode_varint((key.len() - shared) as u64, &mut self.buf);
+        encode_varint(value.len() as u64, &mut self.buf);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.counter += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn estimated_size(&self) -> usize {
+        self.buf.len() + 4 * self.restarts.len() + 4
+    }
+
+    /// Append restart points, and reset the builder for the next block.
+    fn finish(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.buf);
+        for restart in &self.restarts {
+            block.put_u32_le(*restart);
+        }
+        block.put_u32_le(self.restarts.len() as u32);
+        self.restarts = vec![0];
+        self.counter = 0;
+        self.last_key.clear();
+        block
+    }
+}
+
+/// Writes entries into an SST of RocksDB's block based table format, which
+/// can be read by `sst_dump`. Blocks are not compressed, and are protected
+/// by CRC32C. There are no filters.
+///
+/// If all entries have sequence number 0, the SST is marked as written by
+/// `SstFileWriter`, so that it can be ingested by
+/// `DB::IngestExternalFile`. Otherwise RocksDB only ingests it as a DB
+/// generated file.
+pub struct RocksSstWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    last_key: Vec<u8>,
+    num_entries: u64,
+    num_deletions: u64,
+    num_merge_operands: u64,
+    raw_key_size: u64,
+    raw_value_size: u64,
+    num_data_blocks: u64,
+    max_sequence: u64,
+}
+
+impl<W: Write> RocksSstWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            data_block: BlockBuilder::new(BLOCK_RESTART_INTERVAL),
+            index_block: BlockBuilder::new(1),
+            last_key: vec![],
+            num_entries: 0,
+            num_deletions: 0,
+            num_merge_operands: 0,
+            raw_key_size: 0,
+            raw_value_size: 0,
+            num_data_blocks: 0,
+            max_sequence: 0,
+        }
+    }
+
+    /// Add an entry. Entries must be added in ascending order of user keys,
+    /// and descending order of sequence numbers for the same user key.
+    pub fn add(&mut self, entry: &RocksEntry) -> Result<()> {
+        if entry.sequence > ROCKSDB_MAX_SEQUENCE {
+            return Err(Error::CustomError(format!(
+                "sequence number {} is too large for RocksDB",
+                entry.sequence
+            )));
+        }
+        let value_type = match entry.kind {
+            RocksEntryKind::Value => TYPE_VALUE,
+            RocksEntryKind::Deletion => TYPE_DELETION,
+            RocksEntryKind::Merge => TYPE_MERGE,
+        };
+        let mut key = Vec::with_capacity(entry.user_key.len() + 8);
+        key.extend_from_slice(&entry.user_key);
+        key.put_u64_le(entry.sequence << 8 | value_type as u64);
+        if !self.last_key.is_empty()
+            && compare_internal_key(&key, &self.last_key) != Ordering::Greater
+        {
+            return Err(Error::CustomError(format!(
+                "RocksDB entries out of order: {:?}@{} after {:?}",
+                entry.user_key,
+                entry.sequence,
+                Bytes::copy_from_slice(&self.last_key[..self.last_key.len() - 8])
+            )));
+        }
+
+        if self.data_block.estimated_size() >= BLOCK_SIZE {
+            self.flush_data_block()?;
+        }
+        self.data_block.add(&key, &entry.value);
+        self.num_entries += 1;
+        match entry.kind {
+            RocksEntryKind::Value => {}
+            RocksEntryKind::Deletion => self.num_deletions += 1,
+            RocksEntryKind::Merge => self.num_merge_operands += 1,
+        }
+        self.raw_key_size += key.len() as u64;
+        self.raw_value_size += entry.value.len() as u64;
+        self.max_sequence = self.max_sequence.max(entry.sequence);
+        self.last_key = key;
+        Ok(())
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    fn write_block(&mut self, mut block: Vec<u8>) -> Result<BlockHandle> {
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: block.len() as u64,
+        };
+        // Checksum covers the compression type as well.
+        block.push(NO_COMPRESSION);
+        let checksum = mask_crc(checksum::crc32c(&block));
+        block.put_u32_le(checksum);
+        self.writer.write_all(&block)?;
+        self.offset += block.len() as u64;
+        Ok(handle)
+    }
+
+    fn flush_data_block(&mut self) -> Result<()> {
+        let block = self.data_block.finish();
+        let handle = self.write_block(block)?;
+        self.num_data_blocks += 1;
+        // The last key of a block separates it from the next block.
+        let mut value = vec![];
+        handle.encode_to(&mut value);
+        self.index_block.add(&self.last_key, &value);
+        Ok(())
+    }
+
+    /// Write the remaining data, index, properties and footer. Returns the
+    /// underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        if !self.data_block.is_empty() {
+            self.flush_data_block()?;
+        }
+        let data_size = self.offset;
+        let index = self.index_block.finish();
+        let index_handle = self.write_block(index)?;
+
+        let varint = |n: u64| {
+            let mut buf = vec![];
+            encode_varint(n, &mut buf);
+            buf
+        };
+        let mut props = BTreeMap::new();
+        props.insert("rocksdb.comparator", b"leveldb.BytewiseComparator".to_vec());
+        props.insert("rocksdb.data.size", varint(data_size));
+        props.insert("rocksdb.deleted.keys", varint(self.num_deletions));
+        props.insert(
+            "rocksdb.index.size",
+            varint(index_handle.size + BLOCK_TRAILER_SIZE),
+        );
+        props.insert("rocksdb.merge.operands", varint(self.num_merge_operands));
+        props.insert("rocksdb.num.data.blocks", varint(self.num_data_blocks));
+        props.insert("rocksdb.num.entries", varint(self.num_entries));
+        props.insert("rocksdb.raw.key.size", varint(self.raw_key_size));
+        props.insert("rocksdb.raw.value.size", varint(self.raw_value_size));
+        if self.max_sequence == 0 {
+            props.insert(
+                PROP_EXTERNAL_SST_VERSION,
+                EXTERNAL_SST_VERSION.to_le_bytes().to_vec(),
+            );
+            props.insert(PROP_GLOBAL_SEQNO, 0u64.to_le_bytes().to_vec());
+        }
+        let mut block = BlockBuilder::new(1);
+        for (name, value) in &props {
+            block.add(name.as_bytes(), value);
+        }
+        let props_handle = self.write_block(block.finish())?;
+
+        let mut metaindex = BlockBuilder::new(1);
+        let mut value = vec![];
+        props_handle.encode_to(&mut value);
+        metaindex.add(PROPERTIES_BLOCK, &value);
+        let metaindex_handle = self.write_block(metaindex.finish())?;
+
+        let mut footer = vec![CRC32C_CHECKSUM];
+        metaindex_handle.encode_to(&mut footer);
+        index_handle.encode_to(&mut footer);
+        footer.resize(1 + 2 * MAX_BLOCK_HANDLE_LEN, 0);
+        footer.put_u32_le(FORMAT_VERSION);
+        footer.put_u64_le(BLOCK_BASED_TABLE_MAGIC);
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Find where restart points start in `block`, and collect them.
+fn block_restarts(block: &[u8]) -> Result<(usize, Vec<u32>)> {
+    let corrupted = || Error::TableRead("RocksDB block is corrupted".to_string());
+    if block.len() < 4 {
+        return Err(corrupted());
+    }
+    let packed = (&block[block.len() - 4..]).get_u32_le();
+    if packed & (1 << 31) != 0 {
+        return Err(Error::CustomError(
+            "hash index of RocksDB data blocks is not supported".to_string(),
+        ));
+    }
+    let start = (block.len() - 4)
+        .checked_sub(4 * packed as usize)
+        .ok_or_else(corrupted)?;
+    let mut buf = &block[start..block.len() - 4];
+    let restarts = (0..packed).map(|_| buf.get_u32_le()).collect();
+    Ok((start, restarts))
+}
+
+/// Decode all entries of `block`, where values are delimited by lengths
+/// unless `value_delta_encoded` is set, in which case `decode_value` reads
+/// the value from the rest of the block. `decode_value` is told whether the
+/// entry is at a restart point.
+fn parse_block<T>(
+    block: &Bytes,
+    value_delta_encoded: bool,
+    mut decode_value: impl FnMut(Bytes, &mut &[u8], bool) -> Result<T>,
+) -> Result<Vec<(Vec<u8>, T)>> {
+    let corrupted = || Error::TableRead("RocksDB block is corrupted".to_string());
+    let (end, restarts) = block_restarts(block)?;
+    let mut entries = vec![];
+    let mut key = vec![];
+    let mut pos = 0;
+    while pos < end {
+        let mut buf = &block[pos..end];
+        let shared = decode_varint(&mut buf)? as usize;
+        let non_shared = decode_varint(&mut buf)? as usize;
+        let value_len = if value_delta_encoded {
+            None
+        } else {
+            Some(decode_varint(&mut buf)? as usize)
+        };
+        if shared > key.len() || buf.len() < non_shared + value_len.unwrap_or(0) {
+            return Err(corrupted());
+        }
+        key.truncate(shared);
+        key.extend_from_slice(&buf[..non_shared]);
+        let value_start = end - buf.len() + non_shared;
+        let is_restart = restarts.binary_search(&(pos as u32)).is_ok();
+        let (value, next) = match value_len {
+            Some(len) => {
+                let value = block.slice(value_start..value_start + len);
+                let mut rest = &block[value_start..value_start + len];
+                (
+                    decode_value(value, &mut rest, is_restart)?,
+                    value_start + len,
+                )
+            }
+            None => {
+                let mut rest = &block[value_start..end];
+                let value = decode_value(Bytes::new(), &mut rest, is_restart)?;
+                (value, end - rest.len())
+            }
+        };
+        entries.push((key.clone(), value));
+        pos = next;
+    }
+    Ok(entries)
+}
+
+/// Reads an SST of RocksDB's block based table format. Only uncompressed
+/// tables with binary search indexes, CRC32C or no checksum, and format
+/// version up to 5 are supported, like those written by `RocksSstWriter`,
+/// or by RocksDB with `compression = kNoCompression` and
+/// `checksum = kCRC32c`.
+pub struct RocksSstReader {
+    data: Bytes,
+    checksum_type: u8,
+    format_version: u32,
+    index_handle: BlockHandle,
+    has_range_deletions: bool,
+    properties: HashMap<String, Bytes>,
+}
+
+impl RocksSstReader {
+    /// Parse footer and properties of SST `data`.
+    pub fn new(data: Bytes) -> Result<Self> {
+        let not_sst = || Error::TableRead("not a RocksDB block based table".to_string());
+        if data.len() < LEGACY_FOOTER_LEN {
+            return Err(not_sst());
+        }
+        let magic = (&data[data.len() - 8..]).get_u64_le();
+        let (mut handles, checksum_type, format_version) = match magic {
+            BLOCK_BASED_TABLE_MAGIC if data.len() >= FOOTER_LEN => {
+                let footer = &data[data.len() - FOOTER_LEN..];
+                let version = (&footer[FOOTER_LEN - 12..]).get_u32_le();
+                (&footer[1..], footer[0], version)
+            }
+            LEGACY_BLOCK_BASED_TABLE_MAGIC => {
+                (&data[data.len() - LEGACY_FOOTER_LEN..], CRC32C_CHECKSUM, 0)
+            }
+            _ => return Err(not_sst()),
+        };
+        if format_version > MAX_FORMAT_VERSION {
+            return Err(Error::CustomError(format!(
+                "RocksDB table format version {} is not supported",
+                format_version
+            )));
+        }
+        if checksum_type != NO_CHECKSUM && checksum_type != CRC32C_CHECKSUM {
+            return Err(Error::CustomError(format!(
+                "RocksDB checksum type {} is not supported",
+                checksum_type
+            )));
+        }
+        let metaindex_handle = BlockHandle::decode_from(&mut handles)?;
+        let index_handle = BlockHandle::decode_from(&mut handles)?;
+
+        let mut reader = Self {
+            data,
+            checksum_type,
+            format_version,
+            index_handle,
+            has_range_deletions: false,
+            properties: HashMap::new(),
+        };
+        let metaindex = reader.read_block(metaindex_handle)?;
+        let mut props_handle = None;
+        for (name, handle) in
+            parse_block(&metaindex, false, |_, buf, _| BlockHandle::decode_from(buf))?
+        {
+            if name == PROPERTIES_BLOCK {
+                props_handle = Some(handle);
+            } else if name == RANGE_DEL_BLOCK {
+                reader.has_range_deletions = true;
+            }
+        }
+        if let Some(handle) = props_handle {
+            let block = reader.read_block(handle)?;
+            for (name, value) in parse_block(&block, false, |value, _, _| Ok(value))? {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                reader.properties.insert(name, value);
+            }
+        }
+        Ok(reader)
+    }
+
+    /// Get table properties by names, like `rocksdb.num.entries`. Values are
+    /// encoded as RocksDB does, e.g. numbers are varints.
+    pub fn properties(&self) -> &HashMap<String, Bytes> {
+        &self.properties
+    }
+
+    /// Get the sequence number assigned to all entries by ingestion, which
+    /// is 0 if not assigned.
+    pub fn global_seqno(&self) -> u64 {
+        match self.properties.get(PROP_GLOBAL_SEQNO) {
+            Some(value) if value.len() == 8 => (&value[..]).get_u64_le(),
+            _ => 0,
+        }
+    }
+
+    fn read_block(&self, handle: BlockHandle) -> Result<Bytes> {
+        let start = handle.offset as usize;
+        let end = start + handle.size as usize;
+        if handle.next_offset() > self.data.len() as u64 {
+            return Err(Error::TableRead(format!(
+                "RocksDB block at {} exceeds table size {}",
+                start,
+                self.data.len()
+            )));
+        }
+        if self.checksum_type == CRC32C_CHECKSUM {
+            let expected = (&self.data[end + 1..end + 5]).get_u32_le();
+            let actual = mask_crc(checksum::crc32c(&self.data[start..end + 1]));
+            if actual != expected {
+                return Err(Error::InvalidChecksum(format!(
+                    "RocksDB block at {} has checksum mismatch",
+                    start
+                )));
+            }
+        }
+        if self.data[end] != NO_COMPRESSION {
+            return Err(Error::CustomError(format!(
+                "RocksDB compression type {} is not supported",
+                self.data[end]
+            )));
+        }
+        Ok(self.data.slice(start..end))
+    }
+
+    /// Get handles of all data blocks in order.
+    fn data_block_handles(&self) -> Result<Vec<BlockHandle>> {
+        if let Some(value) = self.properties.get(PROP_INDEX_TYPE) {
+            // 0 is binary search, and 1 is hash search with the same layout.
+            let index_type = (&value[..]).get_u32_le();
+            if index_type > 1 {
+                return Err(Error::CustomError(format!(
+                    "RocksDB index type {} is not supported",
+                    index_type
+                )));
+            }
+        }
+        // Since format version 4, only sizes are stored after restart points.
+        let delta_encoded = self.format_version >= 4
+            && self
+                .properties
+                .get(PROP_INDEX_DELTA_ENCODED)
+                .is_some_and(|value| value.iter().any(|b| *b != 0));
+        let index = self.read_block(self.index_handle)?;
+        let mut last: Option<BlockHandle> = None;
+        let handles = parse_block(&index, delta_encoded, |_, buf, is_restart| {
+            let handle = match last {
+                Some(last) if delta_encoded && !is_restart => {
+                    let delta = decode_varint(buf)?;
+                    let delta = (delta >> 1) as i64 ^ -((delta & 1) as i64);
+                    BlockHandle {
+                        offset: last.next_offset(),
+                        size: (last.size as i64 + delta) as u64,
+                    }
+                }
+                _ => BlockHandle::decode_from(buf)?,
+            };
+            last = Some(handle);
+            Ok(handle)
+        })?;
+        Ok(handles.into_iter().map(|(_, handle)| handle).collect())
+    }
+
+    /// Read all entries in order, and pass them to `f`. Stops at the first
+    /// error returned by `f`.
+    pub fn for_each(&self, mut f: impl FnMut(RocksEntry) -> Result<()>) -> Result<()> {
+        if self.has_range_deletions {
+            return Err(Error::CustomError(
+                "RocksDB range deletions are not supported".to_string(),
+            ));
+        }
+        for handle in self.data_block_handles()? {
+            let block = self.read_block(handle)?;
+            for (key, value) in parse_block(&block, false, |value, _, _| Ok(value))? {
+                if key.len() < 8 {
+                    return Err(Error::TableRead(
+                        "RocksDB internal key is too short".to_string(),
+                    ));
+                }
+                let (user_key, mut trailer) = key.split_at(key.len() - 8);
+                let trailer = trailer.get_u64_le();
+                let kind = match trailer as u8 {
+                    TYPE_VALUE => RocksEntryKind::Value,
+                    TYPE_DELETION | TYPE_SINGLE_DELETION => RocksEntryKind::Deletion,
+                    TYPE_MERGE => RocksEntryKind::Merge,
+                    t => {
+                        return Err(Error::CustomError(format!(
+                            "RocksDB value type {} is not supported",
+                            t
+                        )))
+                    }
+                };
+                f(RocksEntry {
+                    user_key: Bytes::copy_from_slice(user_key),
+                    sequence: trailer >> 8,
+                    kind,
+                    value,
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(i: u64, sequence: u64, kind: RocksEntryKind) -> RocksEntry {
+        RocksEntry {
+            user_key: Bytes::from(format!("key{:05}", i)),
+            sequence,
+            kind,
+            value: Bytes::from(format!("value{:05}", i)),
+        }
+    }
+
+    fn read_all(data: Vec<u8>) -> Result<Vec<RocksEntry>> {
+        let reader = RocksSstReader::new(Bytes::from(data))?;
+        let mut entries = vec![];
+        reader.for_each(|entry| {
+            entries.push(entry);
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_rocksdb_sst() {
+        let mut entries = vec![];
+        for i in 0..1000 {
+            let kind = match i % 10 {
+                3 => RocksEntryKind::Deletion,
+                7 => RocksEntryKind::Merge,
+                _ => RocksEntryKind::Value,
+            };
+            entries.push(entry(i, 2000 - i, kind));
+            entries.push(entry(i, 10, RocksEntryKind::Value));
+        }
+        let mut writer = RocksSstWriter::new(vec![]);
+        for entry in &entries {
+            writer.add(entry).unwrap();
+        }
+        assert!(writer.add(&entry(999, 11, RocksEntryKind::Value)).is_err());
+        assert!(writer
+            .add(&entry(
+                1000,
+                ROCKSDB_MAX_SEQUENCE + 1,
+                RocksEntryKind::Value
+            ))
+            .is_err());
+        assert_eq!(writer.num_entries(), 2000);
+        let data = writer.finish().unwrap();
+        assert_eq!(
+            (&data[data.len() - 8..]).get_u64_le(),
+            BLOCK_BASED_TABLE_MAGIC
+        );
+
+        let reader = RocksSstReader::new(Bytes::from(data.clone())).unwrap();
+        assert!(reader.data_block_handles().unwrap().len() > 1);
+        let mut num_entries = &reader.properties()["rocksdb.num.entries"][..];
+        assert_eq!(decode_varint(&mut num_entries).unwrap(), 2000);
+        assert_eq!(reader.global_seqno(), 0);
+        assert!(!reader.properties().contains_key(PROP_EXTERNAL_SST_VERSION));
+        assert_eq!(read_all(data.clone()).unwrap(), entries);
+
+        let mut corrupted = data.clone();
+        corrupted[10] ^= 1;
+        assert!(matches!(
+            read_all(corrupted),
+            Err(Error::InvalidChecksum(_))
+        ));
+        assert!(read_all(data[1..].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_delta_encoded_index() {
+        // Index block written by format version 4: keys are not length
+        // prefixed, and only size deltas follow non-restart entries.
+        let handles = vec![
+            BlockHandle {
+                offset: 0,
+                size: 100,
+            },
+            BlockHandle {
+                offset: 105,
+                size: 90,
+            },
+            BlockHandle {
+                offset: 200,
+                size: 120,
+            },
+        ];
+        let mut block = vec![];
+        let mut restarts = vec![];
+        for (i, handle) in handles.iter().enumerate() {
+            let key = format!("k{}", i);
+            if i % 2 == 0 {
+                restarts.push(block.len() as u32);
+                encode_varint(0, &mut block);
+                encode_varint(key.len() as u64, &mut block);
+                block.extend_from_slice(key.as_bytes());
+                handle.encode_to(&mut block);
+            } else {
+                encode_varint(1, &mut block);
+                encode_varint(1, &mut block);
+                block.extend_from_slice(&key.as_bytes()[1..]);
+                let delta = handle.size as i64 - handles[i - 1].size as i64;
+                encode_varint(((delta << 1) ^ (delta >> 63)) as u64, &mut block);
+            }
+        }
+        for restart in &restarts {
+            block.put_u32_le(*restart);
+        }
+        block.put_u32_le(restarts.len() as u32);
+
+        let mut data = block.clone();
+        data.push(NO_COMPRESSION);
+        data.put_u32_le(0);
+        let mut properties = HashMap::new();
+        properties.insert(PROP_INDEX_DELTA_ENCODED.to_string(), Bytes::from(vec![1]));
+        let reader = RocksSstReader {
+            data: Bytes::from(data),
+            checksum_type: NO_CHECKSUM,
+            format_version: 4,
+            index_handle: BlockHandle {
+                offset: 0,
+                size: block.len() as u64,
+            },
+            has_range_deletions: false,
+            properties,
+        };
+        assert_eq!(reader.data_block_handles().unwrap(), handles);
+    }
+}
@@ -1,7 +1,9 @@
 use std::ops::{Deref, DerefMut};
 
 use super::*;
+use crate::cache::IndexCache;
 use crate::format::{key_with_ts, user_key};
+use crate::opt::{ChecksumAlgorithm, Compression};
 use crate::value::Value;
 use builder::Builder;
 use iterator::IteratorError;
@@ -27,6 +29,7 @@ pub(crate) fn get_test_table_options() -> Options {
         table_size: 0,
         bloom_false_positive: 0.01,
         checksum_mode: ChecksumVerificationMode::OnTableRead,
+        ..Default::default()
     }
 }
 
@@ -275,6 +278,39 @@ fn test_table() {
     assert_eq!(user_key(it.key()), key(b"key", 0));
 }
 
+#[test]
+fn test_table_index_cache() {
+    // A cache which can't hold the index reads it on every lookup.
+    for capacity in [1 << 20, 1] {
+        let cache = Arc::new(IndexCache::new(capacity));
+        let opts = Options {
+            index_cache: Some(cache.clone()),
+            ..get_test_table_options()
+        };
+        let table = build_test_table(b"key", 10000, opts);
+        assert!(table.inner.index.is_none());
+        assert_eq!(table.key_count(), 10000);
+        let misses = cache.misses();
+
+        let mut it = table.new_iterator(0);
+        it.seek(&key_with_ts(&key(b"key", 1010)[..], 0));
+        assert_eq!(user_key(it.key()), &key(b"key", 1010)[..]);
+        it.rewind();
+        assert_eq!(user_key(it.key()), &key(b"key", 0)[..]);
+        if capacity == 1 {
+            assert_eq!(cache.usage(), 0);
+            assert!(cache.misses() > misses);
+        } else {
+            assert_eq!(cache.usage(), table.inner.index_size());
+            assert_eq!(cache.misses(), misses);
+            assert!(cache.hits() > 0);
+        }
+        drop(it);
+        drop(table);
+        assert_eq!(cache.usage(), 0);
+    }
+}
+
 #[test]
 fn test_iterate_back_and_forth() {
     let opts = get_test_table_options();
@@ -353,6 +389,7 @@ fn test_table_big_values() {
         bloom_false_positive: 0.01,
         table_size: (n as u64) * (1 << 20),
         checksum_mode: ChecksumVerificationMode::OnTableRead,
+        ..Default::default()
     };
     let mut builder = Builder::new(opts.clone());
 
@@ -386,6 +423,17 @@ fn test_table_big_values() {
     // assert_eq!(n, table.max_version());
 }
 
+#[test]
+fn test_table_stale_data_size() {
+    let opts = get_test_table_options();
+    let mut builder = Builder::new(opts.clone());
+    builder.add(&key_with_ts("key", 2), Value::new(Bytes::from("new")), 0);
+    builder.add_stale_key(&key_with_ts("key", 1), Value::new(Bytes::from("old")), 0);
+    let table = Table::open_in_memory(builder.finish(), 1, opts).unwrap();
+    assert_eq!(table.stale_data_size(), 11 + 3 + 4);
+    assert_eq!(table.key_count(), 2);
+}
+
 #[test]
 fn test_table_checksum() {
     let mut rng = thread_rng();
@@ -404,7 +452,10 @@ fn test_table_checksum() {
 
         let result = Table::open_in_memory(Bytes::from(table_data), 233, opts.clone());
         assert!(result.is_err());
-        if !matches!(result, Err(Error::InvalidChecksum(_))) {
+        if !matches!(
+            result.as_ref().map_err(Error::root_cause),
+            Err(Error::InvalidChecksum(_))
+        ) {
             println!(
                 "expected invalid checksum error, found {:?}",
                 result.err().unwrap()
@@ -413,6 +464,176 @@ fn test_table_checksum() {
     }
 }
 
+#[test]
+fn test_table_checksum_context() {
+    let mut opts = get_test_table_options();
+    opts.block_size = 256;
+    opts.checksum_mode = ChecksumVerificationMode::OnBlockRead;
+    let kv_pairs = generate_table_data(b"k", 100, opts.clone());
+    let table_data = build_table_data(kv_pairs, opts.clone());
+    let table = Table::open_in_memory(table_data.clone(), 233, opts.clone()).unwrap();
+    let block = table.offsets(1).unwrap();
+    let index_start = table.inner.index_start as u64;
+
+    // Corrupted blocks are reported with their offsets, even when they are
+    // read by iterators.
+    let mut data = table_data.to_vec();
+    data[block.offset as usize] ^= 0xff;
+    let table = Table::open_in_memory(Bytes::from(data), 233, opts.clone()).unwrap();
+    let err = table.block(1, false).err().unwrap();
+    assert!(
+        matches!(err.root_cause(), Error::InvalidChecksum(_)),
+        "{:?}",
+        err
+    );
+    let context = err.context().unwrap();
+    assert_eq!(context.table_id, Some(233));
+    assert_eq!(context.offset, Some(block.offset as u64));
+    let mut it = table.new_iterator(0);
+    it.rewind();
+    while it.valid() {
+        it.next();
+    }
+    let msg = format!("{:?}", it.error());
+    assert!(
+        msg.contains(&format!("table 233, offset {}", block.offset)),
+        "{}",
+        msg
+    );
+
+    // So is corrupted index.
+    let mut data = table_data.to_vec();
+    data[index_start as usize] ^= 0xff;
+    let err = Table::open_in_memory(Bytes::from(data), 233, opts)
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err.root_cause(), Error::InvalidChecksum(_)),
+        "{:?}",
+        err
+    );
+    assert_eq!(err.context().unwrap().offset, Some(index_start));
+}
+
+#[test]
+fn test_table_checksum_algorithm() {
+    let mut opts = get_test_table_options();
+    opts.checksum_mode = ChecksumVerificationMode::OnTableAndBlockRead;
+    let kv_pairs = generate_table_data(b"k", 1000, opts.clone());
+    let crc_data = build_table_data(kv_pairs.clone(), opts.clone());
+    let mut xxhash_opts = opts.clone();
+    xxhash_opts.checksum_algorithm = ChecksumAlgorithm::XxHash64;
+    let xxhash_data = build_table_data(kv_pairs.clone(), xxhash_opts.clone());
+    assert_ne!(crc_data, xxhash_data);
+
+    // Algorithms are read from tables instead of options.
+    for (data, opts) in [(crc_data, xxhash_opts), (xxhash_data, opts)] {
+        let table = Table::open_in_memory(data, 1, opts).unwrap();
+        table.verify_checksum().unwrap();
+        let mut it = table.new_iterator(0);
+        it.rewind();
+        for (k, v) in &kv_pairs {
+            assert!(it.valid());
+            assert_eq!(user_key(it.key()), &k[..]);
+            assert_eq!(&it.value().value, v);
+            it.next();
+        }
+        assert!(!it.valid());
+    }
+}
+
+#[test]
+fn test_table_compression() {
+    let mut opts = get_test_table_options();
+    opts.checksum_mode = ChecksumVerificationMode::NoVerification;
+    let kv_pairs: Vec<_> = (0..1000)
+        .map(|i| (key(b"k", i), Bytes::from(vec![b'a' + (i % 26) as u8; 100])))
+        .collect();
+    let plain = build_table_data(kv_pairs.clone(), opts.clone());
+
+    for compression in [Compression::Snappy, Compression::Zstd { level: 3 }] {
+        let mut compressed_opts = opts.clone();
+        compressed_opts.compression = compression;
+        let data = build_table_data(kv_pairs.clone(), compressed_opts);
+        assert!(data.len() < plain.len(), "{:?}", compression);
+
+        // Compression types are read from blocks instead of options.
+        let table = Table::open_in_memory(data.clone(), 1, opts.clone()).unwrap();
+        table.verify_checksum().unwrap();
+        let mut it = table.new_iterator(0);
+        it.rewind();
+        for (k, v) in &kv_pairs {
+            assert!(it.valid());
+            assert_eq!(user_key(it.key()), &k[..]);
+            assert_eq!(&it.value().value, v);
+            it.next();
+        }
+        assert!(!it.valid());
+
+        // Compressed blocks are verified before decompressing, regardless
+        // of checksum mode.
+        let mut corrupted = data.to_vec();
+        corrupted[0] ^= 1;
+        let table = Table::open_in_memory(Bytes::from(corrupted), 1, opts.clone()).unwrap();
+        let err = table.block(0, false).err().unwrap();
+        assert!(
+            matches!(err.root_cause(), Error::InvalidChecksum(_)),
+            "{:?}",
+            err
+        );
+    }
+
+    // Blocks which don't shrink are stored uncompressed.
+    let kv_pairs = generate_table_data(b"k", 10, opts.clone());
+    let plain = build_table_data(kv_pairs.clone(), opts.clone());
+    let plain = Table::open_in_memory(plain, 1, opts.clone()).unwrap();
+    opts.compression = Compression::Snappy;
+    let data = build_table_data(kv_pairs, opts.clone());
+    let table = Table::open_in_memory(data, 1, opts).unwrap();
+    // The compression type is appended to the only block.
+    assert_eq!(table.offsets_length(), 1);
+    assert_eq!(
+        table.offsets(0).unwrap().len,
+        plain.offsets(0).unwrap().len + 1
+    );
+    assert_eq!(table.key_count(), 10);
+}
+
+#[test]
+fn test_corrupted_table() {
+    let mut opts = get_test_table_options();
+    opts.block_size = 256;
+    opts.checksum_mode = ChecksumVerificationMode::NoVerification;
+    let kv_pairs = generate_table_data(b"k", 100, opts.clone());
+    let table_data = build_table_data(kv_pairs, opts.clone());
+
+    let read_all = |data: Bytes| -> Result<()> {
+        let table = Table::open_in_memory(data, 1, opts.clone())?;
+        let mut it = table.new_iterator(0);
+        it.rewind();
+        while it.valid() {
+            it.value();
+            it.next();
+        }
+        match it.error() {
+            Some(IteratorError::Error(e)) => Err(Error::TableRead(e.clone())),
+            _ => Ok(()),
+        }
+    };
+    read_all(table_data.clone()).unwrap();
+
+    // Corrupted tables must be rejected without panicking.
+    for len in 0..table_data.len() {
+        assert!(read_all(table_data.slice(..len)).is_err());
+    }
+    let mut data = table_data.to_vec();
+    for i in 0..data.len() {
+        data[i] ^= 0xff;
+        let _ = read_all(Bytes::copy_from_slice(&data));
+        data[i] ^= 0xff;
+    }
+}
+
 fn test_iterator_error_eof() {
     let opts = get_test_table_options();
     let table = build_test_table(b"key", 10000, opts);
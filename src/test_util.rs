@@ -0,0 +1,219 @@
+//! Helpers for tests of crates built on top of agatedb, enabled by the
+//! `test-util` feature.
+
+use crate::format::key_with_ts;
+use crate::opt::Options as TableOptions;
+use crate::table::builder::Builder;
+use crate::{Agate, Error, Result, Table, Value};
+
+use bytes::Bytes;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempdir::TempDir;
+
+/// Number of keys written by a transaction in `write_kvs`.
+const KEYS_PER_TXN: usize = 100;
+const TABLE_FILENAME: &str = "1.sst";
+
+/// Close `agate`, waiting for all its background work to finish, and open
+/// the same directory again with the same options. Mutable memtable is not
+/// flushed, so its WAL is replayed the same way as restarting after a clean
+/// shutdown. In-memory databases are reopened empty.
+///
+/// Fails if transactions or bulk loaders created from `agate` are still
+/// alive, as they keep the directory locked.
+pub fn reopen(agate: Agate) -> Result<Agate> {
+    let core = agate.core.clone();
+    let opts = core.opts().clone();
+    agate.close(false)?;
+    if Arc::strong_count(&core) > 1 {
+        return Err(Error::CustomError(
+            "database is still referenced by transactions or bulk loaders".to_string(),
+        ));
+    }
+    // Dropping the last reference releases the directory lock.
+    drop(core);
+    let dir = opts.dir.clone();
+    Agate::open(opts, dir)
+}
+
+/// Key `i` of a dataset, which sorts in the order of `i`.
+pub fn key(prefix: &str, i: usize) -> Bytes {
+    Bytes::from(format!("{}{:08}", prefix, i))
+}
+
+/// Value of key `i` of a dataset, which is `value_size` bytes at least.
+pub fn value(i: usize, value_size: usize) -> Bytes {
+    let mut value = format!("value{:08}", i).into_bytes();
+    let len = value.len().max(value_size);
+    value.resize(len, b'.');
+    Bytes::from(value)
+}
+
+/// Generate `n` key-value pairs sorted by keys, see `key` and `value`.
+pub fn generate_kvs(prefix: &str, n: usize, value_size: usize) -> Vec<(Bytes, Bytes)> {
+    (0..n)
+        .map(|i| (key(prefix, i), value(i, value_size)))
+        .collect()
+}
+
+/// Write `kvs` to `agate` with transactions.
+pub fn write_kvs(agate: &Agate, kvs: &[(Bytes, Bytes)]) -> Result<()> {
+    for batch in kvs.chunks(KEYS_PER_TXN) {
+        let mut txn = agate.new_transaction(true);
+        for (key, value) in batch {
+            txn.set(key.clone(), value.clone())?;
+        }
+        txn.commit()?;
+    }
+    Ok(())
+}
+
+/// Latest versions of all keys visible to a new snapshot of `agate`, in the
+/// order of keys.
+pub fn collect_kvs(agate: &Agate) -> Result<Vec<(Bytes, Bytes)>> {
+    let mut kvs = vec![];
+    agate.snapshot().scan(b"", b"", |item| {
+        kvs.push((item.key().clone(), item.value().clone()));
+        Ok(true)
+    })?;
+    Ok(kvs)
+}
+
+/// Assert that `lhs` and `rhs` contain the same keys and values, see
+/// `collect_kvs`. Versions and metadata are not compared.
+pub fn assert_db_eq(lhs: &Agate, rhs: &Agate) {
+    let lhs = collect_kvs(lhs).unwrap();
+    let rhs = collect_kvs(rhs).unwrap();
+    for (i, (l, r)) in lhs.iter().zip(&rhs).enumerate() {
+        assert_eq!(l, r, "databases differ at key #{}", i);
+    }
+    assert_eq!(
+        lhs.len(),
+        rhs.len(),
+        "databases have different number of keys"
+    );
+}
+
+/// A table in a temporary directory, which is removed after the table is
+/// dropped.
+pub struct TempTable {
+    // Fields are dropped in order, so the table is closed before the
+    // directory is removed.
+    table: Table,
+    dir: TempDir,
+}
+
+impl TempTable {
+    /// Path of the table file.
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().join(TABLE_FILENAME)
+    }
+}
+
+impl Deref for TempTable {
+    type Target = Table;
+
+    fn deref(&self) -> &Table {
+        &self.table
+    }
+}
+
+/// Build a table of `kvs`, which must be sorted by keys, in a temporary
+/// directory. Keys are written with timestamp 0.
+pub fn build_table(kvs: &[(Bytes, Bytes)], opts: TableOptions) -> Result<TempTable> {
+    let dir = TempDir::new("agatedb")?;
+    let mut builder = Builder::new(opts.clone());
+    for (key, value) in kvs {
+        builder.add(&key_with_ts(&key[..], 0), Value::new(value.clone()), 0);
+    }
+    let table = Table::create(&dir.path().join(TABLE_FILENAME), builder.finish(), opts)?;
+    Ok(TempTable { table, dir })
+}
+
+/// Flip all bits of `len` bytes at `pos` of the file at `path`, like
+/// `SeekFrom::End(-4)` for the checksum length in the footer of a table.
+pub fn corrupt_file(path: &Path, pos: SeekFrom, len: usize) -> Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let offset = file.seek(pos)?;
+    let mut buf = vec![0; len];
+    file.read_exact(&mut buf)?;
+    for b in &mut buf {
+        *b = !*b;
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AgateOptions;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn test_reopen() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from("key"), Bytes::from("value")).unwrap();
+        txn.commit().unwrap();
+
+        let agate = reopen(agate).unwrap();
+        let txn = agate.new_transaction(false);
+        assert_eq!(
+            txn.get(&Bytes::from("key")).unwrap().value(),
+            &Bytes::from("value")
+        );
+
+        // The transaction keeps the closed database, so the directory is
+        // still locked.
+        assert!(matches!(reopen(agate), Err(Error::CustomError(_))));
+        drop(txn);
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        reopen(agate).unwrap();
+    }
+
+    #[test]
+    fn test_dataset() {
+        let kvs = generate_kvs("key", 1000, 100);
+        assert!(kvs.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(kvs.iter().all(|(_, v)| v.len() == 100));
+
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        write_kvs(&agate, &kvs).unwrap();
+        assert_eq!(collect_kvs(&agate).unwrap(), kvs);
+        let agate = reopen(agate).unwrap();
+
+        let other_dir = TempDir::new("agatedb").unwrap();
+        let other = Agate::open(AgateOptions::default(), other_dir.path()).unwrap();
+        write_kvs(&other, &kvs[..999]).unwrap();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| assert_db_eq(&agate, &other)));
+        assert!(res.is_err());
+        write_kvs(&other, &kvs[999..]).unwrap();
+        assert_db_eq(&agate, &other);
+    }
+
+    #[test]
+    fn test_build_and_corrupt_table() {
+        let kvs = generate_kvs("key", 100, 0);
+        let table = build_table(&kvs, TableOptions::default()).unwrap();
+        assert_eq!(table.key_count(), 100);
+        let path = table.path();
+        let open = || {
+            let data = Bytes::from(std::fs::read(&path).unwrap());
+            Table::open_in_memory(data, 1, TableOptions::default())
+        };
+
+        corrupt_file(&path, SeekFrom::End(-4), 4).unwrap();
+        assert!(open().is_err());
+        corrupt_file(&path, SeekFrom::End(-4), 4).unwrap();
+        assert_eq!(open().unwrap().biggest(), table.biggest());
+    }
+}
@@ -1,74 +1,94 @@
-pub use skiplist::FixedLengthSuffixComparator as Comparator;
-pub use skiplist::{FixedLengthSuffixComparator, KeyComparator};
+pub use skiplist::KeyComparator;
 
 use crate::format::user_key;
-use crate::Result;
 
-use std::fs::File;
-use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{cmp, ptr};
 
-pub static COMPARATOR: FixedLengthSuffixComparator = make_comparator();
-
-pub const fn make_comparator() -> FixedLengthSuffixComparator {
-    FixedLengthSuffixComparator::new(8)
-}
-
-unsafe fn u64(ptr: *const u8) -> u64 {
-    ptr::read_unaligned(ptr as *const u64)
-}
-
-unsafe fn u32(ptr: *const u8) -> u32 {
-    ptr::read_unaligned(ptr as *const u32)
+#[inline]
+unsafe fn u64_le(ptr: *const u8) -> u64 {
+    u64::from_le(ptr::read_unaligned(ptr as *const u64))
 }
 
+/// Get length of the common prefix of `a` and `b`. Keys are compared 16
+/// bytes at a time with SSE2 on x86_64, and 8 bytes at a time otherwise, so
+/// long keys sharing long prefixes, which are common in sorted tables, are
+/// cheap to diff.
 #[inline]
-pub fn bytes_diff<'a, 'b>(base: &'a [u8], target: &'b [u8]) -> &'b [u8] {
-    let end = cmp::min(base.len(), target.len());
+pub fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    let end = cmp::min(a.len(), b.len());
     let mut i = 0;
     unsafe {
-        while i + 8 <= end {
-            if u64(base.as_ptr().add(i)) != u64(target.as_ptr().add(i)) {
-                break;
+        #[cfg(target_arch = "x86_64")]
+        {
+            use std::arch::x86_64::*;
+
+            while i + 16 <= end {
+                let x = _mm_loadu_si128(a.as_ptr().add(i) as *const __m128i);
+                let y = _mm_loadu_si128(b.as_ptr().add(i) as *const __m128i);
+                // Bit n is set if byte n is equal.
+                let mask = _mm_movemask_epi8(_mm_cmpeq_epi8(x, y)) as u32;
+                if mask != 0xffff {
+                    return i + (!mask).trailing_zeros() as usize;
+                }
+                i += 16;
             }
-            i += 8;
         }
-        if i + 4 <= end {
-            if u32(base.as_ptr().add(i)) == u32(target.as_ptr().add(i)) {
-                i += 4;
+        while i + 8 <= end {
+            // The lowest byte is the first one in little endian.
+            let x = u64_le(a.as_ptr().add(i)) ^ u64_le(b.as_ptr().add(i));
+            if x != 0 {
+                return i + x.trailing_zeros() as usize / 8;
             }
+            i += 8;
         }
         while i < end {
-            if base.get_unchecked(i) != target.get_unchecked(i) {
-                return target.get_unchecked(i..);
+            if a.get_unchecked(i) != b.get_unchecked(i) {
+                return i;
             }
             i += 1;
         }
-        target.get_unchecked(end..)
     }
+    end
 }
 
-/// simple rewrite of golang sort.Search
+/// Get the part of `target` after its common prefix with `base`.
+#[inline]
+pub fn bytes_diff<'a, 'b>(base: &'a [u8], target: &'b [u8]) -> &'b [u8] {
+    &target[common_prefix_len(base, target)..]
+}
+
+/// Find the smallest index in `[0, n)` at which `f` is true, or `n` if
+/// there is none, like golang sort.Search. `f` must be false for a prefix
+/// of the range and true for the rest.
+///
+/// The search range is halved without branching on `f`, so that it compiles
+/// to conditional moves, and seeks don't pay for mispredicted branches.
 pub fn search<F>(n: usize, mut f: F) -> usize
 where
     F: FnMut(usize) -> bool,
 {
-    let mut i = 0;
-    let mut j = n;
-    while i < j {
-        let h = (i + j) / 2;
-        if !f(h) {
-            i = h + 1;
-        } else {
-            j = h;
-        }
+    if n == 0 {
+        return 0;
+    }
+    // `f` is false for all indices before `base`.
+    let mut base = 0;
+    let mut size = n;
+    while size > 1 {
+        let half = size / 2;
+        let mid = base + half;
+        base = if f(mid) { base } else { mid };
+        size -= half;
     }
-    i
+    base + !f(base) as usize
 }
 
-pub fn sync_dir(path: &impl AsRef<Path>) -> Result<()> {
-    File::open(path.as_ref())?.sync_all()?;
-    Ok(())
+/// Get current unix timestamp in seconds.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
 pub fn same_key(a: &[u8], b: &[u8]) -> bool {
@@ -77,3 +97,35 @@ pub fn same_key(a: &[u8], b: &[u8]) -> bool {
     }
     return user_key(a) == user_key(b);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_common_prefix_len() {
+        let base: Vec<u8> = (0..100).collect();
+        for len in 0..base.len() {
+            for diff in 0..=len {
+                let mut target = base[..len].to_vec();
+                if diff < len {
+                    target[diff] ^= 0x80;
+                }
+                assert_eq!(common_prefix_len(&base, &target), diff);
+                assert_eq!(common_prefix_len(&target, &base), diff);
+                assert_eq!(bytes_diff(&base, &target), &target[diff..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        for n in 0..40 {
+            let data: Vec<usize> = (0..n).map(|i| i * 2).collect();
+            for target in 0..n * 2 + 2 {
+                let expected = data.partition_point(|x| *x < target);
+                assert_eq!(search(n, |i| data[i] >= target), expected);
+            }
+        }
+    }
+}
@@ -1,15 +1,20 @@
+use crate::checksum;
 use crate::entry::Entry;
 use crate::entry::EntryRef;
 use crate::wal::Header;
 use crate::{Error, Result};
-use bytes::{BufMut, Bytes, BytesMut};
-use std::io::{Cursor, Read};
-use std::mem::MaybeUninit;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io::{self, Cursor, Read};
+use std::time::Instant;
 
 pub const VALUE_DELETE: u8 = 1 << 0;
 pub const VALUE_POINTER: u8 = 1 << 1;
 pub const VALUE_DISCARD_EARLIER_VERSIONS: u8 = 1 << 2;
 pub const VALUE_MERGE_ENTRY: u8 = 1 << 3;
+/// Set in encoded `meta` if a varint `meta2` follows `user_meta`. It's only
+/// used in encoding, and never set in `meta` of decoded values or entries.
+/// New flags should be added to `meta2`, as there are few bits left in `meta`.
+pub const VALUE_META2: u8 = 1 << 4;
 pub const VALUE_TXN: u8 = 1 << 6;
 pub const VALUE_FIN_TXN: u8 = 1 << 7;
 
@@ -17,6 +22,8 @@ pub const VALUE_FIN_TXN: u8 = 1 << 7;
 #[derive(Default, Debug, Clone)]
 pub struct Value {
     pub meta: u8,
+    /// Extended flags, only encoded if not zero.
+    pub meta2: u64,
     pub user_meta: u8,
     pub expires_at: u64,
     pub value: Bytes,
@@ -33,7 +40,7 @@ impl Into<Bytes> for Value {
 }
 
 #[inline]
-fn var_size(n: u64) -> usize {
+pub(crate) fn var_size(n: u64) -> usize {
     if n >= (1 << 28) {
         if n < (1 << 35) {
             return 5;
@@ -59,33 +66,33 @@ fn var_size(n: u64) -> usize {
     1
 }
 
-fn decode_var(bytes: &[u8]) -> (u64, usize) {
-    if !bytes.is_empty() && bytes[0] == 0 {
-        return (0, 1);
-    }
+/// Decode a varint, or returns `None` if `bytes` is truncated or corrupted.
+fn try_decode_var(bytes: &[u8]) -> Option<(u64, usize)> {
     let mut ans = 0;
-    let mut index = 0;
-    while index > bytes.len() && index <= 9 {
-        ans |= (bytes[index] as u64) << (index * 7);
-        index += 1;
-    }
-    if index > 0 && index <= 9 {
-        return (ans, index);
+    for (index, b) in bytes.iter().take(10).enumerate() {
+        ans |= ((b & 0x7f) as u64) << (index * 7);
+        if b & 0x80 == 0 {
+            return Some((ans, index + 1));
+        }
     }
-    if index == 10 && (bytes[index] == 0 || bytes[index] == 1) {
-        return (ans, index);
+    None
+}
+
+fn decode_var(bytes: &[u8]) -> (u64, usize) {
+    match try_decode_var(bytes) {
+        Some(res) => res,
+        None => panic!("data is truncated or corrupted {:?}", bytes),
     }
-    panic!("data is truncated or corrupted {:?}", &bytes[..index]);
 }
 
 fn encode_var(bytes: &mut [u8], mut data: u64) -> usize {
     let mut i = 0;
-    while data >= 0x128 && i < bytes.len() {
-        bytes[i] = data as u8 & 0x7f;
+    while data >= 0x80 && i < bytes.len() {
+        bytes[i] = data as u8 | 0x80;
         i += 1;
         data >>= 7;
     }
-    if data < 0x128 && i < bytes.len() {
+    if i < bytes.len() {
         bytes[i] = data as u8;
         return i + 1;
     }
@@ -110,34 +117,90 @@ impl Value {
     }
 
     pub fn encoded_size(&self) -> u32 {
-        let l = self.value.len() + 2;
-        if self.expires_at == 0 {
-            return l as u32 + 1;
+        let mut l = self.value.len() + 2 + var_size(self.expires_at);
+        if self.meta2 != 0 {
+            l += var_size(self.meta2);
         }
-        (l + var_size(self.expires_at)) as u32
+        l as u32
+    }
+
+    /// Check if value has expired at `now` seconds since unix epoch. Expired
+    /// values should be treated as missing.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
     }
 
+    /// Value is encoded as:
+    ///
+    /// +------+-------------------+-----------+------------+-------+
+    /// | meta | meta2 (optional)  | user_meta | expires_at | value |
+    /// +------+-------------------+-----------+------------+-------+
+    /// |  u8  |      var len      |    u8     |  var len   |       |
+    /// +------+-------------------+-----------+------------+-------+
+    ///
+    /// `meta2` only exists if `VALUE_META2` is set in `meta`.
+    ///
+    /// Panics if `bytes` is not a valid encoding, see `is_valid_encoding`.
     pub fn decode(&mut self, bytes: &Bytes) {
-        self.meta = bytes[0];
-        self.user_meta = bytes[1];
-        let res = decode_var(&bytes[2..]);
+        self.meta = bytes[0] & !VALUE_META2;
+        let mut pos = 1;
+        self.meta2 = 0;
+        if bytes[0] & VALUE_META2 != 0 {
+            let res = decode_var(&bytes[pos..]);
+            self.meta2 = res.0;
+            pos += res.1;
+        }
+        self.user_meta = bytes[pos];
+        pos += 1;
+        let res = decode_var(&bytes[pos..]);
         self.expires_at = res.0;
-        self.value = bytes.slice(res.1 + 2..);
+        self.value = bytes.slice(pos + res.1..);
+    }
+
+    /// Check if `bytes` can be decoded by `decode`, so that corrupted data
+    /// read from disk is reported as errors instead of panics.
+    pub(crate) fn is_valid_encoding(bytes: &[u8]) -> bool {
+        let mut pos = 1;
+        match bytes.first() {
+            Some(meta) if meta & VALUE_META2 != 0 => match try_decode_var(&bytes[pos..]) {
+                Some((_, len)) => pos += len,
+                None => return false,
+            },
+            Some(_) => {}
+            None => return false,
+        }
+        // user_meta
+        pos += 1;
+        pos <= bytes.len() && try_decode_var(&bytes[pos..]).is_some()
     }
 
     pub fn encode(&self, buf: &mut BytesMut) {
-        let mut arr: [u8; 12] = unsafe { MaybeUninit::uninit().assume_init() };
-        arr[0] = self.meta;
-        arr[1] = self.user_meta;
-        let written = encode_var(&mut arr[2..], self.expires_at);
-        buf.put_slice(&arr[..written + 2]);
+        let mut arr = [0; 22];
+        arr[0] = self.meta & !VALUE_META2;
+        let mut pos = 1;
+        if self.meta2 != 0 {
+            arr[0] |= VALUE_META2;
+            pos += encode_var(&mut arr[pos..], self.meta2);
+        }
+        arr[pos] = self.user_meta;
+        pos += 1;
+        pos += encode_var(&mut arr[pos..], self.expires_at);
+        buf.put_slice(&arr[..pos]);
         buf.put_slice(&self.value);
     }
 }
 
+/// Callback which receives the result of a write request.
+pub type WriteCallback = Box<dyn FnOnce(Result<()>) + Send>;
+
 /// A request contains multiple entries to be written into LSM tree.
 pub struct Request {
     pub entries: Vec<Entry>,
+    /// Give up with `Error::WriteStalled` if there's still no room for write
+    /// at this moment.
+    pub deadline: Option<Instant>,
+    /// `done` is called with result of the write if it's set.
+    pub done: Option<WriteCallback>,
 }
 
 /// `ValuePointer` records the position of value saved in value log.
@@ -167,26 +230,100 @@ impl EntryReader {
         }
     }
 
-    /// Entry returns header, key and value.
+    /// Entry returns header, key and value, after verifying the checksum
+    /// following them.
     pub fn entry(&mut self, reader: &mut Cursor<&[u8]>) -> Result<EntryRef> {
+        let start = reader.position() as usize;
         self.header.decode(reader)?;
+        // Garbage at the tail of WAL may decode as a huge key length, which
+        // ends replay like other undecodable entries.
         if self.header.key_len > (1 << 16) {
-            return Err(Error::LogRead(
-                "key length must not be larger than 1 << 16".to_string(),
+            return Err(Error::VarDecode(
+                "key length must not be larger than 1 << 16",
             ));
         }
+        // Lengths of corrupted entries may be huge, check them before
+        // allocating buffers.
+        let remaining = reader.get_ref().len() as u64 - reader.position();
+        let kv_len = self.header.key_len as u64 + self.header.value_len as u64;
+        if kv_len + crate::wal::CRC_SIZE as u64 > remaining {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
         // TODO: resize key and value without initialization
         self.key.resize(self.header.key_len as usize, 0);
         reader.read_exact(&mut self.key)?;
         self.value.resize(self.header.value_len as usize, 0);
         reader.read_exact(&mut self.value)?;
+        let end = reader.position() as usize;
+        let crc = reader.get_u32();
+        if checksum::crc32c(&reader.get_ref()[start..end]) != crc {
+            return Err(Error::InvalidChecksum(format!(
+                "entry checksum mismatch at offset {}",
+                start
+            )));
+        }
         Ok(EntryRef {
             key: &self.key,
             value: &self.value,
             meta: self.header.meta,
+            meta2: self.header.meta2,
             user_meta: self.header.user_meta,
             expires_at: self.header.expires_at,
             version: 0,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_var_encode_decode() {
+        let mut buf = [0; 10];
+        for n in [0, 1, 127, 128, 300, 1 << 35, u64::MAX - 1, u64::MAX].iter() {
+            let written = encode_var(&mut buf, *n);
+            assert_eq!(written, var_size(*n), "{}", n);
+            assert_eq!(decode_var(&buf[..written]), (*n, written), "{}", n);
+        }
+    }
+
+    #[test]
+    fn test_value_encode_decode() {
+        let mut value = Value::new_with_meta(Bytes::from("value"), 1, 2);
+        value.expires_at = 1 << 40;
+        let mut buf = BytesMut::new();
+        value.encode(&mut buf);
+        assert_eq!(buf.len(), value.encoded_size() as usize);
+
+        let mut decoded = Value::default();
+        decoded.decode(&buf.freeze());
+        assert_eq!(decoded.meta, 1);
+        assert_eq!(decoded.user_meta, 2);
+        assert_eq!(decoded.expires_at, 1 << 40);
+        assert_eq!(decoded.value, "value");
+        assert_eq!(decoded.meta2, 0);
+
+        value.meta2 = 1 << 20;
+        let mut buf = BytesMut::new();
+        value.encode(&mut buf);
+        assert_eq!(buf.len(), value.encoded_size() as usize);
+        assert_eq!(buf[0], 1 | VALUE_META2);
+        let mut decoded = Value::default();
+        decoded.decode(&buf.freeze());
+        assert_eq!(decoded.meta, 1);
+        assert_eq!(decoded.meta2, 1 << 20);
+        assert_eq!(decoded.user_meta, 2);
+        assert_eq!(decoded.expires_at, 1 << 40);
+        assert_eq!(decoded.value, "value");
+
+        let mut buf = BytesMut::new();
+        value.encode(&mut buf);
+        assert!(Value::is_valid_encoding(&buf));
+        // Truncated within meta2 or expires_at.
+        let header_len = buf.len() - value.value.len();
+        for len in [0, 2, header_len - 1] {
+            assert!(!Value::is_valid_encoding(&buf[..len]), "{}", len);
+        }
+    }
+}
@@ -1,17 +1,20 @@
+use crate::buffer_pool;
+use crate::checksum;
 use crate::entry::{Entry, EntryRef};
-use crate::util::sync_dir;
-use crate::value::{EntryReader, ValuePointer};
+use crate::env::MappedFile;
+use crate::value::{EntryReader, ValuePointer, VALUE_META2, VALUE_TXN};
 use crate::AgateOptions;
 use crate::Error;
 use crate::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use memmap::{MmapMut, MmapOptions};
 use prost::{decode_length_delimiter, encode_length_delimiter, length_delimiter_len};
-use std::fs::{File, OpenOptions};
 use std::io::Cursor;
 use std::path::PathBuf;
 
-pub const MAX_HEADER_SIZE: usize = 21;
+pub const MAX_HEADER_SIZE: usize = 31;
+
+/// Size of the CRC32 checksum following every entry.
+pub const CRC_SIZE: usize = 4;
 
 /// `Header` stores metadata of an entry in WAL and in value log.
 #[derive(Default, Debug, PartialEq)]
@@ -24,6 +27,8 @@ pub struct Header {
     pub expires_at: u64,
     /// metadata
     pub(crate) meta: u8,
+    /// extended metadata, only encoded if not zero
+    pub(crate) meta2: u64,
     /// user metadata
     pub user_meta: u8,
 }
@@ -31,7 +36,13 @@ pub struct Header {
 impl Header {
     /// Get length of header if being encoded
     pub fn encoded_len(&self) -> usize {
+        let meta2_len = if self.meta2 != 0 {
+            length_delimiter_len(self.meta2 as usize)
+        } else {
+            0
+        };
         1 + 1
+            + meta2_len
             + length_delimiter_len(self.expires_at as usize)
             + length_delimiter_len(self.key_len as usize)
             + length_delimiter_len(self.value_len as usize)
@@ -40,18 +51,24 @@ impl Header {
     /// Encode header into bytes
     ///
     /// Header consists of a variable-size key length, variable-size value length,
-    /// and fixed-size `expires_at`, `meta`, and `user_meta`.
+    /// and fixed-size `expires_at`, `meta`, and `user_meta`. A variable-size
+    /// `meta2` follows `meta` if `VALUE_META2` is set.
     ///
-    /// +------+-----------+---------+-----------+-----------+
-    /// | meta | user_meta | key_len | value_len | expires_at|
-    /// +----------------------------------------------------+
-    /// | u64  |    u64    | var len |  var len  |    u64    |
-    /// +------+-----------+---------+-----------+-----------+
+    /// +------+-------------------+-----------+---------+-----------+-----------+
+    /// | meta | meta2 (optional)  | user_meta | key_len | value_len | expires_at|
+    /// +------------------------------------------------------------------------+
+    /// | u64  |      var len      |    u64    | var len |  var len  |    u64    |
+    /// +------+-------------------+-----------+---------+-----------+-----------+
     pub fn encode(&self, bytes: &mut BytesMut) {
         let encoded_len = self.encoded_len();
         bytes.reserve(encoded_len);
 
-        bytes.put_u8(self.meta);
+        if self.meta2 != 0 {
+            bytes.put_u8(self.meta | VALUE_META2);
+            encode_length_delimiter(self.meta2 as usize, bytes).unwrap();
+        } else {
+            bytes.put_u8(self.meta & !VALUE_META2);
+        }
         bytes.put_u8(self.user_meta);
         encode_length_delimiter(self.key_len as usize, bytes).unwrap();
         encode_length_delimiter(self.value_len as usize, bytes).unwrap();
@@ -63,7 +80,15 @@ impl Header {
         if bytes.remaining() <= 2 {
             return Err(Error::VarDecode("should be at least 2 bytes"));
         }
-        self.meta = bytes.get_u8();
+        let meta = bytes.get_u8();
+        self.meta = meta & !VALUE_META2;
+        self.meta2 = 0;
+        if meta & VALUE_META2 != 0 {
+            self.meta2 = decode_length_delimiter(&mut bytes)? as u64;
+            if !bytes.has_remaining() {
+                return Err(Error::VarDecode("user_meta is missing"));
+            }
+        }
         self.user_meta = bytes.get_u8();
         self.key_len = decode_length_delimiter(&mut bytes)? as u32;
         self.value_len = decode_length_delimiter(&mut bytes)? as u32;
@@ -72,15 +97,24 @@ impl Header {
     }
 }
 
+/// Upper bound of bytes taken by `entries` in WAL.
+pub(crate) fn encoded_size(entries: &[Entry]) -> usize {
+    entries
+        .iter()
+        .map(|e| MAX_HEADER_SIZE + e.key.len() + e.value.len() + CRC_SIZE)
+        .sum()
+}
+
 /// WAL of a memtable or a value log
 ///
-/// TODO: This WAL simply stores key-value pair in sequence without checksum,
-/// encryption and compression. These will be done later.
+/// Every entry is followed by a checksum, so that garbage left by a crash is
+/// detected on replay.
+/// TODO: This WAL stores key-value pair without encryption and compression.
+/// These will be done later.
 /// TODO: delete WAL file when reference to WAL (or memtable) comes to 0
 pub struct Wal {
     path: PathBuf,
-    file: File,
-    mmap_file: MmapMut,
+    mmap_file: Box<dyn MappedFile>,
     opts: AgateOptions,
     write_at: u32,
     buf: BytesMut,
@@ -90,30 +124,9 @@ pub struct Wal {
 impl Wal {
     /// open or create a WAL from options
     pub fn open(path: PathBuf, opts: AgateOptions) -> Result<Wal> {
-        let (file, bootstrap) = if path.exists() {
-            (
-                OpenOptions::new()
-                    .create(false)
-                    .read(true)
-                    .write(true)
-                    .open(&path)?,
-                false,
-            )
-        } else {
-            let file = OpenOptions::new()
-                .create_new(true)
-                .read(true)
-                .write(true)
-                .open(&path)?;
-            file.set_len(2 * opts.value_log_file_size)?;
-            file.sync_all()?;
-            sync_dir(&path.parent().unwrap())?;
-            (file, true)
-        };
-        let mmap_file = unsafe { MmapOptions::new().map_mut(&file)? };
+        let (mmap_file, bootstrap) = opts.env.open_mapped(&path, 2 * opts.value_log_file_size)?;
         let mut wal = Wal {
             path,
-            file,
             size: mmap_file.len() as u32,
             mmap_file,
             opts,
@@ -138,18 +151,51 @@ impl Wal {
     }
 
     pub(crate) fn write_entry(&mut self, entry: &Entry) -> Result<()> {
+        self.write_entries(std::slice::from_ref(entry))?;
+        Ok(())
+    }
+
+    /// Encode all entries into buffer and write them to WAL at once.
+    /// Returns the number of bytes written.
+    pub(crate) fn write_entries(&mut self, entries: &[Entry]) -> Result<usize> {
+        if self.buf.capacity() == 0 {
+            // Reuse the buffer released by WAL of an older memtable.
+            self.buf = buffer_pool::take(encoded_size(entries));
+        }
         self.buf.clear();
-        Self::encode_entry(&mut self.buf, entry);
+        for entry in entries {
+            Self::encode_entry(&mut self.buf, entry);
+        }
+        // Header of the next entry is zeroed after the batch.
+        let room = self.mmap_file.len() - self.write_at as usize - MAX_HEADER_SIZE;
+        if self.buf.len() > room {
+            return Err(Error::TooLong {
+                what: "WAL batch",
+                len: self.buf.len(),
+                limit: room,
+            });
+        }
         self.mmap_file[self.write_at as usize..self.write_at as usize + self.buf.len()]
             .clone_from_slice(&self.buf[..]);
         self.write_at += self.buf.len() as u32;
         self.zero_next_entry()?;
-        Ok(())
+        Ok(self.buf.len())
+    }
+
+    /// Capacity of the buffer used for encoding entries, which grows to the
+    /// size of the largest batch.
+    pub(crate) fn buffer_size(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Give the encoding buffer back to the buffer pool. Later writes
+    /// allocate a new one.
+    pub(crate) fn release_buffer(&mut self) {
+        buffer_pool::give_back(std::mem::take(&mut self.buf));
     }
 
     pub fn sync(&mut self) -> Result<()> {
-        self.mmap_file.flush()?;
-        Ok(())
+        self.mmap_file.flush()
     }
 
     pub fn zero_next_entry(&mut self) -> Result<()> {
@@ -163,20 +209,23 @@ impl Wal {
 
     /// Encode entry to buffer
     ///
-    /// The entry is encoded to a header followed by plain key and value.
-    /// +--------+-----+-------+
-    /// | header | key | value |
-    /// +--------+-----+-------+
+    /// The entry is encoded to a header followed by plain key and value, and
+    /// a big-endian CRC32 (Castagnoli) of all of them.
+    /// +--------+-----+-------+-------+
+    /// | header | key | value | crc32 |
+    /// +--------+-----+-------+-------+
     pub(crate) fn encode_entry(mut buf: &mut BytesMut, entry: &Entry) -> usize {
         let header = Header {
             key_len: entry.key.len() as u32,
             value_len: entry.value.len() as u32,
             expires_at: entry.expires_at,
             meta: entry.meta,
+            meta2: entry.meta2,
             user_meta: entry.user_meta,
         };
 
         // write header to buffer
+        let start = buf.len();
         header.encode(&mut buf);
 
         // write key and value to buffer
@@ -184,18 +233,32 @@ impl Wal {
         buf.extend_from_slice(&entry.key);
         buf.extend_from_slice(&entry.value);
 
-        // TODO: add CRC32 check
+        let crc = checksum::crc32c(&buf[start..]);
+        buf.put_u32(crc);
 
         return buf.len();
     }
 
     /// Decode entry from buffer
     fn decode_entry(buf: &mut Bytes) -> Result<Entry> {
+        let data = buf.clone();
         let mut header = Header::default();
         header.decode(buf)?;
+        let kv_len = header.key_len as usize + header.value_len as usize;
+        if buf.len() < kv_len + CRC_SIZE {
+            return Err(Error::VarDecode("entry is truncated"));
+        }
+        let entry_len = data.len() - buf.len() + kv_len;
+        let crc = (&buf[kv_len..]).get_u32();
+        if checksum::crc32c(&data[..entry_len]) != crc {
+            return Err(Error::InvalidChecksum(
+                "entry checksum mismatch".to_string(),
+            ));
+        }
         let kv = buf;
         Ok(Entry {
             meta: header.meta,
+            meta2: header.meta2,
             user_meta: header.user_meta,
             expires_at: header.expires_at,
             key: kv.slice(..header.key_len as usize),
@@ -203,6 +266,7 @@ impl Wal {
                 header.key_len as usize..header.key_len as usize + header.value_len as usize,
             ),
             version: 0,
+            ttl: None,
         })
     }
 
@@ -228,20 +292,19 @@ impl Wal {
     /// Truncate WAL
     pub fn truncate(&mut self, end: u64) -> Result<()> {
         // TODO: check read only
-        let metadata = self.file.metadata()?;
-        if metadata.len() == end {
+        if self.mmap_file.file_len()? == end {
             return Ok(());
         }
         self.size = end as u32;
-        self.file.set_len(end)?;
-        self.file.sync_all()?;
+        self.mmap_file.set_len(end)?;
+        self.mmap_file.sync_all()?;
         Ok(())
     }
 
     /// Finish WAL writing
     pub(crate) fn done_writing(&mut self, offset: u32) -> Result<()> {
         if self.opts.sync_writes {
-            self.file.sync_all()?;
+            self.mmap_file.sync_all()?;
         }
         self.truncate(offset as u64)?;
         Ok(())
@@ -254,6 +317,31 @@ impl Wal {
         )))
     }
 
+    /// Close WAL and remove the file from disk.
+    pub(crate) fn close_and_remove(self) -> Result<()> {
+        let Wal {
+            path,
+            mmap_file,
+            opts,
+            ..
+        } = self;
+        drop(mmap_file);
+        opts.env.remove_file(&path)
+    }
+
+    /// Set position of next write. Used after replaying an existing WAL.
+    pub(crate) fn set_write_at(&mut self, write_at: u32) {
+        self.write_at = write_at;
+    }
+
+    /// Whether a batch of `size` bytes encoded, see `encoded_size`, can be
+    /// appended. An empty WAL always takes the batch, which fails to be
+    /// written if it's larger than the file.
+    pub(crate) fn has_room(&self, size: usize) -> bool {
+        self.write_at == 0
+            || self.write_at as usize + size + MAX_HEADER_SIZE <= self.mmap_file.len()
+    }
+
     pub fn should_flush(&self) -> bool {
         self.write_at as u64 > self.opts.value_log_file_size
     }
@@ -273,11 +361,10 @@ impl Wal {
     /// When using WAL as value log, we will need to extend or shrink actual size
     /// of WAL file from outside functions.
     pub(crate) fn set_len(&mut self, len: u64) -> Result<()> {
-        self.file.set_len(len)?;
-        Ok(())
+        self.mmap_file.set_len(len)
     }
 
-    pub(crate) fn data(&mut self) -> &mut MmapMut {
+    pub(crate) fn data(&mut self) -> &mut [u8] {
         &mut self.mmap_file
     }
 }
@@ -287,6 +374,8 @@ pub struct WalIterator<'a> {
     reader: Cursor<&'a [u8]>,
     /// `entry_reader` operates on `reader` and buffers entry information
     entry_reader: EntryReader,
+    /// end offset of last valid entry
+    valid_end: u32,
 }
 
 impl<'a> WalIterator<'a> {
@@ -294,9 +383,16 @@ impl<'a> WalIterator<'a> {
         Self {
             reader,
             entry_reader: EntryReader::new(),
+            valid_end: 0,
         }
     }
 
+    /// Get end offset of last valid entry, which is where the next
+    /// entry should be written.
+    pub fn valid_end(&self) -> u32 {
+        self.valid_end
+    }
+
     /// Get next entry from WAL
     ///
     /// This function will:
@@ -313,13 +409,19 @@ impl<'a> WalIterator<'a> {
                 if entry.is_zero() {
                     return Ok(None);
                 }
-                // TODO: process transaction-related metadata
+                // Entries of a transaction only become valid after the
+                // entry finishing the transaction is read.
+                if entry.meta & VALUE_TXN == 0 {
+                    self.valid_end = self.reader.position() as u32;
+                }
                 Ok(Some(entry))
             }
             // ignore prost varint decode error
             Err(Error::Decode(_)) => Ok(None),
             // ignore custom decode error (e.g. header <= 2)
             Err(Error::VarDecode(_)) => Ok(None),
+            // ignore entries partially written or overwritten by garbage
+            Err(Error::InvalidChecksum(_)) => Ok(None),
             // ignore file length < key, value size
             Err(Error::Io(err)) => {
                 if err.kind() == ErrorKind::UnexpectedEof {
@@ -336,6 +438,7 @@ impl<'a> WalIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::OpenOptions;
     use tempdir::TempDir;
     #[test]
     fn test_wal_create() {
@@ -353,6 +456,7 @@ mod tests {
             expires_at: std::u64::MAX - 2333333,
             user_meta: b'A',
             meta: b'B',
+            meta2: 0,
         };
 
         let mut buf = BytesMut::new();
@@ -362,6 +466,19 @@ mod tests {
         let mut new_header = Header::default();
         new_header.decode(&mut buf).unwrap();
         assert_eq!(new_header, header);
+
+        let header = Header {
+            meta2: 1 << 40,
+            ..header
+        };
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf);
+        assert_eq!(buf.len(), header.encoded_len());
+        assert_eq!(buf[0], b'B' | VALUE_META2);
+        let mut buf = buf.freeze();
+        let mut new_header = Header::default();
+        new_header.decode(&mut buf).unwrap();
+        assert_eq!(new_header, header);
     }
 
     #[test]
@@ -424,4 +541,109 @@ mod tests {
             assert!(cnt < 20);
         }
     }
+
+    #[test]
+    fn test_wal_iterator_corrupted() {
+        let mut buf = BytesMut::new();
+        for i in 0..20 {
+            let entry = Entry::new(Bytes::from(i.to_string()), Bytes::from(i.to_string()));
+            Wal::encode_entry(&mut buf, &entry);
+        }
+        // Corrupted lengths must not cause huge allocations or panics.
+        for i in 0..buf.len() {
+            let mut data = buf.to_vec();
+            data[i] = 0xff;
+            let mut it = WalIterator::new(Cursor::new(&data[..]));
+            let mut cnt = 0;
+            while let Ok(Some(_)) = it.next() {
+                cnt += 1;
+            }
+            assert!(cnt <= 20);
+        }
+    }
+
+    #[test]
+    fn test_wal_iterator_checksum() {
+        let mut buf = BytesMut::new();
+        let mut ends = vec![];
+        for i in 0..20 {
+            let entry = Entry::new(Bytes::from(i.to_string()), Bytes::from(i.to_string()));
+            ends.push(Wal::encode_entry(&mut buf, &entry));
+        }
+        // Flip the last byte of the value of the 11th entry, whose lengths
+        // are still valid.
+        let mut data = buf.to_vec();
+        data[ends[10] - CRC_SIZE - 1] ^= 1;
+        let mut it = WalIterator::new(Cursor::new(&data[..]));
+        let mut cnt = 0;
+        while let Some(entry) = it.next().unwrap() {
+            assert_eq!(entry.key, cnt.to_string().as_bytes());
+            cnt += 1;
+        }
+        assert_eq!(cnt, 10);
+        assert_eq!(it.valid_end() as usize, ends[9]);
+
+        let mut entry = buf.freeze().slice(ends[9]..ends[10]);
+        assert_eq!(Wal::decode_entry(&mut entry).unwrap().key, "10");
+        let mut entry = Bytes::copy_from_slice(&data[ends[9]..ends[10]]);
+        assert!(matches!(
+            Wal::decode_entry(&mut entry),
+            Err(Error::InvalidChecksum(_))
+        ));
+    }
+
+    #[test]
+    fn test_wal_iterator_garbage_tail() {
+        let mut buf = BytesMut::new();
+        let mut ends = vec![];
+        for i in 0..10 {
+            let entry = Entry::new(Bytes::from(i.to_string()), Bytes::from(i.to_string()));
+            ends.push(Wal::encode_entry(&mut buf, &entry));
+        }
+        // Garbage whose header has a key longer than allowed.
+        let header = Header {
+            key_len: 1 << 17,
+            value_len: 1,
+            ..Default::default()
+        };
+        header.encode(&mut buf);
+        buf.extend_from_slice(&[0xff; 64]);
+        let mut it = WalIterator::new(Cursor::new(&buf[..]));
+        let mut cnt = 0;
+        while let Some(entry) = it.next().unwrap() {
+            assert_eq!(entry.key, cnt.to_string().as_bytes());
+            cnt += 1;
+        }
+        assert_eq!(cnt, 10);
+        assert_eq!(it.valid_end() as usize, ends[9]);
+    }
+
+    #[test]
+    fn test_wal_full() {
+        let tmp_dir = TempDir::new("agatedb").unwrap();
+        let opts = AgateOptions {
+            value_log_file_size: 4096,
+            ..Default::default()
+        };
+        let mut wal = Wal::open(tmp_dir.path().join("1.wal"), opts).unwrap();
+        let entries = vec![Entry::new(Bytes::from("key"), Bytes::from(vec![0; 3000]))];
+        let size = encoded_size(&entries);
+        assert!(wal.has_room(size * 3));
+        wal.write_entries(&entries).unwrap();
+        wal.write_entries(&entries).unwrap();
+        assert!(!wal.has_room(size));
+        // A batch out of the file fails without being written.
+        let write_at = wal.write_at;
+        assert!(matches!(
+            wal.write_entries(&entries),
+            Err(Error::TooLong { .. })
+        ));
+        assert_eq!(wal.write_at, write_at);
+        let mut it = wal.iter().unwrap();
+        let mut cnt = 0;
+        while it.next().unwrap().is_some() {
+            cnt += 1;
+        }
+        assert_eq!(cnt, 2);
+    }
 }
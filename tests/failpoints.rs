@@ -0,0 +1,234 @@
+//! Crash consistency tests, which inject failures at critical ordering
+//! points and check data is still intact after reopening.
+//!
+//! Run with `cargo test --features failpoints --test failpoints`.
+#![cfg(feature = "failpoints")]
+
+use agatedb::{key_with_ts, Agate, AgateOptions, Entry, Error};
+use bytes::Bytes;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempdir::TempDir;
+
+fn options() -> AgateOptions {
+    AgateOptions::default().with_mem_table_size(1 << 20)
+}
+
+fn write_keys(agate: &Agate, start: u64, end: u64) {
+    let entries = (start..end)
+        .map(|i| {
+            Entry::new(
+                key_with_ts(format!("key{:05}", i).as_str(), i + 1),
+                Bytes::from(format!("value{:05}", i)),
+            )
+        })
+        .collect();
+    agate.write_entries(entries).unwrap();
+}
+
+fn check_keys(agate: &Agate, start: u64, end: u64) {
+    for i in start..end {
+        let key = key_with_ts(format!("key{:05}", i).as_str(), u64::MAX);
+        let value = agate.get(&key).unwrap();
+        assert_eq!(value.value, Bytes::from(format!("value{:05}", i)));
+    }
+}
+
+fn count_files(dir: &Path, ext: &str) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_str()
+                .unwrap()
+                .ends_with(ext)
+        })
+        .count()
+}
+
+#[test]
+fn test_write_after_wal() {
+    let _scenario = fail::FailScenario::setup();
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    write_keys(&agate, 0, 10);
+
+    // Write thread crashes after the batch is appended to WAL.
+    fail::cfg("write_after_wal", "panic").unwrap();
+    let entry = Entry::new(key_with_ts("key00010", 11), Bytes::from("value00010"));
+    assert!(agate.write_entries(vec![entry]).is_err());
+    fail::remove("write_after_wal");
+    drop(agate);
+
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    check_keys(&agate, 0, 11);
+}
+
+#[test]
+fn test_flush_before_manifest() {
+    let _scenario = fail::FailScenario::setup();
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    write_keys(&agate, 0, 10);
+
+    fail::cfg("flush_before_manifest", "return").unwrap();
+    assert!(matches!(
+        agate.flush_memtable(true),
+        Err(Error::FlushFailed(_))
+    ));
+    fail::remove("flush_before_manifest");
+    drop(agate);
+    // Table not recorded in manifest is removed.
+    assert_eq!(count_files(tmp_dir.path(), ".sst"), 0);
+
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    check_keys(&agate, 0, 10);
+    agate.flush_memtable(true).unwrap();
+    check_keys(&agate, 0, 10);
+}
+
+#[test]
+fn test_manifest_before_sync() {
+    let _scenario = fail::FailScenario::setup();
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    write_keys(&agate, 0, 10);
+
+    // The failed change set is dropped, so retrying the flush records the
+    // table again.
+    fail::cfg("manifest_before_sync", "1*return").unwrap();
+    agate.flush_memtable(true).unwrap();
+    fail::remove("manifest_before_sync");
+    assert_eq!(agate.tables().unwrap().len(), 1);
+    drop(agate);
+
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    assert_eq!(agate.tables().unwrap().len(), 1);
+    check_keys(&agate, 0, 10);
+}
+
+#[test]
+fn test_flush_after_add_l0() {
+    let _scenario = fail::FailScenario::setup();
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    write_keys(&agate, 0, 10);
+
+    // The retried flush doesn't add the table again.
+    fail::cfg("flush_after_add_l0", "1*return").unwrap();
+    agate.flush_memtable(true).unwrap();
+    fail::remove("flush_after_add_l0");
+    assert_eq!(agate.tables().unwrap().len(), 1);
+    drop(agate);
+
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    assert_eq!(agate.tables().unwrap().len(), 1);
+    check_keys(&agate, 0, 10);
+}
+
+#[test]
+fn test_flush_before_delete_wal() {
+    let _scenario = fail::FailScenario::setup();
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    write_keys(&agate, 0, 10);
+
+    fail::cfg("flush_before_delete_wal", "return").unwrap();
+    assert!(agate.flush_memtable(true).is_err());
+    fail::remove("flush_before_delete_wal");
+    drop(agate);
+    assert_eq!(count_files(tmp_dir.path(), ".sst"), 1);
+
+    // Data exists in both L0 and WAL, which is replayed again.
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+    check_keys(&agate, 0, 10);
+    agate.flush_memtable(true).unwrap();
+    check_keys(&agate, 0, 10);
+}
+
+#[test]
+fn test_bulk_load_before_manifest() {
+    let _scenario = fail::FailScenario::setup();
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let agate = Agate::open(options(), tmp_dir.path()).unwrap();
+
+    fail::cfg("bulk_load_before_manifest", "return").unwrap();
+    let mut loader = agate.new_bulk_loader();
+    for i in 0..10 {
+        let key = key_with_ts(format!("key{:05}", i).as_str(), i + 1);
+        let value = agatedb::Value::new(Bytes::from(format!("value{:05}", i)));
+        loader.add(key, value).unwrap();
+    }
+    assert!(loader.finish().is_err());
+    fail::remove("bulk_load_before_manifest");
+    drop(agate);
+    assert_eq!(count_files(tmp_dir.path(), ".sst"), 0);
+
+    Agate::open(options(), tmp_dir.path()).unwrap();
+}
+
+#[test]
+fn test_compaction_before_manifest() {
+    let _scenario = fail::FailScenario::setup();
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let opts = options()
+        .with_num_compactors(1)
+        .with_level_zero_tables(2, 15);
+    let agate = Agate::open(opts.clone(), tmp_dir.path()).unwrap();
+
+    // The first compaction fails after its tables are built, and the next
+    // one compacts the same tables again.
+    fail::cfg("compaction_before_manifest", "1*return").unwrap();
+    for _ in 0..2 {
+        write_keys(&agate, 0, 10);
+        agate.flush_memtable(true).unwrap();
+    }
+    for _ in 0..100 {
+        if agate.tables().unwrap().iter().any(|t| t.level > 0) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(agate.tables().unwrap().iter().any(|t| t.level > 0));
+    fail::remove("compaction_before_manifest");
+    check_keys(&agate, 0, 10);
+    drop(agate);
+
+    // Only tables recorded in manifest are left.
+    let agate = Agate::open(opts, tmp_dir.path()).unwrap();
+    check_keys(&agate, 0, 10);
+    let tables = agate.tables().unwrap();
+    assert_eq!(count_files(tmp_dir.path(), ".sst"), tables.len());
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[test]
+fn test_uring_submit() {
+    use agatedb::{Env, IoPriority, UringEnv};
+
+    let _scenario = fail::FailScenario::setup();
+    let tmp_dir = TempDir::new("agatedb").unwrap();
+    let env = UringEnv::new().unwrap();
+    let path = tmp_dir.path().join("file");
+    let data: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+    env.create_file(&path, &data, IoPriority::Foreground, false)
+        .unwrap();
+    let file = env.open_readable(&path, IoPriority::Foreground).unwrap();
+
+    fail::cfg("uring_submit", "1*return").unwrap();
+    let reads: Vec<_> = (0..100).map(|i| (i * 4000, 100)).collect();
+    assert!(file.read_batch(&reads).is_err());
+    fail::remove("uring_submit");
+
+    // Entries of the failed batch are not submitted with following reads.
+    assert_eq!(file.read_at(10, 5).unwrap(), &data[10..15]);
+    let res = file.read_batch(&reads).unwrap();
+    for (&(offset, len), bytes) in reads.iter().zip(res) {
+        assert_eq!(bytes, &data[offset..offset + len]);
+    }
+}